@@ -0,0 +1,140 @@
+//! Throughput of the limiter's hot path under synthetic event storms.
+//!
+//! Run with `cargo bench`. Each benchmark is run a few times and the fastest
+//! run is reported, to keep scheduling noise out of the baseline. No tracing
+//! is involved: the limiter runs in unit test mode, so pausing and resuming a
+//! process only moves it between queues.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use nix::unistd::Pid;
+use nix_ubw::limiter::{Limiter, LimiterOptions};
+use nix_ubw::nixutil::Environ;
+use nix_ubw::resources::{profile_for, Matcher, ProfileSpec, ResourceProfile, RuleEntry, RuleSet};
+
+/// Runs of each benchmark; the fastest is reported.
+const RUNS: usize = 5;
+
+/// PIDs of synthetic processes start here, above the default `pid_max`, so
+/// that reads of their /proc entries fail like they would for a process that
+/// is already gone.
+const FIRST_PID: i32 = 5_000_000;
+
+/// Programs of the synthetic build, in the order they are started. The
+/// untracked ones stand for the shells and coreutils around the compilers.
+const PROGRAMS: &[&str] = &[
+    "cc", "bash", "rustc", "ld", "sed", "cc", "gzip", "cc", "ar", "lto1",
+];
+
+fn main() {
+    exec_exit_storm(10_000);
+    profile_lookups("profile_for, 1000 names", &literal_rules(1000), 100_000);
+    profile_lookups("profile_for, 300 regexes", &regex_rules(300), 100_000);
+}
+
+/// Exec `count` processes into a limiter with a small budget, so most of
+/// them queue up, then let them all exit in order, resuming the queue as
+/// budget frees up.
+fn exec_exit_storm(count: i32) {
+    let args: Vec<Vec<String>> = PROGRAMS.iter().map(|&p| vec![p.to_owned()]).collect();
+    let mut admitted = 0;
+    let elapsed = fastest(|| {
+        let mut limiter = Limiter::with_options(
+            ResourceProfile::new(16, 64),
+            LimiterOptions::default(),
+            true,
+        );
+        let start = Instant::now();
+        for i in 0..count {
+            let pid = Pid::from_raw(FIRST_PID + i);
+            black_box(limiter.on_exec(pid, &args[i as usize % args.len()], &[], None));
+        }
+        for i in 0..count {
+            limiter.on_exit(Pid::from_raw(FIRST_PID + i));
+        }
+        let elapsed = start.elapsed();
+        admitted = limiter.stats().total_admitted;
+        elapsed
+    });
+    report(
+        "exec/exit storm",
+        u64::from(count.unsigned_abs()) * 2,
+        "events",
+        elapsed,
+    );
+    report("  admissions in that run", admitted, "admissions", elapsed);
+}
+
+/// `count` configured rules for one program name each, `tool<i>`.
+fn literal_rules(count: usize) -> RuleSet {
+    let mut rules = RuleSet::default();
+    for i in 0..count {
+        rules.insert(format!("tool{}", i), Some(ResourceProfile::new(1, 2)));
+    }
+    rules
+}
+
+/// `count` configured `[[rule]]` entries matching names by regular
+/// expression, `tool<i>-<version>`. Names that no pattern matches are tried
+/// against every one of them.
+fn regex_rules(count: usize) -> RuleSet {
+    let mut rules = RuleSet::default();
+    let entries = (0..count)
+        .map(|i| RuleEntry {
+            matcher: Matcher::Pattern(format!("tool{}-[0-9]+", i).parse().unwrap()),
+            ..RuleEntry::plain(
+                format!("versioned{}", i),
+                Some(ProfileSpec::from(ResourceProfile::new(1, 2))),
+            )
+        })
+        .collect();
+    rules.prepend(entries);
+    rules
+}
+
+/// Classify a mix of built-in, configured, cross-prefixed and unknown
+/// program names against `rules`. The configured names stand for a literal
+/// rule (`tool500`) and a regex one (`tool150-12`), each of which only one
+/// of the rule sets matches.
+fn profile_lookups(name: &str, rules: &RuleSet, count: usize) {
+    let total = ResourceProfile::new(16, 64);
+    let env = Environ::default();
+    let names = [
+        "cc",
+        "tool500",
+        "tool150-12",
+        "aarch64-unknown-linux-gnu-gcc",
+        "x86_64-w64-mingw32-ld",
+        "bash",
+        "coreutils",
+    ];
+    let args: Vec<Vec<String>> = names
+        .iter()
+        .map(|&name| vec![name.to_owned(), "-c".to_owned(), "foo.c".to_owned()])
+        .collect();
+    let elapsed = fastest(|| {
+        let start = Instant::now();
+        for i in 0..count {
+            black_box(profile_for(&args[i % args.len()], &env, rules, &total));
+        }
+        start.elapsed()
+    });
+    report(name, count as u64, "lookups", elapsed);
+}
+
+fn fastest(mut run: impl FnMut() -> Duration) -> Duration {
+    (0..RUNS).map(|_| run()).min().unwrap()
+}
+
+fn report(name: &str, ops: u64, unit: &str, elapsed: Duration) {
+    println!(
+        "{:<28} {:>9} {} in {:>8.2?} ({:>10.0} {}/s)",
+        name,
+        ops,
+        unit,
+        elapsed,
+        ops as f64 / elapsed.as_secs_f64(),
+        unit
+    );
+}
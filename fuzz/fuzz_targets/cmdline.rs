@@ -0,0 +1,22 @@
+//! Raw /proc/<pid>/cmdline contents through argument splitting and argv[0]
+//! resolution, as done for every traced exec.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nix_ubw::nixutil;
+
+fuzz_target!(|data: &[u8]| {
+    let args = nixutil::parse_cmdline(data);
+    // argv[0] may resolve to nothing (`foo/`, `.-wrapped`); nothing else may.
+    assert!(args.iter().skip(1).all(|arg| !arg.is_empty()));
+    assert!(args.iter().all(|arg| !arg.contains('\0')));
+    if let Some(first) = args.first() {
+        assert!(!first.contains('/'));
+        // Resolving is idempotent: whatever is left is no wrapper name.
+        let mut again = args[..1].to_vec();
+        nixutil::resolve_argv0(&mut again);
+        assert_eq!(&again[0], first);
+    }
+    let _ = nixutil::shell_join(&args);
+});
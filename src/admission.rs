@@ -0,0 +1,178 @@
+use std::cmp::Reverse;
+use std::time::Duration;
+
+use nix::unistd::Pid;
+
+use crate::resources::ResourceProfile;
+
+/// Wait after which a paused process is resumed ahead of the others under
+/// `Lifo` and `Lpt`.
+pub const MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// A paused process, as an admission policy sees it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Queued {
+    pub pid: Pid,
+    /// Uid the process is attributed to, if known.
+    pub owner: Option<u32>,
+    /// What it would claim if resumed now.
+    pub claim: ResourceProfile,
+    /// How long it has been paused.
+    pub waited: Duration,
+    /// The `[est_duration]` of its rule, if any.
+    pub est_duration: Option<Duration>,
+}
+
+/// The paused processes offered to a policy, oldest first, by their index in
+/// the limiter's queue. Processes held back by their user's share are not
+/// offered, so one user can't block the others. Entries are built as the
+/// policy looks at them, so one that only looks at the head doesn't pay for
+/// the rest of a long queue.
+pub struct Queue<'a> {
+    len: usize,
+    /// The entry at an index, `None` if it is not offered.
+    entry: &'a dyn Fn(usize) -> Option<Queued>,
+}
+
+impl<'a> Queue<'a> {
+    pub fn new(len: usize, entry: &'a dyn Fn(usize) -> Option<Queued>) -> Self {
+        Self { len, entry }
+    }
+
+    /// The entry at index `i`, if it is offered.
+    pub fn get(&self, i: usize) -> Option<Queued> {
+        (i < self.len).then(|| (self.entry)(i)).flatten()
+    }
+
+    /// The offered entries with their indices, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (usize, Queued)> + '_ {
+        (0..self.len).filter_map(|i| (self.entry)(i).map(|q| (i, q)))
+    }
+}
+
+/// Decides which paused process is resumed next when budget frees up
+/// (`--resume-order`), and whether a claim fits in the budget left.
+///
+/// On every pass over the queue the limiter resumes the process picked until
+/// the policy picks none or the one picked doesn't fit. Processes resumed are
+/// taken off the queue offered next. `[weights]` in the rules take over the
+/// order from the policy.
+pub trait AdmissionPolicy {
+    /// Whether a process claiming `claim` may start with `free` left. A
+    /// process that doesn't fit is still admitted when nothing else throttled
+    /// runs, under `--min-parallel` and by `--adaptive`.
+    fn should_admit(&self, claim: &ResourceProfile, free: &ResourceProfile) -> bool {
+        claim.has_free_resources(free)
+    }
+
+    /// Index of the offered process in `queue` to resume next, or `None` to
+    /// end the pass.
+    fn next_to_resume(&self, queue: &Queue, free: &ResourceProfile) -> Option<usize>;
+}
+
+/// The process paused longest ago, so the queue is walked front to back and
+/// stops at the first that doesn't fit.
+pub struct Fifo;
+
+impl AdmissionPolicy for Fifo {
+    fn next_to_resume(&self, queue: &Queue, _free: &ResourceProfile) -> Option<usize> {
+        queue.iter().next().map(|(i, _)| i)
+    }
+}
+
+/// The process paused most recently, whose working set is likeliest to still
+/// be in the page cache. Processes paused for longer than `MAX_WAIT` go
+/// first, oldest first, so a steady stream of new arrivals can't starve the
+/// old ones.
+pub struct Lifo;
+
+impl AdmissionPolicy for Lifo {
+    fn next_to_resume(&self, queue: &Queue, _free: &ResourceProfile) -> Option<usize> {
+        match queue.iter().next()? {
+            (i, oldest) if oldest.waited >= MAX_WAIT => Some(i),
+            _ => queue.iter().next_back().map(|(i, _)| i),
+        }
+    }
+}
+
+/// The process expected to run longest, by the `[est_duration]` of its rule,
+/// to shorten the build's critical path (longest processing time first).
+/// Processes without an estimate go after those with one, and ties go to the
+/// oldest. Processes paused for longer than `MAX_WAIT` go first, as under
+/// `Lifo`.
+pub struct Lpt;
+
+impl AdmissionPolicy for Lpt {
+    fn next_to_resume(&self, queue: &Queue, _free: &ResourceProfile) -> Option<usize> {
+        match queue.iter().next()? {
+            (i, oldest) if oldest.waited >= MAX_WAIT => Some(i),
+            _ => queue
+                .iter()
+                .min_by_key(|(i, q)| (Reverse(q.est_duration), *i))
+                .map(|(i, _)| i),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FREE: ResourceProfile = ResourceProfile::new(4, 16);
+
+    /// Queue entries of `(waited, est_duration)`, in seconds. Entries
+    /// without a wait are held back.
+    fn entries(entries: &[(Option<u64>, Option<u64>)]) -> Vec<Option<Queued>> {
+        entries
+            .iter()
+            .enumerate()
+            .map(|(i, &(waited, est))| {
+                waited.map(|waited| Queued {
+                    pid: Pid::from_raw(100 + i as i32),
+                    owner: None,
+                    claim: ResourceProfile::new(1, 1),
+                    waited: Duration::from_secs(waited),
+                    est_duration: est.map(Duration::from_secs),
+                })
+            })
+            .collect()
+    }
+
+    fn pick(policy: &dyn AdmissionPolicy, entries: &[Option<Queued>]) -> Option<usize> {
+        let entry = |i: usize| entries[i].clone();
+        policy.next_to_resume(&Queue::new(entries.len(), &entry), &FREE)
+    }
+
+    #[test]
+    fn test_should_admit() {
+        assert!(Fifo.should_admit(&ResourceProfile::new(4, 16), &FREE));
+        assert!(!Fifo.should_admit(&ResourceProfile::new(1, 17), &FREE));
+    }
+
+    #[test]
+    fn test_policies() {
+        let fresh = entries(&[
+            (Some(3), None),
+            (Some(2), Some(2)),
+            (Some(1), Some(60)),
+            (Some(0), Some(60)),
+        ]);
+        assert_eq!(pick(&Fifo, &fresh), Some(0));
+        assert_eq!(pick(&Lifo, &fresh), Some(3));
+        assert_eq!(pick(&Lpt, &fresh), Some(2));
+
+        // The oldest offered has waited too long and goes first under every
+        // policy. Held-back entries are never picked.
+        let aged = entries(&[
+            (None, Some(90)),
+            (Some(30), None),
+            (Some(2), Some(2)),
+            (None, None),
+        ]);
+        for policy in [&Fifo as &dyn AdmissionPolicy, &Lifo, &Lpt] {
+            assert_eq!(pick(policy, &aged), Some(1));
+            assert_eq!(pick(policy, &entries(&[(None, None)])), None);
+            assert_eq!(pick(policy, &[]), None);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use crate::limiter::Limiter;
+use crate::resources::ResourceProfile;
+use crate::sampler::Sampler;
+
+/// Memory assumed per job when turning free memory into a job count: that of
+/// the `c-compiler` rule, the most common throttled process.
+const MEM_GB_PER_JOB: i32 = 1;
+
+/// A recommended build parallelism, published to a file that build wrappers
+/// read to set `NIX_BUILD_CORES` or `-j` for builds they start
+/// (`--advise-jobs-file`).
+///
+/// The file holds a single decimal integer and a newline, e.g. `6\n`. It is
+/// at least 1, replaced atomically, so a reader never sees it half-written,
+/// and removed on drop, so a missing file means no advice.
+pub struct AdvisoryFile {
+    path: PathBuf,
+    /// Value last written.
+    written: Option<i32>,
+}
+
+impl AdvisoryFile {
+    pub fn create(path: PathBuf, total: ResourceProfile) -> Result<Self> {
+        let mut file = Self {
+            path,
+            written: None,
+        };
+        file.write(advised_jobs(total, 0))
+            .with_context(|| format!("Failed to write {:?}", file.path))?;
+        info!("Publishing recommended parallelism to {:?}", file.path);
+        Ok(file)
+    }
+
+    /// Publish the recommendation for `available` resources and `paused`
+    /// waiting processes, if it changed since the last update.
+    pub fn update(&mut self, available: ResourceProfile, paused: usize) {
+        let jobs = advised_jobs(available, paused);
+        if self.written != Some(jobs) {
+            if let Err(e) = self.write(jobs) {
+                warn!("Failed to write {:?}: {}", self.path, e);
+            }
+        }
+    }
+
+    fn write(&mut self, jobs: i32) -> std::io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, format!("{}\n", jobs))?;
+        fs::rename(&tmp, &self.path)?;
+        self.written = Some(jobs);
+        Ok(())
+    }
+}
+
+impl Sampler for AdvisoryFile {
+    fn on_tick(&mut self, limiter: &mut Limiter) {
+        self.update(limiter.available(), limiter.paused_count());
+    }
+}
+
+impl Drop for AdvisoryFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// How many jobs fit in `available`, one CPU and `MEM_GB_PER_JOB` each. While
+/// processes wait in the queue nothing fits, and a new build should start
+/// with a single job. Never below 1, so that builds reading it still make
+/// progress.
+fn advised_jobs(available: ResourceProfile, paused: usize) -> i32 {
+    if paused > 0 {
+        return 1;
+    }
+    available.cpus.min(available.mem_gb / MEM_GB_PER_JOB).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advised_jobs() {
+        assert_eq!(advised_jobs(ResourceProfile::new(8, 16), 0), 8);
+        // Bounded by memory as well as CPUs.
+        assert_eq!(advised_jobs(ResourceProfile::new(8, 3), 0), 3);
+        assert_eq!(advised_jobs(ResourceProfile::new(0, 16), 0), 1);
+        assert_eq!(advised_jobs(ResourceProfile::new(-2, 16), 0), 1);
+        assert_eq!(advised_jobs(ResourceProfile::new(8, 16), 2), 1);
+    }
+
+    #[test]
+    fn test_advisory_file_lifecycle() {
+        let path = std::env::temp_dir().join(format!("nix-ubw-{}-jobs", std::process::id()));
+        let mut file = AdvisoryFile::create(path.clone(), ResourceProfile::new(8, 16)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "8\n");
+        file.update(ResourceProfile::new(2, 16), 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2\n");
+        drop(file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,209 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use nix::unistd::Pid;
+
+use crate::limiter::Limiter;
+use crate::nixutil;
+use crate::resources::ResourceProfile;
+use crate::sampler::Sampler;
+
+/// An active throttled process as saved in a checkpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SavedProcess {
+    pub pid: Pid,
+    /// Start time in clock ticks since boot, to tell the process from a
+    /// later one that reused its PID.
+    pub start_time: u64,
+    /// What it took from the budget.
+    pub claim: ResourceProfile,
+    pub owner: Option<u32>,
+    pub name: String,
+}
+
+/// The active throttled processes, saved to a file (`--checkpoint`) so that
+/// a restarted nix-ubw knows what is still running and doesn't over-admit.
+///
+/// The file holds one line per process, `<pid> <start time> <cpus>
+/// <mem_gb> <owner uid or -> <name>`. It is replaced atomically whenever the
+/// active set changes and on shutdown. Paused processes are not saved: they
+/// are resumed when nix-ubw exits, and a crash leaves them running too.
+pub struct Checkpoint {
+    path: PathBuf,
+    /// PIDs and claims last written.
+    written: Option<Vec<(Pid, ResourceProfile)>>,
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            written: None,
+        }
+    }
+
+    /// The processes saved by a previous run that are still running. A
+    /// missing file means there is nothing to restore.
+    pub fn load(&self) -> Result<Vec<SavedProcess>> {
+        let data = match fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", self.path)),
+        };
+        let saved = parse(&data)
+            .into_iter()
+            .filter(|p| {
+                let alive = nixutil::read_start_time(p.pid) == Some(p.start_time);
+                if !alive {
+                    debug!("Not restoring {} (PID {}): gone", p.name, p.pid);
+                }
+                alive
+            })
+            .collect();
+        Ok(saved)
+    }
+
+    /// Save the active processes `(pid, claim, owner, name)`, if their PIDs
+    /// or claims changed since the last save.
+    pub fn update(&mut self, active: Vec<(Pid, ResourceProfile, Option<u32>, String)>) {
+        let key: Vec<(Pid, ResourceProfile)> = active
+            .iter()
+            .map(|(pid, claim, ..)| (*pid, *claim))
+            .collect();
+        if self.written.as_ref() == Some(&key) {
+            return;
+        }
+        let saved: Vec<SavedProcess> = active
+            .into_iter()
+            .filter_map(|(pid, claim, owner, name)| {
+                Some(SavedProcess {
+                    pid,
+                    start_time: nixutil::read_start_time(pid)?,
+                    claim,
+                    owner,
+                    name,
+                })
+            })
+            .collect();
+        match self.write(&saved) {
+            Ok(()) => self.written = Some(key),
+            Err(e) => warn!("Failed to write checkpoint {:?}: {}", self.path, e),
+        }
+    }
+
+    fn write(&self, saved: &[SavedProcess]) -> std::io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, format(saved))?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl Sampler for Checkpoint {
+    fn on_tick(&mut self, limiter: &mut Limiter) {
+        self.update(limiter.active_claims());
+    }
+}
+
+/// Log what a checkpoint restored.
+pub fn log_restored(saved: &[SavedProcess]) {
+    if !saved.is_empty() {
+        let claimed = saved
+            .iter()
+            .fold(ResourceProfile::new(0, 0), |sum, p| sum + p.claim);
+        info!(
+            "Restored {} throttled processes still running from the checkpoint, claiming {}",
+            saved.len(),
+            claimed
+        );
+    }
+}
+
+fn format(saved: &[SavedProcess]) -> String {
+    saved
+        .iter()
+        .map(|p| {
+            let owner = p.owner.map_or("-".to_owned(), |uid| uid.to_string());
+            format!(
+                "{} {} {} {} {} {}\n",
+                p.pid, p.start_time, p.claim.cpus, p.claim.mem_gb, owner, p.name
+            )
+        })
+        .collect()
+}
+
+/// Parse checkpoint lines, skipping malformed ones.
+fn parse(data: &str) -> Vec<SavedProcess> {
+    data.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(6, ' ');
+            let pid = Pid::from_raw(fields.next()?.parse().ok()?);
+            let start_time = fields.next()?.parse().ok()?;
+            let cpus = fields.next()?.parse().ok()?;
+            let mem_gb = fields.next()?.parse().ok()?;
+            let owner = match fields.next()? {
+                "-" => None,
+                uid => Some(uid.parse().ok()?),
+            };
+            let name = fields.next().filter(|n| !n.is_empty())?.to_owned();
+            Some(SavedProcess {
+                pid,
+                start_time,
+                claim: ResourceProfile::new(cpus, mem_gb),
+                owner,
+                name,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_parse() {
+        let saved = vec![
+            SavedProcess {
+                pid: Pid::from_raw(4242),
+                start_time: 123456,
+                claim: ResourceProfile::new(1, 4),
+                owner: Some(30001),
+                name: "rustc".into(),
+            },
+            SavedProcess {
+                pid: Pid::from_raw(4300),
+                start_time: 123999,
+                claim: ResourceProfile::new(2, 1),
+                owner: None,
+                name: "my tool".into(),
+            },
+        ];
+        let data = format(&saved);
+        assert_eq!(
+            data,
+            "4242 123456 1 4 30001 rustc\n4300 123999 2 1 - my tool\n"
+        );
+        assert_eq!(parse(&data), saved);
+        assert!(parse("4242 123456 1 4 x rustc\n4243 1 1\n\n").is_empty());
+    }
+
+    #[test]
+    fn test_load_keeps_live_processes() {
+        let path = std::env::temp_dir().join(format!("nix-ubw-{}-checkpoint", std::process::id()));
+        let mut checkpoint = Checkpoint::new(path.clone());
+        assert!(checkpoint.load().unwrap().is_empty());
+        let own = nix::unistd::getpid();
+        checkpoint.update(vec![(own, ResourceProfile::new(1, 1), None, "cc".into())]);
+        let mut data = fs::read_to_string(&path).unwrap();
+        // A process that is gone, or whose PID was reused since.
+        data.push_str(&format!("{} 1 1 1 - gone\n", own));
+        fs::write(&path, data).unwrap();
+        let saved = checkpoint.load().unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].name, "cc");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Source of the current time for the limiter's timers (claim grace, resume
+/// debounce, pause kill, pause durations), so tests can move time forward
+/// instead of sleeping or backdating entries.
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// Time since `earlier`, zero if `earlier` is in the future.
+    fn since(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+/// The monotonic system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until advanced. Clones share the same time, so a
+/// test keeps one to advance the clone it handed to the limiter.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct MockClock(Rc<Cell<Instant>>);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        Self(Rc::new(Cell::new(Instant::now())))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.0.set(self.0.get() + by);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.0.get()
+    }
+}
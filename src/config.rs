@@ -0,0 +1,536 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+use crate::resources::{
+    EnvCondition, Matcher, ProfileSpec, RuleEntry, RuleGroup, RuleSet, SkipIf, RULES_VAR,
+};
+
+/// A rule file:
+///
+/// ```toml
+/// [[rule]]
+/// id = "proc-macro"
+/// name = "rustc"
+/// args = ["--crate-type", "proc-macro"]
+/// profile = "1cpu,1gb"
+///
+/// [[rule]]
+/// regex = 'clang(\+\+)?-[0-9]+'
+/// env = { NIX_BUILD_TOP = true }
+/// profile = "1cpu,2gb"
+/// max_instances = 4
+///
+/// [[rule]]
+/// name = "make"
+/// profile = "1cpu,1gb"
+/// scale_cpus = '-j ?([0-9]+)'
+///
+/// [rules]
+/// rustc = "1cpu,6gb"
+/// mytool = { cpus = 2, mem_gb = 2 }
+/// tar = false
+///
+/// [skip_if]
+/// go = { args = ["env", "version", "list"] }
+///
+/// [weights]
+/// c-compiler = 1
+/// rustc = 3
+///
+/// [require_env]
+/// gcc = { NIX_BUILD_TOP = true }
+///
+/// [est_duration]
+/// rustc = 60
+/// c-compiler = 2
+///
+/// [mem_hard_gb]
+/// rustc = 12
+///
+/// [groups.c-family]
+/// members = ["cc", "gcc", "g++", "clang", "clang++"]
+/// max_instances = 8
+/// budget = "6cpu,12gb"
+/// ```
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    /// Rules in the order they are tried, ahead of `rules`.
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+    /// Rules for one program each, by program name.
+    #[serde(default)]
+    rules: BTreeMap<String, Rule>,
+    /// Trivial invocations by program name.
+    #[serde(default)]
+    skip_if: BTreeMap<String, SkipIf>,
+    /// Resume weights by rule id, e.g. `rustc` or `config:mytool`.
+    #[serde(default)]
+    weights: BTreeMap<String, u32>,
+    /// Environment conditions for throttling, by program name.
+    #[serde(default)]
+    require_env: BTreeMap<String, BTreeMap<String, EnvCondition>>,
+    /// Families of programs with shared caps, by group name.
+    #[serde(default)]
+    groups: BTreeMap<String, RuleGroup>,
+    /// Typical run time in seconds by rule id, for `--resume-order lpt`.
+    #[serde(default)]
+    est_duration: BTreeMap<String, u64>,
+    /// Resident memory in GiB above which a process is killed, by rule id.
+    #[serde(default)]
+    mem_hard_gb: BTreeMap<String, u32>,
+    /// Programs of Nix itself, never throttled, replacing the built-in list.
+    nix_internal: Option<BTreeSet<String>>,
+}
+
+/// A rule as written: a profile, or `false` to never throttle the program.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Rule {
+    Throttle(bool),
+    Profile(ProfileSpec),
+}
+
+impl Rule {
+    fn profile(self, id: &str) -> Result<Option<ProfileSpec>> {
+        match self {
+            Rule::Profile(profile) => Ok(Some(profile)),
+            Rule::Throttle(false) => Ok(None),
+            Rule::Throttle(true) => bail!("rule {:?}: expected a profile or false", id),
+        }
+    }
+}
+
+/// One entry of the ordered `[[rule]]` list, matching programs by `name` or
+/// by `regex`.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    /// Defaults to the name, the names joined by commas, or the regex.
+    id: Option<String>,
+    name: Option<Names>,
+    regex: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, EnvCondition>,
+    profile: Rule,
+    /// Resume weight, as in `[weights]`.
+    weight: Option<u32>,
+    max_instances: Option<usize>,
+    /// Hard memory limit, as in `[mem_hard_gb]`.
+    mem_hard_gb: Option<u32>,
+    /// Pattern capturing the parallelism from the arguments, which scales
+    /// the profile's CPUs.
+    scale_cpus: Option<String>,
+}
+
+/// One program name, or a list of them.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Names {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl RuleSpec {
+    fn into_entry(self) -> Result<RuleEntry> {
+        let matcher = match (self.name, self.regex) {
+            (Some(Names::One(name)), None) => Matcher::Names(vec![name]),
+            (Some(Names::Many(names)), None) => Matcher::Names(names),
+            (None, Some(regex)) => Matcher::Pattern(regex.parse()?),
+            _ => bail!("expected either name or regex"),
+        };
+        let id = self.id.unwrap_or_else(|| match &matcher {
+            Matcher::Names(names) => names.join(","),
+            Matcher::Pattern(pattern) => pattern.as_str().to_owned(),
+        });
+        Ok(RuleEntry {
+            profile: self.profile.profile(&id)?,
+            id,
+            matcher,
+            args: self.args,
+            env: self.env,
+            scale_cpus: self.scale_cpus.as_deref().map(str::parse).transpose()?,
+        })
+    }
+}
+
+/// Where the base rules come from.
+#[derive(Debug, PartialEq)]
+pub enum RuleSource {
+    /// A rule file, from `--config` or `NIXUBW_RULES`.
+    File(PathBuf),
+    /// The text of a rule file, from `NIXUBW_RULES`.
+    Inline(String),
+}
+
+impl RuleSource {
+    /// Interpret a `NIXUBW_RULES` value: the text of a rule file if it has
+    /// an `=`, as every rule does, and a path otherwise.
+    pub fn from_env_value(value: String) -> Self {
+        if value.contains('=') {
+            RuleSource::Inline(value)
+        } else {
+            RuleSource::File(value.into())
+        }
+    }
+}
+
+/// Load the base rules (`--config` or `NIXUBW_RULES`), then those of every
+/// `*.toml` in each of `dirs` (`--config-dir`) in lexical order. A rule for
+/// a name replaces any earlier rule for the same name, so drop-ins override
+/// the base file.
+pub fn load_rules(base: Option<RuleSource>, dirs: &[PathBuf]) -> Result<RuleSet> {
+    let mut rules = RuleSet::default();
+    let mut paths: Vec<PathBuf> = Vec::new();
+    match base {
+        Some(RuleSource::File(path)) => paths.push(path),
+        Some(RuleSource::Inline(text)) => {
+            let count = merge(&mut rules, &text).with_context(|| format!("In {}", RULES_VAR))?;
+            info!("Loaded {} rules from {}", count, RULES_VAR);
+        }
+        None => {}
+    }
+    for dir in dirs {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                entries.push(path);
+            }
+        }
+        entries.sort();
+        paths.extend(entries);
+    }
+
+    for path in &paths {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let count = merge(&mut rules, &text).with_context(|| format!("In {:?}", path))?;
+        info!("Loaded {} rules from {:?}", count, path);
+    }
+    Ok(rules)
+}
+
+/// Merge the rules, skip conditions, weights, instance caps, environment
+/// conditions, groups, duration estimates, hard memory limits and Nix
+/// internal programs of one file into `rules`. Returns how many rules it had.
+///
+/// The file's `[[rule]]` list, followed by its `[rules]` table, goes ahead
+/// of the rules of earlier files.
+fn merge(rules: &mut RuleSet, text: &str) -> Result<usize> {
+    let file: RuleFile = toml::from_str(text)?;
+    let mut entries = Vec::new();
+    for (i, spec) in file.rule.into_iter().enumerate() {
+        let weight = spec.weight;
+        let max_instances = spec.max_instances;
+        let mem_hard_gb = spec.mem_hard_gb;
+        let entry = spec
+            .into_entry()
+            .with_context(|| format!("In [[rule]] #{}", i + 1))?;
+        let id = format!("config:{}", entry.id);
+        if let Some(weight) = weight {
+            rules.set_weight(id.clone(), weight);
+        }
+        if let Some(max) = max_instances {
+            rules.set_max_instances(id.clone(), max);
+        }
+        if let Some(gb) = mem_hard_gb {
+            rules.set_mem_hard(id, gb);
+        }
+        entries.push(entry);
+    }
+    for (name, rule) in file.rules {
+        let profile = rule.profile(&name)?;
+        entries.push(RuleEntry::plain(name, profile));
+    }
+    let count = entries.len();
+    rules.prepend(entries);
+    for (name, skip_if) in file.skip_if {
+        rules.set_skip_if(name, skip_if);
+    }
+    for (id, weight) in file.weights {
+        rules.set_weight(id, weight);
+    }
+    for (name, vars) in file.require_env {
+        rules.set_require_env(name, vars);
+    }
+    for (name, group) in file.groups {
+        rules.set_group(name, group);
+    }
+    for (id, secs) in file.est_duration {
+        rules.set_est_duration(id, Duration::from_secs(secs));
+    }
+    for (id, gb) in file.mem_hard_gb {
+        rules.set_mem_hard(id, gb);
+    }
+    if let Some(names) = file.nix_internal {
+        rules.set_nix_internal(names);
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixutil::Environ;
+    use crate::resources::{profile_for, ResourceProfile, RuleMatch};
+
+    const TOTAL: ResourceProfile = ResourceProfile::new(8, 16);
+
+    fn matched(rules: &RuleSet, args: &[&str]) -> Option<RuleMatch> {
+        let args: Vec<String> = args.iter().map(|&a| a.into()).collect();
+        profile_for(&args, &Environ::default(), rules, &TOTAL)
+    }
+
+    fn rule_of(rules: &RuleSet, args: &[&str]) -> Option<String> {
+        matched(rules, args).map(|m| m.rule.to_string())
+    }
+
+    #[test]
+    fn test_merge_last_wins() {
+        let mut rules = RuleSet::default();
+        let base = "[rules]\nrustc = \"1cpu,6gb\"\nghc = { cpus = 1, mem_gb = 8 }\n";
+        let drop_in = "[rules]\nrustc = { cpus = 2, mem_gb = 3 }\ntar = false\n";
+        assert_eq!(merge(&mut rules, base).unwrap(), 2);
+        assert_eq!(merge(&mut rules, drop_in).unwrap(), 2);
+        assert_eq!(rules.len(), 3);
+        let profile = |name| matched(&rules, &[name]).map(|m| m.profile);
+        assert_eq!(profile("rustc"), Some(ResourceProfile::new(2, 3)));
+        assert_eq!(profile("ghc"), Some(ResourceProfile::new(1, 8)));
+        assert_eq!(profile("tar"), None);
+        assert_eq!(rule_of(&rules, &["gcc"]).unwrap(), "c-compiler");
+    }
+
+    #[test]
+    fn test_merge_relative_profiles() {
+        let mut rules = RuleSet::default();
+        let file = "[rules]\nrustc = \"50%,25%\"\nghc = { cpus = 1, mem_gb = \"1/2\" }\n";
+        assert_eq!(merge(&mut rules, file).unwrap(), 2);
+        let profile = |name| matched(&rules, &[name]).map(|m| m.profile);
+        assert_eq!(profile("rustc"), Some(ResourceProfile::new(4, 4)));
+        assert_eq!(profile("ghc"), Some(ResourceProfile::new(1, 8)));
+        assert!(merge(&mut rules, "[rules]\nrustc = \"150%,1gb\"\n").is_err());
+    }
+
+    #[test]
+    fn test_merge_scale_cpus() {
+        let mut rules = RuleSet::default();
+        let file =
+            "[[rule]]\nname = \"make\"\nprofile = \"1cpu,1gb\"\nscale_cpus = '-j ?([0-9]+)'\n";
+        merge(&mut rules, file).unwrap();
+        let profile = |args: &[&str]| matched(&rules, args).map(|m| m.profile);
+        assert_eq!(
+            profile(&["make", "-j", "6"]),
+            Some(ResourceProfile::new(6, 1))
+        );
+        assert_eq!(profile(&["make"]), Some(ResourceProfile::new(1, 1)));
+        let no_group =
+            "[[rule]]\nname = \"make\"\nprofile = \"1cpu,1gb\"\nscale_cpus = '-j[0-9]+'\n";
+        assert!(merge(&mut rules, no_group).is_err());
+    }
+
+    #[test]
+    fn test_merge_rule_list() {
+        let mut rules = RuleSet::default();
+        let base = r#"
+            [[rule]]
+            regex = 'clang(\+\+)?-[0-9]+'
+            profile = "1cpu,2gb"
+
+            [[rule]]
+            id = "rust"
+            name = ["rustc", "clippy-driver"]
+            profile = "1cpu,6gb"
+            weight = 3
+
+            [rules]
+            rustc = "1cpu,8gb"
+            clang-18 = false
+        "#;
+        assert_eq!(merge(&mut rules, base).unwrap(), 4);
+        // The list goes ahead of the file's table.
+        assert_eq!(rule_of(&rules, &["rustc"]).unwrap(), "config:rust");
+        assert_eq!(
+            rule_of(&rules, &["clang++-18"]).unwrap(),
+            "config:clang(\\+\\+)?-[0-9]+"
+        );
+        assert_eq!(
+            rule_of(&rules, &["clang-18"]).unwrap(),
+            "config:clang(\\+\\+)?-[0-9]+"
+        );
+        assert_eq!(rules.weight("config:rust"), 3);
+
+        // A later file goes ahead of the earlier ones, its table included.
+        let drop_in = r#"
+            [[rule]]
+            id = "proc-macro"
+            name = "rustc"
+            args = ["--crate-type", "proc-macro"]
+            profile = "1cpu,1gb"
+            max_instances = 2
+
+            [rules]
+            clippy-driver = false
+        "#;
+        assert_eq!(merge(&mut rules, drop_in).unwrap(), 2);
+        assert_eq!(
+            rule_of(&rules, &["rustc", "--crate-type", "proc-macro"]).unwrap(),
+            "config:proc-macro"
+        );
+        assert_eq!(
+            rule_of(&rules, &["rustc", "--crate-type", "lib"]).unwrap(),
+            "config:rust"
+        );
+        assert_eq!(rule_of(&rules, &["clippy-driver"]), None);
+        assert_eq!(rules.max_instances("config:proc-macro"), Some(2));
+        assert!(rules.validate(&TOTAL).is_ok());
+    }
+
+    #[test]
+    fn test_merge_rule_list_invalid() {
+        for text in [
+            "[[rule]]\nprofile = \"1cpu,1gb\"\n",
+            "[[rule]]\nname = \"cc\"\nregex = \"cc\"\nprofile = false\n",
+            "[[rule]]\nregex = \"cc(\"\nprofile = false\n",
+            "[[rule]]\nname = \"cc\"\nprofile = true\n",
+            "[[rule]]\nname = \"cc\"\n",
+            "[[rule]]\nname = \"cc\"\nprofile = false\npriority = 1\n",
+        ] {
+            assert!(merge(&mut RuleSet::default(), text).is_err(), "{}", text);
+        }
+        let mut rules = RuleSet::default();
+        merge(
+            &mut rules,
+            "[[rule]]\nname = \"cc\"\nprofile = false\nmax_instances = 0\n",
+        )
+        .unwrap();
+        assert!(rules.validate(&TOTAL).is_err());
+    }
+
+    #[test]
+    fn test_merge_skip_if() {
+        let mut rules = RuleSet::default();
+        let text = "[skip_if]\ngo = { args = [\"list\"], min_args = 1 }\n";
+        merge(&mut rules, text).unwrap();
+        let total = ResourceProfile::new(8, 16);
+        let throttled = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|&a| a.into()).collect();
+            profile_for(&args, &Environ::default(), &rules, &total).is_some()
+        };
+        assert!(!throttled(&["go"]));
+        assert!(!throttled(&["go", "list", "./..."]));
+        assert!(throttled(&["go", "version"]));
+        assert!(merge(
+            &mut rules.clone(),
+            "[skip_if]\ngo = { argv = [\"list\"] }\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_merge_mem_hard() {
+        let mut rules = RuleSet::default();
+        let text = "[mem_hard_gb]\nrustc = 12\n\n[[rule]]\nid = \"big\"\nname = \"ld\"\nprofile = \"1cpu,2gb\"\nmem_hard_gb = 8\n";
+        merge(&mut rules, text).unwrap();
+        assert_eq!(rules.mem_hard("rustc"), Some(12));
+        assert_eq!(rules.mem_hard("config:big"), Some(8));
+        assert_eq!(rules.mem_hard("c-compiler"), None);
+        merge(&mut rules, "[mem_hard_gb]\nrustc = 0\n").unwrap();
+        assert!(rules.validate(&ResourceProfile::new(4, 16)).is_err());
+    }
+
+    #[test]
+    fn test_merge_weights() {
+        let mut rules = RuleSet::default();
+        merge(&mut rules, "[weights]\nrustc = 3\nc-compiler = 2\n").unwrap();
+        merge(&mut rules, "[weights]\nrustc = 1\n").unwrap();
+        assert!(rules.has_weights());
+        assert_eq!(rules.weight("rustc"), 1);
+        assert_eq!(rules.weight("c-compiler"), 2);
+        assert_eq!(rules.weight("config:mytool"), 1);
+        assert!(merge(&mut rules, "[weights]\nrustc = -1\n").is_err());
+    }
+
+    #[test]
+    fn test_merge_require_env() {
+        let mut rules = RuleSet::default();
+        let text = "[require_env]\ngcc = { NIX_BUILD_TOP = true, IN_NIX_SHELL = false }\nrustc = { MODE = \"release\" }\n";
+        merge(&mut rules, text).unwrap();
+        let total = ResourceProfile::new(8, 16);
+        let throttled = |name: &str, vars: &[(&str, &str)]| {
+            let env = Environ::from_vars(vars);
+            profile_for(&[name.into()], &env, &rules, &total).is_some()
+        };
+        assert!(throttled("gcc", &[("NIX_BUILD_TOP", "/build")]));
+        assert!(!throttled("gcc", &[]));
+        assert!(throttled("rustc", &[("MODE", "release")]));
+        assert!(!throttled("rustc", &[("MODE", "debug")]));
+        assert!(merge(&mut rules, "[require_env]\ngcc = { NIX_BUILD_TOP = 1 }\n").is_err());
+    }
+
+    #[test]
+    fn test_merge_est_duration() {
+        let mut rules = RuleSet::default();
+        merge(&mut rules, "[est_duration]\nrustc = 60\nc-compiler = 2\n").unwrap();
+        merge(&mut rules, "[est_duration]\nc-compiler = 3\n").unwrap();
+        assert_eq!(rules.est_duration("rustc"), Some(Duration::from_secs(60)));
+        assert_eq!(
+            rules.est_duration("c-compiler"),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(rules.est_duration("compressor"), None);
+        assert!(merge(&mut rules, "[est_duration]\nrustc = \"1m\"\n").is_err());
+    }
+
+    #[test]
+    fn test_merge_groups() {
+        let mut rules = RuleSet::default();
+        let text = "[groups.c-family]\nmembers = [\"cc\", \"gcc\"]\nmax_instances = 2\nbudget = \"2cpu,4gb\"\n";
+        merge(&mut rules, text).unwrap();
+        let (name, group) = rules.group_of("gcc").unwrap();
+        assert_eq!(name, "c-family");
+        assert_eq!(group.max_instances, Some(2));
+        assert_eq!(group.budget, Some(ResourceProfile::new(2, 4)));
+        merge(&mut rules, "[groups.c-family]\nmembers = [\"clang\"]\n").unwrap();
+        assert!(rules.group_of("gcc").is_none());
+        assert!(merge(&mut rules, "[groups.x]\nmember = [\"cc\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_rule_source_from_env() {
+        assert_eq!(
+            RuleSource::from_env_value("/etc/nix-ubw/rules.toml".into()),
+            RuleSource::File("/etc/nix-ubw/rules.toml".into())
+        );
+        let text = "[rules]\nrustc = \"1cpu,6gb\"\n";
+        assert_eq!(
+            RuleSource::from_env_value(text.into()),
+            RuleSource::Inline(text.into())
+        );
+        let rules = load_rules(Some(RuleSource::Inline(text.into())), &[]).unwrap();
+        assert_eq!(
+            matched(&rules, &["rustc"]).unwrap().profile,
+            ResourceProfile::new(1, 6)
+        );
+        assert!(load_rules(Some(RuleSource::Inline("rustc = 1".into())), &[]).is_err());
+    }
+
+    #[test]
+    fn test_merge_invalid() {
+        for text in [
+            "[rules]\nrustc = true\n",
+            "[rules]\nrustc = \"lots\"\n",
+            "[rulez]\nrustc = \"1,2\"\n",
+            "rules = 3\n",
+        ] {
+            assert!(merge(&mut RuleSet::default(), text).is_err(), "{}", text);
+        }
+    }
+}
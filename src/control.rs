@@ -0,0 +1,500 @@
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use nix::unistd::Pid;
+
+use crate::events::EventKind;
+use crate::limiter::ProcessState;
+use crate::resources::ResourceProfile;
+use crate::tracer::Tracer;
+
+/// How long to wait for a connected client to send its command.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for a connected client to take its response. We serve
+/// clients on the tracer thread, so one that never reads must not hold up
+/// the builds.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A command accepted on the control socket.
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    /// Stop tracing a process and its descendants.
+    Detach(Pid),
+    /// List the active throttled processes.
+    ListActive,
+    /// Budget, active and paused processes, as text or as a JSON object.
+    Status { json: bool },
+    /// Counters and the pause duration histogram, in OpenMetrics text format.
+    Metrics,
+    /// Replace the total budget.
+    SetBudget(ResourceProfile),
+    /// The traced processes as an indented tree.
+    Tree,
+    /// Whether a process is active, paused or not throttled.
+    State(Pid),
+    /// Admit nothing more, and exit once the active processes are done.
+    Drain,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("detach"), Some(pid)) => {
+                let pid = pid
+                    .parse()
+                    .with_context(|| format!("invalid PID {:?}", pid))?;
+                Command::Detach(Pid::from_raw(pid))
+            }
+            (Some("detach"), None) => bail!("usage: detach <pid>"),
+            (Some("state"), Some(pid)) => {
+                let pid = pid
+                    .parse()
+                    .with_context(|| format!("invalid PID {:?}", pid))?;
+                Command::State(Pid::from_raw(pid))
+            }
+            (Some("state"), None) => bail!("usage: state <pid>"),
+            (Some("list-active"), None) => Command::ListActive,
+            (Some("status"), None) => Command::Status { json: false },
+            (Some("status"), Some("--json")) => Command::Status { json: true },
+            (Some("status"), Some(_)) => bail!("usage: status [--json]"),
+            (Some("metrics"), None) => Command::Metrics,
+            (Some("tree"), None) => Command::Tree,
+            (Some("drain"), None) => Command::Drain,
+            (Some("set-budget"), Some(budget)) => {
+                let budget: ResourceProfile = budget.parse()?;
+                if budget.cpus <= 0 || budget.mem_gb <= 0 {
+                    bail!("budget must be positive, got {}", budget);
+                }
+                Command::SetBudget(budget)
+            }
+            (Some("set-budget"), None) => bail!("usage: set-budget <cpus,mem>"),
+            (Some(other), _) => bail!("unknown command {:?}", other),
+            (None, _) => bail!("empty command"),
+        };
+        if words.next().is_some() {
+            bail!("trailing arguments in {:?}", line.trim());
+        }
+        Ok(command)
+    }
+}
+
+/// Unix socket accepting one-line text commands, one per connection.
+///
+/// The socket is non-blocking and polled from the main loop, so commands run
+/// on the tracer thread and can act on tracer state directly.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn bind(path: PathBuf) -> Result<Self> {
+        // A socket left behind by a previous run would make bind fail.
+        if path.exists() {
+            fs::remove_file(&path).with_context(|| format!("Failed to remove stale {:?}", path))?;
+        }
+        let listener =
+            UnixListener::bind(&path).with_context(|| format!("Failed to bind {:?}", path))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        info!("Control socket listening on {:?}", path);
+        Ok(Self { listener, path })
+    }
+
+    /// Serve every connection that is waiting.
+    pub fn poll(&self, tracer: &mut Tracer) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = serve(stream, tracer) {
+                        warn!("Control socket client error: {:#}", e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Control socket accept failed: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve(mut stream: UnixStream, tracer: &mut Tracer) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match Command::parse(&line) {
+        Ok(command) => execute(command, tracer),
+        Err(e) => format!("error: {:#}\n", e),
+    };
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+fn execute(command: Command, tracer: &mut Tracer) -> String {
+    match command {
+        Command::Detach(pid) => {
+            info!("[control] detach {}", pid);
+            match tracer.detach_subtree(pid) {
+                0 => format!("error: PID {} is not traced\n", pid),
+                n => format!("ok: detaching {} processes\n", n),
+            }
+        }
+        Command::ListActive => {
+            let active = tracer.limiter.active_summary();
+            let mut response = format!("ok: {} active\n", active.len());
+            for entry in active {
+                let owner = entry.owner.map_or("-".to_owned(), |uid| uid.to_string());
+                response += &format!(
+                    "{} {} {} {} {} {:.0}\n",
+                    entry.pid,
+                    entry.name,
+                    entry.claim.cpus,
+                    entry.claim.mem_gb,
+                    owner,
+                    entry.running_for.as_secs_f64()
+                );
+            }
+            response
+        }
+        Command::Status { json: false } => status_text(tracer),
+        Command::Status { json: true } => status_json(tracer),
+        Command::Metrics => format!("ok: metrics\n{}", metrics(tracer)),
+        Command::Tree => {
+            let tree = tracer.tree();
+            format!("ok: {} processes\n{}", tree.lines().count(), tree)
+        }
+        Command::State(pid) => match tracer.limiter.state_of(pid) {
+            ProcessState::NotTracked => format!("ok: {} unthrottled\n", pid),
+            state @ (ProcessState::Grace { profile } | ProcessState::Paused { profile }) => {
+                format!(
+                    "ok: {} {} {} {}\n",
+                    pid,
+                    state.name(),
+                    profile.cpus,
+                    profile.mem_gb
+                )
+            }
+            ProcessState::Active { claim, .. } => {
+                format!("ok: {} active {} {}\n", pid, claim.cpus, claim.mem_gb)
+            }
+        },
+        Command::Drain => {
+            info!("[control] drain");
+            tracer.limiter.start_drain();
+            format!(
+                "ok: draining, {} active, {} paused\n",
+                tracer.limiter.active_summary().len(),
+                tracer.limiter.paused_count()
+            )
+        }
+        Command::SetBudget(budget) => {
+            info!("[control] set-budget {}", budget);
+            tracer.limiter.set_total(budget);
+            format!(
+                "ok: budget {}, free {}\n",
+                tracer.limiter.total(),
+                tracer.limiter.free()
+            )
+        }
+    }
+}
+
+/// OpenMetrics text exposition of our counters, ending in `# EOF`.
+fn metrics(tracer: &Tracer) -> String {
+    let mut out = String::new();
+    out += "# TYPE nixubw_events counter\n";
+    out += "# HELP nixubw_events Traced process events handled.\n";
+    for kind in EventKind::ALL {
+        out += &format!(
+            "nixubw_events_total{{kind=\"{}\"}} {}\n",
+            kind.name(),
+            tracer.events.total(kind)
+        );
+    }
+
+    let name = "nixubw_pause_duration_seconds";
+    let histogram = &tracer.limiter.stats().pause_durations;
+    out += &format!("# TYPE {} histogram\n", name);
+    out += &format!("# UNIT {} seconds\n", name);
+    out += &format!(
+        "# HELP {} Time throttled processes waited in the paused queue before being resumed.\n",
+        name
+    );
+    for (bound, count) in histogram.cumulative() {
+        let le = if bound.is_finite() {
+            format!("{:?}", bound)
+        } else {
+            "+Inf".to_owned()
+        };
+        out += &format!("{}_bucket{{le=\"{}\"}} {}\n", name, le, count);
+    }
+    out += &format!("{}_count {}\n", name, histogram.count());
+    out += &format!("{}_sum {}\n", name, histogram.sum().as_secs_f64());
+    out += "# EOF\n";
+    out
+}
+
+fn status_text(tracer: &Tracer) -> String {
+    let limiter = &tracer.limiter;
+    let active = limiter.active_summary();
+    let paused = limiter.paused_summary();
+    let mut response = format!(
+        "ok: {} active, {} paused, free: {}, total: {}\n",
+        active.len(),
+        paused.len(),
+        limiter.free(),
+        limiter.total()
+    );
+    if limiter.draining() {
+        response += "draining\n";
+    }
+    for entry in active {
+        response += &format!(
+            "active {} {} ({}) running {:.0}s\n",
+            entry.pid,
+            entry.name,
+            entry.claim,
+            entry.running_for.as_secs_f64()
+        );
+    }
+    for entry in paused {
+        response += &format!(
+            "paused {} {} ({}) waiting {:.0}s\n",
+            entry.pid,
+            entry.name,
+            entry.profile,
+            entry.waiting_for.as_secs_f64()
+        );
+    }
+    for group in limiter.group_summary() {
+        let max = group
+            .max_instances
+            .map_or_else(String::new, |max| format!("/{}", max));
+        let budget = group
+            .budget
+            .map_or_else(String::new, |budget| format!(" of {}", budget));
+        response += &format!(
+            "group {} {}{} instances, claimed {}{}\n",
+            group.name, group.instances, max, group.claimed, budget
+        );
+    }
+    for kind in EventKind::ALL {
+        response += &format!(
+            "events {} {} total {:.1}/s\n",
+            kind.name(),
+            tracer.events.total(kind),
+            tracer.events.rate(kind)
+        );
+    }
+    response
+}
+
+/// `status --json`: an `ok:` line, then the status as a single-line JSON
+/// object. Fields are only ever added, never renamed or removed.
+#[cfg(feature = "serde")]
+fn status_json(tracer: &Tracer) -> String {
+    use std::collections::BTreeMap;
+
+    #[derive(serde::Serialize)]
+    struct Status {
+        total: ResourceProfile,
+        free: ResourceProfile,
+        active: Vec<Active>,
+        paused: Vec<Paused>,
+        events: BTreeMap<&'static str, Events>,
+        groups: Vec<Group>,
+        draining: bool,
+    }
+    #[derive(serde::Serialize)]
+    struct Active {
+        pid: i32,
+        name: String,
+        cpus: i32,
+        mem_gb: i32,
+        runtime_secs: f64,
+    }
+    #[derive(serde::Serialize)]
+    struct Paused {
+        pid: i32,
+        name: String,
+        cpus: i32,
+        mem_gb: i32,
+        wait_secs: f64,
+    }
+    #[derive(serde::Serialize)]
+    struct Group {
+        name: String,
+        instances: usize,
+        max_instances: Option<usize>,
+        claimed: ResourceProfile,
+        budget: Option<ResourceProfile>,
+    }
+    #[derive(serde::Serialize)]
+    struct Events {
+        total: u64,
+        /// Per second, over the last minute.
+        rate: f64,
+    }
+
+    let limiter = &tracer.limiter;
+    let status = Status {
+        total: limiter.total(),
+        free: limiter.free(),
+        active: limiter
+            .active_summary()
+            .into_iter()
+            .map(|e| Active {
+                pid: e.pid.as_raw(),
+                name: e.name,
+                cpus: e.claim.cpus,
+                mem_gb: e.claim.mem_gb,
+                runtime_secs: e.running_for.as_secs_f64(),
+            })
+            .collect(),
+        paused: limiter
+            .paused_summary()
+            .into_iter()
+            .map(|e| Paused {
+                pid: e.pid.as_raw(),
+                name: e.name,
+                cpus: e.profile.cpus,
+                mem_gb: e.profile.mem_gb,
+                wait_secs: e.waiting_for.as_secs_f64(),
+            })
+            .collect(),
+        events: EventKind::ALL
+            .into_iter()
+            .map(|kind| {
+                let events = Events {
+                    total: tracer.events.total(kind),
+                    rate: tracer.events.rate(kind),
+                };
+                (kind.name(), events)
+            })
+            .collect(),
+        groups: limiter
+            .group_summary()
+            .into_iter()
+            .map(|g| Group {
+                name: g.name,
+                instances: g.instances,
+                max_instances: g.max_instances,
+                claimed: g.claimed,
+                budget: g.budget,
+            })
+            .collect(),
+        draining: limiter.draining(),
+    };
+    match serde_json::to_string(&status) {
+        Ok(json) => format!("ok: status\n{}\n", json),
+        Err(e) => format!("error: {}\n", e),
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+fn status_json(_tracer: &Tracer) -> String {
+    "error: built without the serde feature\n".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_detach() {
+        assert_eq!(
+            Command::parse("detach 1234\n").unwrap(),
+            Command::Detach(Pid::from_raw(1234))
+        );
+    }
+
+    #[test]
+    fn test_parse_state() {
+        assert_eq!(
+            Command::parse("state 1234\n").unwrap(),
+            Command::State(Pid::from_raw(1234))
+        );
+        assert!(Command::parse("state").is_err());
+        assert!(Command::parse("state rustc").is_err());
+    }
+
+    #[test]
+    fn test_parse_list_active() {
+        assert_eq!(
+            Command::parse("list-active\n").unwrap(),
+            Command::ListActive
+        );
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(
+            Command::parse("status\n").unwrap(),
+            Command::Status { json: false }
+        );
+        assert_eq!(
+            Command::parse("status --json\n").unwrap(),
+            Command::Status { json: true }
+        );
+        assert!(Command::parse("status --yaml").is_err());
+    }
+
+    #[test]
+    fn test_parse_metrics() {
+        assert_eq!(Command::parse("metrics\n").unwrap(), Command::Metrics);
+        assert!(Command::parse("metrics now").is_err());
+    }
+
+    #[test]
+    fn test_parse_tree() {
+        assert_eq!(Command::parse("tree\n").unwrap(), Command::Tree);
+        assert!(Command::parse("tree 812").is_err());
+    }
+
+    #[test]
+    fn test_parse_drain() {
+        assert_eq!(Command::parse("drain\n").unwrap(), Command::Drain);
+        assert!(Command::parse("drain now").is_err());
+    }
+
+    #[test]
+    fn test_parse_set_budget() {
+        assert_eq!(
+            Command::parse("set-budget 8cpu,32gb\n").unwrap(),
+            Command::SetBudget(ResourceProfile::new(8, 32))
+        );
+        assert!(Command::parse("set-budget").is_err());
+        assert!(Command::parse("set-budget 8").is_err());
+        assert!(Command::parse("set-budget 0,32").is_err());
+        assert!(Command::parse("set-budget 8,32 now").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("detach").is_err());
+        assert!(Command::parse("detach abc").is_err());
+        assert!(Command::parse("detach 1 2").is_err());
+        assert!(Command::parse("list-active 1").is_err());
+        assert!(Command::parse("frobnicate").is_err());
+    }
+}
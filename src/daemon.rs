@@ -1,22 +1,45 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
 use std::fs;
 
 use anyhow::{bail, Context, Result};
-use log::{info, warn};
+use log::{debug, error, info, warn};
+use nix::errno::Errno;
+use nix::libc;
 use nix::sys::ptrace;
-use nix::unistd::Pid;
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{execvp, fork, getpid, ForkResult, Pid};
 
-use crate::nixutil;
+use crate::nixutil::{self, ProcMount};
+use crate::ptrace::Ptrace;
+use crate::tracer::TracedProcess;
 
 /// The ptrace options we set on every tracee.
-fn trace_options() -> ptrace::Options {
+///
+/// Options are inherited by auto-attached children, so processes spawned deep
+/// in the build tree (e.g. `lto1` workers started by `lto-wrapper` via
+/// `posix_spawn`, which uses a `CLONE_VFORK` clone) are traced as well.
+///
+/// With `exec_only`, forks are not followed and only exec is traced; new
+/// processes are found by scanning /proc instead (see
+/// `Tracer::scan_new_processes`).
+pub fn trace_options(exec_only: bool) -> ptrace::Options {
+    if exec_only {
+        return ptrace::Options::PTRACE_O_TRACEEXEC;
+    }
     ptrace::Options::PTRACE_O_TRACEFORK
         | ptrace::Options::PTRACE_O_TRACEVFORK
         | ptrace::Options::PTRACE_O_TRACECLONE
         | ptrace::Options::PTRACE_O_TRACEEXEC
 }
 
+/// How many levels below each daemon to adopt already-running processes:
+/// the per-connection workers and the builders they have spawned.
+const ADOPT_DEPTH: usize = 2;
+
 /// Scan /proc for all processes whose cmdline is "nix-daemon --daemon".
-fn find_nix_daemon_pids() -> Result<Vec<Pid>> {
+pub fn find_nix_daemon_pids() -> Result<Vec<Pid>> {
     let mut pids = Vec::new();
     for entry in fs::read_dir("/proc").context("Failed to read /proc")? {
         let entry = match entry {
@@ -39,31 +62,587 @@ fn find_nix_daemon_pids() -> Result<Vec<Pid>> {
     Ok(pids)
 }
 
-/// Find all nix-daemon processes and attach to them with ptrace.
-/// Returns the number of successfully attached processes.
-pub fn attach_to_nix_daemons() -> Result<usize> {
-    let daemon_pids = find_nix_daemon_pids()?;
-    if daemon_pids.is_empty() {
-        bail!("No nix-daemon processes found (looking for cmdline 'nix-daemon --daemon')");
+/// Where a systemd unit's cgroup can be found: the unified (v2) hierarchy,
+/// the unified part of a hybrid setup, or systemd's own v1 hierarchy.
+const CGROUP_ROOTS: &[&str] = &[
+    "/sys/fs/cgroup",
+    "/sys/fs/cgroup/unified",
+    "/sys/fs/cgroup/systemd",
+];
+
+/// Find the main processes of the systemd unit `unit` (`--unit`) from its
+/// cgroup. A bare name is taken to be a service in `system.slice`, and a name
+/// with a `/` as a cgroup path below the hierarchy root.
+///
+/// The cgroup also holds the daemon's workers and everything the builds
+/// run, so only processes whose parent is outside it are returned; the
+/// rest is found from those as usual. `None` if the unit has no cgroup,
+/// e.g. because it is not running.
+pub fn find_unit_pids(unit: &str) -> Option<Vec<Pid>> {
+    let unit = if unit.contains('.') || unit.contains('/') {
+        unit.to_owned()
+    } else {
+        format!("{}.service", unit)
+    };
+    let path = if unit.contains('/') {
+        unit.trim_start_matches('/').to_owned()
+    } else {
+        format!("system.slice/{}", unit)
+    };
+    let data = CGROUP_ROOTS
+        .iter()
+        .find_map(|root| fs::read_to_string(format!("{}/{}/cgroup.procs", root, path)).ok())?;
+    Some(top_level(&parse_cgroup_procs(&data), nixutil::read_ppid))
+}
+
+/// Parse the newline-separated PIDs of a `cgroup.procs` file.
+fn parse_cgroup_procs(data: &str) -> Vec<Pid> {
+    data.lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .map(Pid::from_raw)
+        .collect()
+}
+
+/// The processes of `pids` whose parent, as told by `ppid`, is not among them.
+fn top_level(pids: &[Pid], ppid: impl Fn(Pid) -> Option<Pid>) -> Vec<Pid> {
+    let all: HashSet<Pid> = pids.iter().copied().collect();
+    pids.iter()
+        .copied()
+        .filter(|&pid| ppid(pid).is_none_or(|parent| !all.contains(&parent)))
+        .collect()
+}
+
+/// Outcome of an attach pass.
+#[derive(Debug, Default)]
+pub struct AttachReport {
+    /// Processes newly seized by this pass, including adopted children.
+    pub seized: usize,
+    /// Candidates skipped because we already trace them.
+    pub already_traced: usize,
+    /// Candidates skipped because another process (gdb, strace, another
+    /// nix-ubw) traces them, with that tracer's PID.
+    pub traced_elsewhere: Vec<(Pid, Pid)>,
+    /// Candidates that could not be seized for any other reason, with the
+    /// error.
+    pub failed: Vec<(Pid, Errno)>,
+    /// Candidates that were seized, but without the fork tracing options,
+    /// with the error setting them. Children they fork are not traced.
+    pub degraded: Vec<(Pid, Errno)>,
+}
+
+/// How a process was seized.
+enum Seized {
+    /// With the options asked for.
+    Full,
+    /// With exec tracing only, after the kernel rejected the full options.
+    ExecOnly(Errno),
+}
+
+/// Seize `pid` and confirm that the kernel reports us as its tracer.
+///
+/// PTRACE_SEIZE sets the options atomically, so a seize that succeeds has
+/// them all. If the kernel rejects the options (EINVAL), the process is
+/// seized with exec tracing alone so its own execs are still seen, and the
+/// degradation is returned for the caller to report. A process that turns
+/// out not to be ours after all is let go again, since it would otherwise
+/// stay attached without anyone handling its stops.
+fn seize_verified(
+    ptrace: &dyn Ptrace,
+    pid: Pid,
+    options: ptrace::Options,
+) -> Result<Seized, Errno> {
+    let exec_only = trace_options(true);
+    let seized = match ptrace.seize(pid, options) {
+        Ok(()) => Seized::Full,
+        Err(Errno::EINVAL) if options != exec_only => {
+            ptrace.seize(pid, exec_only)?;
+            Seized::ExecOnly(Errno::EINVAL)
+        }
+        Err(e) => return Err(e),
+    };
+    let err = match nixutil::read_tracer_pid(pid) {
+        Some(tracer) if tracer == getpid() => return Ok(seized),
+        Some(_) => Errno::EPERM,
+        None => Errno::ESRCH,
+    };
+    release_seized(ptrace, pid);
+    Err(err)
+}
+
+/// Detach from a process we seized but won't track. Detaching needs a
+/// ptrace-stop, so a running process is interrupted first, and a signal it
+/// stopped for is passed on.
+fn release_seized(ptrace: &dyn Ptrace, pid: Pid) {
+    if ptrace.detach(pid, None).is_ok() || ptrace.interrupt(pid).is_err() {
+        return;
+    }
+    let sig = match waitpid(pid, Some(WaitPidFlag::__WALL)) {
+        Ok(WaitStatus::Stopped(_, sig)) => (sig != Signal::SIGSTOP).then_some(sig),
+        Ok(WaitStatus::PtraceEvent(..)) => None,
+        _ => return,
+    };
+    if let Err(e) = ptrace.detach(pid, sig) {
+        warn!("Failed to let go of pid {}: {}", pid, e);
+    }
+}
+
+/// The process other than us that traces `pid`, if any.
+fn other_tracer(pid: Pid) -> Option<Pid> {
+    nixutil::read_tracer_pid(pid).filter(|&tracer| tracer.as_raw() != 0 && tracer != getpid())
+}
+
+/// The program name of `pid`, for messages.
+fn describe_process(pid: Pid) -> String {
+    nixutil::read_cmdline(pid)
+        .and_then(|args| args.into_iter().next())
+        .unwrap_or_else(|| "<unavailable>".into())
+}
+
+/// `<pid> (traced by <name>, pid <tracer>)` for each of `traced`.
+pub fn format_tracers(traced: &[(Pid, Pid)]) -> String {
+    traced
+        .iter()
+        .map(|&(pid, tracer)| {
+            format!(
+                "{} (traced by {}, pid {})",
+                pid,
+                describe_process(tracer),
+                tracer
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Find all nix-daemon processes, plus any explicitly requested PIDs,
+/// deduplicated. Fails if there are none.
+///
+/// With `unit`, the daemons are the main processes of that systemd unit
+/// (see `find_unit_pids`). If it can't be found, we fall back to looking
+/// for the daemon's command line.
+pub fn find_daemons(unit: Option<&str>, extra: &[Pid]) -> Result<Vec<Pid>> {
+    let unit_pids = unit.and_then(|unit| match find_unit_pids(unit) {
+        Some(pids) if !pids.is_empty() => {
+            info!("Found {} process(es) of unit {}", pids.len(), unit);
+            Some(pids)
+        }
+        _ => {
+            warn!(
+                "No running processes found for unit {}, looking for nix-daemon by command line",
+                unit
+            );
+            None
+        }
+    });
+    let mut candidates = match unit_pids {
+        Some(pids) => pids,
+        None => find_nix_daemon_pids()?,
+    };
+    candidates.extend_from_slice(extra);
+    let mut seen = HashSet::new();
+    candidates.retain(|pid| seen.insert(*pid));
+    let problem = proc_access_problem();
+    if candidates.is_empty() {
+        match problem {
+            Some(problem) => bail!("No nix-daemon processes found: {}", problem),
+            None => {
+                bail!("No nix-daemon processes found (looking for cmdline 'nix-daemon --daemon')")
+            }
+        }
+    }
+    if let Some(problem) = problem {
+        warn!("{}. Build processes may go unclassified", problem);
+    }
+    Ok(candidates)
+}
+
+/// Why /proc doesn't show us other users' processes, if it doesn't: it isn't
+/// mounted, or `hidepid` hides them. nix-daemon is then not found by its
+/// command line, and builds can't be classified.
+pub fn proc_access_problem() -> Option<String> {
+    let Some(mount) = nixutil::read_proc_mount() else {
+        return Some(
+            "/proc is not mounted, and nix-ubw needs it to find and classify processes".into(),
+        );
+    };
+    // PID 1 always exists, so failing to read it means it is hidden from us.
+    let init_readable = nixutil::read_cmdline(Pid::from_raw(1)).is_some();
+    describe_proc_problem(&mount, init_readable)
+}
+
+fn describe_proc_problem(mount: &ProcMount, init_readable: bool) -> Option<String> {
+    if init_readable {
+        return None;
     }
+    let how = match &mount.hidepid {
+        Some(hidepid) => format!("/proc is mounted with hidepid={}", hidepid),
+        None => "/proc seems restricted".to_owned(),
+    };
+    let exempt = match mount.gid {
+        Some(gid) => format!("run it in group {} (the mount's gid=)", gid),
+        None => "remount /proc with gid=<a group nix-ubw runs in>".to_owned(),
+    };
+    Some(format!(
+        "{} and hides other users' processes: the command line of PID 1 can't be read. \
+         Run nix-ubw with CAP_SYS_PTRACE (e.g. as root), which also exempts it from hidepid, or {}",
+        how, exempt
+    ))
+}
 
+/// Print the processes `find_daemons` finds, one `<pid> <cmdline>` per
+/// line, without attaching to them (`--list-daemons`).
+pub fn list_daemons(unit: Option<&str>, extra: &[Pid]) -> Result<()> {
+    for pid in find_daemons(unit, extra)? {
+        let cmdline = nixutil::read_cmdline(pid)
+            .map(|args| nixutil::shell_join(&args))
+            .unwrap_or_else(|| "<unavailable>".into());
+        println!("{} {}", pid, cmdline);
+    }
+    Ok(())
+}
+
+/// Attach with ptrace to the processes `find_daemons` finds.
+///
+/// PIDs already in `traced` are skipped, so overlapping discovery paths
+/// never seize the same process twice. Newly seized PIDs are added to
+/// `traced`.
+pub fn attach_to_nix_daemons(
+    ptrace: &dyn Ptrace,
+    unit: Option<&str>,
+    extra: &[Pid],
+    options: ptrace::Options,
+    traced: &mut HashMap<Pid, TracedProcess>,
+) -> Result<AttachReport> {
+    let candidates = find_daemons(unit, extra)?;
+
+    let mut report = AttachReport::default();
     let mut attached = 0usize;
 
-    for &pid in &daemon_pids {
-        match ptrace::seize(pid, trace_options()) {
-            Ok(()) => {
-                info!("Attached to nix-daemon (pid {})", pid);
+    for &pid in &candidates {
+        if traced.contains_key(&pid) {
+            debug!("Already tracing pid {}, skipping", pid);
+            report.already_traced += 1;
+            continue;
+        }
+        match seize_verified(ptrace, pid, options) {
+            Ok(seized) => {
+                let options = match seized {
+                    Seized::Full => {
+                        info!("Attached to nix-daemon (pid {})", pid);
+                        options
+                    }
+                    Seized::ExecOnly(e) => {
+                        error!(
+                            "Attached to pid {}, but could not enable fork tracing ({}): processes it starts will not be throttled",
+                            pid, e
+                        );
+                        report.degraded.push((pid, e));
+                        trace_options(true)
+                    }
+                };
+                traced.insert(pid, TracedProcess::root());
                 attached += 1;
+                let adopted = adopt_children(ptrace, pid, ADOPT_DEPTH, options, traced);
+                if adopted > 0 {
+                    info!(
+                        "Adopted {} pre-existing children of nix-daemon (pid {})",
+                        adopted, pid
+                    );
+                }
+                report.seized += 1 + adopted;
+            }
+            // A tracee can have only one tracer, and the kernel says EPERM
+            // for that just as for a lack of privileges.
+            Err(e) => match other_tracer(pid).filter(|_| e == Errno::EPERM) {
+                Some(tracer) => {
+                    warn!(
+                        "Not attaching to pid {}: it is already traced by {} (pid {})",
+                        pid,
+                        describe_process(tracer),
+                        tracer
+                    );
+                    report.traced_elsewhere.push((pid, tracer));
+                }
+                None => {
+                    error!(
+                        "Failed to attach to pid {}: {} (run `nix-ubw doctor` to diagnose)",
+                        pid, e
+                    );
+                    report.failed.push((pid, e));
+                }
+            },
+        }
+    }
+
+    if attached == 0 && report.already_traced == 0 {
+        if report.failed.is_empty() {
+            bail!(
+                "Every nix-daemon process is already traced by another process: {}",
+                format_tracers(&report.traced_elsewhere)
+            );
+        }
+        bail!("Failed to attach to any nix-daemon process");
+    }
+
+    Ok(report)
+}
+
+/// Start `command` as a traced child (`nix-ubw exec`), for single-user Nix
+/// where there is no daemon to attach to or we may not attach to it.
+///
+/// Rather than having the child call PTRACE_TRACEME, it stops itself before
+/// exec and we seize it: tracing our own child needs no extra privileges
+/// either way, but only seized tracees support the PTRACE_INTERRUPT that
+/// detaching relies on, and the exec then goes through the usual exec event
+/// so the command itself is classified too.
+pub fn spawn_traced(
+    ptrace: &dyn Ptrace,
+    command: &[String],
+    options: ptrace::Options,
+    traced: &mut HashMap<Pid, TracedProcess>,
+) -> Result<Pid> {
+    let Some(program) = command.first() else {
+        bail!("No command given");
+    };
+    // Everything the child needs is allocated before forking.
+    let args = command
+        .iter()
+        .map(|a| CString::new(a.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Command contains a NUL byte")?;
+
+    // SAFETY: we are single-threaded, and the child only calls
+    // async-signal-safe functions before exec.
+    let child = match unsafe { fork() }.context("Failed to fork")? {
+        ForkResult::Child => {
+            let _ = signal::raise(Signal::SIGSTOP);
+            let _ = execvp(&args[0], &args);
+            // SAFETY: exiting without running our own atexit handlers.
+            unsafe { libc::_exit(127) }
+        }
+        ForkResult::Parent { child } => child,
+    };
+
+    match waitpid(child, Some(WaitPidFlag::WUNTRACED)).context("Failed to wait for child")? {
+        WaitStatus::Stopped(_, Signal::SIGSTOP) => {}
+        other => bail!("{} did not stop before exec: {:?}", program, other),
+    }
+    match seize_verified(ptrace, child, options) {
+        Ok(Seized::Full) => {}
+        Ok(Seized::ExecOnly(e)) => warn!(
+            "Could not enable fork tracing for {} ({}): processes it starts will not be throttled",
+            program, e
+        ),
+        Err(e) => {
+            let _ = ptrace.kill(child, Signal::SIGKILL);
+            bail!("Failed to trace {}: {}", program, e);
+        }
+    }
+    traced.insert(child, TracedProcess::root());
+    ptrace
+        .kill(child, Signal::SIGCONT)
+        .context("Failed to resume child")?;
+    info!("Started {} (pid {})", program, child);
+    Ok(child)
+}
+
+/// Seize the existing descendants of `pid` up to `depth` levels down.
+///
+/// Children forked before the seize took effect are not covered by
+/// PTRACE_O_TRACEFORK, so right after a daemon restart a whole build could
+/// otherwise run untraced. Children forked after the seize are already
+/// auto-attached and in `traced`, so they are skipped. Returns the number of
+/// newly seized processes.
+fn adopt_children(
+    ptrace: &dyn Ptrace,
+    pid: Pid,
+    depth: usize,
+    options: ptrace::Options,
+    traced: &mut HashMap<Pid, TracedProcess>,
+) -> usize {
+    if depth == 0 {
+        return 0;
+    }
+    let mut adopted = 0;
+    for child in nixutil::read_children(pid) {
+        if traced.contains_key(&child) {
+            continue;
+        }
+        match seize_verified(ptrace, child, options) {
+            Ok(seized) => {
+                debug!(
+                    "Adopted pre-existing PID {} (child of {}){}",
+                    child,
+                    pid,
+                    match seized {
+                        Seized::Full => "",
+                        Seized::ExecOnly(_) => " with exec tracing only",
+                    }
+                );
+                traced.insert(child, TracedProcess::child_of(pid));
+                adopted += 1;
             }
             Err(e) => {
-                warn!("Failed to attach to pid {}: {} (are you root?)", pid, e);
+                debug!("Not adopting PID {} (child of {}): {}", child, pid, e);
             }
         }
+        adopted += adopt_children(ptrace, child, depth - 1, options, traced);
     }
+    adopted
+}
 
-    if attached == 0 {
-        bail!("Failed to attach to any nix-daemon process");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ptrace::{MockPtrace, PtraceCall, SystemPtrace};
+
+    /// A PID over any `pid_max`, so it never names a live process.
+    const GONE: Pid = Pid::from_raw(i32::MAX);
+
+    #[test]
+    fn test_seize_verified_exec_only_fallback() {
+        let mock = MockPtrace::new();
+        let (full, exec_only) = (trace_options(false), trace_options(true));
+        // The kernel rejects the fork tracing options, so exec tracing is
+        // tried alone; the process is gone by the time it is verified, so it
+        // is let go.
+        mock.fail(GONE, [Errno::EINVAL]);
+        assert_eq!(seize_verified(&mock, GONE, full).err(), Some(Errno::ESRCH));
+        assert_eq!(
+            mock.take_calls(),
+            vec![
+                PtraceCall::Seize(GONE, full),
+                PtraceCall::Seize(GONE, exec_only),
+                PtraceCall::Detach(GONE, None),
+            ]
+        );
+        // Exec tracing alone is not retried, and nothing is left to release.
+        mock.fail(GONE, [Errno::EINVAL]);
+        assert_eq!(
+            seize_verified(&mock, GONE, exec_only).err(),
+            Some(Errno::EINVAL)
+        );
+        assert_eq!(mock.take_calls(), vec![PtraceCall::Seize(GONE, exec_only)]);
     }
 
-    Ok(attached)
+    #[test]
+    fn test_release_seized() {
+        let mock = MockPtrace::new();
+        release_seized(&mock, GONE);
+        assert_eq!(mock.take_calls(), vec![PtraceCall::Detach(GONE, None)]);
+        // Not in a ptrace-stop: it is interrupted to be detached in the
+        // stop, which the wait then fails to see for a process that is gone.
+        mock.fail(GONE, [Errno::ESRCH]);
+        release_seized(&mock, GONE);
+        assert_eq!(
+            mock.take_calls(),
+            vec![PtraceCall::Detach(GONE, None), PtraceCall::Interrupt(GONE)]
+        );
+    }
+
+    #[test]
+    fn test_parse_cgroup_procs() {
+        assert_eq!(
+            parse_cgroup_procs("812\n4521\n\n4530\n"),
+            vec![Pid::from_raw(812), Pid::from_raw(4521), Pid::from_raw(4530)]
+        );
+        assert!(parse_cgroup_procs("").is_empty());
+    }
+
+    #[test]
+    fn test_describe_proc_problem() {
+        let hardened = ProcMount {
+            hidepid: Some("2".into()),
+            gid: Some(27),
+        };
+        assert_eq!(describe_proc_problem(&hardened, true), None);
+        let problem = describe_proc_problem(&hardened, false).unwrap();
+        assert!(problem.starts_with("/proc is mounted with hidepid=2 and hides"));
+        assert!(problem.ends_with("or run it in group 27 (the mount's gid=)"));
+        let problem = describe_proc_problem(&ProcMount::default(), false).unwrap();
+        assert!(problem.starts_with("/proc seems restricted"));
+        assert!(problem.contains("remount /proc with gid="));
+    }
+
+    #[test]
+    fn test_top_level() {
+        // The daemon (812, started by init), a worker it forked (4521) and a
+        // builder of that worker (4530).
+        let parents = HashMap::from([(812, 1), (4521, 812), (4530, 4521)]);
+        let ppid = |pid: Pid| parents.get(&pid.as_raw()).copied().map(Pid::from_raw);
+        let pids: Vec<Pid> = [4530, 812, 4521].into_iter().map(Pid::from_raw).collect();
+        assert_eq!(top_level(&pids, ppid), vec![Pid::from_raw(812)]);
+        // A process we can't read the parent of is kept.
+        let pids = [Pid::from_raw(812), Pid::from_raw(9999)];
+        assert_eq!(top_level(&pids, ppid), pids.to_vec());
+    }
+
+    #[test]
+    fn test_no_fds_leak_into_children() {
+        use std::os::unix::net::UnixDatagram;
+        use std::process::Command;
+
+        use crate::control::ControlSocket;
+        use crate::health::{self, Health};
+
+        // The fds a child started now inherits, as seen by its shell.
+        let inherited = || {
+            let output = Command::new("sh")
+                .args(["-c", "ls /proc/$$/fd"])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        let before = inherited();
+        let path = std::env::temp_dir().join(format!("nix-ubw-{}-fds.sock", std::process::id()));
+        let control = ControlSocket::bind(path).unwrap();
+        health::listen(
+            "127.0.0.1:0".parse().unwrap(),
+            Health::new(std::time::Duration::from_millis(200)),
+        )
+        .unwrap();
+        let journal = UnixDatagram::unbound().unwrap();
+        assert_eq!(inherited(), before);
+        drop((control, journal));
+    }
+
+    #[test]
+    fn test_traced_elsewhere() {
+        use std::process::Command;
+        use std::time::{Duration, Instant};
+
+        use nix::sys::signal::{kill, Signal};
+        use nix::sys::wait::waitpid;
+        use nix::unistd::{fork, ForkResult};
+
+        let mut target = Command::new("sleep").arg("10").spawn().unwrap();
+        let target_pid = Pid::from_raw(target.id() as i32);
+        // SAFETY: the child only makes syscalls before it is killed.
+        let other = match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let _ = ptrace::seize(target_pid, ptrace::Options::empty());
+                loop {
+                    nix::unistd::pause();
+                }
+            }
+            ForkResult::Parent { child } => child,
+        };
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while other_tracer(target_pid).is_none() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let tracer = other_tracer(target_pid);
+        let seized = seize_verified(&SystemPtrace, target_pid, trace_options(false)).err();
+        // Clean up before asserting, so a failure can't leave them behind.
+        kill(other, Signal::SIGKILL).unwrap();
+        waitpid(other, None).unwrap();
+        target.kill().unwrap();
+        target.wait().unwrap();
+        if tracer.is_none() {
+            eprintln!("skipping: no privileges to trace a sibling (see ptrace_scope)");
+            return;
+        }
+        assert_eq!(tracer, Some(other));
+        assert_eq!(seized, Some(Errno::EPERM));
+    }
 }
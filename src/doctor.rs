@@ -0,0 +1,165 @@
+use std::fs;
+
+use anyhow::{bail, Result};
+
+use crate::daemon;
+
+/// Bit of CAP_SYS_PTRACE in the capability sets of /proc/<pid>/status.
+const CAP_SYS_PTRACE: u32 = 19;
+
+/// Outcome of a single readiness check.
+struct Check {
+    name: &'static str,
+    ok: bool,
+    detail: String,
+    /// What to do about a failed check.
+    fix: Option<&'static str>,
+}
+
+/// Check that we would be able to attach to nix-daemon, without attaching,
+/// and print the result of each check. Fails if any check failed.
+pub fn run() -> Result<()> {
+    let cap = has_cap_sys_ptrace();
+    let checks = [
+        check_capability(cap),
+        check_ptrace_scope(read_ptrace_scope(), cap),
+        check_proc(daemon::proc_access_problem()),
+        check_nix_daemon(),
+    ];
+
+    let mut failed = 0;
+    for check in &checks {
+        println!(
+            "[{}] {}: {}",
+            if check.ok { "ok" } else { "FAIL" },
+            check.name,
+            check.detail
+        );
+        if let (false, Some(fix)) = (check.ok, check.fix) {
+            println!("       fix: {}", fix);
+        }
+        failed += usize::from(!check.ok);
+    }
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    println!("Ready to trace nix-daemon.");
+    Ok(())
+}
+
+fn check_capability(cap: Option<bool>) -> Check {
+    let (ok, detail) = match cap {
+        Some(true) => (true, "CAP_SYS_PTRACE is effective".to_owned()),
+        Some(false) => (false, "CAP_SYS_PTRACE is not effective".to_owned()),
+        None => (false, "could not read /proc/self/status".to_owned()),
+    };
+    Check {
+        name: "capabilities",
+        ok,
+        detail,
+        fix: Some("run as root, or grant CAP_SYS_PTRACE (e.g. AmbientCapabilities=CAP_SYS_PTRACE)"),
+    }
+}
+
+/// Yama restricts ptrace of non-descendants: mode 1 and 2 need
+/// CAP_SYS_PTRACE, mode 3 disables attaching entirely.
+fn check_ptrace_scope(scope: Option<u32>, cap: Option<bool>) -> Check {
+    let cap = cap.unwrap_or(false);
+    let (ok, detail, fix) = match scope {
+        None => (true, "Yama not enabled".to_owned(), None),
+        Some(0) => (true, "0 (classic ptrace permissions)".to_owned(), None),
+        Some(s @ (1 | 2)) if cap => (true, format!("{} (allowed with CAP_SYS_PTRACE)", s), None),
+        Some(s @ (1 | 2)) => (
+            false,
+            format!("{} requires CAP_SYS_PTRACE to attach to nix-daemon", s),
+            Some("run as root, or set kernel.yama.ptrace_scope to 0"),
+        ),
+        Some(s) => (
+            false,
+            format!("{} disables ptrace attach until reboot", s),
+            Some("set kernel.yama.ptrace_scope to 0 or 1 at boot"),
+        ),
+    };
+    Check {
+        name: "ptrace_scope",
+        ok,
+        detail,
+        fix,
+    }
+}
+
+/// Other users' processes must be visible in /proc to find nix-daemon and
+/// classify builds.
+fn check_proc(problem: Option<String>) -> Check {
+    Check {
+        name: "proc",
+        ok: problem.is_none(),
+        detail: problem.unwrap_or_else(|| "other users' processes are visible".to_owned()),
+        fix: Some(
+            "mount /proc without hidepid, or give nix-ubw CAP_SYS_PTRACE or the mount's gid=",
+        ),
+    }
+}
+
+fn check_nix_daemon() -> Check {
+    let (ok, detail) = match daemon::find_nix_daemon_pids() {
+        Ok(pids) if !pids.is_empty() => {
+            let pids: Vec<String> = pids.iter().map(|p| p.to_string()).collect();
+            (true, format!("found PID {}", pids.join(", ")))
+        }
+        Ok(_) => (false, "no `nix-daemon --daemon` process found".to_owned()),
+        Err(e) => (false, format!("{:#}", e)),
+    };
+    Check {
+        name: "nix-daemon",
+        ok,
+        detail,
+        fix: Some("start nix-daemon (e.g. systemctl start nix-daemon), or pass --attach-pid"),
+    }
+}
+
+/// Current Yama mode, or `None` if Yama is not built in.
+fn read_ptrace_scope() -> Option<u32> {
+    fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn has_cap_sys_ptrace() -> Option<bool> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let caps = parse_cap_eff(&status)?;
+    Some(caps & (1 << CAP_SYS_PTRACE) != 0)
+}
+
+/// Effective capability set from the contents of /proc/<pid>/status.
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    let hex = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?
+        .trim();
+    u64::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cap_eff() {
+        let status = "Name:\tcat\nCapPrm:\t0000000000000000\nCapEff:\t000001ffffffffff\n";
+        assert_eq!(parse_cap_eff(status), Some(0x1ff_ffff_ffff));
+        assert_eq!(parse_cap_eff("Name:\tcat\n"), None);
+    }
+
+    #[test]
+    fn test_ptrace_scope() {
+        assert!(check_ptrace_scope(None, Some(false)).ok);
+        assert!(check_ptrace_scope(Some(0), Some(false)).ok);
+        assert!(check_ptrace_scope(Some(1), Some(true)).ok);
+        assert!(!check_ptrace_scope(Some(1), Some(false)).ok);
+        assert!(!check_ptrace_scope(Some(2), None).ok);
+        assert!(!check_ptrace_scope(Some(3), Some(true)).ok);
+    }
+}
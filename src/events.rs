@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+/// Window of the rolling event rate.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A kind of tracee event the main loop handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Fork,
+    Vfork,
+    Clone,
+    /// A clone that created a thread rather than a process.
+    Thread,
+    Exec,
+    Exit,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 6] = [
+        EventKind::Fork,
+        EventKind::Vfork,
+        EventKind::Clone,
+        EventKind::Thread,
+        EventKind::Exec,
+        EventKind::Exit,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Fork => "fork",
+            EventKind::Vfork => "vfork",
+            EventKind::Clone => "clone",
+            EventKind::Thread => "thread",
+            EventKind::Exec => "exec",
+            EventKind::Exit => "exit",
+        }
+    }
+}
+
+/// Lifetime and rolling one-minute counts of tracee events, to tell how much
+/// work tracing costs (and whether `--exec-only` would pay off).
+pub struct EventCounters {
+    start: Instant,
+    totals: [u64; EventKind::ALL.len()],
+    /// Counts per whole second since `start`, covering the last `WINDOW`.
+    recent: VecDeque<(u64, [u64; EventKind::ALL.len()])>,
+}
+
+impl Default for EventCounters {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl EventCounters {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            totals: [0; EventKind::ALL.len()],
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, kind: EventKind) {
+        self.record_at(kind, Instant::now());
+    }
+
+    fn record_at(&mut self, kind: EventKind, now: Instant) {
+        let second = self.second(now);
+        self.totals[kind as usize] += 1;
+        self.expire(second);
+        match self.recent.back_mut() {
+            Some((s, counts)) if *s == second => counts[kind as usize] += 1,
+            _ => {
+                let mut counts = [0; EventKind::ALL.len()];
+                counts[kind as usize] = 1;
+                self.recent.push_back((second, counts));
+            }
+        }
+    }
+
+    /// Events of `kind` since we started.
+    pub fn total(&self, kind: EventKind) -> u64 {
+        self.totals[kind as usize]
+    }
+
+    /// Events of `kind` per second, averaged over the last minute (or the
+    /// time since we started, if shorter).
+    pub fn rate(&self, kind: EventKind) -> f64 {
+        self.rate_at(kind, Instant::now())
+    }
+
+    fn rate_at(&self, kind: EventKind, now: Instant) -> f64 {
+        let second = self.second(now);
+        let count: u64 = self
+            .recent
+            .iter()
+            .filter(|(s, _)| second.saturating_sub(*s) < WINDOW.as_secs())
+            .map(|(_, counts)| counts[kind as usize])
+            .sum();
+        let window = now
+            .saturating_duration_since(self.start)
+            .clamp(Duration::from_secs(1), WINDOW);
+        count as f64 / window.as_secs_f64()
+    }
+
+    /// Log lifetime totals and the current rates.
+    pub fn log_summary(&self) {
+        let parts: Vec<String> = EventKind::ALL
+            .iter()
+            .map(|&kind| {
+                format!(
+                    "{} {} ({:.1}/s)",
+                    kind.name(),
+                    self.total(kind),
+                    self.rate(kind)
+                )
+            })
+            .collect();
+        info!("[stats] events: {}", parts.join(", "));
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_secs()
+    }
+
+    fn expire(&mut self, second: u64) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|(s, _)| second.saturating_sub(*s) >= WINDOW.as_secs())
+        {
+            self.recent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_totals_and_rate() {
+        let start = Instant::now();
+        let mut events = EventCounters::new(start);
+        for i in 0..30 {
+            events.record_at(EventKind::Fork, start + Duration::from_secs(i));
+        }
+        events.record_at(EventKind::Exec, start + Duration::from_secs(29));
+        assert_eq!(events.total(EventKind::Fork), 30);
+        assert_eq!(events.total(EventKind::Exec), 1);
+        assert_eq!(events.total(EventKind::Exit), 0);
+        // 30 forks in the first 30 seconds.
+        let rate = events.rate_at(EventKind::Fork, start + Duration::from_secs(30));
+        assert!((rate - 1.0).abs() < 1e-9, "{}", rate);
+    }
+
+    #[test]
+    fn test_rate_window_rolls() {
+        let start = Instant::now();
+        let mut events = EventCounters::new(start);
+        for _ in 0..120 {
+            events.record_at(EventKind::Clone, start);
+        }
+        let later = start + Duration::from_secs(90);
+        events.record_at(EventKind::Clone, later);
+        assert_eq!(events.total(EventKind::Clone), 121);
+        // Only the event within the last minute counts towards the rate.
+        let rate = events.rate_at(EventKind::Clone, later);
+        assert!((rate - 1.0 / 60.0).abs() < 1e-9, "{}", rate);
+        assert_eq!(events.recent.len(), 1);
+    }
+}
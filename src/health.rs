@@ -0,0 +1,138 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+/// How long the main loop may be late for its tick before we report it
+/// stuck, on top of a few missed ticks.
+const STALE_SLACK: Duration = Duration::from_secs(5);
+
+/// How long to wait for a connected client to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Liveness of the main loop, written by it on every tick and read by the
+/// health endpoint's thread.
+pub struct Health {
+    started: Instant,
+    /// How long the main loop may go without a tick.
+    stale_after: Duration,
+    /// Milliseconds since `started` of the last tick.
+    last_tick_ms: AtomicU64,
+    /// Traced roots (daemons, or the launched command) as of the last tick.
+    attached: AtomicUsize,
+}
+
+impl Health {
+    /// For a main loop that ticks every `interval` (`--sample-interval`).
+    pub fn new(interval: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            stale_after: interval * 3 + STALE_SLACK,
+            last_tick_ms: AtomicU64::new(0),
+            attached: AtomicUsize::new(0),
+        })
+    }
+
+    /// Record that the main loop is alive with `attached` traced roots.
+    pub fn beat(&self, attached: usize) {
+        let ms = self.started.elapsed().as_millis() as u64;
+        self.last_tick_ms.store(ms, Ordering::Relaxed);
+        self.attached.store(attached, Ordering::Relaxed);
+    }
+
+    /// `Ok` with a summary if we are functioning, `Err` with the reason if not.
+    fn check(&self, now: Instant) -> Result<String, String> {
+        let last_tick =
+            self.started + Duration::from_millis(self.last_tick_ms.load(Ordering::Relaxed));
+        let since = now.saturating_duration_since(last_tick);
+        let attached = self.attached.load(Ordering::Relaxed);
+        if since > self.stale_after {
+            Err(format!("main loop stalled for {:.1}s", since.as_secs_f64()))
+        } else if attached == 0 {
+            Err("nothing attached".to_owned())
+        } else {
+            Ok(format!("{} attached", attached))
+        }
+    }
+}
+
+/// Serve `GET /healthz` on `addr` (`--health-listen`) from a thread of its
+/// own, so that it can tell when the main loop is stuck.
+pub fn listen(addr: SocketAddr, health: Arc<Health>) -> Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("Failed to bind {}", addr))?;
+    info!("Health endpoint listening on http://{}/healthz", addr);
+    thread::Builder::new()
+        .name("health".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(anyhow::Error::from)
+                    .and_then(|stream| serve(stream, &health));
+                if let Err(e) = result {
+                    warn!("Health endpoint client error: {:#}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn serve(stream: TcpStream, health: &Health) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let response = respond(&request_line, health, Instant::now());
+    (&stream).write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// The whole HTTP/1.0 response to a request starting with `request_line`.
+fn respond(request_line: &str, health: &Health, now: Instant) -> String {
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/healthz")) => match health.check(now) {
+            Ok(summary) => ("200 OK", format!("ok: {}\n", summary)),
+            Err(reason) => ("503 Service Unavailable", format!("error: {}\n", reason)),
+        },
+        (Some("GET"), Some(_)) => ("404 Not Found", "error: not found\n".to_owned()),
+        _ => ("400 Bad Request", "error: bad request\n".to_owned()),
+    };
+    format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_respond() {
+        let health = Health::new(Duration::from_secs(10));
+        let now = Instant::now();
+        let get = "GET /healthz HTTP/1.1\r\n";
+        assert!(respond(get, &health, now).starts_with("HTTP/1.0 503 "));
+        health.beat(2);
+        let response = respond(get, &health, Instant::now());
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+        assert!(
+            response.ends_with("\r\n\r\nok: 2 attached\n"),
+            "{}",
+            response
+        );
+        // A few ticks of a long interval may go by.
+        let later = Instant::now() + Duration::from_secs(30);
+        assert!(respond(get, &health, later).starts_with("HTTP/1.0 200 OK\r\n"));
+        let later = Instant::now() + Duration::from_secs(36);
+        assert!(respond(get, &health, later).contains("main loop stalled"));
+        assert!(respond("GET /metrics HTTP/1.1", &health, now).starts_with("HTTP/1.0 404 "));
+        assert!(respond("", &health, now).starts_with("HTTP/1.0 400 "));
+    }
+}
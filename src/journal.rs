@@ -0,0 +1,146 @@
+use std::env;
+use std::os::unix::net::UnixDatagram;
+
+use log::kv::{self, VisitSource};
+use log::{Level, Log, Metadata, Record};
+
+/// Where journald accepts native protocol datagrams.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Logger writing native journald entries (`--log-format journald`).
+///
+/// Besides `MESSAGE` and `PRIORITY`, every key-value pair attached to a log
+/// call becomes a field named `NIXUBW_<KEY>`, so e.g. `event = "pause"` can be
+/// filtered on with `journalctl NIXUBW_EVENT=pause`. Filtering by level and
+/// target follows `RUST_LOG`, as with the default text logger.
+pub struct JournalLogger {
+    socket: UnixDatagram,
+    filter: env_logger::Logger,
+}
+
+impl JournalLogger {
+    /// Connect to the journal, or `None` if we don't run under it or its
+    /// socket is not there.
+    pub fn connect(filter: env_logger::Logger) -> Option<Self> {
+        env::var_os("JOURNAL_STREAM")?;
+        let socket = UnixDatagram::unbound().ok()?;
+        socket.connect(JOURNAL_SOCKET).ok()?;
+        Some(Self { socket, filter })
+    }
+
+    /// Most verbose level any target is logged at.
+    pub fn max_level(&self) -> log::LevelFilter {
+        self.filter.filter()
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        // Nothing sensible to do if the journal drops an entry.
+        let _ = self.socket.send(&encode(record));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Serialize a record in the journal's native protocol.
+fn encode(record: &Record) -> Vec<u8> {
+    let mut entry = Vec::new();
+    add_field(&mut entry, "MESSAGE", &record.args().to_string());
+    add_field(&mut entry, "PRIORITY", priority(record.level()));
+    add_field(&mut entry, "SYSLOG_IDENTIFIER", "nix-ubw");
+    add_field(&mut entry, "CODE_MODULE", record.target());
+    if let Some(file) = record.file() {
+        add_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        add_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    let _ = record.key_values().visit(&mut Fields(&mut entry));
+    entry
+}
+
+/// syslog(3) priority of a log level.
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+/// Append `NAME=value`, or the length-prefixed binary form if the value
+/// contains a newline.
+fn add_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Turns the key-value pairs of a record into `NIXUBW_*` fields.
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        // Field names may only contain A-Z, 0-9 and '_'.
+        let name: String = key
+            .as_str()
+            .chars()
+            .map(|c| match c.to_ascii_uppercase() {
+                c @ ('A'..='Z' | '0'..='9') => c,
+                _ => '_',
+            })
+            .collect();
+        add_field(self.0, &format!("NIXUBW_{}", name), &value.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_field() {
+        let mut entry = Vec::new();
+        add_field(&mut entry, "MESSAGE", "hello");
+        assert_eq!(entry, b"MESSAGE=hello\n");
+
+        let mut entry = Vec::new();
+        add_field(&mut entry, "MESSAGE", "a\nb");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn test_encode_key_values() {
+        let kvs = [("event", "pause"), ("mem_gb", "4")];
+        let record = Record::builder()
+            .args(format_args!("paused"))
+            .level(Level::Info)
+            .target("nix_ubw::limiter")
+            .key_values(&kvs)
+            .build();
+        let entry = String::from_utf8(encode(&record)).unwrap();
+        assert!(entry.contains("MESSAGE=paused\n"));
+        assert!(entry.contains("PRIORITY=6\n"));
+        assert!(entry.contains("NIXUBW_EVENT=pause\n"));
+        assert!(entry.contains("NIXUBW_MEM_GB=4\n"));
+    }
+}
@@ -0,0 +1,31 @@
+//! Throttles resource-hungry programs started by the Nix daemon, by tracing
+//! it with ptrace and pausing them at exec until the budget has room. The
+//! `nix-ubw` binary is the command line front end; the modules live in this
+//! library so that benchmarks can drive the limiter directly.
+
+pub mod admission;
+pub mod advisory;
+pub mod checkpoint;
+pub mod clock;
+#[cfg(feature = "serde")]
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod doctor;
+pub mod events;
+pub mod health;
+pub mod journal;
+pub mod limiter;
+pub mod logfmt;
+pub mod nixutil;
+pub mod pidfile;
+pub mod pressure;
+pub mod probe;
+pub mod ptrace;
+pub mod resources;
+pub mod sampler;
+pub mod shared;
+pub mod signals;
+pub mod statusline;
+pub mod timeline;
+pub mod tracer;
@@ -1,17 +1,105 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use log::debug;
-use log::{info, warn};
-use nix::sys::ptrace;
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
 use nix::unistd::Pid;
 
-use crate::resources::{profile_for, ResourceProfile};
+use crate::admission::{self, AdmissionPolicy, Queue, Queued};
+use crate::checkpoint::SavedProcess;
+use crate::clock::{Clock, SystemClock};
+use crate::nixutil::{self, Environ};
+use crate::ptrace::{MockPtrace, Ptrace, SystemPtrace};
+#[cfg(test)]
+use crate::resources::RuleGroup;
+use crate::resources::{profile_for, ResourceProfile, RuleMatch, RuleSet, SiblingMem};
+use crate::shared::SharedState;
+use crate::timeline::Timeline;
 
 /// Per-PID record of claimed resources.
+///
+/// A throttled process running under another active throttled process (a
+/// linker under `collect2` under `gcc`) is part of the same unit of work: the
+/// ancestor is just waiting for it. Such a process borrows the ancestor's
+/// claim and only claims the amount by which it exceeds it, so a whole chain
+/// reserves the component-wise maximum of its profiles rather than the sum.
+/// An ancestor lends to one process at a time; further concurrent children
+/// (parallel LTO workers) are genuinely parallel work and claim in full.
 struct ActiveEntry {
+    pid: Pid,
     name: String,
     profile: ResourceProfile,
+    /// What this process actually took from `free`.
+    claim: ResourceProfile,
+    /// What the chain this process belongs to reserves, up to this process.
+    unit: ResourceProfile,
+    /// The active ancestor this process borrows from.
+    lender: Option<Pid>,
+    /// Whether a descendant is currently borrowing this process's claim.
+    lending: bool,
+    /// When the process was admitted, i.e. started running for real.
+    started: Instant,
+    /// Last CPU time sample, for `--adaptive active-cpu`.
+    cpu: Cell<CpuSample>,
+    /// Effective uid of the nix-daemon worker the process runs under.
+    owner: Option<u32>,
+    /// Its total CPU time in clock ticks, read at its exit stop.
+    exit_cpu_ticks: Option<u64>,
+    /// Its CPU time in clock ticks when it was admitted, under
+    /// `--suggest-rules`, so what it used while paused or in its claim
+    /// grace period isn't counted against its claim.
+    admit_cpu_ticks: u64,
+    /// Its peak resident memory in KiB, read at its exit stop under
+    /// `--suggest-rules`.
+    exit_peak_rss_kb: Option<u64>,
+    /// CPU time in clock ticks of the children it waited for, read at its
+    /// exit stop under `--suggest-rules`.
+    exit_children_cpu_ticks: Option<u64>,
+    /// Admitted from a `listen` hold, so its group-stop is still pending.
+    group_stopped: bool,
+    /// Start time of a process restored from a `--checkpoint`, which we may
+    /// not trace, so its exit is noticed by polling instead.
+    restored_start: Option<u64>,
+}
+
+/// A CPU time reading of an active process.
+#[derive(Clone, Copy)]
+struct CpuSample {
+    ticks: u64,
+    at: Instant,
+    /// Cores used between the previous sample and this one, if measured.
+    usage: Option<f64>,
+}
+
+/// Samples closer together than this are too noisy; the previous measurement
+/// is reused instead.
+const MIN_CPU_SAMPLE_WINDOW: Duration = Duration::from_secs(1);
+
+impl ActiveEntry {
+    /// Cores this process used recently. Until a full sample window has
+    /// passed, its claim is assumed to be fully used.
+    fn recent_cpu(&self) -> f64 {
+        let sample = self.cpu.get();
+        if sample.at.elapsed() < MIN_CPU_SAMPLE_WINDOW {
+            return sample.usage.unwrap_or(f64::from(self.profile.cpus));
+        }
+        let Some(ticks) = nixutil::read_cpu_ticks(self.pid) else {
+            return 0.0;
+        };
+        let usage = nixutil::cpu_usage(sample.ticks, ticks, sample.at.elapsed());
+        self.cpu.set(CpuSample {
+            ticks,
+            at: Instant::now(),
+            usage: Some(usage),
+        });
+        usage
+    }
 }
 
 /// A paused process waiting for resources to free up.
@@ -19,16 +107,646 @@ struct PausedEntry {
     pid: Pid,
     name: String,
     profile: ResourceProfile,
+    /// Traced ancestors of the process, nearest first.
+    ancestors: Vec<Pid>,
+    /// Failed attempts to resume this process so far.
+    retries: u32,
+    /// Effective uid of the nix-daemon worker the process runs under.
+    owner: Option<u32>,
+    /// Id of the rule the process matched, its class for weighted resumes.
+    class: String,
+    /// When the process was paused.
+    since: Instant,
+    /// How the process is being held right now. Every paused process starts
+    /// out in its exec ptrace-stop.
+    held: PauseMethod,
+    /// Still running: its claim grace period ran out without room for it, and
+    /// it has been interrupted but has not stopped yet.
+    running: bool,
+}
+
+/// Outcome of trying to resume one paused process.
+#[derive(Debug, PartialEq, Eq)]
+enum Attempt {
+    /// Resumed, or dropped from the queue for good.
+    Resumed,
+    /// Over its user's share; others may still go.
+    HeldBack,
+    /// Doesn't fit, or failed to resume for now: end the pass.
+    Stop,
+}
+
+/// A throttled process running on probation during `--claim-grace`,
+/// without a claim.
+struct GraceEntry {
+    name: String,
+    profile: ResourceProfile,
+    ancestors: Vec<Pid>,
+    owner: Option<u32>,
+    class: String,
+    since: Instant,
+}
+
+/// A process whose name is to be read again after exec (`--recheck-comm`),
+/// with what it exec'd.
+struct CommCheck {
+    /// Its comm at exec.
+    comm: String,
+    args: Vec<String>,
+    ancestors: Vec<Pid>,
+    owner: Option<u32>,
+    due: Instant,
+}
+
+/// The rule and profile of a classification, for log lines.
+fn describe_match(matched: &Option<RuleMatch>) -> String {
+    match matched {
+        Some(RuleMatch { rule, profile }) => format!("rule '{}' ({})", rule, profile),
+        None => "no rule".into(),
+    }
+}
+
+/// `name (pid)` for log lines, with the owning uid if known.
+fn describe(name: &str, pid: Pid, owner: Option<u32>) -> String {
+    match owner {
+        Some(uid) => format!("{} ({}, uid {})", name, pid, uid),
+        None => format!("{} ({})", name, pid),
+    }
+}
+
+/// `, used 24.1s CPU (2.0 cores)` for the `finished` line of a process that
+/// used `ticks` of CPU time over `wall`, or nothing if its CPU time is unknown.
+fn cpu_used(ticks: Option<u64>, wall: Duration) -> String {
+    match ticks {
+        Some(ticks) => format!(
+            ", used {:.1}s CPU ({:.1} cores)",
+            ticks as f64 / nixutil::clock_ticks_per_sec(),
+            nixutil::cpu_usage(0, ticks, wall)
+        ),
+        None => String::new(),
+    }
+}
+
+/// An active process as reported to the outside (e.g. `list-active`).
+pub struct ActiveSummary {
+    pub pid: Pid,
+    pub name: String,
+    /// What the process took from the budget.
+    pub claim: ResourceProfile,
+    pub owner: Option<u32>,
+    /// Time since admission.
+    pub running_for: Duration,
+}
+
+/// A paused process as reported to the outside (e.g. `status`).
+pub struct PausedSummary {
+    pub pid: Pid,
+    pub name: String,
+    /// What the process needs to be admitted.
+    pub profile: ResourceProfile,
+    /// Time since it was paused.
+    pub waiting_for: Duration,
+}
+
+/// A group of programs with shared caps as reported to the outside
+/// (`status`).
+pub struct GroupSummary {
+    pub name: String,
+    /// Active processes of the group.
+    pub instances: usize,
+    pub max_instances: Option<usize>,
+    /// What those processes claim together.
+    pub claimed: ResourceProfile,
+    pub budget: Option<ResourceProfile>,
+}
+
+/// Where a process stands with the limiter, as returned by
+/// [`Limiter::state_of`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessState {
+    /// Not throttled: it matched no rule, has exited, or was never seen.
+    NotTracked,
+    /// Running unclaimed in its `--claim-grace` period.
+    Grace { profile: ResourceProfile },
+    /// Admitted. `claim` is what it took from the budget, which is less than
+    /// its `profile` while it borrows from an active ancestor.
+    Active {
+        profile: ResourceProfile,
+        claim: ResourceProfile,
+    },
+    /// Waiting in the queue for `profile` to fit.
+    Paused { profile: ResourceProfile },
+}
+
+impl ProcessState {
+    /// The state as shown by `tree` and `state`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProcessState::NotTracked => "unthrottled",
+            ProcessState::Grace { .. } => "grace",
+            ProcessState::Active { .. } => "active",
+            ProcessState::Paused { .. } => "paused",
+        }
+    }
 }
 
 /// Result of the on_exec call.
 pub enum OnExecResult {
-    /// Process is not throttled.
+    /// Process is not throttled, or was admitted without being held. The
+    /// caller continues it.
     NotThrottled,
     /// Process might be throttled.
     Throttled,
 }
 
+/// What adaptive admission measures to decide whether claimed cores are
+/// actually busy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum AdaptiveSource {
+    /// The system-wide 1-minute load average.
+    Loadavg,
+    /// Recent CPU time of our own active throttled processes.
+    ActiveCpu,
+}
+
+impl AdaptiveSource {
+    /// How long a measurement is reused. The kernel updates the load
+    /// average every 5 seconds.
+    fn sample_window(self) -> Duration {
+        match self {
+            AdaptiveSource::Loadavg => Duration::from_secs(5),
+            AdaptiveSource::ActiveCpu => MIN_CPU_SAMPLE_WINDOW,
+        }
+    }
+}
+
+/// MemAvailable is read again for `--mem-floor-gb` after this long.
+const MEM_SAMPLE_WINDOW: Duration = Duration::from_secs(1);
+
+/// MemAvailable as last read for `--mem-floor-gb`.
+#[derive(Clone, Copy)]
+struct MemSample {
+    available_kb: u64,
+    at: Instant,
+    /// Memory claimed by processes admitted since, in KiB, which they have
+    /// yet to allocate.
+    admitted_kb: u64,
+}
+
+/// Busy cores as last measured for `--adaptive`.
+#[derive(Clone, Copy)]
+struct UsageSample {
+    used: f64,
+    at: Instant,
+    /// CPUs claimed by processes admitted since, which the measurement
+    /// doesn't show yet.
+    admitted: f64,
+}
+
+/// How a paused process is held until it is admitted (`--pause-method`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PauseMethod {
+    /// Leave it in its exec ptrace-stop and continue it on admission.
+    #[default]
+    Ptrace,
+    /// Put it in a job-control stop with SIGSTOP and resume it with SIGCONT.
+    /// The stop outlives the tracer: a process we let go of without resuming
+    /// shows as `T` and anyone can resume it with SIGCONT.
+    Sigstop,
+    /// Put it in a job-control stop with SIGSTOP like `sigstop`, but admit it
+    /// with PTRACE_INTERRUPT and PTRACE_CONT from its PTRACE_LISTEN stop
+    /// rather than with SIGCONT, so that it never receives a SIGCONT or runs
+    /// a handler for one while we trace it. The group-stop stays pending
+    /// though, and comes back once the process is detached, so it gets its
+    /// SIGCONT when we let go of it.
+    Listen,
+}
+
+impl PauseMethod {
+    /// Whether the process is held in a job-control stop, which outlives the
+    /// tracer and must be ended with SIGCONT if we let go of it.
+    pub fn group_stop(self) -> bool {
+        matches!(self, PauseMethod::Sigstop | PauseMethod::Listen)
+    }
+}
+
+/// Which paused process is resumed first when budget frees up
+/// (`--resume-order`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ResumeOrder {
+    /// The one paused longest ago.
+    #[default]
+    Fifo,
+    /// The one paused most recently, whose working set is likeliest to still
+    /// be in the page cache. Processes paused for longer than `MAX_WAIT` go
+    /// first, oldest first, so a steady stream of new arrivals can't starve
+    /// the old ones.
+    Lifo,
+    /// The one expected to run longest, by the `[est_duration]` of its rule,
+    /// to shorten the build's critical path (longest processing time first).
+    /// Processes without an estimate go after those with one, and ties go to
+    /// the oldest. Processes paused for longer than `MAX_WAIT` go first, as
+    /// under `Lifo`.
+    Lpt,
+}
+
+impl ResumeOrder {
+    /// The admission policy implementing this order.
+    pub fn policy(self) -> Box<dyn AdmissionPolicy> {
+        match self {
+            ResumeOrder::Fifo => Box::new(admission::Fifo),
+            ResumeOrder::Lifo => Box::new(admission::Lifo),
+            ResumeOrder::Lpt => Box::new(admission::Lpt),
+        }
+    }
+}
+
+/// Tunables for the limiter's admission behavior.
+pub struct LimiterOptions {
+    /// Treat CPU claims as a ceiling rather than a reservation: admit a
+    /// process that doesn't fit the CPU budget if measured usage shows the
+    /// cores aren't actually busy.
+    pub adaptive: Option<AdaptiveSource>,
+    /// How many times to retry resuming a paused process after a transient
+    /// `ptrace::cont` failure before giving up on it.
+    pub cont_retries: u32,
+    /// File shared with other nix-ubw instances whose claims are subtracted
+    /// from our own free budget.
+    pub shared_state: Option<PathBuf>,
+    /// Always let at least this many throttled processes run, whatever their
+    /// combined profile.
+    pub min_parallel: usize,
+    /// Reduced claims for concurrent instances of the same program.
+    pub sibling_mem: Vec<SiblingMem>,
+    /// Kill processes that have been paused for longer than this.
+    pub pause_kill_after: Option<Duration>,
+    /// How paused processes are held.
+    pub pause_method: PauseMethod,
+    /// Cap on what the processes of each worker uid may claim together.
+    pub per_user_budget: Option<ResourceProfile>,
+    /// Processes that are admitted straight away whatever the budget
+    /// (`--never-pause-pid`). They still claim their profile.
+    pub never_pause: HashSet<Pid>,
+    /// Let throttled processes run this long before they claim anything, so
+    /// that trivial invocations never take budget or wait for it.
+    pub claim_grace: Option<Duration>,
+    /// Rules from config files, taking precedence over the built-in ones.
+    pub rules: RuleSet,
+    /// Batch resumes after releases over this window instead of resuming on
+    /// every exit.
+    pub resume_debounce: Option<Duration>,
+    /// Order of resumes from the paused queue, unless resume weights are
+    /// configured.
+    pub resume_order: ResumeOrder,
+    /// Hold back processes with a memory claim while admitting them would
+    /// leave less than this many GiB of MemAvailable.
+    pub mem_floor_gb: Option<u32>,
+    /// Read the name of each exec'd process again this long after exec, and
+    /// reclassify it if it renamed itself.
+    pub recheck_comm: Option<Duration>,
+    /// Compare what the processes of each rule use with what they claim, and
+    /// suggest better claims in the summary.
+    pub suggest_rules: bool,
+    /// Read the environment of each throttled process to report the ones
+    /// running under a make jobserver.
+    pub report_jobserver: bool,
+}
+
+impl Default for LimiterOptions {
+    fn default() -> Self {
+        Self {
+            adaptive: None,
+            cont_retries: 3,
+            shared_state: None,
+            min_parallel: 1,
+            sibling_mem: Vec::new(),
+            pause_kill_after: None,
+            pause_method: PauseMethod::default(),
+            per_user_budget: None,
+            never_pause: HashSet::new(),
+            claim_grace: None,
+            rules: RuleSet::default(),
+            resume_debounce: None,
+            resume_order: ResumeOrder::default(),
+            mem_floor_gb: None,
+            recheck_comm: None,
+            suggest_rules: false,
+            report_jobserver: false,
+        }
+    }
+}
+
+/// Upper bounds of the pause duration histogram buckets, in seconds.
+const PAUSE_BUCKETS: [f64; 4] = [0.1, 1.0, 10.0, 60.0];
+
+/// Distribution of how long processes waited in the paused queue before
+/// being resumed.
+#[derive(Default)]
+pub struct PauseHistogram {
+    /// Resumes per bucket of `PAUSE_BUCKETS`, then those over the last bound.
+    counts: [u64; PAUSE_BUCKETS.len() + 1],
+    sum: Duration,
+}
+
+impl PauseHistogram {
+    fn observe(&mut self, wait: Duration) {
+        let secs = wait.as_secs_f64();
+        let bucket = PAUSE_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(PAUSE_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += wait;
+    }
+
+    /// `(upper bound, resumes that waited at most that long)` for each
+    /// bucket, ending with `f64::INFINITY` and the total count.
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let bounds = PAUSE_BUCKETS.iter().copied().chain([f64::INFINITY]);
+        bounds
+            .zip(self.counts.iter().scan(0, |total, &n| {
+                *total += n;
+                Some(*total)
+            }))
+            .collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Total time waited by all resumed processes.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+}
+
+/// Upper bounds of the usage histogram buckets, in GiB or cores.
+const USAGE_BUCKETS: [f64; 14] = [
+    0.25, 0.5, 1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0, 16.0, 24.0, 32.0, 48.0, 64.0,
+];
+
+/// Processes of a rule to observe before suggesting another claim for it.
+const MIN_RULE_SAMPLES: u64 = 10;
+
+/// Distribution of what the processes of one rule used of a resource, for
+/// `--suggest-rules`.
+#[derive(Default)]
+pub struct UsageHistogram {
+    /// Processes per bucket of `USAGE_BUCKETS`, then those over the last bound.
+    counts: [u64; USAGE_BUCKETS.len() + 1],
+}
+
+/// Another claim for a rule, from the usage its processes were observed with.
+#[derive(Debug, PartialEq)]
+pub struct Suggestion {
+    /// Bounds of the histogram bucket holding the median usage.
+    pub median: (f64, f64),
+    pub samples: u64,
+    /// The claim to raise or lower to.
+    pub claim: i32,
+}
+
+impl UsageHistogram {
+    fn observe(&mut self, usage: f64) {
+        let bucket = USAGE_BUCKETS
+            .iter()
+            .position(|&bound| usage <= bound)
+            .unwrap_or(USAGE_BUCKETS.len());
+        self.counts[bucket] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Bounds of the bucket holding the median, `None` without samples.
+    pub fn median(&self) -> Option<(f64, f64)> {
+        let half = self.count().div_ceil(2);
+        let mut seen = 0;
+        let bucket = self.counts.iter().position(|&n| {
+            seen += n;
+            half > 0 && seen >= half
+        })?;
+        let low = bucket.checked_sub(1).map_or(0.0, |i| USAGE_BUCKETS[i]);
+        let high = USAGE_BUCKETS.get(bucket).copied().unwrap_or(f64::INFINITY);
+        Some((low, high))
+    }
+
+    /// A claim to replace `claimed` with, once enough processes were
+    /// observed: raised to the top of the median bucket if the median is
+    /// over the claim (or over 1 for a claim of 0), lowered to it if the
+    /// median is at most half the claim.
+    pub fn suggest(&self, claimed: i32) -> Option<Suggestion> {
+        let samples = self.count();
+        if samples < MIN_RULE_SAMPLES {
+            return None;
+        }
+        let median @ (low, high) = self.median()?;
+        let claim = if low >= f64::from(claimed.max(1)) {
+            // Over the last bound, the best we can tell is that it's more.
+            if high.is_finite() {
+                high.ceil() as i32
+            } else {
+                low as i32 * 2
+            }
+        } else if claimed > 1 && high <= f64::from(claimed) / 2.0 {
+            (high.ceil() as i32).max(1)
+        } else {
+            return None;
+        };
+        Some(Suggestion {
+            median,
+            samples,
+            claim,
+        })
+    }
+}
+
+/// What the processes of one rule used against what they claimed.
+pub struct RuleUsage {
+    /// Largest profile the rule's processes matched with, before sibling
+    /// reductions and lending.
+    pub claimed: ResourceProfile,
+    /// Peak resident memory of each process, in GiB.
+    pub peak_mem: UsageHistogram,
+    /// Cores each process used over its lifetime. Processes that ran for
+    /// less than a second are left out, their usage is too noisy.
+    pub cores: UsageHistogram,
+    /// How many of its processes spent CPU time in children of their own.
+    /// Neither histogram covers the children, so such a rule is not judged.
+    pub forked: u64,
+}
+
+/// Format the bucket bounds of a median.
+fn median_range((low, high): (f64, f64)) -> String {
+    if high.is_finite() {
+        format!("{}-{}", low, high)
+    } else {
+        format!("over {}", low)
+    }
+}
+
+/// Counters accumulated over the whole run, for sizing the budget.
+#[derive(Default)]
+pub struct LimiterStats {
+    /// Most throttled processes running at the same time.
+    pub peak_active: usize,
+    /// Longest the paused queue has been.
+    pub peak_paused: usize,
+    /// Total number of admissions.
+    pub total_admitted: u64,
+    /// Admissions per matched process name.
+    pub admitted_by_name: BTreeMap<String, u64>,
+    /// Times the rule table was consulted (classification cache misses).
+    pub rule_lookups: u64,
+    /// Throttled processes that were already running under a make jobserver.
+    pub under_jobserver: u64,
+    /// Throttled processes that exited within `--claim-grace` unclaimed.
+    pub short_lived: u64,
+    /// How long resumed processes had been paused.
+    pub pause_durations: PauseHistogram,
+    /// Usage of the processes of each rule, by rule id, under
+    /// `--suggest-rules`.
+    pub rule_usage: BTreeMap<String, RuleUsage>,
+    /// Time spent with a non-empty paused queue, excluding the current stretch.
+    paused_time: Duration,
+    /// Start of the current stretch with a non-empty paused queue.
+    paused_since: Option<Instant>,
+}
+
+impl LimiterStats {
+    /// Total time spent with a non-empty paused queue so far.
+    pub fn paused_time(&self) -> Duration {
+        self.paused_time + self.paused_since.map_or(Duration::ZERO, |t| t.elapsed())
+    }
+
+    /// Log a human-readable summary of the run.
+    pub fn log_summary(&self) {
+        info!(
+            "[stats] peak active: {}, peak paused: {}, admitted: {}, time with paused queue: {:.1}s",
+            self.peak_active,
+            self.peak_paused,
+            self.total_admitted,
+            self.paused_time().as_secs_f64(),
+        );
+        for (name, count) in &self.admitted_by_name {
+            info!("[stats]   {}: {} admitted", name, count);
+        }
+        if self.short_lived > 0 {
+            info!(
+                "[stats] {} throttled processes finished within the claim grace period",
+                self.short_lived
+            );
+        }
+        if self.under_jobserver > 0 {
+            info!(
+                "[stats] {} throttled processes ran under a make jobserver",
+                self.under_jobserver
+            );
+        }
+        self.log_suggestions();
+    }
+
+    /// Warn about rules whose processes use consistently more or less than
+    /// they claim.
+    fn log_suggestions(&self) {
+        let adjust = |suggestion: &Suggestion, claimed| {
+            if suggestion.claim > claimed {
+                "raising"
+            } else {
+                "lowering"
+            }
+        };
+        for (rule, usage) in &self.rule_usage {
+            if usage.forked > 0 {
+                debug!(
+                    "[stats] not judging rule '{}': {} of its processes ran children",
+                    rule, usage.forked
+                );
+                continue;
+            }
+            let claimed = usage.claimed;
+            if let Some(s) = usage.peak_mem.suggest(claimed.mem_gb) {
+                warn!(
+                    "[stats] rule '{}' claimed {} GiB but observed median peak {} GiB over {} processes - consider {} it to {} GiB",
+                    rule,
+                    claimed.mem_gb,
+                    median_range(s.median),
+                    s.samples,
+                    adjust(&s, claimed.mem_gb),
+                    s.claim
+                );
+            }
+            if let Some(s) = usage.cores.suggest(claimed.cpus) {
+                warn!(
+                    "[stats] rule '{}' claimed {} CPUs but observed median {} cores over {} processes - consider {} it to {} CPUs",
+                    rule,
+                    claimed.cpus,
+                    median_range(s.median),
+                    s.samples,
+                    adjust(&s, claimed.cpus),
+                    s.claim
+                );
+            }
+        }
+    }
+
+    /// Record what a finished process of the rule `matched` used, and
+    /// whether it ran children of its own.
+    fn observe_usage(
+        &mut self,
+        matched: &RuleMatch,
+        peak_rss_kb: Option<u64>,
+        cores: Option<f64>,
+        forked: bool,
+    ) {
+        let usage = self
+            .rule_usage
+            .entry(matched.rule.to_string())
+            .or_insert_with(|| RuleUsage {
+                claimed: matched.profile,
+                peak_mem: UsageHistogram::default(),
+                cores: UsageHistogram::default(),
+                forked: 0,
+            });
+        usage.claimed = ResourceProfile::new(
+            usage.claimed.cpus.max(matched.profile.cpus),
+            usage.claimed.mem_gb.max(matched.profile.mem_gb),
+        );
+        if let Some(kb) = peak_rss_kb {
+            usage
+                .peak_mem
+                .observe(kb as f64 / nixutil::KIB_PER_GIB as f64);
+        }
+        if let Some(cores) = cores {
+            usage.cores.observe(cores);
+        }
+        if forked {
+            usage.forked += 1;
+        }
+    }
+
+    fn admitted(&mut self, name: &str) {
+        self.total_admitted += 1;
+        *self.admitted_by_name.entry(name.to_owned()).or_default() += 1;
+    }
+
+    /// Record the current queue sizes after a state change.
+    fn observe(&mut self, active: usize, paused: usize) {
+        self.peak_active = self.peak_active.max(active);
+        self.peak_paused = self.peak_paused.max(paused);
+        match (paused > 0, self.paused_since) {
+            (true, None) => self.paused_since = Some(Instant::now()),
+            (false, Some(since)) => {
+                self.paused_time += since.elapsed();
+                self.paused_since = None;
+            }
+            _ => {}
+        }
+    }
+}
+
 /// Tracks resource consumption of rate-limited processes and pauses new ones
 /// when the budget (CPU cores or memory) is exhausted.
 pub struct Limiter {
@@ -38,182 +756,2449 @@ pub struct Limiter {
     active: HashMap<Pid, ActiveEntry>,
     /// Queue of processes waiting for resources.
     paused: VecDeque<PausedEntry>,
+    /// Processes running unclaimed in their `--claim-grace` period.
+    grace: HashMap<Pid, GraceEntry>,
+    /// Processes to reclassify if they rename themselves, with `--recheck-comm`.
+    comm_checks: HashMap<Pid, CommCheck>,
+    /// First release since the last resume pass, with `--resume-debounce`.
+    resume_pending: Option<Instant>,
+    /// Smooth weighted round-robin credit of each class with paused
+    /// processes, when resume weights are configured.
+    class_credit: HashMap<String, i64>,
     /// Currently available (free) resources.
     free: ResourceProfile,
-    /// Whether running in unit test and do not perform actual ptrace::cont operations.
+    /// Admission tunables.
+    options: LimiterOptions,
+    /// Counters over the whole run.
+    stats: LimiterStats,
+    /// Classification of each exec'd process, keyed by PID, with the argv it
+    /// was computed for. Cleared when the process exits.
+    classified: HashMap<Pid, (Vec<String>, Option<RuleMatch>)>,
+    /// Cross-instance claim ledger, if enabled.
+    shared: Option<SharedState>,
+    /// Time source for queue and grace timers.
+    clock: Box<dyn Clock>,
+    /// Resumes, releases and kills go through here.
+    ptrace: Box<dyn Ptrace>,
+    /// Picks the next paused process to resume, from `--resume-order`.
+    policy: Box<dyn AdmissionPolicy>,
+    /// Resources claimed by other instances, as of the last ledger update.
+    others: ResourceProfile,
+    /// MemAvailable in KiB when `--mem-floor-gb` last held back a process
+    /// during the current resume pass.
+    mem_floor_hit: Cell<Option<u64>>,
+    /// Last measurement of busy cores, with `--adaptive`.
+    cpu_usage: Cell<Option<UsageSample>>,
+    /// Last reading of MemAvailable, with `--mem-floor-gb`.
+    mem_available: Cell<Option<MemSample>>,
+    /// Whether the last resume pass held a process back by `--mem-floor-gb`,
+    /// so the queue must be retried as memory frees up outside our
+    /// accounting.
+    below_mem_floor: bool,
+    /// Admitting nothing more ahead of shutdown (the control socket's
+    /// `drain`): every throttled process is paused, and stays paused.
+    draining: bool,
+    /// Pauses, admits and exits of throttled processes, for `--trace-output`.
+    timeline: Option<Timeline>,
+    /// Whether running in unit test, reading the fakes below instead of /proc.
+    #[cfg(test)]
     unit_test: bool,
+    /// MemAvailable in KiB that unit tests pretend to read.
+    #[cfg(test)]
+    fake_mem_available_kb: Option<u64>,
+    /// Load average that unit tests pretend to read.
+    #[cfg(test)]
+    fake_loadavg: Option<f64>,
+    /// Resident memory in KiB of each process that unit tests pretend to read.
+    #[cfg(test)]
+    fake_rss_kb: HashMap<Pid, u64>,
+    /// CPU time in clock ticks of each process that unit tests pretend to
+    /// read.
+    #[cfg(test)]
+    fake_cpu_ticks: HashMap<Pid, u64>,
+    /// Comm of each process that unit tests pretend to read.
+    #[cfg(test)]
+    fake_comm: HashMap<Pid, String>,
 }
 
 impl Limiter {
+    #[cfg(test)]
     pub fn new(total: ResourceProfile, unit_test: bool) -> Self {
+        Self::with_options(total, LimiterOptions::default(), unit_test)
+    }
+
+    /// With `unit_test`, ptrace calls are only recorded (see `MockPtrace`).
+    pub fn with_options(total: ResourceProfile, options: LimiterOptions, unit_test: bool) -> Self {
+        let shared = options.shared_state.clone().map(SharedState::new);
+        let policy = options.resume_order.policy();
         Self {
             total,
             active: HashMap::new(),
             paused: VecDeque::new(),
+            grace: HashMap::new(),
+            comm_checks: HashMap::new(),
+            resume_pending: None,
+            class_credit: HashMap::new(),
             free: total,
-            unit_test: unit_test,
+            options,
+            stats: LimiterStats::default(),
+            classified: HashMap::new(),
+            shared,
+            clock: Box::new(SystemClock),
+            ptrace: if unit_test {
+                Box::new(MockPtrace::new())
+            } else {
+                Box::new(SystemPtrace)
+            },
+            policy,
+            others: ResourceProfile::new(0, 0),
+            #[cfg(test)]
+            unit_test,
+            mem_floor_hit: Cell::new(None),
+            cpu_usage: Cell::new(None),
+            mem_available: Cell::new(None),
+            below_mem_floor: false,
+            draining: false,
+            timeline: None,
+            #[cfg(test)]
+            fake_mem_available_kb: None,
+            #[cfg(test)]
+            fake_loadavg: None,
+            #[cfg(test)]
+            fake_rss_kb: HashMap::new(),
+            #[cfg(test)]
+            fake_cpu_ticks: HashMap::new(),
+            #[cfg(test)]
+            fake_comm: HashMap::new(),
+        }
+    }
+
+    /// Use `clock` instead of the system clock for queue and grace timers.
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Make ptrace calls through `ptrace`, e.g. a `MockPtrace` to inspect.
+    pub fn set_ptrace(&mut self, ptrace: impl Ptrace + 'static) {
+        self.ptrace = Box::new(ptrace);
+    }
+
+    /// Resume paused processes in the order `policy` picks, instead of the
+    /// one of `--resume-order`.
+    pub fn set_policy(&mut self, policy: impl AdmissionPolicy + 'static) {
+        self.policy = Box::new(policy);
+    }
+
+    /// Start recording when throttled processes wait and run.
+    pub fn record_timeline(&mut self) {
+        self.timeline.get_or_insert_with(Timeline::default);
+    }
+
+    /// The recorded timeline, with open slices ended now.
+    pub fn timeline(&mut self) -> Option<&Timeline> {
+        let now = self.clock.now();
+        let timeline = self.timeline.as_mut()?;
+        timeline.close(now);
+        Some(timeline)
+    }
+
+    /// Report a transition to the timeline, if it is being recorded.
+    fn record(&mut self, event: impl FnOnce(&mut Timeline, Instant)) {
+        if let Some(timeline) = &mut self.timeline {
+            event(timeline, self.clock.now());
+        }
+    }
+
+    pub fn stats(&self) -> &LimiterStats {
+        &self.stats
+    }
+
+    pub fn total(&self) -> ResourceProfile {
+        self.total
+    }
+
+    /// Budget not claimed by our active processes.
+    pub fn free(&self) -> ResourceProfile {
+        self.free
+    }
+
+    /// Number of active throttled processes.
+    pub fn active_count(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Number of paused processes.
+    pub fn paused_count(&self) -> usize {
+        self.paused.len()
+    }
+
+    /// Replace the total budget at runtime, e.g. after CPUs were hot-plugged.
+    ///
+    /// Active processes keep their claims, so shrinking the budget below what
+    /// they hold leaves `free` negative until enough of them exit. If the
+    /// budget grew, paused processes that now fit are resumed right away.
+    pub fn set_total(&mut self, total: ResourceProfile) {
+        let old = self.total;
+        let claimed = self
+            .active
+            .values()
+            .fold(ResourceProfile::new(0, 0), |sum, e| sum + e.claim);
+        self.total = total;
+        self.free = total - claimed;
+        info!(
+            "[limit] Budget changed from {} to {}, free: {} ({} paused)",
+            old,
+            total,
+            self.free,
+            self.paused.len()
+        );
+        if total.cpus > old.cpus || total.mem_gb > old.mem_gb {
+            self.try_resume_paused();
+        } else {
+            self.sync_shared();
         }
     }
 
+    /// The paused processes, in queue order.
+    pub fn paused_summary(&self) -> Vec<PausedSummary> {
+        self.paused
+            .iter()
+            .map(|e| PausedSummary {
+                pid: e.pid,
+                name: e.name.clone(),
+                profile: e.profile,
+                waiting_for: self.clock.since(e.since),
+            })
+            .collect()
+    }
+
+    /// The active processes, ordered by PID.
+    pub fn active_summary(&self) -> Vec<ActiveSummary> {
+        let mut active: Vec<ActiveSummary> = self
+            .active
+            .values()
+            .map(|e| ActiveSummary {
+                pid: e.pid,
+                name: e.name.clone(),
+                claim: e.claim,
+                owner: e.owner,
+                running_for: self.clock.since(e.started),
+            })
+            .collect();
+        active.sort_by_key(|e| e.pid);
+        active
+    }
+
+    /// Active processes and claims per configured group, ordered by name.
+    pub fn group_summary(&self) -> Vec<GroupSummary> {
+        self.options
+            .rules
+            .groups()
+            .map(|(name, group)| {
+                let mut summary = GroupSummary {
+                    name: name.to_owned(),
+                    instances: 0,
+                    max_instances: group.max_instances,
+                    claimed: ResourceProfile::new(0, 0),
+                    budget: group.budget,
+                };
+                for entry in self
+                    .active
+                    .values()
+                    .filter(|e| self.in_group(&e.name, name))
+                {
+                    summary.instances += 1;
+                    summary.claimed += entry.claim;
+                }
+                summary
+            })
+            .collect()
+    }
+
     /// Called on exec of a process. Returns Throttled or NotThrottled.
     ///
     /// The resource profile is calculated here and persisted for the lifecycle
-    /// of the process in the limiter.
-    pub fn on_exec(&mut self, pid: Pid, args: &[String]) -> OnExecResult {
-        if let Some(profile) = profile_for(args, &self.total) {
+    /// of the process in the limiter. `ancestors` lists the process's traced
+    /// ancestors, nearest first, so that nested throttled processes are
+    /// accounted as one chain. `owner` is the uid the process is attributed
+    /// to, if known; it is only reported.
+    pub fn on_exec(
+        &mut self,
+        pid: Pid,
+        args: &[String],
+        ancestors: &[Pid],
+        owner: Option<u32>,
+    ) -> OnExecResult {
+        if self
+            .active
+            .get(&pid)
+            .is_some_and(|e| e.restored_start.is_some())
+        {
+            // A restored process the tracer has caught up with (found by the
+            // scan, or exec'ing again) is classified afresh.
+            self.release(pid);
+        }
+        if let Some(delay) = self.options.recheck_comm {
+            self.schedule_comm_check(pid, args, ancestors, owner, delay);
+        }
+        if let Some(RuleMatch { rule, profile }) = self.classify(pid, args) {
             let name = args
                 .first()
                 .cloned()
                 .unwrap_or_else(|| "<unavailable>".into());
+            if self.options.report_jobserver {
+                self.note_jobserver(pid, &name);
+            }
+            self.record(|timeline, now| timeline.exec(pid, &name, now));
+            if !self.draining && self.options.never_pause.contains(&pid) {
+                if !profile.has_free_resources(&self.available()) {
+                    warn!(
+                        "[limit] {} is protected by --never-pause-pid, admitting over budget - matched rule '{}', need {}, free: {}",
+                        describe(&name, pid, owner),
+                        rule,
+                        profile,
+                        self.free,
+                    );
+                }
+                self.admit(pid, name, profile, ancestors, owner);
+                // Admitted without being held, so the caller continues it.
+                return OnExecResult::NotThrottled;
+            }
+            if !self.draining && self.options.claim_grace.is_some() {
+                debug!(
+                    "[limit] {} running unclaimed during grace period - matched rule '{}', need {}",
+                    describe(&name, pid, owner),
+                    rule,
+                    profile
+                );
+                self.grace.insert(
+                    pid,
+                    GraceEntry {
+                        name,
+                        profile,
+                        ancestors: ancestors.to_vec(),
+                        owner,
+                        class: rule.to_string(),
+                        since: self.clock.now(),
+                    },
+                );
+                return OnExecResult::NotThrottled;
+            }
             info!(
-                "[limit] {} ({}) PAUSED - need {}, free: {}, total: {} ({} paused)",
-                name,
-                pid,
+                event = "pause", pid = pid.as_raw(), name = name.as_str(),
+                cpus = profile.cpus, mem_gb = profile.mem_gb;
+                "[limit] {} PAUSED{} - matched rule '{}', need {}, free: {}, total: {} ({} paused)",
+                describe(&name, pid, owner),
+                if self.draining { " for drain" } else { "" },
+                rule,
                 profile,
                 self.free,
                 self.total,
                 self.paused.len() + 1,
             );
-            self.paused.push_back(PausedEntry { pid, name, profile });
-            self.try_resume_paused();
+            self.record(|timeline, now| timeline.paused(pid, &name, now));
+            self.paused.push_back(PausedEntry {
+                pid,
+                name,
+                profile,
+                ancestors: ancestors.to_vec(),
+                retries: 0,
+                owner,
+                class: rule.to_string(),
+                since: self.clock.now(),
+                held: PauseMethod::Ptrace,
+                running: false,
+            });
+            self.stats.observe(self.active.len(), self.paused.len());
+            // While a debounced resume is pending, newcomers queue up too.
+            if self.resume_pending.is_none() {
+                self.try_resume_paused();
+            }
+            if self.options.pause_method.group_stop() {
+                self.hold_with_sigstop(pid);
+            }
             OnExecResult::Throttled
         } else {
             OnExecResult::NotThrottled
         }
     }
 
-    /// Called when any process exits. If it was throttled, free its resources
-    /// and try to resume waiting processes.
-    pub fn on_exit(&mut self, pid: Pid) {
-        if let Some(entry) = self.active.remove(&pid) {
-            self.free += entry.profile;
-            info!(
-                "[limit] {} ({}) finished - free: {}, total: {} ({} paused)",
-                entry.name,
+    /// End the `--claim-grace` period of processes that have outlived it:
+    /// each is admitted if it fits, and otherwise queued as paused. Returns
+    /// the queued ones, which are still running and must be interrupted by
+    /// the caller; see `stopped_for_pause`.
+    pub fn expire_grace(&mut self) -> Vec<Pid> {
+        let Some(grace) = self.options.claim_grace else {
+            return Vec::new();
+        };
+        let now = self.clock.now();
+        let mut expired: Vec<(Pid, GraceEntry)> = Vec::new();
+        self.grace.retain(|&pid, entry| {
+            if now.saturating_duration_since(entry.since) < grace {
+                return true;
+            }
+            expired.push((
                 pid,
-                self.free,
-                self.total,
-                self.paused.len(),
-            );
-            self.try_resume_paused();
-        }
-        // Remove from paused too in case it exited before being resumed.
-        self.paused.retain(|e| e.pid != pid);
-    }
-
-    /// Whether the given profile fits within remaining resources.
-    /// Failsafe: if nothing else is active, it always fits (deadlock prevention).
-    fn fits(&self, profile: &ResourceProfile) -> bool {
-        if profile.has_free_resources(&self.free) {
-            true
-        } else if self.active.is_empty() {
-            warn!(
-                "[limit] Budget exceeded but no active tasks, force admitting process needing {}",
-                profile
-            );
-            true
-        } else {
+                GraceEntry {
+                    name: std::mem::take(&mut entry.name),
+                    ancestors: std::mem::take(&mut entry.ancestors),
+                    class: std::mem::take(&mut entry.class),
+                    ..*entry
+                },
+            ));
             false
+        });
+        // Oldest first, as if they had queued in exec order.
+        expired.sort_by_key(|(_, e)| e.since);
+
+        let mut to_stop = Vec::new();
+        for (pid, entry) in expired {
+            if !self.claim_running(pid, entry, "after grace period") {
+                to_stop.push(pid);
+            }
         }
+        self.stats.observe(self.active.len(), self.paused.len());
+        to_stop
     }
 
-    fn admit(&mut self, pid: Pid, name: String, profile: ResourceProfile) {
-        self.free -= profile;
+    /// Admit a process that is already running if it fits, and otherwise
+    /// queue it as paused, logging `why` it was paused mid-run. Returns
+    /// whether it was admitted; if not, the caller must interrupt it.
+    fn claim_running(&mut self, pid: Pid, entry: GraceEntry, why: &str) -> bool {
+        let profile = self.sibling_profile(&entry.name, entry.profile);
+        let (claim, _) = self.claim_for(&profile, self.lender_for(&entry.ancestors));
+        if (!self.draining || self.has_active_ancestor(&entry.ancestors))
+            && self.paused.is_empty()
+            && self.caps_allow(
+                &claim,
+                &entry.name,
+                &entry.class,
+                entry.owner,
+                &entry.ancestors,
+            )
+            && self.fits(&claim, &entry.ancestors)
+        {
+            self.admit(pid, entry.name, profile, &entry.ancestors, entry.owner);
+            return true;
+        }
         info!(
-            "[limit] {} ({}) admitted - free: {}, total: {} ({} paused)",
-            name,
-            pid,
+            event = "pause", pid = pid.as_raw(), name = entry.name.as_str(),
+            cpus = entry.profile.cpus, mem_gb = entry.profile.mem_gb;
+            "[limit] {} PAUSED {} - need {}, free: {}, total: {} ({} paused)",
+            describe(&entry.name, pid, entry.owner),
+            why,
+            entry.profile,
             self.free,
             self.total,
-            self.paused.len(),
+            self.paused.len() + 1,
         );
-        self.active.insert(pid, ActiveEntry { name, profile });
+        self.record(|timeline, now| timeline.paused(pid, &entry.name, now));
+        self.paused.push_back(PausedEntry {
+            pid,
+            name: entry.name,
+            profile: entry.profile,
+            ancestors: entry.ancestors,
+            retries: 0,
+            owner: entry.owner,
+            class: entry.class,
+            since: self.clock.now(),
+            held: PauseMethod::Ptrace,
+            running: true,
+        });
+        false
     }
 
-    fn try_resume_paused(&mut self) {
-        // Walk the queue front-to-back; stop at the first entry that doesn't
-        // fit (FIFO order preserved).
-        while let Some(front) = self.paused.front() {
-            if !self.fits(&front.profile) {
-                break;
+    /// Note the comm of a process at exec, to tell later whether it renamed
+    /// itself.
+    fn schedule_comm_check(
+        &mut self,
+        pid: Pid,
+        args: &[String],
+        ancestors: &[Pid],
+        owner: Option<u32>,
+        delay: Duration,
+    ) {
+        let Some(comm) = self.comm(pid) else {
+            return;
+        };
+        self.comm_checks.insert(
+            pid,
+            CommCheck {
+                comm,
+                args: args.to_vec(),
+                ancestors: ancestors.to_vec(),
+                owner,
+                due: self.clock.now() + delay,
+            },
+        );
+    }
+
+    /// Called periodically with `--recheck-comm`: read the comm of processes
+    /// exec'd at least the delay ago, and reclassify those that renamed
+    /// themselves since exec (e.g. a multiplexer dispatching to the tool it
+    /// acts as) under their new name. A process that no longer matches a
+    /// rule gives back its claim. One that now does is admitted if it fits,
+    /// or else queued mid-run like at the end of a grace period. Returns the
+    /// queued ones, which the caller must interrupt; see `stopped_for_pause`.
+    pub fn recheck_comm(&mut self) -> Vec<Pid> {
+        let Some(delay) = self.options.recheck_comm else {
+            return Vec::new();
+        };
+        let now = self.clock.now();
+        let mut due = Vec::new();
+        for (&pid, check) in &mut self.comm_checks {
+            if check.due > now {
+                continue;
             }
-            let entry = self.paused.pop_front().unwrap();
-            debug!(
-                "[limit] Resuming {} ({}) - need {}",
-                entry.name, entry.pid, entry.profile,
-            );
-            let pid = entry.pid;
-            self.admit(pid, entry.name, entry.profile);
-            if let Err(e) = self.cont(pid) {
-                warn!("Failed to resume paused PID {}: {}", pid, e);
-                if let Some(entry) = self.active.remove(&pid) {
-                    self.free += entry.profile;
-                }
+            if self.paused.iter().any(|e| e.pid == pid) {
+                // Not running, so it can't rename itself before it is resumed.
+                check.due = now + delay;
+            } else {
+                due.push(pid);
+            }
+        }
+        let mut to_stop = Vec::new();
+        for pid in due {
+            let Some(check) = self.comm_checks.remove(&pid) else {
+                continue;
+            };
+            let Some(comm) = self.comm(pid).filter(|comm| *comm != check.comm) else {
+                continue;
+            };
+            let mut args = check.args;
+            match args.first_mut() {
+                Some(argv0) => argv0.clone_from(&comm),
+                None => args.push(comm.clone()),
+            }
+            let before = self.classified.get(&pid).and_then(|(_, m)| m.clone());
+            let after = self.classify(pid, &args);
+            if after == before {
+                debug!(
+                    "[limit] {} renamed itself to {}, still {}",
+                    describe(&check.comm, pid, check.owner),
+                    comm,
+                    describe_match(&after)
+                );
+                continue;
+            }
+            info!(
+                "[limit] {} renamed itself to {}, reclassified from {} to {}",
+                describe(&check.comm, pid, check.owner),
+                comm,
+                describe_match(&before),
+                describe_match(&after)
+            );
+            let grace_since = self.grace.remove(&pid).map(|entry| entry.since);
+            if grace_since.is_none() && self.release(pid).is_some() {
+                self.resume_after_release();
+            }
+            let Some(RuleMatch { rule, profile }) = after else {
+                continue;
+            };
+            let entry = GraceEntry {
+                name: comm,
+                profile,
+                ancestors: check.ancestors,
+                owner: check.owner,
+                class: rule.to_string(),
+                since: grace_since.unwrap_or(now),
+            };
+            if grace_since.is_some() {
+                // Still in its grace period, now under the new rule.
+                self.grace.insert(pid, entry);
+            } else if !self.claim_running(pid, entry, "after renaming itself") {
+                to_stop.push(pid);
             }
         }
+        self.stats.observe(self.active.len(), self.paused.len());
+        to_stop
     }
 
-    fn cont(&self, pid: Pid) -> nix::Result<()> {
+    fn comm(&self, pid: Pid) -> Option<String> {
+        #[cfg(test)]
         if self.unit_test {
-            Ok(())
-        } else {
-            ptrace::cont(pid, None)
+            return self.fake_comm.get(&pid).cloned();
         }
+        nixutil::read_comm(pid)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use nix::unistd::Pid;
 
-    #[test]
-    fn test_not_throttled() {
-        let mut limiter = Limiter::new(ResourceProfile::new(2, 2), true);
-        let res = limiter.on_exec(Pid::from_raw(100), &["some_random_process".into()]);
-        assert!(matches!(res, OnExecResult::NotThrottled));
-        assert!(limiter.active.is_empty());
-        assert!(limiter.paused.is_empty());
-        assert_eq!(limiter.free, ResourceProfile::new(2, 2));
+    /// Called when a process interrupted after its grace period stops.
+    /// Returns true if it is still paused and must stay stopped; false if it
+    /// was admitted in the meantime and should be continued.
+    pub fn stopped_for_pause(&mut self, pid: Pid) -> bool {
+        let Some(entry) = self.paused.iter_mut().find(|e| e.pid == pid && e.running) else {
+            return false;
+        };
+        entry.running = false;
+        if self.options.pause_method.group_stop() {
+            self.hold_with_sigstop(pid);
+        }
+        true
     }
 
-    #[test]
-    fn test_admit_and_pause() {
-        let mut limiter = Limiter::new(ResourceProfile::new(2, 2), true);
-
-        // cc needs (1, 1). Normally fits.
-        let res1 = limiter.on_exec(Pid::from_raw(100), &["cc".into()]);
+    /// Log when a throttled process is also limited by a make jobserver, so
+    /// that the combined effect of `make -j` and our budget is explained.
+    fn note_jobserver(&mut self, pid: Pid, name: &str) {
+        let env = Environ::of(pid);
+        let Some(auth) = env.get("MAKEFLAGS").and_then(nixutil::jobserver_auth) else {
+            return;
+        };
+        self.stats.under_jobserver += 1;
+        if self.stats.under_jobserver == 1 {
+            info!(
+                "[limit] {} ({}) runs under a make jobserver ({}); its parallelism is bounded by both make -j and nix-ubw",
+                name, pid, auth
+            );
+        } else {
+            debug!(
+                "[limit] {} ({}) runs under make jobserver {}",
+                name, pid, auth
+            );
+        }
+    }
+
+    /// Rule and resource profile for `pid` running `args`, or `None` if it is
+    /// not throttled.
+    ///
+    /// The result is cached per PID until the process exits; the cached argv
+    /// is compared so a later exec of a different program is reclassified.
+    pub fn classify(&mut self, pid: Pid, args: &[String]) -> Option<RuleMatch> {
+        if let Some((cached_args, matched)) = self.classified.get(&pid) {
+            if cached_args == args {
+                return matched.clone();
+            }
+        }
+        self.stats.rule_lookups += 1;
+        let env = Environ::of(pid);
+        let matched = profile_for(args, &env, &self.options.rules, &self.total);
+        self.classified
+            .insert(pid, (args.to_vec(), matched.clone()));
+        matched
+    }
+
+    /// Replace the rules, e.g. after a reload. Processes already admitted,
+    /// paused or in their grace period keep the rule they were classified
+    /// with, so their claims are released as they were made; every other
+    /// cached classification is dropped and redone under the new rules.
+    pub fn set_rules(&mut self, rules: RuleSet) {
+        self.options.rules = rules;
+        let before = self.classified.len();
+        let (active, paused, grace) = (&self.active, &self.paused, &self.grace);
+        self.classified.retain(|pid, _| {
+            active.contains_key(pid)
+                || grace.contains_key(pid)
+                || paused.iter().any(|entry| entry.pid == *pid)
+        });
+        debug!(
+            "[limit] Rules replaced, dropped {} cached classifications",
+            before - self.classified.len()
+        );
+    }
+
+    /// Called when any process exits. If it was throttled, free its resources
+    /// and try to resume waiting processes.
+    pub fn on_exit(&mut self, pid: Pid) {
+        let matched = self.classified.remove(&pid).and_then(|(_, m)| m);
+        self.comm_checks.remove(&pid);
+        self.record(|timeline, now| timeline.exited(pid, now));
+        if let Some(entry) = self.grace.remove(&pid) {
+            debug!(
+                "[limit] {} finished within grace period after {:.3}s, never claimed",
+                describe(&entry.name, pid, entry.owner),
+                self.clock.since(entry.since).as_secs_f64()
+            );
+            self.stats.short_lived += 1;
+        }
+        if let Some(entry) = self.release(pid) {
+            let wall = self.clock.since(entry.started);
+            info!(
+                event = "finish", pid = pid.as_raw(), name = entry.name.as_str(),
+                cpus = entry.claim.cpus, mem_gb = entry.claim.mem_gb;
+                "[limit] {} finished after {:.1}s{} - freed {}, free: {}, total: {} ({} paused)",
+                describe(&entry.name, pid, entry.owner),
+                wall.as_secs_f64(),
+                cpu_used(entry.exit_cpu_ticks, wall),
+                entry.profile,
+                self.free,
+                self.total,
+                self.paused.len(),
+            );
+            if let Some(matched) = matched.filter(|_| self.options.suggest_rules) {
+                let cores = entry
+                    .exit_cpu_ticks
+                    .filter(|_| wall >= MIN_CPU_SAMPLE_WINDOW)
+                    .map(|ticks| nixutil::cpu_usage(entry.admit_cpu_ticks, ticks, wall));
+                let forked = entry.exit_children_cpu_ticks.is_some_and(|t| t > 0);
+                self.stats
+                    .observe_usage(&matched, entry.exit_peak_rss_kb, cores, forked);
+            }
+            self.resume_after_release();
+        }
+        // Remove from paused too in case it exited before being resumed.
+        self.paused.retain(|e| e.pid != pid);
+        self.stats.observe(self.active.len(), self.paused.len());
+    }
+
+    /// Take the claim of a process that was active when a previous run saved
+    /// its checkpoint and is still running, so that it isn't over-admitted
+    /// around. It is released when the process exits, like any other, or
+    /// when polling finds it gone (see `expire_restored`).
+    pub fn restore(&mut self, saved: &SavedProcess) {
+        if self.active.contains_key(&saved.pid) {
+            return;
+        }
+        self.free -= saved.claim;
+        self.active.insert(
+            saved.pid,
+            ActiveEntry {
+                pid: saved.pid,
+                name: saved.name.clone(),
+                profile: saved.claim,
+                claim: saved.claim,
+                unit: saved.claim,
+                lender: None,
+                lending: false,
+                started: self.clock.now(),
+                cpu: Cell::new(self.first_cpu_sample(saved.pid)),
+                owner: saved.owner,
+                exit_cpu_ticks: None,
+                admit_cpu_ticks: 0,
+                exit_peak_rss_kb: None,
+                exit_children_cpu_ticks: None,
+                group_stopped: false,
+                restored_start: Some(saved.start_time),
+            },
+        );
+        self.sync_shared();
+        self.stats.observe(self.active.len(), self.paused.len());
+    }
+
+    /// The CPU time of a newly active process to measure its usage from,
+    /// read only under `--adaptive active-cpu`.
+    fn first_cpu_sample(&self, pid: Pid) -> CpuSample {
+        CpuSample {
+            ticks: self
+                .options
+                .adaptive
+                .filter(|&s| s == AdaptiveSource::ActiveCpu)
+                .and_then(|_| nixutil::read_cpu_ticks(pid))
+                .unwrap_or(0),
+            at: Instant::now(),
+            usage: None,
+        }
+    }
+
+    /// Called periodically: release the claims of restored processes that
+    /// have exited without us seeing it. Returns how many were released.
+    pub fn expire_restored(&mut self) -> usize {
+        let gone: Vec<Pid> = self
+            .active
+            .values()
+            .filter(|e| {
+                e.restored_start
+                    .is_some_and(|start| nixutil::read_start_time(e.pid) != Some(start))
+            })
+            .map(|e| e.pid)
+            .collect();
+        for &pid in &gone {
+            let entry = self.release(pid).unwrap();
+            info!(
+                event = "finish", pid = pid.as_raw(), name = entry.name.as_str(),
+                cpus = entry.claim.cpus, mem_gb = entry.claim.mem_gb;
+                "[limit] restored {} is gone - freed {}, free: {}, total: {} ({} paused)",
+                describe(&entry.name, pid, entry.owner),
+                entry.claim,
+                self.free,
+                self.total,
+                self.paused.len(),
+            );
+        }
+        if !gone.is_empty() {
+            self.resume_after_release();
+            self.stats.observe(self.active.len(), self.paused.len());
+        }
+        gone.len()
+    }
+
+    /// The active throttled processes as `(pid, claim, owner, name)`, by
+    /// PID, for `--checkpoint`.
+    pub fn active_claims(&self) -> Vec<(Pid, ResourceProfile, Option<u32>, String)> {
+        let mut claims: Vec<_> = self
+            .active
+            .values()
+            .map(|e| (e.pid, e.claim, e.owner, e.name.clone()))
+            .collect();
+        claims.sort_by_key(|&(pid, ..)| pid);
+        claims
+    }
+
+    /// The program `pid` ran at its last exec, if we saw it, the rule it
+    /// matched, if any, and whether it is `active`, `paused`, in its claim
+    /// `grace` period, or `unthrottled`.
+    pub fn classification(&self, pid: Pid) -> (Option<&str>, Option<String>, &'static str) {
+        let (name, rule) = match self.classified.get(&pid) {
+            Some((args, matched)) => (
+                args.first().map(String::as_str),
+                matched.as_ref().map(|m| m.rule.to_string()),
+            ),
+            None => (None, None),
+        };
+        (name, rule, self.state_of(pid).name())
+    }
+
+    /// Whether `pid` is active, paused, in its grace period or not tracked,
+    /// with the profile it was throttled with.
+    pub fn state_of(&self, pid: Pid) -> ProcessState {
+        if let Some(entry) = self.active.get(&pid) {
+            ProcessState::Active {
+                profile: entry.profile,
+                claim: entry.claim,
+            }
+        } else if let Some(entry) = self.paused.iter().find(|e| e.pid == pid) {
+            ProcessState::Paused {
+                profile: entry.profile,
+            }
+        } else if let Some(entry) = self.grace.get(&pid) {
+            ProcessState::Grace {
+                profile: entry.profile,
+            }
+        } else {
+            ProcessState::NotTracked
+        }
+    }
+
+    /// Whether `pid` is an active throttled process.
+    pub fn is_active(&self, pid: Pid) -> bool {
+        self.active.contains_key(&pid)
+    }
+
+    /// Record the total CPU time of an active process at its exit stop, in
+    /// clock ticks, to report it in its `finished` line.
+    pub fn record_exit_cpu(&mut self, pid: Pid, ticks: u64) {
+        if let Some(entry) = self.active.get_mut(&pid) {
+            entry.exit_cpu_ticks = Some(ticks);
+        }
+    }
+
+    /// Whether the peak memory and children's CPU time of active processes
+    /// are wanted at their exit stop, for `--suggest-rules`.
+    pub fn wants_exit_usage(&self) -> bool {
+        self.options.suggest_rules
+    }
+
+    /// Record the peak resident memory (in KiB) of an active process and
+    /// the CPU time of its children (in clock ticks) at its exit stop.
+    pub fn record_exit_usage(
+        &mut self,
+        pid: Pid,
+        peak_rss_kb: Option<u64>,
+        children_cpu_ticks: Option<u64>,
+    ) {
+        if let Some(entry) = self.active.get_mut(&pid) {
+            entry.exit_peak_rss_kb = peak_rss_kb;
+            entry.exit_children_cpu_ticks = children_cpu_ticks;
+        }
+    }
+
+    /// Stop admitting processes ahead of shutdown: from now on every
+    /// throttled process is paused, whatever the budget, `--claim-grace` and
+    /// `--never-pause-pid`, and the paused ones stay paused until they are
+    /// released on exit. Active processes run to completion; see `drained`.
+    /// Only processes with an active ancestor are still resumed as the
+    /// budget allows, since that ancestor waits for them to finish (e.g. a
+    /// linker under a compiler driver).
+    pub fn start_drain(&mut self) {
+        if !self.draining {
+            info!(
+                "[limit] Draining: admitting only children of active processes, waiting for {} active processes ({} paused)",
+                self.active.len() + self.grace.len(),
+                self.paused.len()
+            );
+        }
+        self.draining = true;
+    }
+
+    pub fn draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Whether a drain is complete: no throttled process is running anymore.
+    pub fn drained(&self) -> bool {
+        self.draining && self.active.is_empty() && self.grace.is_empty()
+    }
+
+    /// Watchdog for `--pause-kill-after`: SIGKILL every process that has been
+    /// paused for longer than the limit, so that a wedged queue fails the
+    /// build instead of hanging it. Returns the number of processes killed.
+    pub fn kill_overdue(&mut self) -> usize {
+        let Some(limit) = self.options.pause_kill_after.filter(|_| !self.draining) else {
+            return 0;
+        };
+        let now = self.clock.now();
+        let (overdue, waiting): (VecDeque<_>, VecDeque<_>) = std::mem::take(&mut self.paused)
+            .into_iter()
+            .partition(|e| now.saturating_duration_since(e.since) > limit);
+        self.paused = waiting;
+        for entry in &overdue {
+            error!(
+                event = "kill", pid = entry.pid.as_raw(), name = entry.name.as_str(),
+                cpus = entry.profile.cpus, mem_gb = entry.profile.mem_gb;
+                "[limit] {} ({}) paused for {:.0}s, over the {:.0}s limit - killing it (need {}, free: {}, {} active)",
+                entry.name,
+                entry.pid,
+                now.saturating_duration_since(entry.since).as_secs_f64(),
+                limit.as_secs_f64(),
+                entry.profile,
+                self.free,
+                self.active.len(),
+            );
+            if let Err(e) = self.ptrace.kill(entry.pid, Signal::SIGKILL) {
+                warn!("[limit] Failed to kill {}: {}", entry.pid, e);
+            }
+        }
+        if !overdue.is_empty() {
+            // The queue head may have been what blocked the rest.
+            self.try_resume_paused();
+        }
+        overdue.len()
+    }
+
+    /// Whether `pid` is paused in a SIGSTOP group-stop of ours (`sigstop` or
+    /// `listen`), in which case
+    /// its SIGSTOP must be delivered and the group-stop kept in place.
+    pub fn holds_stopped(&self, pid: Pid) -> bool {
+        self.paused
+            .iter()
+            .any(|e| e.pid == pid && e.held.group_stop() && !e.running)
+    }
+
+    /// Whether `pid` was admitted from a `listen` hold, so that letting go of
+    /// it without a SIGCONT would put it back into its group-stop.
+    pub fn leaves_group_stop(&self, pid: Pid) -> bool {
+        self.active.get(&pid).is_some_and(|e| e.group_stopped)
+    }
+
+    /// Drop a paused process from the queue because a signal that should end
+    /// it, such as the SIGTERM of a cancelled build, is waiting for it, and
+    /// continue it so that the signal is delivered. It held no claim, so
+    /// none is released. Returns whether it was paused.
+    pub fn cancel_pause(&mut self, pid: Pid, sig: Signal) -> bool {
+        let Some(index) = self.paused.iter().position(|e| e.pid == pid && !e.running) else {
+            return false;
+        };
+        let entry = self.paused.remove(index).unwrap();
+        info!(
+            event = "cancel", pid = pid.as_raw(), name = entry.name.as_str(),
+            cpus = entry.profile.cpus, mem_gb = entry.profile.mem_gb;
+            "[limit] {} has a pending {} while paused - resuming it so it can be delivered ({} paused)",
+            describe(&entry.name, pid, entry.owner),
+            sig,
+            self.paused.len(),
+        );
+        if let Err(e) = self.cont(pid, entry.held, false) {
+            warn!("[limit] Failed to resume {} for {}: {}", pid, sig, e);
+        }
+        self.stats.observe(self.active.len(), self.paused.len());
+        true
+    }
+
+    /// Stop managing a process that is still alive, e.g. because we are
+    /// detaching from it. Its claim is released and waiting processes get a
+    /// chance to run. Returns how the process was held if it was paused, in
+    /// which case it is still stopped and the caller must let it go.
+    pub fn forget(&mut self, pid: Pid) -> Option<PauseMethod> {
+        self.classified.remove(&pid);
+        self.grace.remove(&pid);
+        let was_paused = self
+            .paused
+            .iter()
+            .find(|e| e.pid == pid && !e.running)
+            .map(|e| e.held);
+        if let Some(entry) = self.release(pid) {
+            info!(
+                event = "release", pid = pid.as_raw(), name = entry.name.as_str(),
+                cpus = entry.claim.cpus, mem_gb = entry.claim.mem_gb;
+                "[limit] {} ({}) released - free: {}, total: {} ({} paused)",
+                entry.name,
+                pid,
+                self.free,
+                self.total,
+                self.paused.len(),
+            );
+        }
+        self.paused.retain(|e| e.pid != pid);
+        self.resume_after_release();
+        was_paused
+    }
+
+    /// Remove an active process and give back its claim.
+    ///
+    /// If a descendant is still borrowing from it, the claim is handed over to
+    /// that descendant instead of being freed, since the chain's reservation
+    /// is still in use.
+    fn release(&mut self, pid: Pid) -> Option<ActiveEntry> {
+        let entry = self.active.remove(&pid)?;
+        if let Some(lender) = entry.lender.and_then(|l| self.active.get_mut(&l)) {
+            lender.lending = false;
+        }
+        let borrower = entry
+            .lending
+            .then(|| self.active.values_mut().find(|e| e.lender == Some(pid)))
+            .flatten();
+        match borrower {
+            Some(borrower) => {
+                borrower.claim += entry.claim;
+                borrower.lender = None;
+            }
+            None => self.free += entry.claim,
+        }
+        Some(entry)
+    }
+
+    /// The nearest active ancestor that can lend its claim, if any.
+    fn lender_for(&self, ancestors: &[Pid]) -> Option<Pid> {
+        let nearest = ancestors.iter().find(|p| self.active.contains_key(p))?;
+        (!self.active[nearest].lending).then_some(*nearest)
+    }
+
+    /// The claim and chain reservation for `profile` borrowing from `lender`.
+    fn claim_for(
+        &self,
+        profile: &ResourceProfile,
+        lender: Option<Pid>,
+    ) -> (ResourceProfile, ResourceProfile) {
+        match lender {
+            Some(lender) => {
+                let base = self.active[&lender].unit;
+                let unit = profile.max_each(&base);
+                (unit - base, unit)
+            }
+            None => (*profile, *profile),
+        }
+    }
+
+    /// Resume every paused process, for shutdown. Paused processes sit in a
+    /// ptrace-stop, so detaching both resumes them and lets them go. Active
+    /// processes admitted from a `listen` hold get their SIGCONT, since the
+    /// kernel detaching them when we exit would bring back their group-stop.
+    pub fn release_paused(&mut self) {
+        for entry in self.active.values().filter(|e| e.group_stopped) {
+            if let Err(e) = self.ptrace.kill(entry.pid, Signal::SIGCONT) {
+                warn!("Failed to release stopped PID {}: {}", entry.pid, e);
+            }
+        }
+        if !self.paused.is_empty() {
+            info!(
+                "[limit] Releasing {} paused processes{}",
+                self.paused.len(),
+                if self.draining {
+                    " held by the drain"
+                } else {
+                    ""
+                }
+            );
+        }
+        while let Some(entry) = self.paused.pop_front() {
+            if let Err(e) = self.ptrace.detach(entry.pid, None) {
+                warn!("Failed to release paused PID {}: {}", entry.pid, e);
+            }
+            if entry.held.group_stop() {
+                let _ = self.ptrace.kill(entry.pid, Signal::SIGCONT);
+            }
+        }
+        self.stats.observe(self.active.len(), self.paused.len());
+    }
+
+    /// Free resources after subtracting other instances' claims.
+    pub fn available(&self) -> ResourceProfile {
+        self.free - self.others
+    }
+
+    /// Publish our claim to the shared ledger and refresh other instances'
+    /// claims. No-op without `--shared-state`.
+    fn sync_shared(&mut self) {
+        if let Some(shared) = &self.shared {
+            self.others = shared.publish(self.total - self.free);
+        }
+    }
+
+    /// Whether the given claim fits within remaining resources.
+    /// Failsafe: if nothing is active except the process's own ancestors
+    /// (which are waiting for it), it always fits (deadlock prevention).
+    /// Callers admit a process as soon as it fits, and admission adds it to
+    /// `active` right away, so a burst of oversized processes gets exactly
+    /// one of them through the failsafe; the rest wait for it.
+    /// The failsafe only looks at this instance, so with `--shared-state` an
+    /// idle instance may still admit one process over the shared budget.
+    fn fits(&self, profile: &ResourceProfile, ancestors: &[Pid]) -> bool {
+        let below_floor = self.below_mem_floor(profile);
+        if below_floor.is_none() && self.policy.should_admit(profile, &self.available()) {
+            true
+        } else if self.active.keys().all(|p| ancestors.contains(p)) {
+            warn!(
+                "[limit] Budget exceeded but no other active tasks, force admitting process needing {}",
+                profile
+            );
+            true
+        } else if self.active.len() < self.options.min_parallel {
+            info!(
+                "[limit] Budget exceeded but only {} active tasks (--min-parallel {}), admitting process needing {}",
+                self.active.len(),
+                self.options.min_parallel,
+                profile
+            );
+            true
+        } else if let Some(source) = self.options.adaptive.filter(|_| below_floor.is_none()) {
+            self.usage_allows(profile, source)
+        } else {
+            if below_floor.is_some() {
+                self.mem_floor_hit.set(below_floor);
+            }
+            false
+        }
+    }
+
+    /// MemAvailable in KiB if admitting a process claiming `profile` would
+    /// leave less than `--mem-floor-gb` of it. MemAvailable also counts
+    /// memory taken by processes we don't account for. Only read for claims
+    /// with memory.
+    fn below_mem_floor(&self, profile: &ResourceProfile) -> Option<u64> {
+        let floor_gb = self.options.mem_floor_gb.filter(|_| profile.mem_gb > 0)?;
+        let available_kb = self.sampled_mem_available_kb()?;
+        let kib_per_gib = nixutil::KIB_PER_GIB as i64;
+        let left_kb = available_kb as i64 - i64::from(profile.mem_gb) * kib_per_gib;
+        (left_kb < i64::from(floor_gb) * kib_per_gib).then_some(available_kb)
+    }
+
+    /// Watchdog for the rules' `mem_hard_gb`: SIGKILL every active process
+    /// whose resident memory is over the hard limit of the rule it matched.
+    /// Unlike its profile's memory, which only decides when it may start,
+    /// this is what it may actually use. Returns the number of processes
+    /// killed.
+    pub fn enforce_mem_hard(&mut self) -> usize {
+        if !self.options.rules.has_mem_hard() {
+            return 0;
+        }
+        let mut killed = 0;
+        for entry in self.active.values() {
+            let Some((_, Some(matched))) = self.classified.get(&entry.pid) else {
+                continue;
+            };
+            let rule = matched.rule.to_string();
+            let Some(limit_gb) = self.options.rules.mem_hard(&rule) else {
+                continue;
+            };
+            let Some(rss_kb) = self.rss_kb(entry.pid) else {
+                continue;
+            };
+            if rss_kb <= u64::from(limit_gb) * nixutil::KIB_PER_GIB {
+                continue;
+            }
+            error!(
+                event = "kill", pid = entry.pid.as_raw(), name = entry.name.as_str(),
+                cpus = entry.profile.cpus, mem_gb = entry.profile.mem_gb;
+                "[limit] {} ({}) uses {:.1} GiB, over the hard limit of {} GiB of rule '{}' - killing it (claimed {})",
+                entry.name,
+                entry.pid,
+                rss_kb as f64 / nixutil::KIB_PER_GIB as f64,
+                limit_gb,
+                rule,
+                entry.profile,
+            );
+            if let Err(e) = self.ptrace.kill(entry.pid, Signal::SIGKILL) {
+                warn!("[limit] Failed to kill {}: {}", entry.pid, e);
+            }
+            killed += 1;
+        }
+        killed
+    }
+
+    fn cpu_ticks(&self, pid: Pid) -> Option<u64> {
+        #[cfg(test)]
+        if self.unit_test {
+            return self.fake_cpu_ticks.get(&pid).copied();
+        }
+        nixutil::read_cpu_ticks(pid)
+    }
+
+    fn rss_kb(&self, pid: Pid) -> Option<u64> {
+        #[cfg(test)]
+        if self.unit_test {
+            return self.fake_rss_kb.get(&pid).copied();
+        }
+        nixutil::read_rss_kb(pid)
+    }
+
+    /// MemAvailable in KiB, less the claims of processes admitted since it
+    /// was read, so that a burst of large processes can't all pass the
+    /// floor before any of them has allocated its memory.
+    fn sampled_mem_available_kb(&self) -> Option<u64> {
+        let sample = match self.mem_available.get() {
+            Some(sample) if self.clock.since(sample.at) < MEM_SAMPLE_WINDOW => sample,
+            _ => MemSample {
+                available_kb: self.mem_available_kb()?,
+                at: self.clock.now(),
+                admitted_kb: 0,
+            },
+        };
+        self.mem_available.set(Some(sample));
+        Some(sample.available_kb.saturating_sub(sample.admitted_kb))
+    }
+
+    fn loadavg(&self) -> Option<f64> {
+        #[cfg(test)]
+        if self.unit_test {
+            return self.fake_loadavg;
+        }
+        nixutil::read_loadavg()
+    }
+
+    fn mem_available_kb(&self) -> Option<u64> {
+        #[cfg(test)]
+        if self.unit_test {
+            return self.fake_mem_available_kb;
+        }
+        nixutil::read_mem_available_kb()
+    }
+
+    /// Called periodically: retry the queue while processes are held back
+    /// by `--mem-floor-gb`, since memory freed outside our accounting
+    /// doesn't trigger a resume otherwise.
+    pub fn retry_mem_floor(&mut self) {
+        if self.below_mem_floor && !self.paused.is_empty() {
+            self.try_resume_paused();
+        }
+    }
+
+    /// Whether `claim` for a process of program `name` stays within the caps
+    /// on top of the global budget: its user's share and its group's.
+    fn caps_allow(
+        &self,
+        claim: &ResourceProfile,
+        name: &str,
+        class: &str,
+        owner: Option<u32>,
+        ancestors: &[Pid],
+    ) -> bool {
+        self.user_allows(claim, owner, ancestors)
+            && self.group_allows(claim, name, ancestors)
+            && self.rule_allows(class, ancestors)
+    }
+
+    /// Whether another process of rule `class` fits in the rule's
+    /// `max_instances`, next to its other active processes. As for groups,
+    /// a rule whose only active processes are ancestors of this one is always
+    /// allowed.
+    fn rule_allows(&self, class: &str, ancestors: &[Pid]) -> bool {
+        let Some(max) = self.options.rules.max_instances(class) else {
+            return true;
+        };
+        let mut instances = 0;
+        let mut only_ancestors = true;
+        for pid in self.active.keys().filter(|&&pid| {
+            self.classified
+                .get(&pid)
+                .and_then(|(_, matched)| matched.as_ref())
+                .is_some_and(|matched| matched.rule.to_string() == class)
+        }) {
+            instances += 1;
+            only_ancestors &= ancestors.contains(pid);
+        }
+        instances < max || only_ancestors
+    }
+
+    /// Whether a process of program `name` claiming `claim` fits in the
+    /// instance cap and budget of the program's group, next to the group's
+    /// other active processes. A group whose only active processes are
+    /// ancestors of this one is always allowed, as for user shares.
+    fn group_allows(&self, claim: &ResourceProfile, name: &str, ancestors: &[Pid]) -> bool {
+        let Some((group_name, group)) = self.options.rules.group_of(name) else {
+            return true;
+        };
+        let mut used = ResourceProfile::new(0, 0);
+        let mut instances = 0;
+        let mut only_ancestors = true;
+        for entry in self
+            .active
+            .values()
+            .filter(|e| self.in_group(&e.name, group_name))
+        {
+            used += entry.claim;
+            instances += 1;
+            only_ancestors &= ancestors.contains(&entry.pid);
+        }
+        let within_instances = group.max_instances.is_none_or(|max| instances < max);
+        let within_budget = group
+            .budget
+            .is_none_or(|budget| claim.has_free_resources(&(budget - used)));
+        (within_instances && within_budget) || only_ancestors
+    }
+
+    fn in_group(&self, name: &str, group: &str) -> bool {
+        self.options
+            .rules
+            .group_of(name)
+            .is_some_and(|(g, _)| g == group)
+    }
+
+    /// Whether `claim` fits in the `--per-user-budget` share of `owner`, next
+    /// to what the user's other active processes claim. Like the global
+    /// failsafe, a user whose only active processes are ancestors of this one
+    /// is always allowed, so an oversized process still runs eventually.
+    fn user_allows(&self, claim: &ResourceProfile, owner: Option<u32>, ancestors: &[Pid]) -> bool {
+        let (Some(budget), Some(uid)) = (self.options.per_user_budget, owner) else {
+            return true;
+        };
+        let mut used = ResourceProfile::new(0, 0);
+        let mut only_ancestors = true;
+        for entry in self.active.values().filter(|e| e.owner == Some(uid)) {
+            used += entry.claim;
+            only_ancestors &= ancestors.contains(&entry.pid);
+        }
+        claim.has_free_resources(&(budget - used)) || only_ancestors
+    }
+
+    /// Adaptive admission: memory is still reserved strictly, but CPUs are
+    /// checked against measured usage instead of the claims of active
+    /// processes. Processes admitted since the usage was measured count
+    /// with their claims, so that a burst of execs between two measurements
+    /// isn't let through all at once.
+    fn usage_allows(&self, profile: &ResourceProfile, source: AdaptiveSource) -> bool {
+        if profile.mem_gb > self.available().mem_gb {
+            return false;
+        }
+        let sample = match self.cpu_usage.get() {
+            Some(sample) if self.clock.since(sample.at) < source.sample_window() => sample,
+            _ => {
+                let used = match source {
+                    AdaptiveSource::Loadavg => match self.loadavg() {
+                        Some(load) => load,
+                        None => return false,
+                    },
+                    AdaptiveSource::ActiveCpu => {
+                        self.active.values().map(ActiveEntry::recent_cpu).sum()
+                    }
+                };
+                UsageSample {
+                    used,
+                    at: self.clock.now(),
+                    admitted: 0.0,
+                }
+            }
+        };
+        self.cpu_usage.set(Some(sample));
+        let used = sample.used + sample.admitted;
+        if used + f64::from(profile.cpus) <= f64::from(self.total.cpus) {
+            info!(
+                "[limit] Over CPU budget but only {:.2} cores busy ({:?}), opportunistically admitting process needing {}",
+                used, source, profile
+            );
+            true
+        } else {
+            false
+        }
+    }
+
+    fn admit(
+        &mut self,
+        pid: Pid,
+        name: String,
+        profile: ResourceProfile,
+        ancestors: &[Pid],
+        owner: Option<u32>,
+    ) {
+        let lender = self.lender_for(ancestors);
+        let (claim, unit) = self.claim_for(&profile, lender);
+        self.free -= claim;
+        if let Some(mut sample) = self.cpu_usage.get() {
+            sample.admitted += f64::from(claim.cpus);
+            self.cpu_usage.set(Some(sample));
+        }
+        if let Some(mut sample) = self.mem_available.get() {
+            sample.admitted_kb += u64::try_from(claim.mem_gb).unwrap_or(0) * nixutil::KIB_PER_GIB;
+            self.mem_available.set(Some(sample));
+        }
+        self.record(|timeline, now| timeline.admitted(pid, &name, claim, now));
+        match lender {
+            Some(lender) => info!(
+                event = "admit", pid = pid.as_raw(), name = name.as_str(),
+                cpus = claim.cpus, mem_gb = claim.mem_gb;
+                "[limit] {} admitted - borrowing from {} ({}), claimed {}, free: {}, total: {} ({} paused)",
+                describe(&name, pid, owner),
+                self.active[&lender].name,
+                lender,
+                claim,
+                self.free,
+                self.total,
+                self.paused.len(),
+            ),
+            None => info!(
+                event = "admit", pid = pid.as_raw(), name = name.as_str(),
+                cpus = claim.cpus, mem_gb = claim.mem_gb;
+                "[limit] {} admitted - free: {}, total: {} ({} paused)",
+                describe(&name, pid, owner),
+                self.free,
+                self.total,
+                self.paused.len(),
+            ),
+        }
+        if let Some(lender) = lender {
+            self.active.get_mut(&lender).unwrap().lending = true;
+        }
+        self.stats.admitted(&name);
+        self.active.insert(
+            pid,
+            ActiveEntry {
+                pid,
+                name,
+                profile,
+                claim,
+                unit,
+                lender,
+                lending: false,
+                started: self.clock.now(),
+                cpu: Cell::new(self.first_cpu_sample(pid)),
+                owner,
+                exit_cpu_ticks: None,
+                admit_cpu_ticks: if self.options.suggest_rules {
+                    self.cpu_ticks(pid).unwrap_or(0)
+                } else {
+                    0
+                },
+                exit_peak_rss_kb: None,
+                exit_children_cpu_ticks: None,
+                group_stopped: false,
+                restored_start: None,
+            },
+        );
+        self.stats.observe(self.active.len(), self.paused.len());
+    }
+
+    /// After a claim was given back, resume what now fits. With
+    /// `--resume-debounce`, this waits until the window since the first
+    /// release not acted on yet has passed (see `resume_debounced`), so a
+    /// burst of exits leads to one pass over the queue.
+    fn resume_after_release(&mut self) {
+        match self.options.resume_debounce {
+            Some(_) if !self.paused.is_empty() => {
+                self.resume_pending.get_or_insert(self.clock.now());
+            }
+            _ => self.try_resume_paused(),
+        }
+    }
+
+    /// Called periodically: resume waiting processes once the
+    /// `--resume-debounce` window after a release has passed.
+    pub fn resume_debounced(&mut self) {
+        let Some(debounce) = self.options.resume_debounce else {
+            return;
+        };
+        if self
+            .resume_pending
+            .is_some_and(|since| self.clock.since(since) >= debounce)
+        {
+            self.resume_pending = None;
+            self.try_resume_paused();
+        }
+    }
+
+    fn try_resume_paused(&mut self) {
+        self.resume_pending = None;
+        self.sync_shared();
+        if self.draining {
+            self.resume_for_drain();
+            self.finish_resume_pass();
+            return;
+        }
+        if self.options.rules.has_weights() {
+            while let Some((i, profile)) = self.next_weighted() {
+                if !self.resume_at(i, profile) {
+                    break;
+                }
+            }
+        } else {
+            // The policy picks one process at a time, and the pass stops at
+            // the first that doesn't fit.
+            loop {
+                let entry = |i: usize| self.queued(i);
+                let queue = Queue::new(self.paused.len(), &entry);
+                let Some(i) = self.policy.next_to_resume(&queue, &self.available()) else {
+                    break;
+                };
+                // Anything but an offered process ends the pass too.
+                if i >= self.paused.len() || self.try_resume_at(i) != Attempt::Resumed {
+                    break;
+                }
+            }
+        }
+        self.finish_resume_pass();
+    }
+
+    /// While draining, resume the paused processes an active ancestor waits
+    /// for, oldest first. Held forever, they would keep the ancestor from
+    /// finishing and the drain from completing. Those that don't fit wait
+    /// for the next pass, when the ancestor is left alone at the latest.
+    fn resume_for_drain(&mut self) {
+        let mut i = 0;
+        while i < self.paused.len() {
+            if !self.has_active_ancestor(&self.paused[i].ancestors)
+                || self.try_resume_at(i) != Attempt::Resumed
+            {
+                i += 1;
+            }
+        }
+    }
+
+    fn has_active_ancestor(&self, ancestors: &[Pid]) -> bool {
+        ancestors.iter().any(|p| self.active.contains_key(p))
+    }
+
+    /// The paused process at queue index `i` as the policy sees it, unless
+    /// it is held back by its user's share.
+    fn queued(&self, i: usize) -> Option<Queued> {
+        let entry = &self.paused[i];
+        let profile = self.sibling_profile(&entry.name, entry.profile);
+        let (claim, _) = self.claim_for(&profile, self.lender_for(&entry.ancestors));
+        self.caps_allow(
+            &claim,
+            &entry.name,
+            &entry.class,
+            entry.owner,
+            &entry.ancestors,
+        )
+        .then(|| Queued {
+            pid: entry.pid,
+            owner: entry.owner,
+            claim,
+            waited: self.clock.since(entry.since),
+            est_duration: self.options.rules.est_duration(&entry.class),
+        })
+    }
+
+    fn finish_resume_pass(&mut self) {
+        match self.mem_floor_hit.take() {
+            Some(available_kb) if !self.below_mem_floor => {
+                info!(
+                    "[limit] Only {:.1} GiB of memory available - holding back processes to keep {} GiB free (--mem-floor-gb)",
+                    available_kb as f64 / nixutil::KIB_PER_GIB as f64,
+                    self.options.mem_floor_gb.unwrap_or_default(),
+                );
+                self.below_mem_floor = true;
+            }
+            Some(_) => {}
+            None => self.below_mem_floor = false,
+        }
+        self.sync_shared();
+        self.stats.observe(self.active.len(), self.paused.len());
+    }
+
+    /// Resume the paused process at `i` if its claim fits.
+    fn try_resume_at(&mut self, i: usize) -> Attempt {
+        let next = &self.paused[i];
+        let profile = self.sibling_profile(&next.name, next.profile);
+        let (claim, _) = self.claim_for(&profile, self.lender_for(&next.ancestors));
+        if !self.caps_allow(&claim, &next.name, &next.class, next.owner, &next.ancestors) {
+            Attempt::HeldBack
+        } else if self.fits(&claim, &next.ancestors) && self.resume_at(i, profile) {
+            Attempt::Resumed
+        } else {
+            Attempt::Stop
+        }
+    }
+
+    /// Pick the next paused process to resume by weighted round-robin over
+    /// rule classes, with its profile. Returns its queue index, or `None` if
+    /// nothing fits.
+    ///
+    /// Only the oldest process of each class is a candidate, skipping ones
+    /// held back by their user's share, so each class stays FIFO. Every pick
+    /// adds each candidate class's weight to its credit and takes the sum of
+    /// the weights off the class that is picked (smooth weighted round-robin,
+    /// as in nginx), so over time classes are picked in proportion to their
+    /// weights. The class with the most credit goes first; if its process
+    /// doesn't fit, the next class is tried, and so on.
+    ///
+    /// A process paused for longer than `MAX_WAIT` goes first whatever the
+    /// credit, and while it doesn't fit nothing else is resumed, so that a
+    /// large process can't be passed over forever by smaller ones of other
+    /// classes.
+    fn next_weighted(&mut self) -> Option<(usize, ResourceProfile)> {
+        let mut heads: Vec<(usize, ResourceProfile, ResourceProfile, i64)> = Vec::new();
+        for (i, entry) in self.paused.iter().enumerate() {
+            if heads
+                .iter()
+                .any(|&(j, ..)| self.paused[j].class == entry.class)
+            {
+                continue;
+            }
+            let profile = self.sibling_profile(&entry.name, entry.profile);
+            let (claim, _) = self.claim_for(&profile, self.lender_for(&entry.ancestors));
+            if self.caps_allow(
+                &claim,
+                &entry.name,
+                &entry.class,
+                entry.owner,
+                &entry.ancestors,
+            ) {
+                let weight = i64::from(self.options.rules.weight(&entry.class));
+                heads.push((i, profile, claim, weight));
+            }
+        }
+        let paused = &self.paused;
+        self.class_credit
+            .retain(|class, _| paused.iter().any(|e| &e.class == class));
+
+        let credit = |i: usize| self.class_credit.get(&self.paused[i].class).copied();
+        // Most credit first; ties go to the class waiting longest.
+        let aged = heads
+            .iter()
+            .map(|h| h.0)
+            .find(|&i| self.clock.since(self.paused[i].since) >= admission::MAX_WAIT);
+        heads.sort_by_key(|&(i, _, _, weight)| {
+            (std::cmp::Reverse(credit(i).unwrap_or(0) + weight), i)
+        });
+        let &(picked, profile, ..) = heads
+            .iter()
+            .filter(|h| aged.is_none_or(|aged| h.0 == aged))
+            .find(|(i, _, claim, _)| self.fits(claim, &self.paused[*i].ancestors))?;
+
+        let sum: i64 = heads.iter().map(|h| h.3).sum();
+        for &(i, _, _, weight) in &heads {
+            let class = self.paused[i].class.clone();
+            *self.class_credit.entry(class).or_default() += weight;
+        }
+        let class = self.paused[picked].class.clone();
+        *self.class_credit.entry(class).or_default() -= sum;
+        Some((picked, profile))
+    }
+
+    /// Resume the paused process at queue index `i` with `profile`. Returns
+    /// false if the pass over the queue should stop, because resuming it
+    /// failed and will be retried later.
+    fn resume_at(&mut self, i: usize, profile: ResourceProfile) -> bool {
+        let mut entry = self.paused.remove(i).unwrap();
+        debug!(
+            "[limit] Resuming {} ({}) - need {}",
+            entry.name, entry.pid, profile,
+        );
+        match self.cont(entry.pid, entry.held, entry.running) {
+            Ok(()) => {
+                let waited = self.clock.since(entry.since);
+                self.stats.pause_durations.observe(waited);
+                self.admit(
+                    entry.pid,
+                    entry.name,
+                    profile,
+                    &entry.ancestors,
+                    entry.owner,
+                );
+                if entry.held == PauseMethod::Listen {
+                    if let Some(active) = self.active.get_mut(&entry.pid) {
+                        active.group_stopped = true;
+                    }
+                }
+            }
+            Err(Errno::ESRCH) => {
+                warn!(
+                    "[limit] {} ({}) vanished before it could be resumed, dropping",
+                    entry.name, entry.pid
+                );
+            }
+            Err(e) if entry.retries < self.options.cont_retries => {
+                entry.retries += 1;
+                warn!(
+                    "[limit] Failed to resume {} ({}): {}, retrying on next pass ({}/{})",
+                    entry.name, entry.pid, e, entry.retries, self.options.cont_retries
+                );
+                self.paused.insert(i, entry);
+                // Retrying right away would just fail again; wait for the
+                // next event to trigger another pass.
+                return false;
+            }
+            Err(e) => {
+                error!(
+                    "[limit] Giving up on {} ({}) after {} failed resume attempts: {} - letting it go untraced",
+                    entry.name,
+                    entry.pid,
+                    entry.retries + 1,
+                    e
+                );
+                self.let_go(entry.pid, entry.held);
+            }
+        }
+        true
+    }
+
+    /// Stop holding a paused process we failed to resume, so that it doesn't
+    /// stay stopped for good: detaching ends its ptrace-stop, and a SIGCONT
+    /// its group-stop if it is in one. If even detaching fails, it is killed
+    /// instead, failing its build rather than hanging it.
+    fn let_go(&self, pid: Pid, held: PauseMethod) {
+        match self.ptrace.detach(pid, None) {
+            Ok(()) => {
+                if held.group_stop() {
+                    let _ = self.ptrace.kill(pid, Signal::SIGCONT);
+                }
+            }
+            Err(e) => {
+                error!("[limit] Failed to detach from {}: {} - killing it", pid, e);
+                let _ = self.ptrace.kill(pid, Signal::SIGKILL);
+            }
+        }
+    }
+
+    /// `profile` reduced by `--sibling-mem-gb` for the instances of `name`
+    /// already running.
+    fn sibling_profile(&self, name: &str, profile: ResourceProfile) -> ResourceProfile {
+        match self.options.sibling_mem.iter().find(|s| s.name == name) {
+            Some(sibling) => {
+                let running = self.active.values().filter(|e| e.name == name).count();
+                sibling.apply(profile, running)
+            }
+            None => profile,
+        }
+    }
+
+    /// Move a process still waiting in its exec stop into a SIGSTOP
+    /// group-stop (`--pause-method sigstop` or `listen`).
+    ///
+    /// The SIGSTOP is queued before the process is continued, so it stops
+    /// again before running a single instruction of the new program. The
+    /// tracer delivers the signal and keeps the resulting group-stop in place
+    /// (see `holds_stopped`).
+    fn hold_with_sigstop(&mut self, pid: Pid) {
+        let method = self.options.pause_method;
+        // Admitted right away: nothing to hold.
+        let Some(entry) = self.paused.iter_mut().find(|e| e.pid == pid) else {
+            return;
+        };
+        entry.held = method;
+        if let Err(e) = self
+            .ptrace
+            .kill(pid, Signal::SIGSTOP)
+            .and_then(|()| self.ptrace.cont(pid, None))
+        {
+            warn!("[limit] Failed to hold {} with SIGSTOP: {}", pid, e);
+        }
+    }
+
+    fn cont(&mut self, pid: Pid, held: PauseMethod, running: bool) -> nix::Result<()> {
+        if running {
+            // Not stopped yet. The tracer continues it when the interrupt
+            // stop arrives, as it is no longer paused by then.
+            return Ok(());
+        }
+        match held {
+            PauseMethod::Ptrace => self.ptrace.cont(pid, None),
+            PauseMethod::Sigstop => self.ptrace.kill(pid, Signal::SIGCONT),
+            // The tracer continues it from the resulting interrupt stop, as
+            // it is no longer paused by then.
+            PauseMethod::Listen => self.ptrace.interrupt(pid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::Pid;
+
+    use crate::clock::MockClock;
+    use crate::ptrace::PtraceCall;
+
+    #[test]
+    fn test_pause_histogram() {
+        let mut histogram = PauseHistogram::default();
+        for ms in [50, 100, 700, 30_000, 90_000] {
+            histogram.observe(Duration::from_millis(ms));
+        }
+        assert_eq!(
+            histogram.cumulative(),
+            vec![(0.1, 2), (1.0, 3), (10.0, 3), (60.0, 4), (f64::INFINITY, 5)]
+        );
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), Duration::from_millis(120_850));
+
+        // Every resume from the queue is recorded, however short the wait.
+        let clock = MockClock::new();
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 4), true).with_clock(clock.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        clock.advance(Duration::from_secs(3));
+        limiter.on_exit(Pid::from_raw(100));
+        assert_eq!(limiter.stats.pause_durations.count(), 2);
+        assert_eq!(
+            limiter.stats.pause_durations.cumulative(),
+            vec![(0.1, 1), (1.0, 1), (10.0, 2), (60.0, 2), (f64::INFINITY, 2)]
+        );
+    }
+
+    #[test]
+    fn test_resume_debounce() {
+        let options = LimiterOptions {
+            resume_debounce: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 4), options, true)
+            .with_clock(clock.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        limiter.on_exit(Pid::from_raw(100));
+        // The freed budget is not handed out until the window has passed,
+        // and a newcomer queues behind the waiting process.
+        assert!(limiter.active.is_empty());
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        assert_eq!(limiter.paused.len(), 2);
+        clock.advance(Duration::from_secs(3599));
+        limiter.resume_debounced();
+        assert!(limiter.active.is_empty());
+
+        clock.advance(Duration::from_secs(1));
+        limiter.resume_debounced();
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert_eq!(limiter.paused.len(), 1);
+        assert_eq!(limiter.resume_pending, None);
+    }
+
+    #[test]
+    fn test_cpu_used() {
+        let tck = nixutil::clock_ticks_per_sec() as u64;
+        assert_eq!(
+            cpu_used(Some(6 * tck), Duration::from_secs(3)),
+            ", used 6.0s CPU (2.0 cores)"
+        );
+        assert_eq!(cpu_used(None, Duration::from_secs(3)), "");
+
+        let mut limiter = Limiter::new(ResourceProfile::new(8, 16), true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        assert!(limiter.is_active(Pid::from_raw(100)));
+        limiter.record_exit_cpu(Pid::from_raw(100), tck);
+        assert_eq!(
+            limiter.active[&Pid::from_raw(100)].exit_cpu_ticks,
+            Some(tck)
+        );
+        // Processes that aren't throttled are not tracked.
+        limiter.record_exit_cpu(Pid::from_raw(101), tck);
+        assert!(!limiter.is_active(Pid::from_raw(101)));
+    }
+
+    #[test]
+    fn test_usage_histogram() {
+        let histogram = |samples: &[(f64, u64)]| {
+            let mut histogram = UsageHistogram::default();
+            for &(usage, n) in samples {
+                (0..n).for_each(|_| histogram.observe(usage));
+            }
+            histogram
+        };
+        assert_eq!(histogram(&[]).median(), None);
+        assert_eq!(histogram(&[(0.1, 2), (5.0, 1)]).median(), Some((0.0, 0.25)));
+
+        let heavy = histogram(&[(2.5, 8), (0.1, 2)]);
+        assert_eq!(heavy.median(), Some((2.0, 3.0)));
+        assert_eq!(
+            heavy.suggest(1),
+            Some(Suggestion {
+                median: (2.0, 3.0),
+                samples: 10,
+                claim: 3,
+            })
+        );
+        assert_eq!(heavy.suggest(2).map(|s| s.claim), Some(3));
+        assert_eq!(heavy.suggest(3), None);
+        // Not enough data yet.
+        assert_eq!(histogram(&[(2.5, 9)]).suggest(1), None);
+
+        let light = histogram(&[(0.4, 10)]);
+        assert_eq!(light.suggest(4).map(|s| s.claim), Some(1));
+        assert_eq!(light.suggest(1), None);
+        assert_eq!(light.suggest(0), None);
+        assert_eq!(histogram(&[(1.5, 10)]).suggest(0).map(|s| s.claim), Some(2));
+        assert_eq!(
+            histogram(&[(100.0, 10)]).suggest(16).map(|s| s.claim),
+            Some(128)
+        );
+    }
+
+    #[test]
+    fn test_suggest_rules() {
+        let tck = nixutil::clock_ticks_per_sec() as u64;
+        let run = |suggest_rules, children_ticks| {
+            let options = LimiterOptions {
+                suggest_rules,
+                ..Default::default()
+            };
+            let clock = MockClock::new();
+            let mut limiter = Limiter::with_options(ResourceProfile::new(64, 64), options, true)
+                .with_clock(clock.clone());
+            assert_eq!(limiter.wants_exit_usage(), suggest_rules);
+            for i in 0..MIN_RULE_SAMPLES as i32 {
+                let pid = Pid::from_raw(100 + i);
+                // A second of CPU before admission isn't counted.
+                limiter.fake_cpu_ticks.insert(pid, tck);
+                limiter.on_exec(pid, &["cc".into()], &[], None);
+                clock.advance(Duration::from_secs(2));
+                // 3 GiB at peak, 3.5 cores over 2s.
+                limiter.record_exit_usage(
+                    pid,
+                    Some(3 * nixutil::KIB_PER_GIB),
+                    Some(children_ticks),
+                );
+                limiter.record_exit_cpu(pid, 8 * tck);
+                limiter.on_exit(pid);
+            }
+            limiter.stats
+        };
+
+        let stats = run(true, 0);
+        let usage = &stats.rule_usage["c-compiler"];
+        assert_eq!(usage.claimed, ResourceProfile::new(1, 1));
+        assert_eq!(usage.peak_mem.suggest(1).map(|s| s.claim), Some(3));
+        assert_eq!(usage.cores.suggest(1).map(|s| s.claim), Some(4));
+        assert_eq!(usage.forked, 0);
+        // Its children's usage is missing from both, so it isn't judged.
+        let stats = run(true, tck);
+        assert_eq!(stats.rule_usage["c-compiler"].forked, MIN_RULE_SAMPLES);
+        assert!(run(false, 0).rule_usage.is_empty());
+    }
+
+    #[test]
+    fn test_group_caps() {
+        let mut rules = RuleSet::default();
+        rules.set_group(
+            "c-family".into(),
+            RuleGroup {
+                members: vec!["cc".into(), "g++".into()],
+                max_instances: Some(2),
+                budget: None,
+            },
+        );
+        rules.set_group(
+            "rust".into(),
+            RuleGroup {
+                members: vec!["rustc".into()],
+                max_instances: None,
+                budget: Some(ResourceProfile::new(8, 6)),
+            },
+        );
+        let options = LimiterOptions {
+            rules,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(16, 64), options, true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["g++".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        // The third C compiler waits for the group, though the budget has
+        // room, and doesn't hold up other programs.
+        limiter.on_exec(Pid::from_raw(200), &["rustc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(201), &["rustc".into()], &[], None);
+        assert_eq!(limiter.active.len(), 3);
+        assert_eq!(
+            limiter
+                .paused
+                .iter()
+                .map(|e| e.pid.as_raw())
+                .collect::<Vec<_>>(),
+            vec![102, 201]
+        );
+
+        let groups = limiter.group_summary();
+        assert_eq!(groups[0].name, "c-family");
+        assert_eq!(groups[0].instances, 2);
+        assert_eq!(groups[1].claimed, ResourceProfile::new(1, 4));
+
+        limiter.on_exit(Pid::from_raw(101));
+        assert!(limiter.active.contains_key(&Pid::from_raw(102)));
+        assert_eq!(limiter.paused.len(), 1);
+    }
+
+    #[test]
+    fn test_rule_instance_cap() {
+        let mut rules = RuleSet::default();
+        rules.insert("rustc".into(), Some(ResourceProfile::new(1, 2)));
+        rules.set_max_instances("config:rustc".into(), 2);
+        let options = LimiterOptions {
+            rules,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(16, 64), options, true);
+        for pid in 100..103 {
+            limiter.on_exec(Pid::from_raw(pid), &["rustc".into()], &[], None);
+        }
+        // A nested rustc under an active one still goes.
+        limiter.on_exec(
+            Pid::from_raw(104),
+            &["rustc".into()],
+            &[Pid::from_raw(100), Pid::from_raw(101)],
+            None,
+        );
+        limiter.on_exec(Pid::from_raw(200), &["cc".into()], &[], None);
+        assert_eq!(limiter.active.len(), 4);
+        assert_eq!(limiter.paused.len(), 1);
+        assert!(!limiter.active.contains_key(&Pid::from_raw(102)));
+
+        limiter.on_exit(Pid::from_raw(104));
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.contains_key(&Pid::from_raw(102)));
+    }
+
+    #[test]
+    fn test_resume_order_lifo() {
+        let options = LimiterOptions {
+            resume_order: ResumeOrder::Lifo,
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 100), options, true)
+            .with_clock(clock.clone());
+        for pid in 100..104 {
+            limiter.on_exec(Pid::from_raw(pid), &["cc".into()], &[], None);
+        }
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.contains_key(&Pid::from_raw(103)));
+
+        // Once they have waited long enough, the oldest go first again,
+        // ahead of newer arrivals.
+        clock.advance(admission::MAX_WAIT);
+        limiter.on_exec(Pid::from_raw(104), &["cc".into()], &[], None);
+        limiter.on_exit(Pid::from_raw(103));
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        limiter.on_exit(Pid::from_raw(101));
+        assert!(limiter.active.contains_key(&Pid::from_raw(102)));
+        limiter.on_exit(Pid::from_raw(102));
+        assert!(limiter.active.contains_key(&Pid::from_raw(104)));
+        assert!(limiter.paused.is_empty());
+    }
+
+    #[test]
+    fn test_resume_order_lpt() {
+        let mut rules = RuleSet::default();
+        rules.set_est_duration("rustc".into(), Duration::from_secs(60));
+        rules.set_est_duration("c-compiler".into(), Duration::from_secs(2));
+        let options = LimiterOptions {
+            resume_order: ResumeOrder::Lpt,
+            rules,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 100), options, true);
+        for (pid, name) in [(100, "cc"), (101, "xz"), (102, "cc"), (103, "rustc")] {
+            limiter.on_exec(Pid::from_raw(pid), &[name.into()], &[], None);
+        }
+
+        // Longest estimate first, then the rest oldest first, unestimated last.
+        for (done, next) in [(100, 103), (103, 102), (102, 101)] {
+            limiter.on_exit(Pid::from_raw(done));
+            assert!(
+                limiter.active.contains_key(&Pid::from_raw(next)),
+                "{}",
+                next
+            );
+        }
+        assert!(limiter.paused.is_empty());
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 100), true);
+        let own = nix::unistd::getpid();
+        let live = SavedProcess {
+            pid: own,
+            start_time: nixutil::read_start_time(own).unwrap(),
+            claim: ResourceProfile::new(1, 4),
+            owner: None,
+            name: "rustc".into(),
+        };
+        // PIDs never go this high, so it is gone.
+        let gone = SavedProcess {
+            pid: Pid::from_raw(i32::MAX),
+            name: "cc".into(),
+            claim: ResourceProfile::new(1, 1),
+            ..live.clone()
+        };
+        limiter.restore(&live);
+        limiter.restore(&gone);
+        assert_eq!(limiter.free, ResourceProfile::new(0, 95));
+        assert_eq!(limiter.active_claims().len(), 2);
+
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        assert_eq!(limiter.paused.len(), 1);
+        assert_eq!(limiter.expire_restored(), 1);
+        assert!(limiter.active.contains_key(&Pid::from_raw(100)));
+        assert!(limiter.active.contains_key(&own));
+        assert_eq!(limiter.expire_restored(), 0);
+    }
+
+    #[test]
+    fn test_mem_floor() {
+        let options = LimiterOptions {
+            mem_floor_gb: Some(2),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(8, 100), options, true)
+            .with_clock(clock.clone());
+        const GB: u64 = nixutil::KIB_PER_GIB;
+        limiter.fake_mem_available_kb = Some(6 * GB);
+        // 6 GiB available, 1 claimed by cc since, 4 claimed by rustc: only 1
+        // left.
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["rustc".into()], &[], None);
+        assert!(limiter.active.contains_key(&Pid::from_raw(100)));
+        assert_eq!(limiter.paused.len(), 1);
+        assert!(limiter.below_mem_floor);
+
+        // Retried on the tick, and admitted once memory frees up. MemAvailable
+        // is read again, and cc's memory is now in it.
+        clock.advance(MEM_SAMPLE_WINDOW);
+        limiter.fake_mem_available_kb = Some(5 * GB);
+        limiter.retry_mem_floor();
+        assert_eq!(limiter.paused.len(), 1);
+        clock.advance(MEM_SAMPLE_WINDOW);
+        limiter.fake_mem_available_kb = Some(6 * GB);
+        limiter.retry_mem_floor();
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert!(!limiter.below_mem_floor);
+
+        // The failsafe still admits a process when nothing else runs.
+        limiter.fake_mem_available_kb = Some(GB);
+        limiter.on_exit(Pid::from_raw(100));
+        limiter.on_exit(Pid::from_raw(101));
+        limiter.on_exec(Pid::from_raw(102), &["rustc".into()], &[], None);
+        assert!(limiter.active.contains_key(&Pid::from_raw(102)));
+    }
+
+    #[test]
+    fn test_mem_floor_burst() {
+        let options = LimiterOptions {
+            mem_floor_gb: Some(2),
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(8, 100), options, true);
+        limiter.fake_mem_available_kb = Some(12 * nixutil::KIB_PER_GIB);
+        // Each takes 4 of the 12 GiB read before the burst: the third would
+        // go under the floor before the first two have allocated anything.
+        for pid in 100..104 {
+            limiter.on_exec(Pid::from_raw(pid), &["rustc".into()], &[], None);
+        }
+        assert_eq!(limiter.active.len(), 2);
+        assert_eq!(limiter.paused.len(), 2);
+        assert!(limiter.below_mem_floor);
+    }
+
+    #[test]
+    fn test_mem_hard() {
+        let mut rules = RuleSet::default();
+        rules.set_mem_hard("rustc".into(), 6);
+        let options = LimiterOptions {
+            rules,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(8, 32), options, true);
+        const GB: u64 = nixutil::KIB_PER_GIB;
+        let (rustc, cc) = (Pid::from_raw(100), Pid::from_raw(101));
+        limiter.on_exec(rustc, &["rustc".into()], &[], None);
+        limiter.on_exec(cc, &["cc".into()], &[], None);
+        limiter.fake_rss_kb.insert(rustc, 5 * GB);
+        limiter.fake_rss_kb.insert(cc, 20 * GB);
+
+        // Over its 4 GiB claim but under the hard limit, and cc has none.
+        assert_eq!(limiter.enforce_mem_hard(), 0);
+        limiter.fake_rss_kb.insert(rustc, 6 * GB + 1);
+        assert_eq!(limiter.enforce_mem_hard(), 1);
+    }
+
+    #[test]
+    fn test_drain() {
+        let options = LimiterOptions {
+            never_pause: HashSet::from([Pid::from_raw(103)]),
+            pause_kill_after: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(2, 16), options, true)
+            .with_clock(clock.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        assert_eq!(limiter.paused.len(), 1);
+        assert!(!limiter.drained());
+
+        // Nothing more is admitted, not even protected processes.
+        limiter.start_drain();
+        limiter.on_exec(Pid::from_raw(103), &["cc".into()], &[], None);
+        limiter.on_exit(Pid::from_raw(100));
+        assert_eq!(limiter.active.len(), 1);
+        assert_eq!(limiter.paused.len(), 2);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(limiter.kill_overdue(), 0);
+
+        limiter.on_exit(Pid::from_raw(101));
+        assert!(limiter.drained());
+        limiter.release_paused();
+        assert!(limiter.paused.is_empty());
+    }
+
+    #[test]
+    fn test_adaptive_burst() {
+        let options = LimiterOptions {
+            adaptive: Some(AdaptiveSource::Loadavg),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(2, 64), options, true)
+            .with_clock(clock.clone());
+        limiter.fake_loadavg = Some(0.0);
+        for pid in 100..106 {
+            limiter.on_exec(Pid::from_raw(pid), &["cc".into()], &[], None);
+        }
+        // Two fit the budget, and the idle cores let two more through, but
+        // not the whole burst: the load average doesn't show them yet.
+        assert_eq!(limiter.active.len(), 4);
+        assert_eq!(limiter.paused.len(), 2);
+
+        // Once measured again, the load counts them instead.
+        clock.advance(Duration::from_secs(5));
+        limiter.fake_loadavg = Some(3.0);
+        limiter.on_exit(Pid::from_raw(100));
+        assert_eq!(limiter.active.len(), 3);
+        limiter.fake_loadavg = Some(1.0);
+        clock.advance(Duration::from_secs(5));
+        limiter.on_exit(Pid::from_raw(101));
+        assert_eq!(limiter.active.len(), 3);
+        assert_eq!(limiter.paused.len(), 1);
+    }
+
+    #[test]
+    fn test_drain_admits_children() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 4), true);
+        let (parent, other) = (Pid::from_raw(100), Pid::from_raw(101));
+        limiter.on_exec(parent, &["cc".into()], &[], None);
+        limiter.on_exec(other, &["cc".into()], &[], None);
+        limiter.start_drain();
+
+        // The parent waits for its child, which borrows from its claim.
+        let child = Pid::from_raw(102);
+        limiter.on_exec(child, &["ld".into()], &[parent], None);
+        assert!(limiter.is_active(child));
+        // A process of a finished parent is held, like any other.
+        let orphan = Pid::from_raw(103);
+        limiter.on_exec(orphan, &["cc".into()], &[Pid::from_raw(99)], None);
+        assert!(!limiter.is_active(orphan));
+
+        // Over budget, a child waits until its ancestor is left alone.
+        let grandchild = Pid::from_raw(104);
+        limiter.on_exec(grandchild, &["rustc".into()], &[child, parent], None);
+        assert!(!limiter.is_active(grandchild));
+        limiter.on_exit(other);
+        assert!(limiter.is_active(grandchild));
+        assert_eq!(limiter.paused.len(), 1);
+
+        for pid in [grandchild, child, parent] {
+            limiter.on_exit(pid);
+        }
+        assert!(limiter.drained());
+    }
+
+    #[test]
+    fn test_weighted_resume() {
+        let mut rules = RuleSet::default();
+        rules.set_weight("c-compiler".into(), 1);
+        rules.set_weight("rustc".into(), 3);
+        let options = LimiterOptions {
+            rules,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 100), options, true);
+        limiter.on_exec(Pid::from_raw(1), &["cc".into()], &[], None);
+        for pid in 100..110 {
+            limiter.on_exec(Pid::from_raw(pid), &["cc".into()], &[], None);
+        }
+        for pid in 200..210 {
+            limiter.on_exec(Pid::from_raw(pid), &["rustc".into()], &[], None);
+        }
+
+        let mut resumed = Vec::new();
+        let mut running = Pid::from_raw(1);
+        for _ in 0..8 {
+            limiter.on_exit(running);
+            running = *limiter.active.keys().next().unwrap();
+            resumed.push(running.as_raw());
+        }
+        // Three rustc for every cc, each class in FIFO order.
+        let cc: Vec<i32> = resumed.iter().copied().filter(|&p| p < 200).collect();
+        let rustc: Vec<i32> = resumed.iter().copied().filter(|&p| p >= 200).collect();
+        assert_eq!(cc, vec![100, 101]);
+        assert_eq!(rustc, (200..206).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_weighted_resume_aging() {
+        let mut rules = RuleSet::default();
+        rules.set_weight("c-compiler".into(), 3);
+        let options = LimiterOptions {
+            rules,
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(3, 5), options, true)
+            .with_clock(clock.clone());
+        for pid in 100..103 {
+            limiter.on_exec(Pid::from_raw(pid), &["cc".into()], &[], None);
+        }
+        let rustc = Pid::from_raw(200);
+        limiter.on_exec(rustc, &["rustc".into()], &[], None);
+        for pid in 103..110 {
+            limiter.on_exec(Pid::from_raw(pid), &["cc".into()], &[], None);
+        }
+
+        // rustc never fits the memory a single cc frees, so other cc keep
+        // taking it.
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.is_active(Pid::from_raw(103)));
+
+        // Once it has waited too long, nothing else goes until it fits.
+        clock.advance(admission::MAX_WAIT);
+        limiter.on_exit(Pid::from_raw(101));
+        assert_eq!(limiter.active.len(), 2);
+        limiter.on_exit(Pid::from_raw(102));
+        assert!(limiter.is_active(rustc));
+        assert_eq!(limiter.paused.len(), 6);
+    }
+
+    #[test]
+    fn test_set_total() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 4), true);
+        for pid in 100..104 {
+            limiter.on_exec(Pid::from_raw(pid), &["cc".into()], &[], None);
+        }
+        assert_eq!(limiter.paused.len(), 2);
+
+        // Shrinking below the active claims leaves free negative.
+        limiter.set_total(ResourceProfile::new(1, 4));
+        assert_eq!(limiter.free, ResourceProfile::new(-1, 2));
+        limiter.on_exit(Pid::from_raw(100));
+        assert_eq!(limiter.free, ResourceProfile::new(0, 3));
+        assert_eq!(limiter.paused.len(), 2);
+
+        // Growing it resumes what fits right away.
+        limiter.set_total(ResourceProfile::new(3, 4));
+        assert_eq!(limiter.free, ResourceProfile::new(0, 1));
+        assert!(limiter.paused.is_empty());
+        assert_eq!(limiter.total(), ResourceProfile::new(3, 4));
+    }
+
+    #[test]
+    fn test_cancel_pause() {
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 4), true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        assert_eq!(limiter.paused.len(), 2);
+
+        assert!(limiter.cancel_pause(Pid::from_raw(101), Signal::SIGTERM));
+        assert!(!limiter.cancel_pause(Pid::from_raw(101), Signal::SIGTERM));
+        // Active processes are not paused.
+        assert!(!limiter.cancel_pause(Pid::from_raw(100), Signal::SIGTERM));
+        assert_eq!(limiter.paused.len(), 1);
+        assert_eq!(limiter.free, ResourceProfile::new(0, 3));
+
+        // Its exit changes nothing, and the queue moves on as usual.
+        limiter.on_exit(Pid::from_raw(101));
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.contains_key(&Pid::from_raw(102)));
+        assert!(limiter.paused.is_empty());
+    }
+
+    #[test]
+    fn test_not_throttled() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 2), true);
+        let res = limiter.on_exec(
+            Pid::from_raw(100),
+            &["some_random_process".into()],
+            &[],
+            None,
+        );
+        assert!(matches!(res, OnExecResult::NotThrottled));
+        assert!(limiter.active.is_empty());
+        assert!(limiter.paused.is_empty());
+        assert_eq!(limiter.free, ResourceProfile::new(2, 2));
+    }
+
+    #[test]
+    fn test_admit_and_pause() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 2), true);
+
+        // cc needs (1, 1). Normally fits.
+        let res1 = limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
         assert!(matches!(res1, OnExecResult::Throttled));
         assert_eq!(limiter.active.len(), 1);
         assert_eq!(limiter.free, ResourceProfile::new(1, 1));
 
         // another cc fits.
-        let res2 = limiter.on_exec(Pid::from_raw(101), &["cc".into()]);
+        let res2 = limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
         assert!(matches!(res2, OnExecResult::Throttled));
         assert_eq!(limiter.active.len(), 2);
         assert_eq!(limiter.free, ResourceProfile::new(0, 0));
 
         // third cc pauses.
-        let res3 = limiter.on_exec(Pid::from_raw(102), &["cc".into()]);
+        let res3 = limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
         assert!(matches!(res3, OnExecResult::Throttled));
         assert_eq!(limiter.active.len(), 2);
         assert_eq!(limiter.paused.len(), 1);
         assert_eq!(limiter.free, ResourceProfile::new(0, 0));
     }
 
+    #[test]
+    fn test_stats() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 2), true);
+
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["gcc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(103), &["cc".into()], &[], None);
+        limiter.on_exit(Pid::from_raw(100));
+        limiter.on_exit(Pid::from_raw(101));
+
+        let stats = limiter.stats();
+        assert_eq!(stats.peak_active, 2);
+        assert_eq!(stats.peak_paused, 2);
+        assert_eq!(stats.total_admitted, 4);
+        assert_eq!(stats.admitted_by_name.get("cc"), Some(&3));
+        assert_eq!(stats.admitted_by_name.get("gcc"), Some(&1));
+        // The queue drained, so the paused stretch is closed.
+        assert!(stats.paused_since.is_none());
+    }
+
+    #[test]
+    fn test_release_paused() {
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        assert_eq!(limiter.paused.len(), 2);
+
+        limiter.release_paused();
+        assert!(limiter.paused.is_empty());
+        assert_eq!(limiter.active.len(), 1);
+    }
+
     #[test]
     fn test_force_admit() {
         let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
 
         // rustc needs (1, 4). > (1, 1).
         // normally it would be paused, but since active is empty, it force admits.
-        let res1 = limiter.on_exec(Pid::from_raw(100), &["rustc".into()]);
+        let res1 = limiter.on_exec(Pid::from_raw(100), &["rustc".into()], &[], None);
         assert!(matches!(res1, OnExecResult::Throttled));
         assert_eq!(limiter.active.len(), 1);
         assert_eq!(limiter.free, ResourceProfile::new(0, -3));
 
         // a second rustc should pause because active is no longer empty.
-        let res2 = limiter.on_exec(Pid::from_raw(101), &["rustc".into()]);
+        let res2 = limiter.on_exec(Pid::from_raw(101), &["rustc".into()], &[], None);
         assert!(matches!(res2, OnExecResult::Throttled));
         assert_eq!(limiter.active.len(), 1);
         assert_eq!(limiter.paused.len(), 1);
@@ -229,14 +3214,474 @@ mod tests {
         assert_eq!(limiter.free, ResourceProfile::new(0, -3));
     }
 
+    #[test]
+    fn test_min_parallel() {
+        let options = LimiterOptions {
+            min_parallel: 2,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true);
+
+        // Each rustc (1, 4) is over budget on its own; two are let through.
+        for pid in 100..103 {
+            limiter.on_exec(Pid::from_raw(pid), &["rustc".into()], &[], None);
+        }
+        assert_eq!(limiter.active.len(), 2);
+        assert_eq!(limiter.paused.len(), 1);
+
+        limiter.on_exit(Pid::from_raw(100));
+        assert_eq!(limiter.active.len(), 2);
+        assert!(limiter.paused.is_empty());
+    }
+
+    #[test]
+    fn test_sibling_mem() {
+        let options = LimiterOptions {
+            sibling_mem: vec!["rustc=2".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(3, 8), options, true);
+        for pid in 100..103 {
+            limiter.on_exec(Pid::from_raw(pid), &["rustc".into()], &[], None);
+        }
+        // 4 GiB for the first, 2 GiB for each further one.
+        assert_eq!(limiter.active.len(), 3);
+        assert_eq!(limiter.free, ResourceProfile::new(0, 0));
+
+        // The first one's release frees its full claim.
+        limiter.on_exit(Pid::from_raw(100));
+        assert_eq!(limiter.free, ResourceProfile::new(1, 4));
+    }
+
+    #[test]
+    fn test_kill_overdue() {
+        let options = LimiterOptions {
+            pause_kill_after: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true)
+            .with_clock(clock.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        clock.advance(Duration::from_secs(30));
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(limiter.kill_overdue(), 0);
+
+        // Only the process paused first is over the limit.
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.kill_overdue(), 1);
+        assert_eq!(limiter.paused.len(), 1);
+        assert_eq!(limiter.paused[0].pid, Pid::from_raw(102));
+        assert_eq!(limiter.active.len(), 1);
+    }
+
+    #[test]
+    fn test_pause_method_sigstop() {
+        let options = LimiterOptions {
+            pause_method: PauseMethod::Sigstop,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+
+        // Admitted right away, so never held.
+        assert!(!limiter.holds_stopped(Pid::from_raw(100)));
+        assert!(limiter.holds_stopped(Pid::from_raw(101)));
+
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert!(!limiter.holds_stopped(Pid::from_raw(101)));
+
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        assert_eq!(
+            limiter.forget(Pid::from_raw(102)),
+            Some(PauseMethod::Sigstop)
+        );
+    }
+
+    #[test]
+    fn test_resume_cycle() {
+        let ptrace = MockPtrace::new();
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 4), true);
+        limiter.set_ptrace(ptrace.clone());
+        let pid = Pid::from_raw;
+        let exec = |limiter: &mut Limiter, p: i32, name: &str| {
+            limiter.on_exec(pid(p), &[name.into()], &[], None)
+        };
+
+        // Unthrottled processes are continued by the tracer. Throttled ones
+        // are continued by the limiter once admitted, right away if they fit.
+        assert!(matches!(
+            exec(&mut limiter, 99, "ls"),
+            OnExecResult::NotThrottled
+        ));
+        for p in [100, 101, 102, 103] {
+            assert!(matches!(
+                exec(&mut limiter, p, "cc"),
+                OnExecResult::Throttled
+            ));
+        }
+        assert_eq!(ptrace.take_calls(), vec![PtraceCall::Cont(pid(100), None)]);
+
+        // Each exit resumes the next in line, and only that one.
+        limiter.on_exit(pid(100));
+        assert_eq!(ptrace.take_calls(), vec![PtraceCall::Cont(pid(101), None)]);
+        // A paused process that exits frees nothing.
+        limiter.on_exit(pid(102));
+        assert!(ptrace.calls().is_empty());
+        limiter.on_exit(pid(101));
+        assert_eq!(ptrace.take_calls(), vec![PtraceCall::Cont(pid(103), None)]);
+
+        // Releasing the queue detaches what is still paused.
+        exec(&mut limiter, 104, "cc");
+        limiter.release_paused();
+        assert_eq!(
+            ptrace.take_calls(),
+            vec![PtraceCall::Detach(pid(104), None)]
+        );
+        limiter.on_exit(pid(103));
+        assert!(ptrace.calls().is_empty());
+        assert_eq!(limiter.free, ResourceProfile::new(1, 4));
+    }
+
+    #[test]
+    fn test_pause_method_resume() {
+        for method in [
+            PauseMethod::Ptrace,
+            PauseMethod::Sigstop,
+            PauseMethod::Listen,
+        ] {
+            let options = LimiterOptions {
+                pause_method: method,
+                ..Default::default()
+            };
+            let ptrace = MockPtrace::new();
+            let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true);
+            limiter.set_ptrace(ptrace.clone());
+            limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+            limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+            assert_eq!(
+                limiter.holds_stopped(Pid::from_raw(101)),
+                method.group_stop()
+            );
+
+            // Resumed the way it is held.
+            ptrace.take_calls();
+            limiter.on_exit(Pid::from_raw(100));
+            let pid = Pid::from_raw(101);
+            let resume = match method {
+                PauseMethod::Ptrace => PtraceCall::Cont(pid, None),
+                PauseMethod::Sigstop => PtraceCall::Kill(pid, Signal::SIGCONT),
+                PauseMethod::Listen => PtraceCall::Interrupt(pid),
+            };
+            assert_eq!(ptrace.calls(), vec![resume]);
+            assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+            assert!(!limiter.holds_stopped(Pid::from_raw(101)));
+
+            // Its pending group-stop is ended before we let go of it.
+            let listen = method == PauseMethod::Listen;
+            assert_eq!(limiter.leaves_group_stop(pid), listen);
+            ptrace.take_calls();
+            limiter.release_paused();
+            assert_eq!(
+                ptrace.calls(),
+                if listen {
+                    vec![PtraceCall::Kill(pid, Signal::SIGCONT)]
+                } else {
+                    vec![]
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_active_summary_owner() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 2), true);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], Some(1000));
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+
+        let active = limiter.active_summary();
+        assert_eq!(active.len(), 2);
+        assert_eq!(active[0].pid, Pid::from_raw(100));
+        assert_eq!(active[0].owner, None);
+        assert_eq!(active[1].owner, Some(1000));
+        assert_eq!(active[1].claim, ResourceProfile::new(1, 1));
+    }
+
+    #[test]
+    fn test_per_user_budget() {
+        let options = LimiterOptions {
+            per_user_budget: Some(ResourceProfile::new(1, 1)),
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(4, 4), options, true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], Some(1000));
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], Some(1000));
+        // Behind 101 in the queue, but another user's share is untouched.
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], Some(1001));
+        // Without attribution, only the global budget applies.
+        limiter.on_exec(Pid::from_raw(103), &["cc".into()], &[], None);
+
+        assert_eq!(limiter.active.len(), 3);
+        assert_eq!(limiter.paused.len(), 1);
+        assert_eq!(limiter.paused[0].pid, Pid::from_raw(101));
+
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert!(limiter.paused.is_empty());
+    }
+
+    #[test]
+    fn test_per_user_budget_oversized() {
+        let options = LimiterOptions {
+            per_user_budget: Some(ResourceProfile::new(1, 1)),
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(4, 8), options, true);
+        // rustc (1, 4) exceeds the share on its own, but the user has
+        // nothing else running.
+        limiter.on_exec(Pid::from_raw(100), &["rustc".into()], &[], Some(1000));
+        assert_eq!(limiter.active.len(), 1);
+        limiter.on_exec(Pid::from_raw(101), &["rustc".into()], &[], Some(1000));
+        assert_eq!(limiter.paused.len(), 1);
+    }
+
+    #[test]
+    fn test_never_pause() {
+        let options = LimiterOptions {
+            never_pause: HashSet::from([Pid::from_raw(101)]),
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        let res = limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+
+        // Admitted over budget and left for the caller to continue.
+        assert!(matches!(res, OnExecResult::NotThrottled));
+        assert_eq!(limiter.active.len(), 2);
+        assert_eq!(limiter.free, ResourceProfile::new(-1, -1));
+
+        limiter.on_exit(Pid::from_raw(101));
+        assert_eq!(limiter.free, ResourceProfile::new(0, 0));
+    }
+
+    #[test]
+    fn test_claim_grace_short_lived() {
+        let options = LimiterOptions {
+            claim_grace: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true);
+        for pid in 100..103 {
+            let res = limiter.on_exec(Pid::from_raw(pid), &["cc".into()], &[], None);
+            assert!(matches!(res, OnExecResult::NotThrottled));
+        }
+        // Running, but nothing is claimed or queued yet.
+        assert!(limiter.active.is_empty());
+        assert!(limiter.paused.is_empty());
+        assert_eq!(
+            limiter
+                .classify(Pid::from_raw(100), &["cc".into()])
+                .map(|m| m.profile),
+            Some(ResourceProfile::new(1, 1))
+        );
+
+        limiter.on_exit(Pid::from_raw(100));
+        assert_eq!(limiter.stats.short_lived, 1);
+        assert_eq!(limiter.free, ResourceProfile::new(1, 1));
+    }
+
+    #[test]
+    fn test_failsafe_admits_one_of_a_burst() {
+        // Every rustc claims more memory than the whole budget.
+        let mut limiter = Limiter::new(ResourceProfile::new(8, 1), true);
+        for pid in 100..110 {
+            limiter.on_exec(Pid::from_raw(pid), &["rustc".into()], &[], None);
+        }
+        assert_eq!(
+            limiter.active.keys().copied().collect::<Vec<_>>(),
+            vec![Pid::from_raw(100)]
+        );
+        assert_eq!(limiter.paused.len(), 9);
+        limiter.on_exit(Pid::from_raw(100));
+        assert_eq!(
+            limiter.active.keys().copied().collect::<Vec<_>>(),
+            vec![Pid::from_raw(101)]
+        );
+
+        // The same burst coming out of the claim grace period at once.
+        let options = LimiterOptions {
+            claim_grace: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(8, 1), options, true)
+            .with_clock(clock.clone());
+        for pid in 100..110 {
+            limiter.on_exec(Pid::from_raw(pid), &["rustc".into()], &[], None);
+        }
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(limiter.expire_grace().len(), 9);
+        assert_eq!(limiter.active.len(), 1);
+
+        // And the budget growing while they wait.
+        limiter.set_total(ResourceProfile::new(8, 2));
+        assert_eq!(limiter.active.len(), 1);
+    }
+
+    #[test]
+    fn test_claim_grace_expired() {
+        let options = LimiterOptions {
+            claim_grace: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true)
+            .with_clock(clock.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        clock.advance(Duration::from_millis(10));
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        clock.advance(Duration::from_millis(39));
+        assert!(limiter.expire_grace().is_empty());
+        assert!(limiter.active.is_empty());
+        clock.advance(Duration::from_millis(11));
+
+        // The older one claims the budget, the other has to be stopped.
+        assert_eq!(limiter.expire_grace(), vec![Pid::from_raw(101)]);
+        assert!(limiter.active.contains_key(&Pid::from_raw(100)));
+        assert_eq!(limiter.paused.len(), 1);
+        // Not stopped yet, so not for a detaching caller to let go of.
+        assert!(!limiter.holds_stopped(Pid::from_raw(101)));
+
+        // Freed before the interrupt stop arrived: continue it at the stop.
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert!(!limiter.stopped_for_pause(Pid::from_raw(101)));
+    }
+
+    #[test]
+    fn test_state_of() {
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 4), true);
+        let (rustc, cc, ls) = (Pid::from_raw(100), Pid::from_raw(101), Pid::from_raw(102));
+        limiter.on_exec(rustc, &["rustc".into()], &[], None);
+        limiter.on_exec(cc, &["cc".into()], &[], None);
+        limiter.on_exec(ls, &["ls".into()], &[], None);
+        let rustc_profile = ResourceProfile::new(1, 4);
+        assert_eq!(
+            limiter.state_of(rustc),
+            ProcessState::Active {
+                profile: rustc_profile,
+                claim: rustc_profile,
+            }
+        );
+        assert_eq!(
+            limiter.state_of(cc),
+            ProcessState::Paused {
+                profile: ResourceProfile::new(1, 1),
+            }
+        );
+        assert_eq!(limiter.state_of(ls), ProcessState::NotTracked);
+        assert_eq!(
+            limiter.state_of(Pid::from_raw(200)),
+            ProcessState::NotTracked
+        );
+
+        limiter.on_exit(rustc);
+        assert_eq!(limiter.state_of(rustc), ProcessState::NotTracked);
+        assert_eq!(limiter.state_of(cc).name(), "active");
+
+        let options = LimiterOptions {
+            claim_grace: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true);
+        limiter.on_exec(cc, &["cc".into()], &[], None);
+        assert_eq!(
+            limiter.state_of(cc),
+            ProcessState::Grace {
+                profile: ResourceProfile::new(1, 1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_recheck_comm() {
+        let options = LimiterOptions {
+            recheck_comm: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 4), options, true)
+            .with_clock(clock.clone());
+        let (rustc, multi, cc) = (Pid::from_raw(100), Pid::from_raw(101), Pid::from_raw(102));
+        for (pid, name) in [(rustc, "rustc"), (multi, "multi"), (cc, "cc")] {
+            limiter.fake_comm.insert(pid, name.into());
+        }
+        limiter.on_exec(rustc, &["rustc".into()], &[], None);
+        limiter.on_exec(multi, &["multi".into(), "-c".into()], &[], None);
+        limiter.on_exec(cc, &["cc".into()], &[], None);
+        assert_eq!(limiter.state_of(multi), ProcessState::NotTracked);
+        assert_eq!(limiter.state_of(cc).name(), "paused");
+
+        // Renames are only looked for once the delay has passed.
+        limiter.fake_comm.insert(multi, "gcc".into());
+        limiter.fake_comm.insert(rustc, "ls".into());
+        assert!(limiter.recheck_comm().is_empty());
+        clock.advance(Duration::from_millis(50));
+
+        // rustc gives back its claim, which goes to the queued cc, and the
+        // multiplexer now runs gcc, so it has to wait mid-run.
+        assert_eq!(limiter.recheck_comm(), vec![multi]);
+        assert_eq!(limiter.state_of(rustc), ProcessState::NotTracked);
+        assert_eq!(limiter.state_of(cc).name(), "active");
+        assert_eq!(limiter.state_of(multi).name(), "paused");
+        assert!(limiter.stopped_for_pause(multi));
+        assert_eq!(
+            limiter.classification(multi),
+            (Some("gcc"), Some("c-compiler".into()), "paused")
+        );
+
+        // cc was paused until now, so it is checked a delay later.
+        limiter.fake_comm.insert(cc, "cc1".into());
+        assert!(limiter.recheck_comm().is_empty());
+        clock.advance(Duration::from_millis(50));
+        limiter.on_exit(multi);
+        assert!(limiter.recheck_comm().is_empty());
+        assert_eq!(limiter.classification(cc).0, Some("cc1"));
+    }
+
+    #[test]
+    fn test_claim_grace_stopped_then_resumed() {
+        let options = LimiterOptions {
+            claim_grace: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let clock = MockClock::new();
+        let mut limiter = Limiter::with_options(ResourceProfile::new(1, 1), options, true)
+            .with_clock(clock.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        clock.advance(Duration::from_millis(10));
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        clock.advance(Duration::from_secs(1));
+        limiter.expire_grace();
+
+        assert!(limiter.stopped_for_pause(Pid::from_raw(101)));
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert!(limiter.paused.is_empty());
+    }
+
     #[test]
     fn test_on_exit() {
         let mut limiter = Limiter::new(ResourceProfile::new(2, 2), true);
 
-        limiter.on_exec(Pid::from_raw(100), &["cc".into()]); // admits, free (1, 1)
-        limiter.on_exec(Pid::from_raw(101), &["cc".into()]); // admits, free (0, 0)
-        limiter.on_exec(Pid::from_raw(102), &["cc".into()]); // pauses
-        limiter.on_exec(Pid::from_raw(103), &["cc".into()]); // pauses
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None); // admits, free (1, 1)
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None); // admits, free (0, 0)
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None); // pauses
+        limiter.on_exec(Pid::from_raw(103), &["cc".into()], &[], None); // pauses
 
         assert_eq!(limiter.active.len(), 2);
         assert_eq!(limiter.paused.len(), 2);
@@ -251,4 +3696,284 @@ mod tests {
         assert_eq!(limiter.paused.len(), 1);
         assert_eq!(limiter.free, ResourceProfile::new(0, 0));
     }
+
+    #[test]
+    fn test_parallel_lto_workers() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 4), true);
+
+        // `lto-wrapper` itself is not throttled.
+        let res = limiter.on_exec(Pid::from_raw(100), &["lto-wrapper".into()], &[], None);
+        assert!(matches!(res, OnExecResult::NotThrottled));
+
+        // Each `lto1` worker of the same link claims its own (1, 2).
+        for pid in 101..104 {
+            limiter.on_exec(Pid::from_raw(pid), &["lto1".into()], &[], None);
+        }
+        assert_eq!(limiter.active.len(), 2);
+        assert_eq!(limiter.paused.len(), 1);
+        assert_eq!(limiter.free, ResourceProfile::new(0, 0));
+    }
+
+    #[test]
+    fn test_start_time_measured_from_admit() {
+        let clock = MockClock::new();
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true).with_clock(clock.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+
+        clock.advance(Duration::from_secs(5));
+        limiter.on_exit(Pid::from_raw(100));
+        clock.advance(Duration::from_secs(2));
+
+        // PID 101 was paused at exec; its run time starts when it's resumed.
+        let active = limiter.active_summary();
+        assert_eq!(active[0].running_for, Duration::from_secs(2));
+        assert_eq!(limiter.stats.pause_durations.sum(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_cont_retry_then_success() {
+        let ptrace = MockPtrace::new();
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
+        limiter.set_ptrace(ptrace.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        ptrace.fail(Pid::from_raw(101), [Errno::EBUSY]);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+
+        // First resume attempt fails transiently: 101 goes back to the front.
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.is_empty());
+        assert_eq!(limiter.paused.front().map(|e| e.retries), Some(1));
+        assert_eq!(limiter.free, ResourceProfile::new(1, 1));
+
+        // The next pass succeeds.
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert_eq!(limiter.paused.len(), 1);
+    }
+
+    #[test]
+    fn test_cont_esrch_dropped() {
+        let ptrace = MockPtrace::new();
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
+        limiter.set_ptrace(ptrace.clone());
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        ptrace.fail(Pid::from_raw(101), [Errno::ESRCH]);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+
+        limiter.on_exit(Pid::from_raw(100));
+        assert!(limiter.active.is_empty());
+        assert!(limiter.paused.is_empty());
+        assert_eq!(limiter.free, ResourceProfile::new(1, 1));
+    }
+
+    #[test]
+    fn test_cont_retries_exhausted() {
+        let ptrace = MockPtrace::new();
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
+        limiter.set_ptrace(ptrace.clone());
+        ptrace.fail(Pid::from_raw(100), [Errno::EBUSY; 4]);
+
+        // Initial attempt, then one retry per resume pass, each pass triggered
+        // by another exec.
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        for pid in 101..104 {
+            assert_eq!(
+                limiter.paused.front().map(|e| e.pid),
+                Some(Pid::from_raw(100))
+            );
+            limiter.on_exec(Pid::from_raw(pid), &["cc".into()], &[], None);
+        }
+
+        // After the last retry 100 is let go and the next in line runs.
+        assert!(limiter.paused.iter().all(|e| e.pid != Pid::from_raw(100)));
+        assert!(ptrace
+            .calls()
+            .contains(&PtraceCall::Detach(Pid::from_raw(100), None)));
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert_eq!(limiter.paused.len(), 2);
+
+        // One that can't even be detached is killed rather than left stopped.
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
+        limiter.set_ptrace(ptrace.clone());
+        limiter.options.cont_retries = 0;
+        ptrace.fail(Pid::from_raw(200), [Errno::EBUSY; 2]);
+        ptrace.take_calls();
+        limiter.on_exec(Pid::from_raw(200), &["cc".into()], &[], None);
+        assert_eq!(
+            ptrace.calls(),
+            vec![
+                PtraceCall::Cont(Pid::from_raw(200), None),
+                PtraceCall::Detach(Pid::from_raw(200), None),
+                PtraceCall::Kill(Pid::from_raw(200), Signal::SIGKILL),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shared_state_subtracts_other_instances() {
+        let path = std::env::temp_dir().join(format!("nix-ubw-{}-limiter", std::process::id()));
+        // PID 1 always exists and stands in for another instance.
+        std::fs::write(&path, "1 1 1\n").unwrap();
+
+        let options = LimiterOptions {
+            shared_state: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut limiter = Limiter::with_options(ResourceProfile::new(2, 2), options, true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+
+        // The other instance's claim leaves room for only one of ours.
+        assert_eq!(limiter.active.len(), 1);
+        assert_eq!(limiter.paused.len(), 1);
+        let ledger = std::fs::read_to_string(&path).unwrap();
+        assert!(ledger.contains(&format!("{} 1 1", nix::unistd::getpid())));
+
+        drop(limiter);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
+        limiter.on_exec(Pid::from_raw(100), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["cc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(102), &["cc".into()], &[], None);
+
+        // Forgetting a paused process hands it back to the caller.
+        assert_eq!(
+            limiter.forget(Pid::from_raw(102)),
+            Some(PauseMethod::Ptrace)
+        );
+        assert_eq!(limiter.paused.len(), 1);
+
+        // Forgetting an active one releases its claim for the next in line.
+        assert_eq!(limiter.forget(Pid::from_raw(100)), None);
+        assert!(limiter.active.contains_key(&Pid::from_raw(101)));
+        assert!(limiter.paused.is_empty());
+        assert_eq!(limiter.free, ResourceProfile::new(0, 0));
+    }
+
+    #[test]
+    fn test_link_chain_borrows() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 4), true);
+        let gcc = Pid::from_raw(100);
+        let collect2 = Pid::from_raw(101);
+        let ld = Pid::from_raw(102);
+
+        limiter.on_exec(gcc, &["gcc".into()], &[], None);
+        assert_eq!(limiter.free, ResourceProfile::new(1, 3));
+
+        // collect2 and ld run inside gcc's reservation.
+        limiter.on_exec(collect2, &["collect2".into()], &[gcc], None);
+        limiter.on_exec(ld, &["ld".into()], &[collect2, gcc], None);
+        assert_eq!(limiter.active.len(), 3);
+        assert_eq!(limiter.free, ResourceProfile::new(1, 3));
+
+        limiter.on_exit(ld);
+        limiter.on_exit(collect2);
+        limiter.on_exit(gcc);
+        assert_eq!(limiter.free, ResourceProfile::new(2, 4));
+    }
+
+    #[test]
+    fn test_link_chain_upgrade() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 4), true);
+        let clang = Pid::from_raw(100);
+        let lld = Pid::from_raw(101);
+
+        // lld needs (1, 2); under clang's (1, 1) it only claims the extra GiB.
+        limiter.on_exec(clang, &["clang".into()], &[], None);
+        limiter.on_exec(lld, &["ld.lld".into()], &[clang], None);
+        assert_eq!(limiter.free, ResourceProfile::new(1, 2));
+
+        limiter.on_exit(lld);
+        assert_eq!(limiter.free, ResourceProfile::new(1, 3));
+        limiter.on_exit(clang);
+        assert_eq!(limiter.free, ResourceProfile::new(2, 4));
+    }
+
+    #[test]
+    fn test_link_chain_no_deadlock() {
+        // gcc fills the budget; its own linker must not wait for it.
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
+        let gcc = Pid::from_raw(100);
+        limiter.on_exec(gcc, &["gcc".into()], &[], None);
+        limiter.on_exec(Pid::from_raw(101), &["ld.lld".into()], &[gcc], None);
+        assert_eq!(limiter.active.len(), 2);
+        assert!(limiter.paused.is_empty());
+        assert_eq!(limiter.free, ResourceProfile::new(0, -1));
+
+        // An unrelated compile still has to wait.
+        limiter.on_exec(Pid::from_raw(200), &["gcc".into()], &[], None);
+        assert_eq!(limiter.paused.len(), 1);
+    }
+
+    #[test]
+    fn test_parallel_workers_claim_in_full() {
+        let mut limiter = Limiter::new(ResourceProfile::new(4, 8), true);
+        let ld = Pid::from_raw(100);
+        limiter.on_exec(ld, &["ld".into()], &[], None);
+
+        // The first worker borrows ld's (1, 1); the second runs in parallel
+        // and claims its own (1, 2).
+        limiter.on_exec(Pid::from_raw(101), &["lto1".into()], &[ld], None);
+        limiter.on_exec(Pid::from_raw(102), &["lto1".into()], &[ld], None);
+        assert_eq!(limiter.free, ResourceProfile::new(2, 4));
+
+        // If ld exits first, its claim moves to the worker still borrowing it.
+        limiter.on_exit(ld);
+        assert_eq!(limiter.free, ResourceProfile::new(2, 4));
+        limiter.on_exit(Pid::from_raw(101));
+        limiter.on_exit(Pid::from_raw(102));
+        assert_eq!(limiter.free, ResourceProfile::new(4, 8));
+    }
+
+    #[test]
+    fn test_classify_cached() {
+        let mut limiter = Limiter::new(ResourceProfile::new(2, 2), true);
+        let pid = Pid::from_raw(100);
+        let args: Vec<String> = vec!["rustc".into(), "lib.rs".into()];
+
+        let first = limiter.classify(pid, &args).unwrap();
+        assert_eq!(first.profile, ResourceProfile::new(1, 4));
+        assert_eq!(limiter.classify(pid, &args), Some(first));
+        assert_eq!(limiter.stats.rule_lookups, 1);
+
+        // A new exec in the same PID is classified afresh.
+        assert_eq!(limiter.classify(pid, &["bash".into()]), None);
+        assert_eq!(limiter.stats.rule_lookups, 2);
+
+        // Exit clears the cache.
+        limiter.on_exit(pid);
+        assert!(limiter.classified.is_empty());
+    }
+
+    #[test]
+    fn test_set_rules_keeps_admitted() {
+        let mut limiter = Limiter::new(ResourceProfile::new(4, 16), true);
+        let admitted = Pid::from_raw(100);
+        let seen = Pid::from_raw(101);
+        let args: Vec<String> = vec!["rustc".into(), "lib.rs".into()];
+        limiter.on_exec(admitted, &args, &[], None);
+        assert_eq!(limiter.free, ResourceProfile::new(3, 12));
+        // Classified by a scan, but not acted on yet.
+        limiter.classify(seen, &args);
+
+        let mut rules = RuleSet::default();
+        rules.insert("rustc".into(), Some(ResourceProfile::new(1, 8)));
+        limiter.set_rules(rules);
+        assert!(limiter.classified.contains_key(&admitted));
+        assert!(!limiter.classified.contains_key(&seen));
+
+        // New processes get the new profile; the admitted one frees its old
+        // claim.
+        limiter.on_exec(seen, &args, &[], None);
+        assert_eq!(limiter.free, ResourceProfile::new(2, 4));
+        limiter.on_exit(admitted);
+        assert_eq!(limiter.free, ResourceProfile::new(3, 8));
+        limiter.on_exit(seen);
+        assert_eq!(limiter.free, ResourceProfile::new(4, 16));
+    }
 }
@@ -0,0 +1,192 @@
+use std::io::{IsTerminal, Write};
+
+use log::Level;
+
+/// Whether log lines on stderr are colored (`--color`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// When stderr is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+        }
+    }
+}
+
+/// Column the budget part of `[limit]` lines (`free: ..., total: ...`) is
+/// aligned to, counted from the start of the message.
+const BUDGET_COLUMN: usize = 96;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const BLUE: &str = "\x1b[34m";
+const MAGENTA: &str = "\x1b[35m";
+const CYAN: &str = "\x1b[36m";
+
+/// Format log lines for `choice`: colored and aligned for reading in a
+/// terminal if enabled, and env_logger's plain default otherwise, so that
+/// piped output and the journal stay free of escape codes.
+pub fn configure(builder: &mut env_logger::Builder, choice: ColorChoice) {
+    if !choice.enabled() {
+        builder.write_style(env_logger::WriteStyle::Never);
+        return;
+    }
+    builder.write_style(env_logger::WriteStyle::Always);
+    builder.format(|buf, record| {
+        let timestamp = buf.timestamp().to_string();
+        let message = record.args().to_string();
+        writeln!(buf, "{}", format_line(record.level(), &timestamp, &message))
+    });
+}
+
+/// One colored log line, without the module path, which the event tag
+/// makes redundant.
+fn format_line(level: Level, timestamp: &str, message: &str) -> String {
+    let level_color = match level {
+        Level::Error => RED,
+        Level::Warn => YELLOW,
+        Level::Info => GREEN,
+        Level::Debug => BLUE,
+        Level::Trace => DIM,
+    };
+    format!(
+        "{DIM}{timestamp}{RESET} {level_color}{level:<5}{RESET} {}",
+        format_message(message)
+    )
+}
+
+fn format_message(message: &str) -> String {
+    let Some((tag, rest)) = split_tag(message) else {
+        return message.to_owned();
+    };
+    // Programs that weren't throttled are the bulk of the log and rarely
+    // what one is looking for.
+    if tag == "[exec]" && !rest.contains("(throttled") {
+        return format!("{DIM}{message}{RESET}");
+    }
+    let tag_color = match tag {
+        "[limit]" => MAGENTA,
+        "[exec]" => CYAN,
+        "[exit]" => BLUE,
+        "[fork]" | "[vfork]" | "[clone]" | "[thread]" => DIM,
+        _ => BOLD,
+    };
+    let rest = highlight_decision(rest);
+    match split_budget(&rest) {
+        Some((head, budget)) => {
+            let width = tag.len() + visible_len(head);
+            let separator = match BUDGET_COLUMN.checked_sub(width) {
+                Some(pad) if pad >= 2 => " ".repeat(pad),
+                _ => ", ".to_owned(),
+            };
+            format!("{tag_color}{tag}{RESET}{head}{separator}{budget}")
+        }
+        None => format!("{tag_color}{tag}{RESET}{rest}"),
+    }
+}
+
+/// Split `[tag] rest` into the tag and the rest, which keeps its leading
+/// space.
+fn split_tag(message: &str) -> Option<(&str, &str)> {
+    if !message.starts_with('[') {
+        return None;
+    }
+    let end = message.find(']')?;
+    Some(message.split_at(end + 1))
+}
+
+/// Color the decision word of a `[limit]` line.
+fn highlight_decision(rest: &str) -> String {
+    for (word, color) in [(" PAUSED ", YELLOW), (" admitted ", GREEN)] {
+        if let Some(i) = rest.find(word) {
+            let (head, tail) = rest.split_at(i);
+            let word = word.trim();
+            return format!(
+                "{head} {BOLD}{color}{word}{RESET}{}",
+                &tail[word.len() + 1..]
+            );
+        }
+    }
+    rest.to_owned()
+}
+
+/// Split off the trailing budget (`free: ..., total: ... (N paused)`).
+fn split_budget(rest: &str) -> Option<(&str, &str)> {
+    let i = rest.find(", free: ").or_else(|| rest.find(" - free: "))?;
+    let head = &rest[..i];
+    let budget = rest[i..].trim_start_matches([',', ' ', '-']);
+    Some((head, budget))
+}
+
+/// Length of `s` as displayed, without escape codes.
+fn visible_len(s: &str) -> usize {
+    let mut len = 0;
+    let mut in_escape = false;
+    for c in s.chars() {
+        match c {
+            '\x1b' => in_escape = true,
+            'm' if in_escape => in_escape = false,
+            _ if in_escape => {}
+            _ => len += 1,
+        }
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_aligned() {
+        let budget_column = |line: &str| visible_len(&line[..line.find("free: ").unwrap()]);
+        let admitted = format_message(
+            "[limit] xz (4951, uid 0) admitted - free: 0 CPUs, 63 GiB, total: 1 CPUs, 64 GiB (0 paused)",
+        );
+        assert_eq!(budget_column(&admitted), BUDGET_COLUMN);
+        assert!(admitted.contains(&format!("{BOLD}{GREEN}admitted{RESET}")));
+        assert!(admitted.ends_with(" free: 0 CPUs, 63 GiB, total: 1 CPUs, 64 GiB (0 paused)"));
+        let finished = format_message(
+            "[limit] xz (4951, uid 0) finished after 0.6s - freed 1 CPUs, 1 GiB, free: 1 CPUs, 64 GiB, total: 1 CPUs, 64 GiB (2 paused)",
+        );
+        assert_eq!(budget_column(&finished), BUDGET_COLUMN);
+        let paused = format_message(
+            "[limit] xz (4951, uid 0) PAUSED - matched rule 'compressor', need 1 CPUs, 1 GiB, free: 1 CPUs, 64 GiB, total: 1 CPUs, 64 GiB (1 paused)",
+        );
+        assert_eq!(budget_column(&paused), BUDGET_COLUMN);
+        // Too long to align.
+        let long = format_message(
+            "[limit] x86_64-unknown-linux-gnu-g++ (4951, uid 30001) PAUSED - matched rule 'c-compiler', need 1 CPUs, 1 GiB, free: 0 CPUs, 64 GiB, total: 1 CPUs, 64 GiB (1 paused)",
+        );
+        assert!(long.contains("need 1 CPUs, 1 GiB, free: 0 CPUs"));
+    }
+
+    #[test]
+    fn test_plain_exec_dimmed() {
+        let plain = "[exec] PID 12: cc --version";
+        assert_eq!(format_message(plain), format!("{DIM}{plain}{RESET}"));
+        let throttled = "[exec] PID 12: cc (throttled, matched rule 'c-compiler')";
+        assert!(format_message(throttled).starts_with(&format!("{CYAN}[exec]{RESET}")));
+        assert_eq!(format_message("Tracing started"), "Tracing started");
+    }
+
+    #[test]
+    fn test_visible_len() {
+        assert_eq!(visible_len(&format!("{BOLD}{GREEN}ok{RESET} x")), 4);
+    }
+}
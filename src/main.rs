@@ -1,30 +1,466 @@
-mod daemon;
-mod limiter;
-mod nixutil;
-mod resources;
-mod tracer;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use std::fs;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use log::{error, info};
-use nix::sys::wait::{waitpid, WaitPidFlag};
-
-use resources::ResourceProfile;
-use tracer::Tracer;
+use nix_ubw::advisory::AdvisoryFile;
+use nix_ubw::checkpoint::{self, Checkpoint};
+#[cfg(feature = "serde")]
+use nix_ubw::config::{self, RuleSource};
+use nix_ubw::control::ControlSocket;
+use nix_ubw::health::{self, Health};
+use nix_ubw::limiter::{AdaptiveSource, LimiterOptions, PauseMethod, ResumeOrder};
+use nix_ubw::logfmt::{self, ColorChoice};
+use nix_ubw::pidfile::PidFile;
+use nix_ubw::pressure::PressureFile;
+use nix_ubw::ptrace::SystemPtrace;
+use nix_ubw::resources::{ArchScale, ResourceProfile, RuleSet, SiblingMem, RULES_VAR};
+use nix_ubw::sampler::Samplers;
+use nix_ubw::statusline::{StatusLine, StatusLogger};
+use nix_ubw::timeline;
+use nix_ubw::tracer::{self, Tracer};
+use nix_ubw::{daemon, doctor, journal, nixutil, probe, signals};
 
 /// Trace all programs execve'd by the Nix daemon and throttle resource-intensive ones.
 #[derive(Parser)]
 #[command(version)]
 struct Args {
-    /// Total CPU cores available for throttled processes [default: system core count].
-    #[arg(short = 'c', long, default_value_t = default_cpus())]
-    total_cpus: i32,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Total CPU cores available for throttled processes [default: from
+    /// NIXUBW_BUDGET, or the system core count less `--reserve`].
+    #[arg(short = 'c', long)]
+    total_cpus: Option<i32>,
+
+    /// Total memory in GiB available for throttled processes [default: from
+    /// NIXUBW_BUDGET, or system RAM, rounded down, less `--reserve`].
+    #[arg(short = 'm', long)]
+    total_mem_gb: Option<i32>,
+
+    /// Keep this much of the system's cores and RAM out of the default
+    /// budget, e.g. `1cpu,4gb` for what runs next to the builds. Also read
+    /// from NIXUBW_RESERVE [default: nothing].
+    #[arg(long, value_name = "CPUS,MEM")]
+    reserve: Option<ResourceProfile>,
+
+    /// Let derivations override the memory claim of their throttled processes
+    /// by exporting NIXUBW_MEM_GB=<GiB>.
+    #[arg(long)]
+    profile_hint: bool,
+
+    /// Admit processes over the CPU budget while measured usage shows spare
+    /// cores: the 1-minute load average (default), or recent CPU time of the
+    /// active throttled processes. Processes admitted since the last
+    /// measurement count with their claims. Memory is always reserved
+    /// strictly.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SOURCE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "loadavg"
+    )]
+    adaptive: Option<AdaptiveSource>,
+
+    /// Retries after a transient failure to resume a paused process before it
+    /// is given up on and detached, to run untraced (or killed if even that
+    /// fails).
+    #[arg(long, default_value_t = LimiterOptions::default().cont_retries)]
+    cont_retries: u32,
+
+    /// Coordinate with other nix-ubw instances through this file: their
+    /// claims are subtracted from our budget before admitting.
+    #[arg(long, value_name = "PATH")]
+    shared_state: Option<PathBuf>,
+
+    /// Log every ptrace event and limiter decision for this PID and its
+    /// descendants at info level, leaving everything else at the normal level.
+    /// Can be repeated.
+    #[arg(long, value_name = "PID")]
+    trace_pid: Vec<i32>,
+
+    /// Accept runtime commands (e.g. `detach <pid>`) on a Unix socket at this path.
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Serve a liveness check over HTTP at this address (e.g.
+    /// `127.0.0.1:9090`): `GET /healthz` answers 200 while the main loop runs
+    /// and something is attached, 503 otherwise.
+    #[arg(long, value_name = "ADDR")]
+    health_listen: Option<SocketAddr>,
+
+    /// Renice ourselves to this niceness so the tracer doesn't compete with the
+    /// builds it manages [default: unchanged].
+    #[arg(long, value_name = "N", allow_hyphen_values = true)]
+    nice_self: Option<i32>,
+
+    /// Run ourselves under SCHED_BATCH.
+    #[arg(long)]
+    sched_batch: bool,
+
+    /// Always let at least this many throttled processes run concurrently,
+    /// even if together they exceed the budget.
+    #[arg(long, value_name = "N", default_value_t = LimiterOptions::default().min_parallel)]
+    min_parallel: usize,
+
+    /// Hold back processes that claim memory while admitting them would leave
+    /// less than this many GiB of MemAvailable, whatever the budget says, to
+    /// account for memory used by programs nix-ubw doesn't throttle.
+    #[arg(long, value_name = "GB")]
+    mem_floor_gb: Option<u32>,
+
+    /// Claim only GIB of memory for each instance of NAME that starts while
+    /// another is already running, instead of the rule's full memory. Can be
+    /// repeated for different programs.
+    #[arg(long, value_name = "NAME=GIB")]
+    sibling_mem_gb: Vec<SiblingMem>,
+
+    /// SIGKILL any process that stays paused for longer than this (e.g. `30m`),
+    /// so a wedged queue fails the build loudly instead of hanging it
+    /// [default: never].
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pause_kill_after: Option<Duration>,
+
+    /// How to hold paused processes: in their ptrace exec stop, or in a
+    /// job-control stop (SIGSTOP) that survives the tracer and SIGCONT resumes.
+    /// `listen` holds them in the same job-control stop but admits them with
+    /// PTRACE_INTERRUPT instead of SIGCONT, so they never see a SIGCONT.
+    #[arg(long, value_enum, value_name = "METHOD", default_value_t = PauseMethod::default())]
+    pause_method: PauseMethod,
+
+    /// Cap what the builds of each nix-daemon worker uid may claim together,
+    /// e.g. `4cpu,16gb`, on top of the global budget.
+    #[arg(long, value_name = "CPUS,MEM")]
+    per_user_budget: Option<ResourceProfile>,
+
+    /// Only throttle the builds of nix-daemon workers running as this uid.
+    /// Those of other users are continued without being classified. Can be
+    /// repeated [default: every user].
+    #[arg(long, value_name = "UID")]
+    only_uid: Vec<u32>,
+
+    /// Trace exec only, not fork: new processes are found by scanning /proc
+    /// on every tick instead of stopping at each fork. Much less overhead on
+    /// fork-heavy builds, but a process that forks and execs a compiler
+    /// between two scans is not throttled.
+    #[arg(long)]
+    exec_only: bool,
+
+    /// Stop following forks this many processes below a traced root (a
+    /// nix-daemon is 0, its workers 1, ...). Processes at that depth are
+    /// still classified at exec, but what they start is not traced at all,
+    /// throttled programs included. Cuts overhead on very deep process
+    /// trees [default: no limit].
+    #[arg(long, value_name = "N", conflicts_with = "exec_only", value_parser = clap::value_parser!(u32).range(1..))]
+    max_depth: Option<u32>,
+
+    /// Never pause this PID: it is admitted as soon as it execs a throttled
+    /// program, over budget if need be, and still claims its profile. For
+    /// long-running helpers whose name matches a rule. Can be repeated.
+    #[arg(long, value_name = "PID")]
+    never_pause_pid: Vec<i32>,
+
+    /// Let throttled processes run this long (e.g. `20ms`) before they claim
+    /// budget. Ones that exit sooner never claim or wait; ones that don't are
+    /// paused mid-run if the budget is exhausted by then [default: claim at
+    /// exec].
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    claim_grace: Option<Duration>,
+
+    /// Read the name (`comm`) of each exec'd process again this long after
+    /// exec (e.g. `50ms`), and reclassify it if it renamed itself, as
+    /// multiplexers dispatching to the tool they act as do. Costs a /proc
+    /// read per exec [default: classify at exec only].
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    recheck_comm: Option<Duration>,
+
+    /// Record the peak memory and CPU usage of the processes of each rule,
+    /// and warn in the summary (on exit and on SIGUSR1) about rules whose
+    /// claims are consistently too low or too high. Costs a /proc read per
+    /// throttled exit. Only the process itself is measured, so rules whose
+    /// processes run children (drivers such as go, nvcc or ghc) are not
+    /// judged.
+    #[arg(long)]
+    suggest_rules: bool,
+
+    /// Log throttled processes that run under a make jobserver, whose
+    /// parallelism make -j bounds as well, and count them in the summary.
+    /// Costs a read of /proc/<pid>/environ per throttled exec.
+    #[arg(long)]
+    report_jobserver: bool,
+
+    /// Where log messages go: text on stderr, or native journald entries with
+    /// structured fields (NIXUBW_EVENT, NIXUBW_PID, ...). Falls back to text
+    /// if we don't run under the journal.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Hide per-process events ([exec], [fork], [exit], ...), even for
+    /// `--trace-pid` and with RUST_LOG=debug, and keep only limiter decisions
+    /// ([limit] pause, admit, finish, ...) and warnings.
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Keep a line at the bottom of the terminal showing the claimed budget
+    /// and the number of paused processes, redrawn in place as they change.
+    /// Only when stderr is a terminal and logging is text.
+    #[arg(long)]
+    status_line: bool,
+
+    /// Color event tags and limiter decisions, align the free budget of
+    /// [limit] lines and dim the [exec] lines of programs that weren't
+    /// throttled: when stderr is a terminal (auto), always, or never.
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::default())]
+    color: ColorChoice,
+
+    /// TOML file of rules that add to or replace the built-in ones, e.g.
+    /// `[rules] rustc = "1cpu,6gb"`, or `tar = false` to never throttle
+    /// [default: from NIXUBW_RULES, a path or the rules themselves].
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Directory of further rule files (`*.toml`), loaded after `--config` in
+    /// lexical order. A later rule for the same program replaces an earlier
+    /// one. Can be repeated.
+    #[arg(long, value_name = "DIR")]
+    config_dir: Vec<PathBuf>,
+
+    /// After a throttled process exits, wait this long (e.g. `50ms`) before
+    /// resuming paused ones, so that a burst of exits is handled in one pass
+    /// instead of churning the queue [default: resume right away].
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    resume_debounce: Option<Duration>,
+
+    /// Which paused process to resume first: the oldest (fifo), the most
+    /// recently paused (lifo), whose files are likelier to still be cached, or
+    /// the one with the longest `[est_duration]` (lpt). Under lifo and lpt,
+    /// processes paused for over 30s still go first. Ignored if resume weights
+    /// are configured.
+    #[arg(long, alias = "schedule", value_enum, value_name = "ORDER", default_value_t = ResumeOrder::default())]
+    resume_order: ResumeOrder,
+
+    /// Scale the profiles of cross tools for ARCH, e.g. `aarch64=1.5` for
+    /// `aarch64-unknown-linux-gnu-gcc` under qemu user-mode emulation.
+    /// Claims are rounded up. Can be repeated.
+    #[arg(long, value_name = "ARCH=FACTOR")]
+    arch_scale: Vec<ArchScale>,
+
+    /// Shut down after running this long (e.g. `6h`): resume every paused
+    /// process, detach from all tracees and exit with code 124, so a hung
+    /// build can't keep the tracer alive forever [default: no limit].
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+
+    /// Turn off a group of built-in rules by its id, e.g. `interpreter` to
+    /// stop throttling python and node. Can be repeated.
+    #[arg(long, value_name = "ID")]
+    disable_rules: Vec<String>,
 
-    /// Total memory in GiB available for throttled processes [default: system RAM, rounded down].
-    #[arg(short = 'm', long, default_value_t = default_mem_gb())]
-    total_mem_gb: i32,
+    /// Write our PID to FILE for service supervisors, and remove it on exit.
+    /// A file left behind by an earlier run is overwritten.
+    #[arg(long, value_name = "FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// Exit with an error if any attach target could not be seized, instead of
+    /// carrying on as long as one succeeded.
+    #[arg(long)]
+    strict_attach: bool,
+
+    /// Exit with an error if an attach target is already traced by another
+    /// process (gdb, strace, another nix-ubw), instead of skipping it with a
+    /// warning. Implied by `--strict-attach`.
+    #[arg(long)]
+    fail_on_traced: bool,
+
+    /// Skip attach targets already traced by another process with a
+    /// warning. This is the default; pass it to keep skipping them under
+    /// `--strict-attach`.
+    #[arg(long, conflicts_with = "fail_on_traced")]
+    skip_traced: bool,
+
+    /// Also attach to this PID, in addition to discovered nix-daemons. Can be repeated.
+    #[arg(long, value_name = "PID")]
+    attach_pid: Vec<i32>,
+
+    /// Find nix-daemon as the main processes of this systemd unit (e.g.
+    /// `nix-daemon.service`) from its cgroup, instead of by command line.
+    /// Falls back to the command line if the unit isn't running.
+    #[arg(long, value_name = "UNIT")]
+    unit: Option<String>,
+
+    /// Print the PID and command line of every process that would be
+    /// attached to, found the same way as for a real run, and exit without
+    /// attaching.
+    #[arg(long)]
+    list_daemons: bool,
+
+    /// Publish how backed up the queue is (active and paused counts, and
+    /// paused per active) to FILE on every tick, for build dispatchers to
+    /// poll. Removed on exit.
+    #[arg(long, value_name = "FILE")]
+    pressure_file: Option<PathBuf>,
+
+    /// Publish a recommended parallelism (how many 1-CPU, 1 GiB jobs fit in
+    /// the free budget, 1 while anything waits) to FILE on every tick, for
+    /// build wrappers to read into NIX_BUILD_CORES or -j. Removed on exit.
+    #[arg(long, value_name = "FILE")]
+    advise_jobs_file: Option<PathBuf>,
+
+    /// Save the active throttled processes to FILE whenever they change and
+    /// on exit, and on startup take back the claims of those still running,
+    /// so that a restart mid-build doesn't over-admit.
+    #[arg(long, value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+
+    /// How often to do periodic work: serve the control socket, run the
+    /// watchdogs and update `--pressure-file`, `--advise-jobs-file` and
+    /// `--checkpoint`. A shorter `--claim-grace` or `--resume-debounce`
+    /// makes the loop wake up more often, but files are still written at
+    /// this interval.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "200ms")]
+    sample_interval: Duration,
+
+    /// On exit, write when each throttled process waited and ran to FILE, as
+    /// a Chrome trace (JSON) for chrome://tracing or Perfetto.
+    #[arg(long, value_name = "FILE")]
+    trace_output: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check ptrace permissions and look for nix-daemon without attaching.
+    /// Exits non-zero if anything would prevent tracing.
+    Doctor,
+
+    /// Run COMMAND under the tracer instead of attaching to nix-daemon, e.g.
+    /// `nix-ubw exec -- nix build` with single-user Nix. Exits with the
+    /// command's exit code.
+    Exec {
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
+    /// Show which rule and profile COMMAND would get, without running it,
+    /// e.g. `nix-ubw probe -- gcc -c foo.c`. Uses the same rules as tracing,
+    /// including config files and rule options.
+    Probe {
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+}
+
+/// Log output format (`--log-format`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    Text,
+    Journald,
+}
+
+/// Set up the logger. Levels follow `RUST_LOG` (default `info`) either way,
+/// except that `quiet` hides per-process events whatever their level. With
+/// `status_line`, text logging leaves room for the status line.
+fn init_logging(format: LogFormat, quiet: bool, color: ColorChoice, status_line: bool) {
+    let builder = || {
+        let mut builder =
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+        logfmt::configure(&mut builder, color);
+        if quiet {
+            builder.filter_module(tracer::PROCESS_LOG, log::LevelFilter::Warn);
+        }
+        builder
+    };
+    if format == LogFormat::Journald {
+        if let Some(logger) = journal::JournalLogger::connect(builder().build()) {
+            log::set_max_level(logger.max_level());
+            log::set_boxed_logger(Box::new(logger)).expect("logger already set");
+            return;
+        }
+        builder().init();
+        warn!("The journal is not available, logging to stderr instead");
+        return;
+    }
+    if status_line {
+        let logger = StatusLogger::new(builder().build());
+        log::set_max_level(logger.max_level());
+        log::set_boxed_logger(Box::new(logger)).expect("logger already set");
+        return;
+    }
+    builder().init();
+}
+
+/// Exit code after `--max-runtime` ran out, as with timeout(1).
+const EXIT_MAX_RUNTIME: i32 = 124;
+
+/// Environment variable with the budget as `<cpus>,<mem_gb>`, for
+/// containers. `-c` and `-m` take precedence.
+const BUDGET_VAR: &str = "NIXUBW_BUDGET";
+
+/// Environment variable with the default of `--reserve`.
+const RESERVE_VAR: &str = "NIXUBW_RESERVE";
+
+/// The profile in environment variable `var`, if set.
+fn env_profile(var: &str) -> Result<Option<ResourceProfile>> {
+    std::env::var(var)
+        .ok()
+        .map(|value| value.parse().with_context(|| format!("Invalid {}", var)))
+        .transpose()
+}
+
+/// The budget: `-c` and `-m`, then `NIXUBW_BUDGET` for what they leave
+/// out, then the system's size less `--reserve` (or `NIXUBW_RESERVE`).
+fn total_budget(args: &Args) -> Result<ResourceProfile> {
+    let reserve = match args.reserve {
+        Some(reserve) => Some(reserve),
+        None => env_profile(RESERVE_VAR)?,
+    };
+    budget_from(
+        args.total_cpus,
+        args.total_mem_gb,
+        env_profile(BUDGET_VAR)?,
+        reserve.unwrap_or(ResourceProfile::new(0, 0)),
+        || ResourceProfile::new(default_cpus(), default_mem_gb()),
+    )
+}
+
+/// The budget from its sources in order of precedence, with `system`
+/// only called if the first two leave something out.
+fn budget_from(
+    cpus: Option<i32>,
+    mem_gb: Option<i32>,
+    from_env: Option<ResourceProfile>,
+    reserve: ResourceProfile,
+    system: impl FnOnce() -> ResourceProfile,
+) -> Result<ResourceProfile> {
+    if let (Some(cpus), Some(mem_gb)) = (cpus, mem_gb) {
+        return Ok(ResourceProfile::new(cpus, mem_gb));
+    }
+    let fallback = match from_env {
+        Some(budget) => budget,
+        None => {
+            let system = system();
+            let left = system - reserve;
+            if (cpus.is_none() && left.cpus <= 0) || (mem_gb.is_none() && left.mem_gb <= 0) {
+                bail!(
+                    "Reserving {} leaves no budget of the system's {}",
+                    reserve,
+                    system
+                );
+            }
+            left
+        }
+    };
+    Ok(ResourceProfile::new(
+        cpus.unwrap_or(fallback.cpus),
+        mem_gb.unwrap_or(fallback.mem_gb),
+    ))
 }
 
 fn default_cpus() -> i32 {
@@ -33,45 +469,263 @@ fn default_cpus() -> i32 {
         .expect("failed to get default CPU count")
 }
 
-/// Read total system RAM from /proc/meminfo, returned in GiB (rounded down).
+/// Parse a duration given as a number with an optional `ms`, `s`, `m` or `h`
+/// suffix. A bare number is in seconds.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let num: u64 = num
+        .parse()
+        .with_context(|| format!("invalid duration {:?}", s))?;
+    let secs = |per_unit: u64| {
+        num.checked_mul(per_unit)
+            .map(Duration::from_secs)
+            .with_context(|| format!("duration {:?} is too long", s))
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(num)),
+        "s" => secs(1),
+        "m" => secs(60),
+        "h" => secs(3600),
+        _ => bail!(
+            "invalid duration unit {:?} in {:?} (expected ms, s, m or h)",
+            unit,
+            s
+        ),
+    }
+}
+
+/// Total system RAM in GiB (rounded down).
 fn default_mem_gb() -> i32 {
-    (|| -> Option<i32> {
-        let data = fs::read_to_string("/proc/meminfo").ok()?;
-        for line in data.lines() {
-            if let Some(rest) = line.strip_prefix("MemTotal:") {
-                // Format: "MemTotal:    16348160 kB"
-                let kb: u64 = rest.split_whitespace().next()?.parse().ok()?;
-                return Some((kb / (1024 * 1024)) as i32);
-            }
-        }
-        None
-    })()
-    .expect("failed to get default total memory")
+    nixutil::read_mem_total_gib().expect("failed to get default total memory")
 }
 
 fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    let args = Args::parse();
+    let mut args = Args::parse();
+    let status_line =
+        args.status_line && args.log_format == LogFormat::Text && std::io::stderr().is_terminal();
+    init_logging(args.log_format, args.quiet, args.color, status_line);
+    let extra_pids: Vec<Pid> = args.attach_pid.iter().copied().map(Pid::from_raw).collect();
+    if args.list_daemons {
+        return daemon::list_daemons(args.unit.as_deref(), &extra_pids);
+    }
+    let launch = match args.command.take() {
+        Some(Command::Doctor) => return doctor::run(),
+        Some(Command::Probe { command }) => {
+            let total_budget = total_budget(&args)?;
+            let rules = configured_rules(&args, &total_budget)?;
+            return probe::run(command, &rules, &total_budget);
+        }
+        Some(Command::Exec { command }) => Some(command),
+        None => None,
+    };
+    let total_budget = total_budget(&args)?;
 
-    let total_budget = ResourceProfile::new(args.total_cpus, args.total_mem_gb);
+    if args.sample_interval.is_zero() {
+        bail!("--sample-interval must be positive");
+    }
+    let rules = configured_rules(&args, &total_budget)?;
+    let pid_file = args.pid_file.clone().map(PidFile::create).transpose()?;
+    let mut samplers = Samplers::new(args.sample_interval);
+    if status_line {
+        samplers.add(StatusLine::new());
+    }
+    if let Some(path) = args.pressure_file.clone() {
+        samplers.add(PressureFile::create(path)?);
+    }
+    if let Some(path) = args.advise_jobs_file.clone() {
+        samplers.add(AdvisoryFile::create(path, total_budget)?);
+    }
+    set_own_priority(args.nice_self, args.sched_batch)?;
+    if !rules.is_empty() {
+        info!("Using {} configured rules", rules.len());
+    }
 
-    daemon::attach_to_nix_daemons().context("Failed to attach to nix-daemon")?;
+    let options = LimiterOptions {
+        adaptive: args.adaptive,
+        cont_retries: args.cont_retries,
+        shared_state: args.shared_state.clone(),
+        min_parallel: args.min_parallel,
+        sibling_mem: args.sibling_mem_gb.clone(),
+        pause_kill_after: args.pause_kill_after,
+        pause_method: args.pause_method,
+        per_user_budget: args.per_user_budget,
+        never_pause: args
+            .never_pause_pid
+            .iter()
+            .copied()
+            .map(Pid::from_raw)
+            .collect(),
+        claim_grace: args.claim_grace,
+        rules,
+        resume_debounce: args.resume_debounce,
+        resume_order: args.resume_order,
+        mem_floor_gb: args.mem_floor_gb,
+        recheck_comm: args.recheck_comm,
+        suggest_rules: args.suggest_rules,
+        report_jobserver: args.report_jobserver,
+    };
+    let mut tracer = Tracer::new(total_budget, options);
+    if let Some(path) = args.checkpoint.clone() {
+        let checkpoint = Checkpoint::new(path);
+        let saved = checkpoint.load()?;
+        for process in &saved {
+            tracer.limiter.restore(process);
+        }
+        checkpoint::log_restored(&saved);
+        samplers.add(checkpoint);
+    }
+    tracer
+        .focused
+        .extend(args.trace_pid.iter().copied().map(Pid::from_raw));
 
-    info!(
-        "Tracing started - budget: {}. Press Ctrl-C to stop.",
-        total_budget
-    );
+    if args.trace_output.is_some() {
+        if !cfg!(feature = "serde") {
+            bail!("--trace-output needs the serde feature");
+        }
+        tracer.limiter.record_timeline();
+    }
+    tracer.exec_only = args.exec_only;
+    tracer.max_depth = args.max_depth.map(|depth| depth as usize);
+    tracer.only_uids = args.only_uid.clone();
+    let trace_options = daemon::trace_options(args.exec_only);
+
+    signals::install()?;
+    if let Some(command) = &launch {
+        tracer.launched = Some(daemon::spawn_traced(
+            &SystemPtrace,
+            command,
+            trace_options,
+            &mut tracer.traced,
+        )?);
+        info!("Tracing started, budget: {}.", total_budget);
+    } else {
+        let report = daemon::attach_to_nix_daemons(
+            &SystemPtrace,
+            args.unit.as_deref(),
+            &extra_pids,
+            trace_options,
+            &mut tracer.traced,
+        )
+        .context("Failed to attach to nix-daemon")?;
+        if args.strict_attach && !report.failed.is_empty() {
+            bail!(
+                "--strict-attach: failed to attach to {}",
+                format_errors(&report.failed)
+            );
+        }
+        let fail_on_traced = args.fail_on_traced || (args.strict_attach && !args.skip_traced);
+        if fail_on_traced && !report.traced_elsewhere.is_empty() {
+            bail!(
+                "--fail-on-traced: already traced by another process: {}",
+                daemon::format_tracers(&report.traced_elsewhere)
+            );
+        }
+        if args.strict_attach && !report.degraded.is_empty() {
+            bail!(
+                "--strict-attach: attached without fork tracing to {}",
+                format_errors(&report.degraded)
+            );
+        }
+
+        info!(
+            "Tracing started - {} processes seized ({} already traced), budget: {}. Press Ctrl-C to stop.",
+            report.seized, report.already_traced, total_budget
+        );
+    }
 
-    let mut tracer = Tracer::new(total_budget);
+    let control = args
+        .control_socket
+        .clone()
+        .map(ControlSocket::bind)
+        .transpose()?;
+    let health = match args.health_listen {
+        Some(addr) => {
+            let health = Health::new(args.sample_interval);
+            health.beat(tracer.root_count());
+            health::listen(addr, health.clone())?;
+            Some(health)
+        }
+        None => None,
+    };
+    // Grace periods, debounce windows and name rechecks end on a tick, so
+    // tick at least that often.
+    let interval = [args.claim_grace, args.resume_debounce, args.recheck_comm]
+        .into_iter()
+        .flatten()
+        .filter(|d| !d.is_zero())
+        .fold(args.sample_interval, Duration::min);
+    signals::start_ticker(interval)?;
 
+    let started = Instant::now();
+    let mut exit_code = None;
     loop {
+        // Signal flags are checked on every iteration, not just on EINTR, so
+        // a busy stream of wait statuses can't starve them.
+        if signals::shutdown_requested() {
+            info!("Shutdown requested. Exiting.");
+            break;
+        }
+        if args
+            .max_runtime
+            .is_some_and(|max_runtime| started.elapsed() >= max_runtime)
+        {
+            warn!("Reached --max-runtime, shutting down.");
+            exit_code = Some(EXIT_MAX_RUNTIME);
+            break;
+        }
+        if signals::take_summary_request() {
+            tracer.limiter.stats().log_summary();
+            tracer.events.log_summary();
+        }
+        if signals::take_reload_request() {
+            match configured_rules(&args, &total_budget) {
+                Ok(rules) => {
+                    info!("Reloaded rules ({} configured)", rules.len());
+                    tracer.limiter.set_rules(rules);
+                }
+                Err(e) => warn!("Failed to reload rules, keeping the old ones: {:#}", e),
+            }
+        }
+        if signals::take_tick() {
+            if let Some(health) = &health {
+                health.beat(tracer.root_count());
+            }
+            tracer.limiter.expire_restored();
+            samplers.tick(&mut tracer.limiter);
+            if let Some(control) = &control {
+                control.poll(&mut tracer);
+            }
+            if tracer.limiter.drained() {
+                info!("Drained: no throttled process is running anymore. Exiting.");
+                break;
+            }
+            tracer.limiter.kill_overdue();
+            tracer.limiter.enforce_mem_hard();
+            tracer.expire_grace();
+            tracer.recheck_comm();
+            tracer.cancel_signalled();
+            tracer.limiter.resume_debounced();
+            tracer.limiter.retry_mem_floor();
+            if tracer.exec_only {
+                tracer.scan_new_processes();
+            }
+        }
         match waitpid(None, Some(WaitPidFlag::__WALL)) {
-            Ok(status) => tracer.handle_wait_status(status),
+            Ok(status) => {
+                if status.pid().is_some() && status.pid() == tracer.launched {
+                    exit_code = launched_exit_code(status).or(exit_code);
+                }
+                tracer.handle_wait_status(status);
+            }
             Err(nix::errno::Errno::ECHILD) => {
                 info!("No more traced processes. Exiting.");
                 break;
             }
-            Err(nix::errno::Errno::EINTR) => continue,
+            Err(nix::errno::Errno::EINTR) => {}
             Err(e) => {
                 error!("waitpid failed: {}", e);
                 break;
@@ -79,5 +733,184 @@ fn main() -> Result<()> {
         }
     }
 
+    drain_pending(&mut tracer);
+    samplers.run(&mut tracer.limiter);
+    if let Some(path) = &args.trace_output {
+        if let Some(timeline) = tracer.limiter.timeline() {
+            match timeline::write(timeline, path) {
+                Ok(()) => info!("Wrote the timeline of throttled processes to {:?}", path),
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+    }
+    // Running tracees are detached by the kernel when we exit; paused ones
+    // are released explicitly so they don't depend on that.
+    tracer.limiter.release_paused();
+    tracer.limiter.stats().log_summary();
+    tracer.events.log_summary();
+
+    if let Some(code) = exit_code {
+        // exit() skips destructors, so clean up first.
+        drop(control);
+        drop(pid_file);
+        drop(samplers);
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// The rules from config files, with the rule options of the command line
+/// applied, checked against the budget.
+fn configured_rules(args: &Args, total: &ResourceProfile) -> Result<RuleSet> {
+    let mut rules = load_rules(args)?;
+    for scale in args.arch_scale.iter().cloned() {
+        rules.set_arch_scale(scale);
+    }
+    for id in &args.disable_rules {
+        rules.disable_builtin(id)?;
+    }
+    rules.set_mem_hint(args.profile_hint);
+    rules
+        .validate(total)
+        .context("Invalid rule configuration")?;
+    Ok(rules)
+}
+
+/// Rules from `--config`, or `NIXUBW_RULES` without it, then `--config-dir`.
+#[cfg(feature = "serde")]
+fn load_rules(args: &Args) -> Result<RuleSet> {
+    let base = match &args.config {
+        Some(path) => Some(RuleSource::File(path.clone())),
+        None => std::env::var(RULES_VAR)
+            .ok()
+            .map(RuleSource::from_env_value),
+    };
+    config::load_rules(base, &args.config_dir)
+}
+
+#[cfg(not(feature = "serde"))]
+fn load_rules(args: &Args) -> Result<RuleSet> {
+    if args.config.is_some() || !args.config_dir.is_empty() || std::env::var_os(RULES_VAR).is_some()
+    {
+        bail!(
+            "--config, --config-dir and {} need the serde feature",
+            RULES_VAR
+        );
+    }
+    Ok(RuleSet::default())
+}
+
+/// Exit code to pass on for a wait status of the launched command, shell
+/// style: 128 + the signal number if it was killed.
+fn launched_exit_code(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Some(code),
+        WaitStatus::Signaled(_, sig, _) => Some(128 + sig as i32),
+        _ => None,
+    }
+}
+
+/// `pid (error), ...` for attach errors.
+fn format_errors(errors: &[(Pid, nix::errno::Errno)]) -> String {
+    errors
+        .iter()
+        .map(|(pid, e)| format!("{} ({})", pid, e))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Apply `--nice-self` and `--sched-batch` to our own process.
+fn set_own_priority(nice: Option<i32>, batch: bool) -> Result<()> {
+    use nix::libc;
+
+    if let Some(nice) = nice {
+        // SAFETY: plain syscall on our own process.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to set niceness");
+        }
+        info!("Running at niceness {}", nice);
+    }
+    if batch {
+        let param = libc::sched_param { sched_priority: 0 };
+        // SAFETY: `param` is valid for the duration of the call.
+        if unsafe { libc::sched_setscheduler(0, libc::SCHED_BATCH, &param) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to set SCHED_BATCH");
+        }
+        info!("Running under SCHED_BATCH");
+    }
     Ok(())
 }
+
+/// Process every wait status that is already pending, without blocking.
+///
+/// Used on shutdown so exits that raced with the shutdown signal still free
+/// their resources and show up in the summary. Each status is consumed by
+/// `waitpid` exactly once, so nothing the main loop already handled can be
+/// seen again here.
+fn drain_pending(tracer: &mut Tracer) {
+    loop {
+        match waitpid(None, Some(WaitPidFlag::__WALL | WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => break,
+            Ok(status) => tracer.handle_wait_status(status),
+            Err(nix::errno::Errno::EINTR) => continue,
+            // ECHILD: nothing left to wait for.
+            Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("20ms").unwrap(), Duration::from_millis(20));
+        for bad in ["", "m", "5d", "1.5h", "-1", "18446744073709551615h"] {
+            assert!(parse_duration(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_budget_precedence() {
+        let none = ResourceProfile::new(0, 0);
+        let system = || ResourceProfile::new(16, 64);
+        let budget = |cpus, mem_gb, from_env, reserve| {
+            budget_from(cpus, mem_gb, from_env, reserve, system).unwrap()
+        };
+        let env = Some(ResourceProfile::new(4, 8));
+        let reserve = ResourceProfile::new(2, 8);
+        assert_eq!(budget(None, None, None, none), system());
+        assert_eq!(
+            budget(None, None, None, reserve),
+            ResourceProfile::new(14, 56)
+        );
+        // The environment replaces the system's size, reserve and all.
+        assert_eq!(budget(None, None, env, reserve), ResourceProfile::new(4, 8));
+        // Flags win over both, one resource at a time.
+        assert_eq!(
+            budget(Some(6), None, env, reserve),
+            ResourceProfile::new(6, 8)
+        );
+        assert_eq!(
+            budget(None, Some(32), None, reserve),
+            ResourceProfile::new(14, 32)
+        );
+        assert_eq!(
+            budget_from(Some(6), Some(12), env, reserve, || unreachable!()).unwrap(),
+            ResourceProfile::new(6, 12)
+        );
+
+        let all = ResourceProfile::new(16, 8);
+        assert!(budget_from(None, None, None, all, system).is_err());
+        // Only the resources taken from the system must be left over.
+        assert_eq!(
+            budget(Some(2), None, None, all),
+            ResourceProfile::new(2, 56)
+        );
+    }
+}
@@ -1,22 +1,541 @@
+use std::cell::OnceCell;
+use std::collections::HashMap;
 use std::fs;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use nix::unistd::Pid;
 
 /// Read /proc/<pid>/cmdline and return the arguments as a Vec<String>.
 /// The first argument (argv[0]) is automatically resolved to its unwrapped
 /// basename via `resolve_basename`.
+///
+/// This is the only cmdline reader; every caller that classifies or matches
+/// processes must go through it so they all agree on argv[0].
+///
+/// Processes inside a Nix build sandbox need no special casing: ptrace gives
+/// us their PIDs in our own PID namespace, which is what our /proc is
+/// indexed by, and the cmdline holds argv exactly as passed to exec. Paths
+/// in it are as seen from inside the sandbox (`/build/...`, or store paths
+/// that may not exist on the host), but only the last component is used, so
+/// they never have to be resolved.
+///
+/// A program exec'd from a file descriptor (`execveat`, `fexecve`, often of
+/// a memfd) may have been given an argv[0] of `/dev/fd/3` or `memfd:...`, or
+/// none at all. The name is then taken from /proc/<pid>/comm, which the
+/// kernel sets from the file.
 pub fn read_cmdline(pid: Pid) -> Option<Vec<String>> {
     let path = format!("/proc/{}/cmdline", pid);
     let data = fs::read(&path).ok()?;
+    Some(parse_cmdline_or_comm(&data, || {
+        fs::read_to_string(format!("/proc/{}/comm", pid)).ok()
+    }))
+}
+
+/// Split raw /proc/<pid>/cmdline contents into arguments and resolve argv[0].
+/// Public for the fuzz target.
+pub fn parse_cmdline(data: &[u8]) -> Vec<String> {
+    parse_cmdline_or_comm(data, || None)
+}
+
+/// `parse_cmdline`, with argv[0] replaced by the name in `comm` (contents of
+/// /proc/<pid>/comm) if it names a file descriptor or is empty. `comm` is
+/// only called then. An empty cmdline, as of a zombie, stays empty.
+fn parse_cmdline_or_comm(data: &[u8], comm: impl FnOnce() -> Option<String>) -> Vec<String> {
     let mut args: Vec<String> = data
         .split(|&b| b == 0)
         .filter(|s| !s.is_empty())
         .map(|s| String::from_utf8_lossy(s).into_owned())
         .collect();
+    let argv0_missing = data.first() == Some(&0);
+    let nameless = argv0_missing || args.first().is_some_and(|argv0| is_fd_name(argv0));
+    match nameless.then(comm).flatten().as_deref().and_then(comm_name) {
+        Some(name) if argv0_missing => args.insert(0, name),
+        Some(name) => args[0] = name,
+        None => resolve_argv0(&mut args),
+    }
+    args
+}
+
+/// Whether an argv[0] names a file descriptor or memfd rather than a program.
+fn is_fd_name(argv0: &str) -> bool {
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    argv0.starts_with("memfd:")
+        || ["/dev/fd/", "/proc/self/fd/", "/proc/thread-self/fd/"]
+            .iter()
+            .any(|dir| argv0.strip_prefix(dir).is_some_and(is_number))
+        || argv0
+            .strip_prefix("/proc/")
+            .and_then(|rest| rest.split_once("/fd/"))
+            .is_some_and(|(pid, fd)| is_number(pid) && is_number(fd))
+}
+
+/// Read the name of a process from /proc/<pid>/comm. It starts out as the
+/// basename of the exec'd file, cut to 15 bytes, and the process may change
+/// it with `prctl(PR_SET_NAME)`.
+pub fn read_comm(pid: Pid) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end_matches('\n').to_owned())
+}
+
+/// The program name in /proc/<pid>/comm contents, without the `memfd:` of a
+/// memfd. `None` if it is only a number, as older kernels name the task
+/// after the descriptor's number on `execveat`.
+fn comm_name(comm: &str) -> Option<String> {
+    let comm = comm.trim_end_matches('\n');
+    let name = comm.strip_prefix("memfd:").unwrap_or(comm);
+    if name.is_empty() || name.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(resolve_basename(name).to_owned())
+}
+
+/// Resolve argv[0] to its unwrapped basename, as `read_cmdline` does, for
+/// arguments that did not come from /proc (`nix-ubw probe`).
+pub fn resolve_argv0(args: &mut [String]) {
     if let Some(first) = args.first_mut() {
         *first = resolve_basename(first).to_owned();
     }
-    Some(args)
+}
+
+/// Join arguments into a single string for logging, quoting the ones a shell
+/// would split or misread.
+///
+/// Arguments come from other users' processes, so control characters are
+/// never passed through: they could forge log lines or drive the terminal.
+/// Such arguments are written in `$'...'` form with the characters escaped.
+pub fn shell_join(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain {
+        arg.to_owned()
+    } else if arg.chars().any(needs_escape) {
+        escape_quote(arg)
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Control characters, and the replacement character standing in for bytes
+/// that were not valid UTF-8 in the original argument.
+fn needs_escape(c: char) -> bool {
+    c.is_control() || c == char::REPLACEMENT_CHARACTER
+}
+
+/// Quote `arg` as a bash `$'...'` string with every special character escaped.
+fn escape_quote(arg: &str) -> String {
+    let mut quoted = String::from("$'");
+    for c in arg.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\\' => quoted.push_str("\\\\"),
+            '\'' => quoted.push_str("\\'"),
+            c if needs_escape(c) && (c as u32) < 0x100 => {
+                quoted.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            c if needs_escape(c) => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// List the direct children of a process.
+///
+/// Uses /proc/<pid>/task/*/children where the kernel provides it, and falls
+/// back to scanning /proc for processes whose parent is `pid` otherwise.
+pub fn read_children(pid: Pid) -> Vec<Pid> {
+    let tasks = match fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(tasks) => tasks,
+        Err(_) => return Vec::new(),
+    };
+    let mut children = Vec::new();
+    for task in tasks.flatten() {
+        match fs::read_to_string(task.path().join("children")) {
+            Ok(data) => children.extend(parse_children(&data)),
+            // Kernel built without CONFIG_PROC_CHILDREN.
+            Err(_) => return scan_children(pid),
+        }
+    }
+    children
+}
+
+/// Find children of `pid` by comparing the parent of every process in /proc.
+fn scan_children(pid: Pid) -> Vec<Pid> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .map(Pid::from_raw)
+        .filter(|&child| read_ppid(child) == Some(pid))
+        .collect()
+}
+
+/// Read the parent PID of a process from /proc/<pid>/stat.
+pub fn read_ppid(pid: Pid) -> Option<Pid> {
+    let data = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_stat_ppid(&data)
+}
+
+/// Whether `pid` is `ancestor` or one of its descendants, following the
+/// parent chain in /proc. False if the chain can't be read.
+pub fn descends_from(pid: Pid, ancestor: Pid) -> bool {
+    let mut current = pid;
+    loop {
+        if current == ancestor {
+            return true;
+        }
+        match read_ppid(current) {
+            // Reached the root of the tree (init or a kernel thread).
+            Some(ppid) if ppid.as_raw() > 0 && ppid != current => current = ppid,
+            _ => return false,
+        }
+    }
+}
+
+/// Read the effective uid of a process from /proc/<pid>/status.
+pub fn read_uid(pid: Pid) -> Option<u32> {
+    let data = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_status_uid(&data)
+}
+
+/// Extract the effective uid from the contents of /proc/<pid>/status, whose
+/// `Uid:` line lists the real, effective, saved and filesystem uids.
+fn parse_status_uid(data: &str) -> Option<u32> {
+    status_field(data, "Uid:")?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// Read the PID of the process tracing `pid` from /proc/<pid>/status.
+/// `Some(0)` means it is not traced.
+pub fn read_tracer_pid(pid: Pid) -> Option<Pid> {
+    let data = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_status_tracer_pid(&data)
+}
+
+fn parse_status_tracer_pid(data: &str) -> Option<Pid> {
+    let pid = status_field(data, "TracerPid:")?.trim().parse().ok()?;
+    Some(Pid::from_raw(pid))
+}
+
+/// Read the thread group (process) ID of the task `pid` from
+/// /proc/<pid>/status. It equals `pid` for a process's main thread.
+pub fn read_tgid(pid: Pid) -> Option<Pid> {
+    let data = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_status_tgid(&data)
+}
+
+fn parse_status_tgid(data: &str) -> Option<Pid> {
+    let pid = status_field(data, "Tgid:")?.trim().parse().ok()?;
+    Some(Pid::from_raw(pid))
+}
+
+/// Read the resident set size of a process from /proc/<pid>/status, in KiB.
+pub fn read_rss_kb(pid: Pid) -> Option<u64> {
+    let data = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_status_rss(&data)
+}
+
+/// Extract `VmRSS` from the contents of /proc/<pid>/status. Kernel threads
+/// and zombies have none.
+fn parse_status_rss(data: &str) -> Option<u64> {
+    status_kb(data, "VmRSS:")
+}
+
+/// Read the peak resident set size of a process so far from
+/// /proc/<pid>/status, in KiB.
+pub fn read_peak_rss_kb(pid: Pid) -> Option<u64> {
+    let data = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_status_peak_rss(&data)
+}
+
+/// Extract `VmHWM`, the high water mark of `VmRSS`.
+fn parse_status_peak_rss(data: &str) -> Option<u64> {
+    status_kb(data, "VmHWM:")
+}
+
+/// The value in KiB of the `name` line of a /proc/<pid>/status file.
+fn status_kb(data: &str, name: &str) -> Option<u64> {
+    status_field(data, name)?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Read the signals pending for `pid`, for the thread or the whole process,
+/// that will take their default action once delivered: neither blocked,
+/// which could leave them pending forever, nor caught or ignored. From
+/// /proc/<pid>/status; bit `n - 1` is set if signal `n` is such a signal.
+pub fn read_default_pending_signals(pid: Pid) -> Option<u64> {
+    let data = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_status_default_pending(&data)
+}
+
+fn parse_status_default_pending(data: &str) -> Option<u64> {
+    let mask = |name| u64::from_str_radix(status_field(data, name)?.trim(), 16).ok();
+    let pending = mask("SigPnd:")? | mask("ShdPnd:")?;
+    Some(pending & !(mask("SigBlk:")? | mask("SigIgn:")? | mask("SigCgt:")?))
+}
+
+/// The value of the `name` line of a /proc/<pid>/status file.
+fn status_field<'a>(data: &'a str, name: &str) -> Option<&'a str> {
+    data.lines().find_map(|line| line.strip_prefix(name))
+}
+
+/// Parse the space-separated PIDs of a /proc/<pid>/task/<tid>/children file.
+fn parse_children(data: &str) -> impl Iterator<Item = Pid> + '_ {
+    data.split_whitespace()
+        .filter_map(|p| p.parse().ok())
+        .map(Pid::from_raw)
+}
+
+/// Extract the parent PID from the contents of /proc/<pid>/stat.
+///
+/// The command name (field 2) is parenthesized and may itself contain spaces
+/// or parentheses, so fields are counted from the last `)`.
+fn parse_stat_ppid(data: &str) -> Option<Pid> {
+    let rest = &data[data.rfind(')')? + 1..];
+    // Fields after comm: state, ppid, ...
+    let ppid = rest.split_whitespace().nth(1)?.parse().ok()?;
+    Some(Pid::from_raw(ppid))
+}
+
+/// Read the 1-minute load average from /proc/loadavg.
+pub fn read_loadavg() -> Option<f64> {
+    parse_loadavg(&fs::read_to_string("/proc/loadavg").ok()?)
+}
+
+/// Extract the 1-minute load average from the contents of /proc/loadavg.
+fn parse_loadavg(data: &str) -> Option<f64> {
+    data.split_whitespace().next()?.parse().ok()
+}
+
+/// KiB in a GiB. Memory is budgeted in GiB (1024^3 bytes) throughout, while
+/// the kernel reports it in KiB: the `kB` of /proc means 1024 bytes.
+pub const KIB_PER_GIB: u64 = 1024 * 1024;
+
+/// Read MemAvailable from /proc/meminfo, in KiB.
+pub fn read_mem_available_kb() -> Option<u64> {
+    parse_meminfo_available(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+/// Read MemTotal from /proc/meminfo, in whole GiB, rounded down.
+pub fn read_mem_total_gib() -> Option<i32> {
+    parse_meminfo_total_gib(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+/// Extract MemAvailable from the contents of /proc/meminfo.
+fn parse_meminfo_available(data: &str) -> Option<u64> {
+    meminfo_kb(data, "MemAvailable:")
+}
+
+/// Extract MemTotal from the contents of /proc/meminfo, in whole GiB.
+fn parse_meminfo_total_gib(data: &str) -> Option<i32> {
+    (meminfo_kb(data, "MemTotal:")? / KIB_PER_GIB)
+        .try_into()
+        .ok()
+}
+
+/// The value in KiB of the /proc/meminfo line starting with `field`, e.g.
+/// `MemTotal:    16348160 kB`.
+fn meminfo_kb(data: &str, field: &str) -> Option<u64> {
+    data.lines()
+        .find_map(|line| line.strip_prefix(field))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Options of the /proc mount that hide processes from us.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProcMount {
+    /// `hidepid` as given (`1`, `2`, `invisible`, ...), unless it is off.
+    pub hidepid: Option<String>,
+    /// Group exempt from `hidepid`.
+    pub gid: Option<u32>,
+}
+
+/// Read the options of the /proc mount from /proc/self/mountinfo. `None` if
+/// /proc isn't mounted.
+pub fn read_proc_mount() -> Option<ProcMount> {
+    parse_mountinfo_proc(&fs::read_to_string("/proc/self/mountinfo").ok()?)
+}
+
+/// Find the /proc mount in the contents of /proc/self/mountinfo. Of several
+/// mounts on /proc, the last one is on top.
+fn parse_mountinfo_proc(data: &str) -> Option<ProcMount> {
+    let line = data
+        .lines()
+        .rfind(|line| line.split(' ').nth(4) == Some("/proc"))?;
+    // Per-superblock options come last, after the separator and the
+    // filesystem type and source.
+    let (_, fs_fields) = line.split_once(" - ")?;
+    let options = fs_fields.split(' ').nth(2).unwrap_or("");
+    let mut mount = ProcMount::default();
+    for option in options.split(',') {
+        match option.split_once('=') {
+            Some(("hidepid", "0" | "off")) => {}
+            Some(("hidepid", value)) => mount.hidepid = Some(value.to_owned()),
+            Some(("gid", gid)) => mount.gid = gid.parse().ok(),
+            _ => {}
+        }
+    }
+    Some(mount)
+}
+
+/// Read the cumulative CPU time (utime + stime) of a process, in clock ticks.
+pub fn read_cpu_ticks(pid: Pid) -> Option<u64> {
+    let data = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_stat_cpu_ticks(&data)
+}
+
+/// Extract utime + stime from the contents of /proc/<pid>/stat.
+fn parse_stat_cpu_ticks(data: &str) -> Option<u64> {
+    let rest = &data[data.rfind(')')? + 1..];
+    // Fields after comm start at field 3 (state); utime and stime are 14 and 15.
+    let mut fields = rest.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Total CPU time of the children `pid` has waited for, in clock ticks.
+pub fn read_children_cpu_ticks(pid: Pid) -> Option<u64> {
+    let data = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_stat_children_cpu_ticks(&data)
+}
+
+/// Extract cutime + cstime (fields 16 and 17) from the contents of
+/// /proc/<pid>/stat.
+fn parse_stat_children_cpu_ticks(data: &str) -> Option<u64> {
+    let rest = &data[data.rfind(')')? + 1..];
+    let mut fields = rest.split_whitespace().skip(13);
+    let cutime: u64 = fields.next()?.parse().ok()?;
+    let cstime: u64 = fields.next()?.parse().ok()?;
+    Some(cutime + cstime)
+}
+
+/// When `pid` started, in clock ticks since boot. Together with the PID it
+/// identifies a process across PID reuse.
+pub fn read_start_time(pid: Pid) -> Option<u64> {
+    let data = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_stat_start_time(&data)
+}
+
+/// Extract starttime (field 22) from the contents of /proc/<pid>/stat.
+fn parse_stat_start_time(data: &str) -> Option<u64> {
+    let rest = &data[data.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Kernel clock ticks per second, the unit of /proc CPU times.
+pub fn clock_ticks_per_sec() -> f64 {
+    static TICKS: OnceLock<f64> = OnceLock::new();
+    *TICKS.get_or_init(|| {
+        // SAFETY: sysconf has no preconditions.
+        match unsafe { nix::libc::sysconf(nix::libc::_SC_CLK_TCK) } {
+            n if n > 0 => n as f64,
+            _ => 100.0,
+        }
+    })
+}
+
+/// Average number of cores used between two CPU tick readings.
+pub fn cpu_usage(prev_ticks: u64, ticks: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs <= 0.0 {
+        return 0.0;
+    }
+    ticks.saturating_sub(prev_ticks) as f64 / clock_ticks_per_sec() / secs
+}
+
+/// Environment of a process, read from /proc/<pid>/environ on first access.
+///
+/// Reading environ is comparatively expensive, so callers construct this
+/// up front and only pay for the read if something actually looks up a
+/// variable.
+#[derive(Default)]
+pub struct Environ {
+    pid: Option<Pid>,
+    vars: OnceCell<HashMap<String, String>>,
+}
+
+impl Environ {
+    /// Environment of a live process, read lazily.
+    pub fn of(pid: Pid) -> Self {
+        Self {
+            pid: Some(pid),
+            vars: OnceCell::new(),
+        }
+    }
+
+    /// Environment with fixed contents, never read from /proc.
+    #[cfg(test)]
+    pub fn from_vars(vars: &[(&str, &str)]) -> Self {
+        let map: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Self {
+            pid: None,
+            vars: OnceCell::from(map),
+        }
+    }
+
+    /// Look up a variable. An unreadable environ behaves as empty.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.vars
+            .get_or_init(|| {
+                self.pid
+                    .and_then(|pid| fs::read(format!("/proc/{}/environ", pid)).ok())
+                    .map(|data| parse_environ(&data))
+                    .unwrap_or_default()
+            })
+            .get(key)
+            .map(|v| v.as_str())
+    }
+}
+
+/// The make jobserver a process inherited, from its `MAKEFLAGS`: the value
+/// of `--jobserver-auth` (or `--jobserver-fds` before make 4.2), e.g. `3,4`
+/// or `fifo:/tmp/GMfifo123`.
+pub fn jobserver_auth(makeflags: &str) -> Option<&str> {
+    makeflags.split_whitespace().rev().find_map(|flag| {
+        flag.strip_prefix("--jobserver-auth=")
+            .or_else(|| flag.strip_prefix("--jobserver-fds="))
+    })
+}
+
+/// Parse the NUL-separated `KEY=VALUE` pairs of /proc/<pid>/environ.
+/// Entries without `=` are skipped.
+fn parse_environ(data: &[u8]) -> HashMap<String, String> {
+    data.split(|&b| b == 0)
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (key, value) = entry.split_once('=')?;
+            Some((key.to_owned(), value.to_owned()))
+        })
+        .collect()
 }
 
 /// Unwrap a NixOS-wrapped executable name by stripping matched pairs of
@@ -107,4 +626,359 @@ mod tests {
     fn test_resolve_basename_no_path() {
         assert_eq!(resolve_basename("gcc"), "gcc");
     }
+
+    #[test]
+    fn test_parse_environ() {
+        let env = parse_environ(b"PATH=/bin\0NIXUBW_MEM_GB=8\0EMPTY=\0A=b=c\0junk\0");
+        assert_eq!(env.get("PATH").map(String::as_str), Some("/bin"));
+        assert_eq!(env.get("NIXUBW_MEM_GB").map(String::as_str), Some("8"));
+        assert_eq!(env.get("EMPTY").map(String::as_str), Some(""));
+        assert_eq!(env.get("A").map(String::as_str), Some("b=c"));
+        assert_eq!(env.len(), 4);
+    }
+
+    #[test]
+    fn test_environ_empty() {
+        assert_eq!(Environ::default().get("PATH"), None);
+    }
+
+    #[test]
+    fn test_parse_children() {
+        let children: Vec<Pid> = parse_children("12 345 6789 \n").collect();
+        assert_eq!(
+            children,
+            vec![Pid::from_raw(12), Pid::from_raw(345), Pid::from_raw(6789)]
+        );
+        assert_eq!(parse_children("").count(), 0);
+    }
+
+    #[test]
+    fn test_parse_stat_ppid() {
+        let stat = "1234 (nix-daemon) S 1 1234 1234 0 -1 4194560 2637";
+        assert_eq!(parse_stat_ppid(stat), Some(Pid::from_raw(1)));
+    }
+
+    #[test]
+    fn test_parse_stat_ppid_tricky_comm() {
+        let stat = "42 (a) b (c) R 7 42 42 0 -1";
+        assert_eq!(parse_stat_ppid(stat), Some(Pid::from_raw(7)));
+        assert_eq!(parse_stat_ppid("garbage"), None);
+    }
+
+    #[test]
+    fn test_parse_loadavg() {
+        assert_eq!(parse_loadavg("3.51 2.10 1.05 4/1234 56789\n"), Some(3.51));
+        assert_eq!(parse_loadavg(""), None);
+    }
+
+    #[test]
+    fn test_parse_cmdline_absolute_argv0() {
+        assert_eq!(
+            parse_cmdline(b"/nix/store/abc-nix-2.24/bin/nix-daemon\0--daemon\0"),
+            vec!["nix-daemon", "--daemon"]
+        );
+    }
+
+    #[test]
+    fn test_parse_cmdline_wrapped_argv0() {
+        assert_eq!(
+            parse_cmdline(b"/run/current-system/sw/bin/.nix-daemon-wrapped\0--daemon\0"),
+            vec!["nix-daemon", "--daemon"]
+        );
+        assert_eq!(
+            parse_cmdline(b"..gcc-wrapped-wrapped\0-c\0foo.c\0"),
+            vec!["gcc", "-c", "foo.c"]
+        );
+    }
+
+    #[test]
+    fn test_parse_cmdline_sandbox_paths() {
+        assert_eq!(
+            parse_cmdline(b"/nix/store/abc-gcc-wrapper-13.2.0/bin/g++\0-c\0/build/source/a.cc\0"),
+            vec!["g++", "-c", "/build/source/a.cc"]
+        );
+        assert_eq!(
+            parse_cmdline(b"/build/source/build/tools/.cc-wrapped\0-O2\0"),
+            vec!["cc", "-O2"]
+        );
+        assert_eq!(
+            parse_cmdline(b"../libtool\0--mode=link\0"),
+            vec!["libtool", "--mode=link"]
+        );
+        assert_eq!(parse_cmdline(b"./configure\0"), vec!["configure"]);
+    }
+
+    #[test]
+    fn test_parse_cmdline_exec_from_fd() {
+        let parse = |data: &[u8], comm: &str| parse_cmdline_or_comm(data, || Some(comm.into()));
+        for argv0 in [
+            "/dev/fd/3",
+            "/proc/self/fd/3",
+            "/proc/thread-self/fd/12",
+            "/proc/4242/fd/3",
+            "memfd:rustc",
+            "memfd:",
+        ] {
+            let data = format!("{}\0--edition\0", argv0);
+            assert_eq!(
+                parse(data.as_bytes(), "rustc\n"),
+                vec!["rustc", "--edition"],
+                "{}",
+                argv0
+            );
+        }
+        // The task of a memfd is named after it.
+        assert_eq!(parse(b"/dev/fd/5\0-c\0", "memfd:gcc\n"), vec!["gcc", "-c"]);
+        // No argv[0] at all.
+        assert_eq!(parse(b"\0-c\0foo.c\0", "cc\n"), vec!["cc", "-c", "foo.c"]);
+        // A comm that is only a descriptor number says nothing more.
+        assert_eq!(parse(b"/dev/fd/3\0-c\0", "3\n"), vec!["3", "-c"]);
+        assert_eq!(parse(b"memfd:x\0", "memfd:\n"), vec!["memfd:x"]);
+        // Ordinary names, and the empty cmdline of a zombie, are left alone.
+        let untouched =
+            |data: &[u8]| parse_cmdline_or_comm(data, || panic!("comm read for {:?}", data));
+        assert_eq!(untouched(b"/usr/bin/gcc\0-c\0"), vec!["gcc", "-c"]);
+        assert_eq!(untouched(b"/dev/fd/x\0"), vec!["x"]);
+        assert_eq!(untouched(b"/proc/1/fdinfo/3\0"), vec!["3"]);
+        assert!(untouched(b"").is_empty());
+    }
+
+    #[test]
+    fn test_parse_cmdline_pathological() {
+        // Deep and unbalanced wrapper layers unwrap in one pass per layer.
+        let deep = format!("{}gcc{}", ".".repeat(10_000), "-wrapped".repeat(10_000));
+        assert_eq!(parse_cmdline(deep.as_bytes()), vec!["gcc"]);
+        let unbalanced = format!("{}x{}", ".".repeat(3), "-wrapped".repeat(5));
+        assert_eq!(
+            parse_cmdline(unbalanced.as_bytes()),
+            vec!["x-wrapped-wrapped"]
+        );
+        assert_eq!(parse_cmdline(b"foo/\0-c\0"), vec!["", "-c"]);
+        assert_eq!(parse_cmdline(b".-wrapped\0"), vec![""]);
+    }
+
+    #[test]
+    fn test_parse_cmdline_empty() {
+        assert!(parse_cmdline(b"").is_empty());
+        assert!(parse_cmdline(b"\0\0").is_empty());
+    }
+
+    #[test]
+    fn test_shell_join() {
+        let args: Vec<String> = ["gcc", "-DNAME=a b", "it's", "", "-o", "out.o"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            shell_join(&args),
+            r#"gcc '-DNAME=a b' 'it'\''s' '' -o out.o"#
+        );
+    }
+
+    #[test]
+    fn test_shell_join_control_chars() {
+        let args: Vec<String> = [
+            "echo",
+            "ok\n[INFO] fake log line",
+            "\x1b[2Jcleared",
+            "tab\there",
+            "it's\\",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            shell_join(&args),
+            r#"echo $'ok\n[INFO] fake log line' $'\x1b[2Jcleared' $'tab\there' 'it'\''s\'"#
+        );
+    }
+
+    #[test]
+    fn test_shell_join_invalid_utf8() {
+        let args = parse_cmdline(b"cc\0bad\xff\x9bbyte\0");
+        assert_eq!(shell_join(&args), r#"cc $'bad\ufffd\ufffdbyte'"#);
+    }
+
+    #[test]
+    fn test_parse_stat_cpu_ticks() {
+        let stat = "4242 (cc1plus) R 4200 4200 4200 0 -1 4194304 9000 0 0 0 250 50 0 0 20 0 1 0";
+        assert_eq!(parse_stat_cpu_ticks(stat), Some(300));
+        assert_eq!(parse_stat_cpu_ticks("4242 (x) R 1"), None);
+    }
+
+    #[test]
+    fn test_parse_stat_children_cpu_ticks() {
+        let stat = "4242 (go) S 4200 4200 4200 0 -1 4194304 9000 0 0 0 250 50 1200 300 20 0 1 0";
+        assert_eq!(parse_stat_children_cpu_ticks(stat), Some(1500));
+        let stat = "4242 (cc1plus) R 4200 4200 4200 0 -1 4194304 9000 0 0 0 250 50 0 0 20 0 1 0";
+        assert_eq!(parse_stat_children_cpu_ticks(stat), Some(0));
+        assert_eq!(parse_stat_children_cpu_ticks("4242 (x) R 1"), None);
+    }
+
+    #[test]
+    fn test_parse_meminfo_available() {
+        let meminfo = "MemTotal:       16348160 kB\nMemFree:         1203456 kB\nMemAvailable:    9876543 kB\n";
+        assert_eq!(parse_meminfo_available(meminfo), Some(9876543));
+        assert_eq!(parse_meminfo_available("MemTotal: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn test_parse_meminfo_total_gib() {
+        // Exactly 16 GiB, and the same less the memory the kernel keeps.
+        assert_eq!(parse_meminfo_total_gib("MemTotal: 16777216 kB\n"), Some(16));
+        assert_eq!(parse_meminfo_total_gib("MemTotal: 16348160 kB\n"), Some(15));
+        // 16 decimal gigabytes are only 14.9 GiB.
+        assert_eq!(parse_meminfo_total_gib("MemTotal: 15625000 kB\n"), Some(14));
+        assert_eq!(parse_meminfo_total_gib("MemFree: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn test_parse_mountinfo_proc() {
+        let mountinfo = "22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/root rw\n\
+            23 22 0:22 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw\n";
+        assert_eq!(parse_mountinfo_proc(mountinfo), Some(ProcMount::default()));
+
+        // A second mount on top of the first wins.
+        let hardened = format!(
+            "{}41 23 0:39 / /proc rw,relatime shared:20 - proc proc rw,hidepid=invisible,gid=27\n",
+            mountinfo
+        );
+        assert_eq!(
+            parse_mountinfo_proc(&hardened),
+            Some(ProcMount {
+                hidepid: Some("invisible".into()),
+                gid: Some(27),
+            })
+        );
+        let off = "23 22 0:22 / /proc rw - proc proc rw,hidepid=0\n";
+        assert_eq!(parse_mountinfo_proc(off), Some(ProcMount::default()));
+        assert_eq!(parse_mountinfo_proc(&mountinfo[..60]), None);
+    }
+
+    #[test]
+    fn test_parse_stat_start_time() {
+        let stat = "4242 (cc1plus) R 4200 4200 4200 0 -1 4194304 9000 0 0 0 250 50 0 0 20 0 1 0 123456 1000";
+        assert_eq!(parse_stat_start_time(stat), Some(123456));
+        assert_eq!(parse_stat_start_time("4242 (x) R 1"), None);
+        let own = nix::unistd::getpid();
+        assert!(read_start_time(own).is_some());
+    }
+
+    #[test]
+    fn test_cpu_usage() {
+        let tck = clock_ticks_per_sec() as u64;
+        // Two seconds of CPU time over one second of wall time: two cores.
+        assert_eq!(cpu_usage(0, 2 * tck, Duration::from_secs(1)), 2.0);
+        assert_eq!(cpu_usage(tck, tck, Duration::from_secs(2)), 0.0);
+        assert_eq!(cpu_usage(0, tck, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn test_descends_from() {
+        let me = nix::unistd::getpid();
+        let parent = nix::unistd::getppid();
+        assert!(descends_from(me, me));
+        assert!(descends_from(me, parent));
+        assert!(!descends_from(parent, me));
+    }
+
+    #[test]
+    fn test_jobserver_auth() {
+        assert_eq!(jobserver_auth(" -j8 --jobserver-auth=3,4"), Some("3,4"));
+        assert_eq!(
+            jobserver_auth("-j --jobserver-auth=fifo:/tmp/GMfifo1"),
+            Some("fifo:/tmp/GMfifo1")
+        );
+        assert_eq!(jobserver_auth("-j4 --jobserver-fds=5,6"), Some("5,6"));
+        assert_eq!(jobserver_auth("-k -j1"), None);
+        // make appends the current jobserver; the last one wins.
+        assert_eq!(
+            jobserver_auth("--jobserver-auth=3,4 --jobserver-auth=7,8"),
+            Some("7,8")
+        );
+    }
+
+    #[test]
+    fn test_parse_status_uid() {
+        let status = "Name:\tnix-daemon\nUmask:\t0022\nUid:\t0\t1000\t0\t1000\nGid:\t0\t0\t0\t0\n";
+        assert_eq!(parse_status_uid(status), Some(1000));
+        assert_eq!(parse_status_uid("Name:\tx\n"), None);
+    }
+
+    #[test]
+    fn test_parse_status_tracer_pid() {
+        let status = "Name:\tnix-daemon\nTracerPid:\t4321\nUid:\t0\t0\t0\t0\n";
+        assert_eq!(parse_status_tracer_pid(status), Some(Pid::from_raw(4321)));
+        assert_eq!(
+            parse_status_tracer_pid("TracerPid:\t0\n"),
+            Some(Pid::from_raw(0))
+        );
+        assert_eq!(parse_status_tracer_pid("Name:\tx\n"), None);
+    }
+
+    #[test]
+    fn test_parse_status_tgid() {
+        let status = "Name:\trustc\nTgid:\t1200\nNgid:\t0\nPid:\t1234\n";
+        assert_eq!(parse_status_tgid(status), Some(Pid::from_raw(1200)));
+        assert_eq!(parse_status_tgid("Name:\tx\n"), None);
+    }
+
+    #[test]
+    fn test_read_tgid_of_thread() {
+        use std::sync::mpsc;
+
+        let own = nix::unistd::getpid();
+        assert_eq!(read_tgid(own), Some(own));
+        let (tid_tx, tid_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            tid_tx.send(nix::unistd::gettid()).unwrap();
+            let _ = done_rx.recv();
+        });
+        let tid = tid_rx.recv().unwrap();
+        assert_ne!(tid, own);
+        assert_eq!(read_tgid(tid), Some(own));
+        drop(done_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_status_rss() {
+        let status =
+            "Name:\trustc\nVmHWM:\t  812340 kB\nVmRSS:\t  798112 kB\nRssAnon:\t  790000 kB\n";
+        assert_eq!(parse_status_rss(status), Some(798112));
+        assert_eq!(parse_status_peak_rss(status), Some(812340));
+        assert_eq!(parse_status_rss("Name:\tkthreadd\n"), None);
+    }
+
+    #[test]
+    fn test_parse_status_default_pending() {
+        // SIGTERM (15) pending for the process, SIGUSR1 (10) for the thread.
+        let status = |blocked: u64, caught: u64| {
+            format!(
+                "Name:\tcc\nSigPnd:\t0000000000000200\nShdPnd:\t0000000000004000\n\
+                 SigBlk:\t{:016x}\nSigIgn:\t0000000000000000\nSigCgt:\t{:016x}\n",
+                blocked, caught
+            )
+        };
+        assert_eq!(
+            parse_status_default_pending(&status(0, 0)),
+            Some((1 << 14) | (1 << 9))
+        );
+        // A blocked SIGTERM may never be delivered, and a caught one needn't
+        // end the process.
+        assert_eq!(
+            parse_status_default_pending(&status(1 << 14, 0)),
+            Some(1 << 9)
+        );
+        assert_eq!(
+            parse_status_default_pending(&status(0, 1 << 9)),
+            Some(1 << 14)
+        );
+        assert_eq!(
+            parse_status_default_pending(&status(1 << 14, 1 << 9)),
+            Some(0)
+        );
+        assert_eq!(parse_status_default_pending("SigPnd:\t0\n"), None);
+    }
 }
@@ -0,0 +1,64 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use nix::unistd::getpid;
+
+/// Our PID, written to a file for supervisors that track services by PID
+/// file (runit, s6, OpenRC). The file is removed again on drop.
+pub struct PidFile {
+    path: PathBuf,
+    contents: String,
+}
+
+impl PidFile {
+    /// Write our PID to `path`, replacing a file left behind by an earlier
+    /// run.
+    pub fn create(path: PathBuf) -> Result<Self> {
+        match fs::read_to_string(&path) {
+            Ok(old) => warn!("Overwriting stale PID file {:?} (PID {})", path, old.trim()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        }
+        let contents = format!("{}\n", getpid());
+        fs::write(&path, &contents).with_context(|| format!("Failed to write {:?}", path))?;
+        info!("Wrote PID file {:?}", path);
+        Ok(Self { path, contents })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave the file alone if another instance has taken it over since.
+        if fs::read_to_string(&self.path).is_ok_and(|c| c == self.contents) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file_lifecycle() {
+        let path = std::env::temp_dir().join(format!("nix-ubw-{}-pid", std::process::id()));
+        fs::write(&path, "1\n").unwrap();
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // A file another instance wrote in the meantime is not ours to remove.
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        fs::write(&path, "1\n").unwrap();
+        drop(pid_file);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use crate::limiter::Limiter;
+use crate::sampler::Sampler;
+
+/// How backed up the limiter is, published to a file for build dispatchers
+/// to poll (`--pressure-file`), so they can hold off starting builds instead
+/// of having their processes paused.
+///
+/// The file holds `key value` lines:
+///
+/// ```text
+/// active 4
+/// paused 12
+/// pressure 3.00
+/// ```
+///
+/// `pressure` is paused per active process, or the paused count if nothing
+/// is active; 0 means nothing waits. The file is replaced atomically, so a
+/// reader never sees it half-written, and removed on drop, so a missing
+/// file means no pressure.
+pub struct PressureFile {
+    path: PathBuf,
+    /// Counts last written.
+    written: Option<(usize, usize)>,
+}
+
+impl PressureFile {
+    pub fn create(path: PathBuf) -> Result<Self> {
+        let mut file = Self {
+            path,
+            written: None,
+        };
+        file.write(0, 0)
+            .with_context(|| format!("Failed to write {:?}", file.path))?;
+        info!("Publishing queue pressure to {:?}", file.path);
+        Ok(file)
+    }
+
+    /// Publish the current counts, if they changed since the last update.
+    pub fn update(&mut self, active: usize, paused: usize) {
+        if self.written != Some((active, paused)) {
+            if let Err(e) = self.write(active, paused) {
+                warn!("Failed to write {:?}: {}", self.path, e);
+            }
+        }
+    }
+
+    fn write(&mut self, active: usize, paused: usize) -> std::io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, format(active, paused))?;
+        fs::rename(&tmp, &self.path)?;
+        self.written = Some((active, paused));
+        Ok(())
+    }
+}
+
+impl Sampler for PressureFile {
+    fn on_tick(&mut self, limiter: &mut Limiter) {
+        self.update(limiter.active_count(), limiter.paused_count());
+    }
+}
+
+impl Drop for PressureFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Contents of the pressure file.
+fn format(active: usize, paused: usize) -> String {
+    let pressure = paused as f64 / active.max(1) as f64;
+    format!(
+        "active {}\npaused {}\npressure {:.2}\n",
+        active, paused, pressure
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format(4, 12), "active 4\npaused 12\npressure 3.00\n");
+        assert_eq!(format(0, 3), "active 0\npaused 3\npressure 3.00\n");
+        assert_eq!(format(2, 0), "active 2\npaused 0\npressure 0.00\n");
+    }
+
+    #[test]
+    fn test_pressure_file_lifecycle() {
+        let path = std::env::temp_dir().join(format!("nix-ubw-{}-pressure", std::process::id()));
+        let mut file = PressureFile::create(path.clone()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format(0, 0));
+        file.update(1, 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), format(1, 2));
+        drop(file);
+        assert!(!path.exists());
+    }
+}
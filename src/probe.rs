@@ -0,0 +1,62 @@
+use anyhow::Result;
+use nix::unistd::getpid;
+
+use crate::nixutil::{self, Environ};
+use crate::resources::{profile_for, ResourceProfile, RuleSet};
+
+/// Print how a command line would be classified, going through the same
+/// steps as a traced exec: argv[0] resolution, then the rule lookup.
+///
+/// Our own environment stands in for the one a traced process would have,
+/// for `NIXUBW_MEM_GB` hints and `[require_env]` conditions.
+pub fn run(mut command: Vec<String>, rules: &RuleSet, total: &ResourceProfile) -> Result<()> {
+    nixutil::resolve_argv0(&mut command);
+    let env = Environ::of(getpid());
+    println!("{}", describe(&command, &env, rules, total));
+    Ok(())
+}
+
+/// `<name>: matched rule '<id>', needs <profile>`, or `<name>: not throttled`.
+fn describe(args: &[String], env: &Environ, rules: &RuleSet, total: &ResourceProfile) -> String {
+    let name = args.first().map_or("", String::as_str);
+    match profile_for(args, env, rules, total) {
+        Some(matched) => format!(
+            "{}: matched rule '{}', needs {}",
+            name, matched.rule, matched.profile
+        ),
+        None => format!("{}: not throttled", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOTAL: ResourceProfile = ResourceProfile::new(8, 16);
+
+    fn probe(argv: &[&str], rules: &RuleSet) -> String {
+        let mut args: Vec<String> = argv.iter().map(|&a| a.into()).collect();
+        nixutil::resolve_argv0(&mut args);
+        describe(&args, &Environ::default(), rules, &TOTAL)
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut rules = RuleSet::default();
+        assert_eq!(
+            probe(
+                &["/nix/store/abc-gcc/bin/.gcc-wrapped", "-c", "foo.c"],
+                &rules
+            ),
+            "gcc: matched rule 'c-compiler', needs 1 CPUs, 1 GiB"
+        );
+        assert_eq!(probe(&["go", "version"], &rules), "go: not throttled");
+        assert_eq!(probe(&["bash"], &rules), "bash: not throttled");
+
+        rules.insert("rustc".into(), Some(ResourceProfile::new(1, 6)));
+        assert_eq!(
+            probe(&["rustc"], &rules),
+            "rustc: matched rule 'config:rustc', needs 1 CPUs, 6 GiB"
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::ptrace::{self, Options};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+/// The ptrace requests (and signals) the tracer, the limiter and attaching
+/// make, so that tests can drive them against recorded calls instead of
+/// real tracees.
+///
+/// Signals go through here too: a process held with SIGSTOP is resumed with
+/// SIGCONT, and a mock must see that like any other resume.
+pub trait Ptrace {
+    fn seize(&self, pid: Pid, options: Options) -> nix::Result<()>;
+    fn cont(&self, pid: Pid, sig: Option<Signal>) -> nix::Result<()>;
+    fn interrupt(&self, pid: Pid) -> nix::Result<()>;
+    /// PTRACE_LISTEN: leave a tracee in group-stop while still being
+    /// notified of signals that would end it. Not wrapped by nix.
+    fn listen(&self, pid: Pid) -> nix::Result<()>;
+    fn detach(&self, pid: Pid, sig: Option<Signal>) -> nix::Result<()>;
+    fn setoptions(&self, pid: Pid, options: Options) -> nix::Result<()>;
+    /// The message of the last ptrace event: a new child's PID, or an exit
+    /// status.
+    fn getevent(&self, pid: Pid) -> nix::Result<libc::c_long>;
+    fn kill(&self, pid: Pid, sig: Signal) -> nix::Result<()>;
+}
+
+/// The real thing.
+#[derive(Clone, Copy)]
+pub struct SystemPtrace;
+
+impl Ptrace for SystemPtrace {
+    fn seize(&self, pid: Pid, options: Options) -> nix::Result<()> {
+        ptrace::seize(pid, options)
+    }
+
+    fn cont(&self, pid: Pid, sig: Option<Signal>) -> nix::Result<()> {
+        ptrace::cont(pid, sig)
+    }
+
+    fn interrupt(&self, pid: Pid) -> nix::Result<()> {
+        ptrace::interrupt(pid)
+    }
+
+    fn listen(&self, pid: Pid) -> nix::Result<()> {
+        // SAFETY: PTRACE_LISTEN takes no pointers.
+        let res = unsafe {
+            libc::ptrace(
+                libc::PTRACE_LISTEN,
+                pid.as_raw(),
+                std::ptr::null_mut::<libc::c_void>(),
+                std::ptr::null_mut::<libc::c_void>(),
+            )
+        };
+        Errno::result(res).map(drop)
+    }
+
+    fn detach(&self, pid: Pid, sig: Option<Signal>) -> nix::Result<()> {
+        ptrace::detach(pid, sig)
+    }
+
+    fn setoptions(&self, pid: Pid, options: Options) -> nix::Result<()> {
+        ptrace::setoptions(pid, options)
+    }
+
+    fn getevent(&self, pid: Pid) -> nix::Result<libc::c_long> {
+        ptrace::getevent(pid)
+    }
+
+    fn kill(&self, pid: Pid, sig: Signal) -> nix::Result<()> {
+        signal::kill(pid, sig)
+    }
+}
+
+/// A call made through a [`MockPtrace`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PtraceCall {
+    Seize(Pid, Options),
+    Cont(Pid, Option<Signal>),
+    Interrupt(Pid),
+    Listen(Pid),
+    Detach(Pid, Option<Signal>),
+    SetOptions(Pid, Options),
+    GetEvent(Pid),
+    Kill(Pid, Signal),
+}
+
+impl PtraceCall {
+    pub fn pid(&self) -> Pid {
+        match *self {
+            PtraceCall::Seize(pid, _)
+            | PtraceCall::Cont(pid, _)
+            | PtraceCall::Interrupt(pid)
+            | PtraceCall::Listen(pid)
+            | PtraceCall::Detach(pid, _)
+            | PtraceCall::SetOptions(pid, _)
+            | PtraceCall::GetEvent(pid)
+            | PtraceCall::Kill(pid, _) => pid,
+        }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<PtraceCall>,
+    failures: HashMap<Pid, VecDeque<Errno>>,
+    events: HashMap<Pid, libc::c_long>,
+}
+
+/// Records every call instead of making it, for unit tests and benchmarks
+/// (a limiter made with `unit_test`). Every call succeeds unless a failure
+/// was queued for its PID. Clones share the same record, so a test keeps one
+/// to inspect the clone it handed over.
+#[derive(Clone, Default)]
+pub struct MockPtrace(Rc<RefCell<MockState>>);
+
+impl MockPtrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls made so far, in order.
+    pub fn calls(&self) -> Vec<PtraceCall> {
+        self.0.borrow().calls.clone()
+    }
+
+    /// Calls made so far, clearing the record.
+    pub fn take_calls(&self) -> Vec<PtraceCall> {
+        std::mem::take(&mut self.0.borrow_mut().calls)
+    }
+
+    /// Fail the next calls on `pid` with `errors`, one call each, in order.
+    pub fn fail(&self, pid: Pid, errors: impl IntoIterator<Item = Errno>) {
+        self.0
+            .borrow_mut()
+            .failures
+            .entry(pid)
+            .or_default()
+            .extend(errors);
+    }
+
+    /// Answer `getevent` for `pid` with `msg`. Without one it fails with
+    /// ESRCH.
+    pub fn set_event(&self, pid: Pid, msg: libc::c_long) {
+        self.0.borrow_mut().events.insert(pid, msg);
+    }
+
+    fn record(&self, call: PtraceCall) -> nix::Result<()> {
+        let mut state = self.0.borrow_mut();
+        state.calls.push(call);
+        match state
+            .failures
+            .get_mut(&call.pid())
+            .and_then(|f| f.pop_front())
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Ptrace for MockPtrace {
+    fn seize(&self, pid: Pid, options: Options) -> nix::Result<()> {
+        self.record(PtraceCall::Seize(pid, options))
+    }
+
+    fn cont(&self, pid: Pid, sig: Option<Signal>) -> nix::Result<()> {
+        self.record(PtraceCall::Cont(pid, sig))
+    }
+
+    fn interrupt(&self, pid: Pid) -> nix::Result<()> {
+        self.record(PtraceCall::Interrupt(pid))
+    }
+
+    fn listen(&self, pid: Pid) -> nix::Result<()> {
+        self.record(PtraceCall::Listen(pid))
+    }
+
+    fn detach(&self, pid: Pid, sig: Option<Signal>) -> nix::Result<()> {
+        self.record(PtraceCall::Detach(pid, sig))
+    }
+
+    fn setoptions(&self, pid: Pid, options: Options) -> nix::Result<()> {
+        self.record(PtraceCall::SetOptions(pid, options))
+    }
+
+    fn getevent(&self, pid: Pid) -> nix::Result<libc::c_long> {
+        self.record(PtraceCall::GetEvent(pid))?;
+        self.0
+            .borrow()
+            .events
+            .get(&pid)
+            .copied()
+            .ok_or(Errno::ESRCH)
+    }
+
+    fn kill(&self, pid: Pid, sig: Signal) -> nix::Result<()> {
+        self.record(PtraceCall::Kill(pid, sig))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_ptrace() {
+        let mock = MockPtrace::new();
+        let ptrace: Box<dyn Ptrace> = Box::new(mock.clone());
+        let pid = Pid::from_raw(100);
+        mock.fail(pid, [Errno::EBUSY]);
+        mock.set_event(pid, 101);
+
+        assert_eq!(ptrace.cont(pid, None), Err(Errno::EBUSY));
+        assert_eq!(ptrace.cont(pid, None), Ok(()));
+        assert_eq!(ptrace.getevent(pid), Ok(101));
+        assert_eq!(ptrace.getevent(Pid::from_raw(102)), Err(Errno::ESRCH));
+        assert_eq!(
+            mock.take_calls(),
+            vec![
+                PtraceCall::Cont(pid, None),
+                PtraceCall::Cont(pid, None),
+                PtraceCall::GetEvent(pid),
+                PtraceCall::GetEvent(Pid::from_raw(102)),
+            ]
+        );
+        assert!(mock.calls().is_empty());
+    }
+}
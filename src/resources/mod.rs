@@ -1,5 +1,12 @@
+mod profile_spec;
 mod resource_profile;
 mod rules;
 
+pub use profile_spec::{Amount, ProfileSpec};
 pub use resource_profile::ResourceProfile;
-pub use rules::profile_for;
+#[cfg(feature = "serde")]
+pub use rules::SkipIf;
+pub use rules::{
+    profile_for, ArchScale, ArgValue, EnvCondition, Matcher, NamePattern, RuleEntry, RuleGroup,
+    RuleMatch, RuleSet, SiblingMem, RULES_VAR,
+};
@@ -0,0 +1,222 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::resources::resource_profile::{strip_unit, ResourceProfile};
+
+/// One resource of a rule's profile as written: a fixed amount, or a share
+/// of the total budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Amount {
+    Fixed(i32),
+    /// `num / den` of the total, at most all of it.
+    Share {
+        num: u32,
+        den: u32,
+    },
+}
+
+impl Amount {
+    /// The amount out of `total`. Shares are rounded up, so that a share of
+    /// anything is at least 1 unless the share is 0.
+    fn resolve(self, total: i32) -> i32 {
+        match self {
+            Amount::Fixed(amount) => amount,
+            Amount::Share { num, den } => {
+                let total = i64::from(total.max(0));
+                let (num, den) = (i64::from(num), i64::from(den));
+                ((total * num + den - 1) / den) as i32
+            }
+        }
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Amount::Fixed(amount) => write!(f, "{}", amount),
+            Amount::Share { num, den: 100 } => write!(f, "{}%", num),
+            Amount::Share { num, den } => write!(f, "{}/{}", num, den),
+        }
+    }
+}
+
+/// A whole number, `N%` or `A/B`.
+impl FromStr for Amount {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (num, den) = if let Some(percent) = s.strip_suffix('%') {
+            (percent.trim_end().parse()?, 100)
+        } else if let Some((num, den)) = s.split_once('/') {
+            (num.trim().parse()?, den.trim().parse()?)
+        } else {
+            return Ok(Amount::Fixed(s.parse()?));
+        };
+        if den == 0 || num > den {
+            bail!("{:?} is not a share between 0 and all of the budget", s);
+        }
+        Ok(Amount::Share { num, den })
+    }
+}
+
+/// A rule's resource profile as written, each resource either fixed or a
+/// share of the total budget (`"50%,8gb"`, `"1/4cpu,25%"`), so that one
+/// config fits machines of any size. Resolved into a `ResourceProfile`
+/// against the budget when a process is classified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(try_from = "SpecRepr")
+)]
+pub struct ProfileSpec {
+    pub cpus: Amount,
+    pub mem_gb: Amount,
+}
+
+impl ProfileSpec {
+    pub fn resolve(&self, total: &ResourceProfile) -> ResourceProfile {
+        ResourceProfile::new(
+            self.cpus.resolve(total.cpus),
+            self.mem_gb.resolve(total.mem_gb),
+        )
+    }
+}
+
+impl From<ResourceProfile> for ProfileSpec {
+    fn from(profile: ResourceProfile) -> Self {
+        Self {
+            cpus: Amount::Fixed(profile.cpus),
+            mem_gb: Amount::Fixed(profile.mem_gb),
+        }
+    }
+}
+
+impl fmt::Display for ProfileSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} CPUs, {} GiB", self.cpus, self.mem_gb)
+    }
+}
+
+/// The compact form of `ResourceProfile`, where either amount may also be a
+/// share: `<cpus>[cpu|cpus],<mem>[gb|gib|g]`.
+impl FromStr for ProfileSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((cpus, mem)) = s.split_once(',') else {
+            bail!(
+                "expected <cpus>,<mem_gb> (e.g. 2cpu,4gb or 50%,25%), got {:?}",
+                s
+            );
+        };
+        let cpus = strip_unit(cpus, &["cpus", "cpu"])
+            .parse()
+            .with_context(|| format!("invalid CPU count in {:?}", s))?;
+        let mem_gb = strip_unit(mem, &["gib", "gb", "g"])
+            .parse()
+            .with_context(|| format!("invalid memory size in {:?}", s))?;
+        Ok(Self { cpus, mem_gb })
+    }
+}
+
+/// Accepted serialized forms of a `ProfileSpec`, as for `ResourceProfile`.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum SpecRepr {
+    Compact(String),
+    Full {
+        cpus: AmountRepr,
+        mem_gb: AmountRepr,
+    },
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum AmountRepr {
+    Fixed(i32),
+    Text(String),
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<AmountRepr> for Amount {
+    type Error = anyhow::Error;
+
+    fn try_from(repr: AmountRepr) -> anyhow::Result<Self> {
+        match repr {
+            AmountRepr::Fixed(amount) => Ok(Amount::Fixed(amount)),
+            AmountRepr::Text(s) => s.trim().parse(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<SpecRepr> for ProfileSpec {
+    type Error = anyhow::Error;
+
+    fn try_from(repr: SpecRepr) -> anyhow::Result<Self> {
+        match repr {
+            SpecRepr::Compact(s) => s.parse(),
+            SpecRepr::Full { cpus, mem_gb } => Ok(Self {
+                cpus: cpus.try_into()?,
+                mem_gb: mem_gb.try_into()?,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let spec: ProfileSpec = "50%cpu,8gb".parse().unwrap();
+        assert_eq!(spec.cpus, Amount::Share { num: 50, den: 100 });
+        assert_eq!(spec.mem_gb, Amount::Fixed(8));
+        assert_eq!(spec.to_string(), "50% CPUs, 8 GiB");
+        let spec: ProfileSpec = "1/4, 100 %".parse().unwrap();
+        assert_eq!(spec.to_string(), "1/4 CPUs, 100% GiB");
+        assert_eq!(
+            "2,4".parse::<ProfileSpec>().unwrap(),
+            ProfileSpec::from(ResourceProfile::new(2, 4))
+        );
+        for invalid in ["150%,1", "1/0,1", "2/1,1", "-5%,1", "1.5,1", "50%"] {
+            assert!(invalid.parse::<ProfileSpec>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_resolve_at_several_sizes() {
+        let spec: ProfileSpec = "50%,1/4".parse().unwrap();
+        let cases = [
+            ((1, 2), (1, 1)),
+            ((4, 16), (2, 4)),
+            ((7, 30), (4, 8)),
+            ((64, 256), (32, 64)),
+        ];
+        for ((cpus, mem_gb), (want_cpus, want_mem_gb)) in cases {
+            assert_eq!(
+                spec.resolve(&ResourceProfile::new(cpus, mem_gb)),
+                ResourceProfile::new(want_cpus, want_mem_gb),
+                "of {},{}",
+                cpus,
+                mem_gb
+            );
+        }
+        let none: ProfileSpec = "0%,0/1".parse().unwrap();
+        assert_eq!(
+            none.resolve(&ResourceProfile::new(8, 8)),
+            ResourceProfile::new(0, 0)
+        );
+        let fixed = ProfileSpec::from(ResourceProfile::new(3, 5));
+        assert_eq!(
+            fixed.resolve(&ResourceProfile::new(1, 1)),
+            ResourceProfile::new(3, 5)
+        );
+    }
+}
@@ -1,12 +1,25 @@
 use std::fmt;
 use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
 
 /// Resource consumption profile for a rate-limited process.
+///
+/// With the `serde` feature this serializes as `{cpus, mem_gb}` and
+/// deserializes from either that object or the compact string form accepted
+/// by `FromStr` (e.g. `"2cpu,4gb"`).
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "ProfileRepr")
+)]
 pub struct ResourceProfile {
     /// Number of CPU cores this process consumes.
     pub cpus: i32,
-    /// Memory this process consumes in GiB.
+    /// Memory this process consumes in GiB (1024^3 bytes), like every
+    /// `_gb` amount in nix-ubw.
     pub mem_gb: i32,
 }
 
@@ -15,6 +28,11 @@ impl ResourceProfile {
         Self { cpus, mem_gb }
     }
 
+    /// Component-wise maximum of two profiles.
+    pub fn max_each(&self, other: &ResourceProfile) -> ResourceProfile {
+        Self::new(self.cpus.max(other.cpus), self.mem_gb.max(other.mem_gb))
+    }
+
     /// Returns true if the provided available resources can satisfy this profile's requirements.
     pub fn has_free_resources(&self, available: &ResourceProfile) -> bool {
         self.cpus <= available.cpus && self.mem_gb <= available.mem_gb
@@ -27,12 +45,69 @@ impl fmt::Display for ResourceProfile {
     }
 }
 
+/// Parse the compact form `<cpus>[cpu|cpus],<mem>[gb|gib|g]`, e.g. `2cpu,4gb`
+/// or just `2,4`. Memory is always in GiB: `gb` and `g` are accepted for
+/// convenience and mean GiB too, never decimal gigabytes.
+impl FromStr for ResourceProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((cpus, mem)) = s.split_once(',') else {
+            bail!("expected <cpus>,<mem_gb> (e.g. 2cpu,4gb), got {:?}", s);
+        };
+        let cpus = strip_unit(cpus, &["cpus", "cpu"])
+            .parse()
+            .with_context(|| format!("invalid CPU count in {:?}", s))?;
+        let mem_gb = strip_unit(mem, &["gib", "gb", "g"])
+            .parse()
+            .with_context(|| format!("invalid memory size in {:?}", s))?;
+        Ok(Self::new(cpus, mem_gb))
+    }
+}
+
+/// Trim whitespace and the first matching (case-insensitive) unit suffix.
+pub(super) fn strip_unit<'a>(value: &'a str, units: &[&str]) -> &'a str {
+    let value = value.trim();
+    for unit in units {
+        if value.len() >= unit.len() {
+            let (num, suffix) = value.split_at(value.len() - unit.len());
+            if suffix.eq_ignore_ascii_case(unit) {
+                return num.trim_end();
+            }
+        }
+    }
+    value
+}
+
+/// Accepted serialized forms of a `ResourceProfile`.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ProfileRepr {
+    Compact(String),
+    Full { cpus: i32, mem_gb: i32 },
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ProfileRepr> for ResourceProfile {
+    type Error = anyhow::Error;
+
+    fn try_from(repr: ProfileRepr) -> anyhow::Result<Self> {
+        match repr {
+            ProfileRepr::Compact(s) => s.parse(),
+            ProfileRepr::Full { cpus, mem_gb } => Ok(Self::new(cpus, mem_gb)),
+        }
+    }
+}
+
+/// Sums and differences saturate at the bounds of `i32` rather than
+/// overflow, so that no accounting error, however large, can panic.
 impl Add for ResourceProfile {
     type Output = Self;
     fn add(self, other: Self) -> Self {
         Self {
-            cpus: self.cpus + other.cpus,
-            mem_gb: self.mem_gb + other.mem_gb,
+            cpus: self.cpus.saturating_add(other.cpus),
+            mem_gb: self.mem_gb.saturating_add(other.mem_gb),
         }
     }
 }
@@ -47,8 +122,8 @@ impl Sub for ResourceProfile {
     type Output = Self;
     fn sub(self, other: Self) -> Self {
         Self {
-            cpus: self.cpus - other.cpus,
-            mem_gb: self.mem_gb - other.mem_gb,
+            cpus: self.cpus.saturating_sub(other.cpus),
+            mem_gb: self.mem_gb.saturating_sub(other.mem_gb),
         }
     }
 }
@@ -58,3 +133,94 @@ impl SubAssign for ResourceProfile {
         *self = *self - other;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compact() {
+        assert_eq!(
+            "2cpu,4gb".parse::<ResourceProfile>().unwrap(),
+            ResourceProfile::new(2, 4)
+        );
+        assert_eq!(
+            "8cpus,32GiB".parse::<ResourceProfile>().unwrap(),
+            ResourceProfile::new(8, 32)
+        );
+        assert_eq!(
+            " 1 , 2 g ".parse::<ResourceProfile>().unwrap(),
+            ResourceProfile::new(1, 2)
+        );
+        assert_eq!(
+            "4,16".parse::<ResourceProfile>().unwrap(),
+            ResourceProfile::new(4, 16)
+        );
+    }
+
+    #[test]
+    fn test_mem_unit_is_gib() {
+        // Every spelling is GiB, and so is what is shown.
+        for s in ["1,4", "1,4g", "1,4gb", "1,4GB", "1,4gib", "1,4GiB"] {
+            assert_eq!(s.parse::<ResourceProfile>().unwrap().mem_gb, 4, "{}", s);
+        }
+        assert_eq!(ResourceProfile::new(1, 4).to_string(), "1 CPUs, 4 GiB");
+    }
+
+    #[test]
+    fn test_parse_compact_invalid() {
+        assert!("4".parse::<ResourceProfile>().is_err());
+        assert!("two,4".parse::<ResourceProfile>().is_err());
+        assert!("2cpu,4tb".parse::<ResourceProfile>().is_err());
+    }
+
+    #[test]
+    fn test_arithmetic_saturates() {
+        let a = ResourceProfile::new(2, 4);
+        let b = ResourceProfile::new(3, -1);
+        assert_eq!(a + b, ResourceProfile::new(5, 3));
+        assert_eq!(a - b, ResourceProfile::new(-1, 5));
+
+        let near_max = ResourceProfile::new(i32::MAX - 1, i32::MAX);
+        assert_eq!(
+            near_max + ResourceProfile::new(1, 0),
+            ResourceProfile::new(i32::MAX, i32::MAX)
+        );
+        assert_eq!(
+            near_max + ResourceProfile::new(5, 5),
+            ResourceProfile::new(i32::MAX, i32::MAX)
+        );
+        let near_min = ResourceProfile::new(i32::MIN + 1, i32::MIN);
+        assert_eq!(
+            near_min - ResourceProfile::new(5, 1),
+            ResourceProfile::new(i32::MIN, i32::MIN)
+        );
+        assert_eq!(
+            ResourceProfile::new(0, 0) - ResourceProfile::new(i32::MIN, i32::MIN),
+            ResourceProfile::new(i32::MAX, i32::MAX)
+        );
+
+        let mut sum = ResourceProfile::new(0, 0);
+        for _ in 0..3 {
+            sum += ResourceProfile::new(i32::MAX / 2, 1);
+        }
+        assert_eq!(sum, ResourceProfile::new(i32::MAX, 3));
+        let mut free = ResourceProfile::new(1, 1);
+        free -= ResourceProfile::new(i32::MAX, i32::MAX);
+        free -= ResourceProfile::new(i32::MAX, i32::MAX);
+        assert_eq!(free, ResourceProfile::new(i32::MIN, i32::MIN));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_forms() {
+        let full: ResourceProfile = serde_json::from_str(r#"{"cpus": 2, "mem_gb": 4}"#).unwrap();
+        let compact: ResourceProfile = serde_json::from_str(r#""2cpu,4gb""#).unwrap();
+        assert_eq!(full, ResourceProfile::new(2, 4));
+        assert_eq!(compact, full);
+        assert!(serde_json::from_str::<ResourceProfile>(r#""nope""#).is_err());
+
+        let json = serde_json::to_string(&full).unwrap();
+        assert_eq!(json, r#"{"cpus":2,"mem_gb":4}"#);
+    }
+}
@@ -1,49 +1,1616 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use log::warn;
+use regex::Regex;
+
+use crate::nixutil::Environ;
+use crate::resources::profile_spec::ProfileSpec;
 use crate::resources::resource_profile::ResourceProfile;
 
+/// Environment variable a derivation can export to override the memory claim
+/// of its throttled processes, in GiB.
+pub const MEM_HINT_VAR: &str = "NIXUBW_MEM_GB";
+
+/// Environment variable with the base rules when `--config` isn't given,
+/// for containers: a path to a rule file, or the text of one.
+pub const RULES_VAR: &str = "NIXUBW_RULES";
+
+/// Programs of Nix itself that nix-daemon runs to coordinate builds
+/// (substitution, signature checks, remote builds) rather than to do their
+/// work. They are never throttled, whatever rules match them, so that a
+/// broad rule can't pause the daemon's own bookkeeping and stall every build
+/// waiting on it. Config files can replace the list (`nix_internal`).
+pub const NIX_INTERNAL: &[&str] = &[
+    "nix",
+    "nix-daemon",
+    "nix-store",
+    "nix-build",
+    "nix-instantiate",
+    "nix-env",
+    "nix-shell",
+    "nix-hash",
+    "nix-prefetch-url",
+    "nix-copy-closure",
+    "nix-collect-garbage",
+    "build-remote",
+];
+
 /// Look up the resource profile for a process given its resolved argv.
 /// `args[0]` is expected to already be the resolved basename (as returned
-/// by `read_cmdline`).
+/// by `read_cmdline`). Rules loaded from config files are tried first, in
+/// order, and the first one that accepts the process decides (see
+/// `RuleEntry`); the built-in table is only consulted if none does.
+///
+/// A cross toolchain's tools are named with the target triple in front
+/// (`aarch64-unknown-linux-gnu-gcc`). A name without a rule of its own, not
+/// even one that opts it out, that starts with a known architecture is
+/// matched again without the triple (see `split_triple_prefix`), and the
+/// profile is scaled by the architecture's `--arch-scale` factor, if any.
+///
+/// An invocation the matched program's `SkipIf` condition holds for, such as
+/// `go version`, is not throttled, and neither is one whose environment
+/// fails the program's `[require_env]` conditions.
+///
+/// With `--profile-hint`, a `NIXUBW_MEM_GB` hint in the environment of a
+/// process that matched a rule replaces the rule's memory claim. Processes
+/// without a rule are never throttled, hint or not.
+///
+/// `env` is only read if one of these needs it: a process whose program has
+/// no environment conditions costs no /proc/<pid>/environ read unless the
+/// hint is enabled.
+///
+/// Programs of Nix itself (`NIX_INTERNAL`) are never throttled.
 ///
 /// Returns `None` if the process has no specific profile and should not be
 /// throttled.
-pub fn profile_for(args: &[String], total: &ResourceProfile) -> Option<ResourceProfile> {
-    let name = args.first().map(|s| s.as_str())?;
+pub fn profile_for(
+    args: &[String],
+    env: &Environ,
+    rules: &RuleSet,
+    total: &ResourceProfile,
+) -> Option<RuleMatch> {
+    let name = args.first()?;
+    if rules.is_nix_internal(name) {
+        return None;
+    }
+    // `Some(None)` for a rule that says not to throttle the program.
+    let lookup = |name: &str| match rules.first_match(name, args, env) {
+        Some(entry) => Some(
+            entry
+                .profile_of(args, total)
+                .map(|profile| (RuleId::Config(entry.id.clone()), profile)),
+        ),
+        None => static_profile_for(name, total)
+            .filter(|(id, _)| !rules.disabled.contains(*id))
+            .map(|(id, profile)| Some((RuleId::Builtin(id), profile))),
+    };
+    let (arch, tool) = match lookup(name) {
+        // Including an opt-out of the full name, which the tool's own rule
+        // must not override.
+        Some(_) => (None, name.as_str()),
+        None => {
+            let (arch, tool) = split_triple_prefix(name, |tool| lookup(tool).is_some())?;
+            (Some(arch), tool)
+        }
+    };
+    let (rule, mut profile) = lookup(tool)??;
+    if rules.skips(tool, args) || !rules.env_allows(tool, env) {
+        return None;
+    }
+    if let Some(arch) = arch {
+        profile = rules.scale_for_arch(arch, profile);
+    }
+    if let Some(hint) = rules.mem_hint.then(|| env.get(MEM_HINT_VAR)).flatten() {
+        match hint.trim().parse::<i32>() {
+            Ok(mem_gb) if mem_gb >= 0 => profile.mem_gb = mem_gb,
+            _ => warn!("Ignoring invalid {}={:?}", MEM_HINT_VAR, hint),
+        }
+    }
+    Some(RuleMatch { rule, profile })
+}
+
+/// Architectures recognized as the first component of a target triple.
+const TRIPLE_ARCHES: &[&str] = &[
+    "aarch64",
+    "aarch64_be",
+    "arm",
+    "armv6l",
+    "armv7a",
+    "armv7l",
+    "i386",
+    "i686",
+    "loongarch64",
+    "m68k",
+    "mips",
+    "mips64",
+    "mips64el",
+    "mipsel",
+    "powerpc",
+    "powerpc64",
+    "powerpc64le",
+    "riscv32",
+    "riscv64",
+    "s390x",
+    "sparc64",
+    "wasm32",
+    "x86_64",
+];
+
+/// Split a cross tool name into its target architecture and the tool name,
+/// e.g. `aarch64-unknown-linux-gnu-gcc` into `aarch64` and `gcc`.
+///
+/// Triples have two to four dash-separated components (`wasm32-wasi`,
+/// `arm-none-eabi`, `aarch64-unknown-linux-gnu`), so the name must start
+/// with a known architecture, and the tool is the shortest such prefix's
+/// remainder that `is_tool` accepts. Tool names may contain dashes
+/// themselves (`lto-wrapper`), which is why the remainder can't just be cut
+/// at a fixed dash.
+fn split_triple_prefix(name: &str, is_tool: impl Fn(&str) -> bool) -> Option<(&str, &str)> {
+    let (arch, _) = name.split_once('-')?;
+    if !TRIPLE_ARCHES.contains(&arch) {
+        return None;
+    }
+    let dashes: Vec<usize> = name.match_indices('-').map(|(i, _)| i).collect();
+    (2..=4)
+        .filter_map(|components| dashes.get(components - 1))
+        .map(|&dash| &name[dash + 1..])
+        .find(|tool| !tool.is_empty() && is_tool(tool))
+        .map(|tool| (arch, tool))
+}
+
+/// Identifies the rule a process matched, for logs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleId {
+    /// A rule of the built-in table, by the name of its group.
+    Builtin(&'static str),
+    /// A rule from a config file, by its id: the program name it is for,
+    /// unless given another one.
+    Config(String),
+}
+
+impl fmt::Display for RuleId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleId::Builtin(id) => write!(f, "{}", id),
+            RuleId::Config(name) => write!(f, "config:{}", name),
+        }
+    }
+}
+
+/// The profile a process is throttled with, and the rule it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleMatch {
+    pub rule: RuleId,
+    pub profile: ResourceProfile,
+}
+
+/// One rule of the ordered list loaded from config files (`[[rule]]`): which
+/// processes it accepts, and what they are throttled with.
+///
+/// A process is accepted if its resolved basename matches and every
+/// predicate holds. The `[rules]` table is sugar for entries with a single
+/// name and no predicates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleEntry {
+    /// Identifies the rule as `config:<id>` in logs, and in `[weights]`,
+    /// `[est_duration]` and instance caps.
+    pub id: String,
+    pub matcher: Matcher,
+    /// Arguments the invocation must all have, in any position after the
+    /// program name.
+    pub args: Vec<String>,
+    /// Conditions on the environment, all of which must hold.
+    pub env: BTreeMap<String, EnvCondition>,
+    /// `None` if the processes it accepts are never throttled.
+    pub profile: Option<ProfileSpec>,
+    /// Multiply the profile's CPUs by the parallelism an invocation asks
+    /// for, e.g. the 8 of `make -j8`.
+    pub scale_cpus: Option<ArgValue>,
+}
+
+impl RuleEntry {
+    /// The rule for `name` alone, as written in the `[rules]` table.
+    pub fn plain(name: String, profile: Option<ProfileSpec>) -> Self {
+        Self {
+            id: name.clone(),
+            matcher: Matcher::Names(vec![name]),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            profile,
+            scale_cpus: None,
+        }
+    }
+
+    /// The profile of an invocation with `args` out of `total`, with its CPUs
+    /// scaled by `scale_cpus` but never past the whole budget. `None` if the
+    /// rule doesn't throttle.
+    fn profile_of(&self, args: &[String], total: &ResourceProfile) -> Option<ResourceProfile> {
+        let mut profile = self.profile?.resolve(total);
+        let factor = self
+            .scale_cpus
+            .as_ref()
+            .and_then(|value| value.find(args.get(1..).unwrap_or_default()));
+        if let Some(factor) = factor.filter(|&n| n > 1) {
+            let factor = i32::try_from(factor).unwrap_or(i32::MAX);
+            profile.cpus = profile
+                .cpus
+                .saturating_mul(factor)
+                .min(total.cpus.max(profile.cpus));
+        }
+        Some(profile)
+    }
+
+    /// The name of a rule that accepts every invocation of that one program.
+    #[cfg(any(feature = "serde", test))]
+    fn plain_name(&self) -> Option<&str> {
+        match &self.matcher {
+            Matcher::Names(names)
+                if names.len() == 1 && self.args.is_empty() && self.env.is_empty() =>
+            {
+                Some(&names[0])
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the rule accepts an invocation of `name` with `args`. Reads
+    /// `env` only if the name and arguments match and there are conditions.
+    fn accepts(&self, name: &str, args: &[String], env: &Environ) -> bool {
+        let rest = args.get(1..).unwrap_or_default();
+        self.matcher.matches(name)
+            && self.args.iter().all(|arg| rest.contains(arg))
+            && self
+                .env
+                .iter()
+                .all(|(var, condition)| condition.matches(env.get(var)))
+    }
+}
+
+/// Which program names a `RuleEntry` is for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Matcher {
+    /// Any of these resolved basenames.
+    Names(Vec<String>),
+    /// Resolved basenames matching a regular expression.
+    Pattern(NamePattern),
+}
+
+impl Matcher {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Matcher::Names(names) => names.iter().any(|n| n == name),
+            Matcher::Pattern(pattern) => pattern.regex.is_match(name),
+        }
+    }
+}
+
+/// A regular expression over resolved basenames. It must match the whole
+/// name, as if written between `^` and `$`.
+#[derive(Clone, Debug)]
+pub struct NamePattern {
+    source: String,
+    regex: Regex,
+}
+
+impl NamePattern {
+    /// The expression as written.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl PartialEq for NamePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl FromStr for NamePattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let regex = Regex::new(&format!("^(?:{})$", s))
+            .with_context(|| format!("invalid regex {:?}", s))?;
+        Ok(Self {
+            source: s.to_owned(),
+            regex,
+        })
+    }
+}
+
+/// A regular expression capturing a number from an invocation's arguments,
+/// such as the job count of `-j8`. It is matched against each argument as a
+/// whole, and against each argument followed by a space and the next one,
+/// so that `-j ?(\d+)` reads both `-j8` and `-j 8`. The last match counts,
+/// as later flags override earlier ones.
+#[derive(Clone, Debug)]
+pub struct ArgValue {
+    source: String,
+    regex: Regex,
+}
+
+impl ArgValue {
+    /// The number the last matching argument gives, if any does.
+    fn find(&self, args: &[String]) -> Option<u32> {
+        let capture = |s: &str| self.regex.captures(s)?.get(1)?.as_str().parse().ok();
+        let mut found = None;
+        for (i, arg) in args.iter().enumerate() {
+            let value = capture(arg).or_else(|| {
+                let next = args.get(i + 1)?;
+                capture(&format!("{} {}", arg, next))
+            });
+            if value.is_some() {
+                found = value;
+            }
+        }
+        found
+    }
+
+    /// The expression as written.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl PartialEq for ArgValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl FromStr for ArgValue {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let regex = Regex::new(&format!("^(?:{})$", s))
+            .with_context(|| format!("invalid regex {:?}", s))?;
+        if regex.captures_len() < 2 {
+            bail!("regex {:?} has no group to capture the number with", s);
+        }
+        Ok(Self {
+            source: s.to_owned(),
+            regex,
+        })
+    }
+}
+
+/// Rules loaded from config files, in the order they are tried, with what
+/// else those files configure. A rule replaces the built-in rule for the
+/// programs it accepts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuleSet {
+    entries: Vec<RuleEntry>,
+    /// Positions in `entries` of the rules naming each program, and of the
+    /// rules matching names by pattern, so that a lookup only tries those.
+    by_name: HashMap<String, Vec<usize>>,
+    by_pattern: Vec<usize>,
+    /// Factor applied to the profile of cross tools per target architecture.
+    arch_scale: BTreeMap<String, f64>,
+    /// Resume weight per rule id (as in `RuleId`'s `Display`), for weighted
+    /// round-robin between classes of paused processes.
+    weights: BTreeMap<String, u32>,
+    /// Trivial invocations per program name, replacing the built-in ones.
+    skip_if: BTreeMap<String, SkipIf>,
+    /// Built-in rule groups turned off with `--disable-rules`.
+    disabled: BTreeSet<String>,
+    /// Environment conditions per program name, all of which must hold for
+    /// the program to be throttled.
+    require_env: BTreeMap<String, BTreeMap<String, EnvCondition>>,
+    /// Honor `NIXUBW_MEM_GB` hints (`--profile-hint`).
+    mem_hint: bool,
+    /// Families of programs with shared caps, by group name.
+    groups: BTreeMap<String, RuleGroup>,
+    /// Advisory run time per rule id, for `--resume-order lpt`.
+    est_durations: BTreeMap<String, Duration>,
+    /// Active processes allowed at once per rule id.
+    instance_caps: BTreeMap<String, usize>,
+    /// Resident memory in GiB above which a process is killed, per rule id.
+    mem_hard: BTreeMap<String, u32>,
+    /// Programs never throttled, replacing `NIX_INTERNAL` if set.
+    nix_internal: Option<BTreeSet<String>>,
+}
+
+impl RuleSet {
+    /// The first rule that accepts an invocation of `name` with `args`.
+    fn first_match(&self, name: &str, args: &[String], env: &Environ) -> Option<&RuleEntry> {
+        let named = self.by_name.get(name).map_or(&[][..], Vec::as_slice);
+        let mut candidates: Vec<usize> = named.iter().chain(&self.by_pattern).copied().collect();
+        candidates.sort_unstable();
+        candidates
+            .into_iter()
+            .map(|i| &self.entries[i])
+            .find(|entry| entry.accepts(name, args, env))
+    }
+
+    /// Add or replace the rule for `name`, ahead of every other rule.
+    #[cfg(any(feature = "serde", test))]
+    pub fn insert(&mut self, name: String, rule: Option<ResourceProfile>) {
+        self.prepend(vec![RuleEntry::plain(name, rule.map(ProfileSpec::from))]);
+    }
+
+    /// Put `entries`, in order, ahead of the rules added so far, so that a
+    /// later file's rules are tried before an earlier one's. An earlier rule
+    /// for exactly one program, which a new one also is, could never match
+    /// again and is dropped.
+    #[cfg(any(feature = "serde", test))]
+    pub fn prepend(&mut self, mut entries: Vec<RuleEntry>) {
+        let replaced: BTreeSet<String> = entries
+            .iter()
+            .filter_map(|entry| entry.plain_name().map(str::to_owned))
+            .collect();
+        entries.extend(self.entries.drain(..).filter(|entry| {
+            entry
+                .plain_name()
+                .is_none_or(|name| !replaced.contains(name))
+        }));
+        self.entries = entries;
+        self.by_name.clear();
+        self.by_pattern.clear();
+        for (i, entry) in self.entries.iter().enumerate() {
+            match &entry.matcher {
+                Matcher::Names(names) => {
+                    for name in names {
+                        self.by_name.entry(name.clone()).or_default().push(i);
+                    }
+                }
+                Matcher::Pattern(_) => self.by_pattern.push(i),
+            }
+        }
+    }
+
+    /// Allow at most `max` active processes of rule `id` at once.
+    #[cfg(any(feature = "serde", test))]
+    pub fn set_max_instances(&mut self, id: String, max: usize) {
+        self.instance_caps.insert(id, max);
+    }
+
+    /// How many processes of rule `id` may be active at once, if capped.
+    pub fn max_instances(&self, id: &str) -> Option<usize> {
+        self.instance_caps.get(id).copied()
+    }
+
+    /// Turn off the built-in rule group `id` (`--disable-rules`).
+    pub fn disable_builtin(&mut self, id: &str) -> anyhow::Result<()> {
+        if !BUILTIN_RULES.contains(&id) {
+            bail!(
+                "unknown built-in rule {:?} (expected one of {})",
+                id,
+                BUILTIN_RULES.join(", ")
+            );
+        }
+        self.disabled.insert(id.to_owned());
+        Ok(())
+    }
+
+    /// Set when invocations of `name` are not throttled, replacing the
+    /// built-in condition for it if any.
+    #[cfg(any(feature = "serde", test))]
+    pub fn set_skip_if(&mut self, name: String, skip_if: SkipIf) {
+        self.skip_if.insert(name, skip_if);
+    }
+
+    /// Whether `args`, an invocation of the program `name` which matched a
+    /// rule, is trivial and should not be throttled.
+    fn skips(&self, name: &str, args: &[String]) -> bool {
+        match self.skip_if.get(name) {
+            Some(skip_if) => skip_if.matches(args),
+            None => args
+                .get(1)
+                .is_some_and(|arg| static_skip_args(name).contains(&arg.as_str())),
+        }
+    }
+
+    /// Never throttle the programs `names`, instead of those of
+    /// `NIX_INTERNAL`.
+    #[cfg(any(feature = "serde", test))]
+    pub fn set_nix_internal(&mut self, names: BTreeSet<String>) {
+        self.nix_internal = Some(names);
+    }
+
+    /// Whether `name` is a program of Nix itself, which is never throttled.
+    fn is_nix_internal(&self, name: &str) -> bool {
+        match &self.nix_internal {
+            Some(names) => names.contains(name),
+            None => NIX_INTERNAL.contains(&name),
+        }
+    }
+
+    /// Throttle `name` only if its environment meets every condition of
+    /// `vars`, replacing any earlier conditions for it.
+    #[cfg(any(feature = "serde", test))]
+    pub fn set_require_env(&mut self, name: String, vars: BTreeMap<String, EnvCondition>) {
+        self.require_env.insert(name, vars);
+    }
+
+    /// Whether the environment of an invocation of `name` meets the
+    /// conditions set for it. Reads `env` only if there are any.
+    fn env_allows(&self, name: &str, env: &Environ) -> bool {
+        self.require_env.get(name).is_none_or(|vars| {
+            vars.iter()
+                .all(|(var, condition)| condition.matches(env.get(var)))
+        })
+    }
+
+    /// Add or replace the group `name`.
+    #[cfg(any(feature = "serde", test))]
+    pub fn set_group(&mut self, name: String, group: RuleGroup) {
+        self.groups.insert(name, group);
+    }
+
+    /// The group the program `name` belongs to, with the group's name. A
+    /// cross tool belongs to the group of the tool without its triple.
+    pub fn group_of(&self, name: &str) -> Option<(&str, &RuleGroup)> {
+        let find = |name: &str| {
+            self.groups
+                .iter()
+                .find(|(_, group)| group.members.iter().any(|m| m == name))
+                .map(|(id, group)| (id.as_str(), group))
+        };
+        if self.groups.is_empty() {
+            return None;
+        }
+        find(name).or_else(|| {
+            let (_, tool) = split_triple_prefix(name, |tool| find(tool).is_some())?;
+            find(tool)
+        })
+    }
+
+    /// Every group, by name.
+    pub fn groups(&self) -> impl Iterator<Item = (&str, &RuleGroup)> {
+        self.groups
+            .iter()
+            .map(|(name, group)| (name.as_str(), group))
+    }
+
+    /// Let `NIXUBW_MEM_GB` in a matched process's environment override its
+    /// memory claim (`--profile-hint`).
+    pub fn set_mem_hint(&mut self, enabled: bool) {
+        self.mem_hint = enabled;
+    }
+
+    /// Set the resume weight of the class of processes matching rule `id`.
+    #[cfg(any(feature = "serde", test))]
+    pub fn set_weight(&mut self, id: String, weight: u32) {
+        self.weights.insert(id, weight);
+    }
+
+    /// Resume weight of rule `id`; 1 unless configured.
+    pub fn weight(&self, id: &str) -> u32 {
+        self.weights.get(id).copied().unwrap_or(1)
+    }
+
+    /// Whether any resume weights are configured, which switches resumes
+    /// from FIFO to weighted round-robin.
+    pub fn has_weights(&self) -> bool {
+        !self.weights.is_empty()
+    }
+
+    /// Set the memory a process matching rule `id` may actually use, in GiB,
+    /// before it is killed. Its profile's memory stays what it claims.
+    #[cfg(any(feature = "serde", test))]
+    pub fn set_mem_hard(&mut self, id: String, gb: u32) {
+        self.mem_hard.insert(id, gb);
+    }
+
+    /// Hard memory limit of processes matching rule `id`, in GiB, if any.
+    pub fn mem_hard(&self, id: &str) -> Option<u32> {
+        self.mem_hard.get(id).copied()
+    }
+
+    /// Whether any rule has a hard memory limit.
+    pub fn has_mem_hard(&self) -> bool {
+        !self.mem_hard.is_empty()
+    }
+
+    /// Set how long a process matching rule `id` typically runs.
+    #[cfg(any(feature = "serde", test))]
+    pub fn set_est_duration(&mut self, id: String, duration: Duration) {
+        self.est_durations.insert(id, duration);
+    }
+
+    /// Estimated run time of processes matching rule `id`, if configured.
+    pub fn est_duration(&self, id: &str) -> Option<Duration> {
+        self.est_durations.get(id).copied()
+    }
+
+    /// Scale the profiles of cross tools for `arch` (`--arch-scale`).
+    pub fn set_arch_scale(&mut self, scale: ArchScale) {
+        self.arch_scale.insert(scale.arch, scale.factor);
+    }
+
+    /// `profile` scaled by the factor for `arch`, rounded up.
+    fn scale_for_arch(&self, arch: &str, profile: ResourceProfile) -> ResourceProfile {
+        match self.arch_scale.get(arch) {
+            Some(&factor) => ResourceProfile::new(
+                (profile.cpus as f64 * factor).ceil() as i32,
+                (profile.mem_gb as f64 * factor).ceil() as i32,
+            ),
+            None => profile,
+        }
+    }
 
-    let profile = match name {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check every rule against the budget. A rule that claims more than the
+    /// whole budget could only ever run alone, through the failsafe.
+    pub fn validate(&self, total: &ResourceProfile) -> anyhow::Result<()> {
+        for entry in &self.entries {
+            let id = &entry.id;
+            if id.is_empty() {
+                bail!("rule without an id");
+            }
+            if let Matcher::Names(names) = &entry.matcher {
+                if names.is_empty() {
+                    bail!("rule {:?} matches no names", id);
+                }
+                if let Some(name) = names.iter().find(|n| self.is_nix_internal(n)) {
+                    if entry.profile.is_some() {
+                        bail!(
+                            "rule {:?} throttles {:?}, which is part of Nix and never throttled (see nix_internal)",
+                            id,
+                            name
+                        );
+                    }
+                }
+                if let Some(name) = names.iter().find(|n| n.is_empty() || n.contains('/')) {
+                    bail!(
+                        "rule {:?}: invalid name {:?}, expected a program basename",
+                        id,
+                        name
+                    );
+                }
+            }
+            if let Some(var) = entry
+                .env
+                .keys()
+                .find(|var| var.is_empty() || var.contains('='))
+            {
+                bail!("rule {:?}: invalid variable name {:?}", id, var);
+            }
+            let Some(spec) = entry.profile else {
+                continue;
+            };
+            let profile = spec.resolve(total);
+            if profile.cpus < 0 || profile.mem_gb < 0 {
+                bail!("rule {:?} has a negative claim ({})", id, spec);
+            }
+            if !profile.has_free_resources(total) {
+                bail!(
+                    "rule {:?} claims {}, more than the whole budget ({})",
+                    id,
+                    profile,
+                    total
+                );
+            }
+        }
+        for (id, max) in &self.instance_caps {
+            if *max == 0 {
+                bail!("rule {:?}: max_instances must be positive", id);
+            }
+        }
+        for (id, gb) in &self.mem_hard {
+            if *gb == 0 {
+                bail!("rule {:?}: mem_hard_gb must be positive", id);
+            }
+        }
+        for name in self.nix_internal.iter().flatten() {
+            if name.is_empty() || name.contains('/') {
+                bail!(
+                    "invalid nix_internal name {:?}: expected a program basename",
+                    name
+                );
+            }
+        }
+        for name in self.skip_if.keys() {
+            if name.is_empty() || name.contains('/') {
+                bail!(
+                    "invalid skip_if name {:?}: expected a program basename",
+                    name
+                );
+            }
+        }
+        for (name, vars) in &self.require_env {
+            if name.is_empty() || name.contains('/') {
+                bail!(
+                    "invalid require_env name {:?}: expected a program basename",
+                    name
+                );
+            }
+            if let Some(var) = vars.keys().find(|var| var.is_empty() || var.contains('=')) {
+                bail!("require_env of {:?}: invalid variable name {:?}", name, var);
+            }
+        }
+        let mut grouped = BTreeSet::new();
+        for (name, group) in &self.groups {
+            if group.members.is_empty() {
+                bail!("group {:?} has no members", name);
+            }
+            if let Some(member) = group
+                .members
+                .iter()
+                .find(|m| m.is_empty() || m.contains('/'))
+            {
+                bail!(
+                    "group {:?}: invalid member {:?}, expected a program basename",
+                    name,
+                    member
+                );
+            }
+            if let Some(member) = group.members.iter().find(|m| !grouped.insert(*m)) {
+                bail!("{:?} is a member of more than one group", member);
+            }
+            if group.max_instances == Some(0) {
+                bail!("group {:?}: max_instances must be positive", name);
+            }
+            if let Some(budget) = group.budget {
+                if budget.cpus <= 0 || budget.mem_gb <= 0 {
+                    bail!("group {:?}: budget must be positive, got {}", name, budget);
+                }
+            }
+        }
+        for (id, weight) in &self.weights {
+            if *weight == 0 {
+                bail!("weight of {:?} must be positive", id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Factor for the profiles of cross tools targeting one architecture
+/// (`--arch-scale ARCH=FACTOR`), e.g. for builds run under qemu user-mode
+/// emulation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchScale {
+    pub arch: String,
+    pub factor: f64,
+}
+
+impl FromStr for ArchScale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((arch, factor)) = s.split_once('=') else {
+            bail!("expected <arch>=<factor> (e.g. aarch64=1.5), got {:?}", s);
+        };
+        let factor: f64 = factor
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid factor in {:?}", s))?;
+        if !TRIPLE_ARCHES.contains(&arch.trim()) {
+            bail!(
+                "unknown architecture {:?} (expected one of {})",
+                arch,
+                TRIPLE_ARCHES.join(", ")
+            );
+        }
+        if !(factor.is_finite() && factor > 0.0) {
+            bail!("factor must be positive, got {:?}", s);
+        }
+        Ok(Self {
+            arch: arch.trim().to_owned(),
+            factor,
+        })
+    }
+}
+
+/// When an invocation of a program that matches a rule is trivial and is not
+/// throttled after all, e.g. `go version` or `go env` for the `go` rule.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct SkipIf {
+    /// Values of the first argument (a subcommand or a flag) that make the
+    /// invocation trivial.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub args: Vec<String>,
+    /// The invocation is trivial if it has fewer arguments than this, not
+    /// counting the program name.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub min_args: usize,
+}
+
+impl SkipIf {
+    pub fn matches(&self, args: &[String]) -> bool {
+        let rest = args.get(1..).unwrap_or_default();
+        rest.len() < self.min_args || rest.first().is_some_and(|arg| self.args.contains(arg))
+    }
+}
+
+/// A named family of programs whose processes share caps on top of the
+/// global budget (`[groups]`), e.g. all C and C++ compilers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct RuleGroup {
+    /// Program names, matched like rule names.
+    pub members: Vec<String>,
+    /// At most this many processes of the group are active at once.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_instances: Option<usize>,
+    /// What the group's active processes may claim together.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub budget: Option<ResourceProfile>,
+}
+
+/// A condition on one environment variable of a process (`[require_env]`).
+///
+/// Written as `true` for a variable that must be set, `false` for one that
+/// must not be, or a string the value must equal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(untagged))]
+pub enum EnvCondition {
+    Set(bool),
+    Equals(String),
+}
+
+impl EnvCondition {
+    /// Whether a variable with `value` (`None` if unset) meets the condition.
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        match self {
+            EnvCondition::Set(set) => value.is_some() == *set,
+            EnvCondition::Equals(expected) => value == Some(expected.as_str()),
+        }
+    }
+}
+
+/// First arguments that make an invocation of a built-in rule's program
+/// trivial, unless a config file sets a `SkipIf` for it.
+fn static_skip_args(name: &str) -> &'static [&'static str] {
+    match name {
+        "go" => &["env", "version", "help"],
+        _ => &[],
+    }
+}
+
+/// Reduced memory claim for additional concurrent instances of one program
+/// (`--sibling-mem-gb NAME=GIB`).
+///
+/// Parallel compiles of the same program share page cache (ccache, common
+/// headers, dependency metadata), so the first instance claims the rule's
+/// memory and every further instance running alongside it claims only
+/// `mem_gb`. Without one, every instance claims the rule's memory in full.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SiblingMem {
+    /// Resolved basename the scaling applies to.
+    pub name: String,
+    /// Memory claim of each instance beyond the first.
+    pub mem_gb: i32,
+}
+
+impl SiblingMem {
+    /// Effective profile for an instance starting while `running` others with
+    /// the same name are active. Never claims more than the rule itself.
+    pub fn apply(&self, profile: ResourceProfile, running: usize) -> ResourceProfile {
+        if running == 0 {
+            profile
+        } else {
+            ResourceProfile::new(profile.cpus, profile.mem_gb.min(self.mem_gb))
+        }
+    }
+}
+
+impl FromStr for SiblingMem {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((name, mem)) = s.split_once('=') else {
+            bail!("expected <name>=<mem_gb> (e.g. cc=1), got {:?}", s);
+        };
+        let mem_gb: i32 = mem
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid memory size in {:?}", s))?;
+        if name.trim().is_empty() || mem_gb < 0 {
+            bail!("expected <name>=<mem_gb> (e.g. cc=1), got {:?}", s);
+        }
+        Ok(Self {
+            name: name.trim().to_owned(),
+            mem_gb,
+        })
+    }
+}
+
+/// Ids of the rule groups in the built-in table, for `--disable-rules`.
+pub const BUILTIN_RULES: &[&str] = &[
+    "c-compiler",
+    "rustc",
+    "llvm-backend",
+    "lto-worker",
+    "collect2",
+    "gnu-ld",
+    "go",
+    "ghc",
+    "jvm",
+    "cuda",
+    "compressor",
+    "parallel-compressor",
+    "meta-build",
+    "interpreter",
+    "doc-codegen",
+];
+
+/// Whether `s` is a version number like `12` (of `python3.12`).
+fn is_version(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The built-in rule table, keyed on the resolved basename. Returns the rule's
+/// identifier along with its profile.
+fn static_profile_for(
+    name: &str,
+    total: &ResourceProfile,
+) -> Option<(&'static str, ResourceProfile)> {
+    let matched = match name {
         // --- C / C++ compilers ---
-        "cc" | "gcc" | "g++" | "c++" | "clang" | "clang++" => ResourceProfile::new(1, 1),
+        "cc" | "gcc" | "g++" | "c++" | "clang" | "clang++" => {
+            ("c-compiler", ResourceProfile::new(1, 1))
+        }
 
         // --- Rust compiler (memory-hungry) ---
-        "rustc" => ResourceProfile::new(1, 4),
+        "rustc" => ("rustc", ResourceProfile::new(1, 4)),
 
         // --- LLVM backend / linker ---
-        "llc" | "lld" | "ld.lld" => ResourceProfile::new(1, 2),
+        "llc" | "lld" | "ld.lld" => ("llvm-backend", ResourceProfile::new(1, 2)),
+
+        // --- Link-time optimization workers ---
+        // `gcc -flto` runs one `lto1` per partition (WPA and each LTRANS unit),
+        // `-flto=N` runs them in parallel. LLVM's standalone LTO drivers behave
+        // the same. Every worker is its own exec'd process, so each claims its
+        // own slot and N parallel workers of one link claim N slots.
+        "lto1" | "llvm-lto" | "llvm-lto2" => ("lto-worker", ResourceProfile::new(1, 2)),
+
+        // `lto-wrapper` only drives the `lto1` workers above (directly or through
+        // `make -jN`) and does no heavy work itself. Throttling it would make
+        // the link claim twice, so it is explicitly not throttled.
+        "lto-wrapper" => return None,
+
+        // --- GCC link driver ---
+        // `collect2` sits between `gcc` and the real `ld`. Its claim is a
+        // placeholder: the linker it spawns borrows it and only claims what it
+        // needs beyond it (see `Limiter`), so the chain counts once.
+        "collect2" => ("collect2", ResourceProfile::new(1, 1)),
 
         // --- GNU linker / gold ---
-        "ld" | "gold" => ResourceProfile::new(1, 1),
+        "ld" | "gold" => ("gnu-ld", ResourceProfile::new(1, 1)),
 
         // --- Go compiler ---
-        "go" => ResourceProfile::new(1, 1),
+        "go" => ("go", ResourceProfile::new(1, 1)),
 
         // --- Haskell (GHC is very memory hungry) ---
-        "ghc" => ResourceProfile::new(1, 4),
+        "ghc" => ("ghc", ResourceProfile::new(1, 4)),
 
         // --- JVM-based compilers ---
-        "java" | "javac" | "scalac" | "kotlinc" => ResourceProfile::new(1, 2),
+        "java" | "javac" | "scalac" | "kotlinc" => ("jvm", ResourceProfile::new(1, 2)),
 
         // --- CUDA toolchain (GPU compile, 1 CPU but lots of RAM) ---
-        "nvcc" | "ptxas" | "cicc" | "cudafe++" | "fatbinary" => ResourceProfile::new(1, 4),
+        "nvcc" | "ptxas" | "cicc" | "cudafe++" | "fatbinary" => {
+            ("cuda", ResourceProfile::new(1, 4))
+        }
 
         // --- Compression / Decompression (Single-threaded baseline) ---
         "gzip" | "gunzip" | "xz" | "unxz" | "bzip2" | "bunzip2" | "zstd" | "unzstd" | "zip"
-        | "unzip" | "tar" => ResourceProfile::new(1, 1),
+        | "unzip" | "tar" => ("compressor", ResourceProfile::new(1, 1)),
 
         // --- Parallel Compressors (Scales to budget) ---
-        "pigz" | "7z" | "7za" | "pixz" => ResourceProfile::new(total.cpus, 1),
+        "pigz" | "7z" | "7za" | "pixz" => {
+            ("parallel-compressor", ResourceProfile::new(total.cpus, 1))
+        }
+
+        // --- Meta-build tools ---
+        // Configure steps of big projects can take a while and a fair amount
+        // of memory, but most invocations are short.
+        "cmake" | "meson" => ("meta-build", ResourceProfile::new(1, 1)),
+
+        // `ninja` (like `make`) only schedules the compilers it runs, which
+        // are throttled on their own. Throttling it too would count its jobs
+        // twice and could stall a build on the scheduler's claim.
+        "ninja" | "samu" => return None,
+
+        // --- Interpreters ---
+        // Code generators and web asset builds. Many runs are tiny scripts,
+        // which is why this group is easy to turn off (`--disable-rules
+        // interpreter`).
+        "python" | "python3" | "node" => ("interpreter", ResourceProfile::new(1, 1)),
+        python if python.strip_prefix("python3.").is_some_and(is_version) => {
+            ("interpreter", ResourceProfile::new(1, 1))
+        }
+
+        // --- Documentation and code generators ---
+        // Single-threaded, but large projects and big LLVM targets can push
+        // them past a gigabyte. Further from the compiler core than the rules
+        // above, so the whole group goes with `--disable-rules doc-codegen`.
+        "doxygen" | "llvm-tblgen" | "clang-tblgen" | "mlir-tblgen" => {
+            ("doc-codegen", ResourceProfile::new(1, 2))
+        }
+        "sphinx-build" => ("doc-codegen", ResourceProfile::new(1, 1)),
 
         // Everything else (orchestrators, wrappers, etc.) is not throttled.
         _ => return None,
     };
 
-    Some(profile)
+    Some(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOTAL: ResourceProfile = ResourceProfile::new(8, 16);
+
+    fn hinted() -> RuleSet {
+        let mut rules = RuleSet::default();
+        rules.set_mem_hint(true);
+        rules
+    }
+
+    #[test]
+    fn test_mem_hint_overrides_rule() {
+        let env = Environ::from_vars(&[(MEM_HINT_VAR, "12")]);
+        let matched = profile_for(&["rustc".into()], &env, &hinted(), &TOTAL);
+        let matched = matched.unwrap();
+        assert_eq!(matched.profile, ResourceProfile::new(1, 12));
+        assert_eq!(matched.rule, RuleId::Builtin("rustc"));
+    }
+
+    #[test]
+    fn test_mem_hint_ignored_unless_enabled() {
+        let env = Environ::from_vars(&[(MEM_HINT_VAR, "12")]);
+        let matched = profile_for(&["rustc".into()], &env, &RuleSet::default(), &TOTAL);
+        assert_eq!(matched.unwrap().profile, ResourceProfile::new(1, 4));
+    }
+
+    #[test]
+    fn test_mem_hint_invalid() {
+        for hint in ["lots", "-1", ""] {
+            let env = Environ::from_vars(&[(MEM_HINT_VAR, hint)]);
+            let matched = profile_for(&["rustc".into()], &env, &hinted(), &TOTAL);
+            assert_eq!(matched.unwrap().profile, ResourceProfile::new(1, 4));
+        }
+    }
+
+    #[test]
+    fn test_mem_hint_does_not_throttle_unknown() {
+        let env = Environ::from_vars(&[(MEM_HINT_VAR, "12")]);
+        assert_eq!(profile_for(&["bash".into()], &env, &hinted(), &TOTAL), None);
+    }
+
+    #[test]
+    fn test_group_of() {
+        let mut rules = RuleSet::default();
+        assert_eq!(rules.group_of("gcc"), None);
+        let cxx = RuleGroup {
+            members: vec!["cc".into(), "gcc".into(), "g++".into(), "clang".into()],
+            max_instances: Some(4),
+            budget: None,
+        };
+        rules.set_group("cxx".into(), cxx.clone());
+        assert_eq!(rules.group_of("gcc"), Some(("cxx", &cxx)));
+        assert_eq!(
+            rules.group_of("aarch64-unknown-linux-gnu-g++"),
+            Some(("cxx", &cxx))
+        );
+        assert_eq!(rules.group_of("rustc"), None);
+        assert!(rules.validate(&TOTAL).is_ok());
+
+        let mut twice = rules.clone();
+        twice.set_group(
+            "c".into(),
+            RuleGroup {
+                members: vec!["cc".into()],
+                ..Default::default()
+            },
+        );
+        assert!(twice.validate(&TOTAL).is_err());
+        let mut empty = rules.clone();
+        empty.set_group(
+            "none".into(),
+            RuleGroup {
+                max_instances: Some(0),
+                members: vec!["ld".into()],
+                ..Default::default()
+            },
+        );
+        assert!(empty.validate(&TOTAL).is_err());
+    }
+
+    #[test]
+    fn test_require_env() {
+        let mut rules = RuleSet::default();
+        rules.set_require_env(
+            "gcc".into(),
+            BTreeMap::from([
+                ("NIX_BUILD_TOP".into(), EnvCondition::Set(true)),
+                ("IN_NIX_SHELL".into(), EnvCondition::Set(false)),
+            ]),
+        );
+        rules.set_require_env(
+            "rustc".into(),
+            BTreeMap::from([(
+                "CARGO_PROFILE".into(),
+                EnvCondition::Equals("release".into()),
+            )]),
+        );
+        let throttled = |name: &str, vars: &[(&str, &str)]| {
+            let env = Environ::from_vars(vars);
+            profile_for(&[name.into()], &env, &rules, &TOTAL).is_some()
+        };
+        assert!(throttled("gcc", &[("NIX_BUILD_TOP", "/build")]));
+        assert!(!throttled("gcc", &[]));
+        assert!(!throttled(
+            "gcc",
+            &[("NIX_BUILD_TOP", "/build"), ("IN_NIX_SHELL", "impure")]
+        ));
+        assert!(throttled("rustc", &[("CARGO_PROFILE", "release")]));
+        assert!(!throttled("rustc", &[("CARGO_PROFILE", "debug")]));
+        // Other programs of the same rule are unaffected.
+        assert!(throttled("cc", &[]));
+
+        let mut invalid = RuleSet::default();
+        invalid.set_require_env(
+            "gcc".into(),
+            BTreeMap::from([("A=B".into(), EnvCondition::Set(true))]),
+        );
+        assert!(invalid.validate(&TOTAL).is_err());
+    }
+
+    #[test]
+    fn test_rule_set_overrides_builtin() {
+        let mut rules = RuleSet::default();
+        rules.insert("rustc".into(), Some(ResourceProfile::new(1, 8)));
+        rules.insert("tar".into(), None);
+        rules.insert("mytool".into(), Some(ResourceProfile::new(2, 2)));
+        let profile = |name: &str| {
+            profile_for(&[name.into()], &Environ::default(), &rules, &TOTAL).map(|m| m.profile)
+        };
+        assert_eq!(profile("rustc"), Some(ResourceProfile::new(1, 8)));
+        assert_eq!(profile("tar"), None);
+        assert_eq!(profile("mytool"), Some(ResourceProfile::new(2, 2)));
+        assert_eq!(profile("gcc"), Some(ResourceProfile::new(1, 1)));
+        let matched =
+            |name: &str| profile_for(&[name.into()], &Environ::default(), &rules, &TOTAL).unwrap();
+        assert_eq!(matched("rustc").rule, RuleId::Config("rustc".into()));
+        assert_eq!(matched("g++").rule, RuleId::Builtin("c-compiler"));
+        assert_eq!(matched("rustc").rule.to_string(), "config:rustc");
+        assert!(rules.validate(&TOTAL).is_ok());
+
+        rules.insert("huge".into(), Some(ResourceProfile::new(1, 64)));
+        assert!(rules.validate(&TOTAL).is_err());
+    }
+
+    fn entry(id: &str, matcher: Matcher, profile: Option<ResourceProfile>) -> RuleEntry {
+        RuleEntry {
+            id: id.into(),
+            matcher,
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            profile: profile.map(ProfileSpec::from),
+            scale_cpus: None,
+        }
+    }
+
+    fn names(names: &[&str]) -> Matcher {
+        Matcher::Names(names.iter().map(|&n| n.into()).collect())
+    }
+
+    fn pattern(regex: &str) -> Matcher {
+        Matcher::Pattern(regex.parse().unwrap())
+    }
+
+    fn rule_of(rules: &RuleSet, args: &[&str], vars: &[(&str, &str)]) -> Option<String> {
+        let args: Vec<String> = args.iter().map(|&a| a.into()).collect();
+        profile_for(&args, &Environ::from_vars(vars), rules, &TOTAL).map(|m| m.rule.to_string())
+    }
+
+    #[test]
+    fn test_rule_list_first_match_wins() {
+        let mut rules = RuleSet::default();
+        rules.prepend(vec![
+            RuleEntry {
+                args: vec!["--crate-type".into(), "proc-macro".into()],
+                ..entry(
+                    "proc-macro",
+                    names(&["rustc"]),
+                    Some(ResourceProfile::new(1, 1)),
+                )
+            },
+            entry("rustc", names(&["rustc"]), Some(ResourceProfile::new(1, 6))),
+            entry("cc-off", names(&["cc", "gcc"]), None),
+            entry(
+                "clang",
+                pattern("clang(\\+\\+)?-[0-9]+"),
+                Some(ResourceProfile::new(1, 2)),
+            ),
+            entry("any-c", pattern(".*cc"), Some(ResourceProfile::new(2, 2))),
+        ]);
+        let rule = |args: &[&str]| rule_of(&rules, args, &[]);
+        // The specific rule goes first, so it wins where it applies; the
+        // generic one takes the rest.
+        assert_eq!(
+            rule(&["rustc", "--crate-type", "proc-macro", "--edition", "2021"]).unwrap(),
+            "config:proc-macro"
+        );
+        assert_eq!(
+            rule(&["rustc", "--crate-type", "lib"]).unwrap(),
+            "config:rustc"
+        );
+        // Arguments must all be there, in any order.
+        assert_eq!(
+            rule(&["rustc", "proc-macro", "-O", "--crate-type"]).unwrap(),
+            "config:proc-macro"
+        );
+        assert_eq!(rule(&["rustc", "proc-macro"]).unwrap(), "config:rustc");
+        // A rule that never throttles ends the lookup too, even if later
+        // rules or the built-in table would match.
+        assert_eq!(rule(&["gcc"]), None);
+        assert_eq!(rule(&["icc"]).unwrap(), "config:any-c");
+        // Patterns match whole names.
+        assert_eq!(rule(&["clang-17"]).unwrap(), "config:clang");
+        assert_eq!(rule(&["clang++-17"]).unwrap(), "config:clang");
+        assert_eq!(rule(&["clang-17-wrapper"]), None);
+        assert_eq!(rule(&["clang"]).unwrap(), "c-compiler");
+        // Cross tools are matched without their triple.
+        assert_eq!(
+            rule(&["aarch64-unknown-linux-gnu-clang-17"]).unwrap(),
+            "config:clang"
+        );
+
+        // With the generic rule first, the specific one is never reached.
+        let mut generic_first = RuleSet::default();
+        generic_first.prepend(vec![
+            entry("rustc", names(&["rustc"]), Some(ResourceProfile::new(1, 6))),
+            RuleEntry {
+                args: vec!["proc-macro".into()],
+                ..entry(
+                    "proc-macro",
+                    names(&["rustc"]),
+                    Some(ResourceProfile::new(1, 1)),
+                )
+            },
+        ]);
+        assert_eq!(
+            rule_of(&generic_first, &["rustc", "proc-macro"], &[]).unwrap(),
+            "config:rustc"
+        );
+    }
+
+    #[test]
+    fn test_rule_scale_cpus() {
+        let mut rules = RuleSet::default();
+        rules.prepend(vec![
+            RuleEntry {
+                scale_cpus: Some("-j ?([0-9]+)".parse().unwrap()),
+                ..entry("make", names(&["make"]), Some(ResourceProfile::new(1, 1)))
+            },
+            RuleEntry {
+                scale_cpus: Some("(?:-C ?)?codegen-units=([0-9]+)".parse().unwrap()),
+                ..entry("rustc", names(&["rustc"]), Some(ResourceProfile::new(1, 4)))
+            },
+        ]);
+        let cpus = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|&a| a.into()).collect();
+            profile_for(&args, &Environ::from_vars(&[]), &rules, &TOTAL)
+                .unwrap()
+                .profile
+                .cpus
+        };
+        assert_eq!(cpus(&["make"]), 1);
+        assert_eq!(cpus(&["make", "-j1"]), 1);
+        assert_eq!(cpus(&["make", "-j4"]), 4);
+        assert_eq!(cpus(&["make", "-j", "3", "install"]), 3);
+        // The last flag counts, and the whole budget is the most.
+        assert_eq!(cpus(&["make", "-j2", "-j6"]), 6);
+        assert_eq!(cpus(&["make", "-j64"]), TOTAL.cpus);
+        assert_eq!(cpus(&["make", "-j0"]), 1);
+        // Arguments are matched whole.
+        assert_eq!(cpus(&["make", "all-j4"]), 1);
+
+        assert_eq!(cpus(&["rustc", "-Ccodegen-units=2"]), 2);
+        assert_eq!(cpus(&["rustc", "-C", "codegen-units=3"]), 3);
+        assert_eq!(cpus(&["rustc", "-C", "opt-level=3"]), 1);
+        assert_eq!(cpus(&["rustc", "-Ccodegen-units=256"]), TOTAL.cpus);
+
+        assert!("-j[0-9]+".parse::<ArgValue>().is_err());
+    }
+
+    #[test]
+    fn test_rule_list_env_predicate() {
+        let mut rules = RuleSet::default();
+        rules.prepend(vec![
+            RuleEntry {
+                env: BTreeMap::from([("IN_NIX_SHELL".into(), EnvCondition::Set(true))]),
+                ..entry("dev-shell", names(&["gcc"]), None)
+            },
+            RuleEntry {
+                env: BTreeMap::from([("LTO".into(), EnvCondition::Equals("1".into()))]),
+                ..entry("lto", pattern("g?cc"), Some(ResourceProfile::new(2, 4)))
+            },
+        ]);
+        let rule = |args: &[&str], vars: &[(&str, &str)]| rule_of(&rules, args, vars);
+        assert_eq!(rule(&["gcc"], &[("IN_NIX_SHELL", "impure")]), None);
+        assert_eq!(rule(&["gcc"], &[("LTO", "1")]).unwrap(), "config:lto");
+        // Rules whose conditions fail are passed over, down to the built-in
+        // table.
+        assert_eq!(rule(&["gcc"], &[("LTO", "0")]).unwrap(), "c-compiler");
+        assert_eq!(rule(&["cc"], &[("LTO", "1")]).unwrap(), "config:lto");
+    }
+
+    #[test]
+    fn test_rule_list_prepend() {
+        let mut rules = RuleSet::default();
+        rules.prepend(vec![
+            entry("gcc", names(&["gcc"]), Some(ResourceProfile::new(1, 2))),
+            entry("c", pattern("gcc|cc"), Some(ResourceProfile::new(1, 3))),
+        ]);
+        rules.insert("cc".into(), Some(ResourceProfile::new(1, 4)));
+        assert_eq!(rule_of(&rules, &["cc"], &[]).unwrap(), "config:cc");
+        assert_eq!(rule_of(&rules, &["gcc"], &[]).unwrap(), "config:gcc");
+        // A plain rule for the same name replaces the earlier one.
+        rules.insert("gcc".into(), None);
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rule_of(&rules, &["gcc"], &[]), None);
+        assert!(rules.validate(&TOTAL).is_ok());
+
+        for bad in [
+            entry("", names(&["cc"]), None),
+            entry("none", names(&[]), None),
+            entry("path", names(&["/bin/cc"]), None),
+            entry("huge", pattern("cc"), Some(ResourceProfile::new(1, 64))),
+            RuleEntry {
+                env: BTreeMap::from([("A=B".into(), EnvCondition::Set(true))]),
+                ..entry("env", names(&["cc"]), None)
+            },
+        ] {
+            let mut invalid = RuleSet::default();
+            invalid.prepend(vec![bad.clone()]);
+            assert!(invalid.validate(&TOTAL).is_err(), "{:?}", bad);
+        }
+        assert!("cc(".parse::<NamePattern>().is_err());
+    }
+
+    #[test]
+    fn test_nix_internal_never_throttled() {
+        let mut rules = RuleSet::default();
+        rules.prepend(vec![entry(
+            "all",
+            pattern(".*"),
+            Some(ResourceProfile::new(1, 1)),
+        )]);
+        assert_eq!(rule_of(&rules, &["nix-store"], &[]), None);
+        assert_eq!(rule_of(&rules, &["nix"], &[]), None);
+        assert_eq!(rule_of(&rules, &["bash"], &[]).unwrap(), "config:all");
+        assert!(rules.validate(&TOTAL).is_ok());
+
+        // A list of one's own replaces the built-in one.
+        rules.set_nix_internal(BTreeSet::from(["bash".into()]));
+        assert_eq!(rule_of(&rules, &["bash"], &[]), None);
+        assert_eq!(rule_of(&rules, &["nix"], &[]).unwrap(), "config:all");
+
+        // Naming one of them in a rule that throttles it is a mistake.
+        let mut invalid = RuleSet::default();
+        invalid.insert("nix".into(), Some(ResourceProfile::new(1, 1)));
+        assert!(invalid.validate(&TOTAL).is_err());
+        invalid.insert("nix".into(), None);
+        assert!(invalid.validate(&TOTAL).is_ok());
+    }
+
+    #[test]
+    fn test_meta_build_and_interpreters() {
+        for (name, id) in [
+            ("cmake", "meta-build"),
+            ("meson", "meta-build"),
+            ("python", "interpreter"),
+            ("python3", "interpreter"),
+            ("python3.12", "interpreter"),
+            ("node", "interpreter"),
+        ] {
+            let matched = static_profile_for(name, &TOTAL);
+            assert_eq!(matched, Some((id, ResourceProfile::new(1, 1))), "{}", name);
+        }
+        for name in ["ninja", "samu", "python3.", "python3.x", "pythonista"] {
+            assert_eq!(static_profile_for(name, &TOTAL), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_doc_codegen() {
+        for (name, mem) in [
+            ("doxygen", 2),
+            ("sphinx-build", 1),
+            ("llvm-tblgen", 2),
+            ("clang-tblgen", 2),
+            ("mlir-tblgen", 2),
+        ] {
+            let matched = static_profile_for(name, &TOTAL);
+            assert_eq!(
+                matched,
+                Some(("doc-codegen", ResourceProfile::new(1, mem))),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(static_profile_for("tblgen", &TOTAL), None);
+
+        let mut rules = RuleSet::default();
+        rules.disable_builtin("doc-codegen").unwrap();
+        for name in ["doxygen", "sphinx-build", "llvm-tblgen"] {
+            assert!(profile_for(&[name.into()], &Environ::default(), &rules, &TOTAL).is_none());
+        }
+    }
+
+    #[test]
+    fn test_disable_builtin() {
+        let mut rules = RuleSet::default();
+        rules.disable_builtin("interpreter").unwrap();
+        assert!(rules.disable_builtin("python").is_err());
+        for name in ["python3", "node"] {
+            assert!(profile_for(&[name.into()], &Environ::default(), &rules, &TOTAL).is_none());
+        }
+        assert!(profile_for(&["cmake".into()], &Environ::default(), &rules, &TOTAL).is_some());
+
+        // A configured rule still applies to a program of a disabled group.
+        rules.insert("node".into(), Some(ResourceProfile::new(1, 2)));
+        assert!(profile_for(&["node".into()], &Environ::default(), &rules, &TOTAL).is_some());
+    }
+
+    #[test]
+    fn test_builtin_rules_listed() {
+        let names = [
+            "cc", "rustc", "llc", "lto1", "collect2", "ld", "go", "ghc", "java", "nvcc", "gzip",
+            "pigz", "cmake", "python3", "doxygen",
+        ];
+        let mut ids: Vec<&str> = names
+            .iter()
+            .map(|name| static_profile_for(name, &TOTAL).unwrap().0)
+            .collect();
+        ids.dedup();
+        assert_eq!(ids, BUILTIN_RULES);
+    }
+
+    #[test]
+    fn test_skip_trivial_invocations() {
+        let rules = RuleSet::default();
+        let profile = |rules: &RuleSet, args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|&a| a.into()).collect();
+            profile_for(&args, &Environ::default(), rules, &TOTAL).map(|m| m.profile)
+        };
+        assert_eq!(
+            profile(&rules, &["go", "build", "./..."]),
+            Some(ResourceProfile::new(1, 1))
+        );
+        assert_eq!(profile(&rules, &["go", "version"]), None);
+        assert_eq!(profile(&rules, &["go", "env", "GOPATH"]), None);
+        assert_eq!(profile(&rules, &["x86_64-linux-gnu-go", "version"]), None);
+        // Only the first argument counts.
+        assert!(profile(&rules, &["go", "build", "version"]).is_some());
+
+        // A config condition replaces the built-in one.
+        let mut rules = RuleSet::default();
+        rules.set_skip_if(
+            "go".into(),
+            SkipIf {
+                args: vec!["vet".into()],
+                min_args: 1,
+            },
+        );
+        assert!(profile(&rules, &["go", "version"]).is_some());
+        assert_eq!(profile(&rules, &["go", "vet"]), None);
+        assert_eq!(profile(&rules, &["go"]), None);
+        rules.set_skip_if(
+            "rustc".into(),
+            SkipIf {
+                args: vec!["-vV".into(), "--version".into()],
+                min_args: 0,
+            },
+        );
+        assert_eq!(profile(&rules, &["rustc", "-vV"]), None);
+        assert!(profile(&rules, &["rustc", "--crate-name", "foo"]).is_some());
+    }
+
+    #[test]
+    fn test_split_triple_prefix() {
+        let is_tool = |tool: &str| static_profile_for(tool, &TOTAL).is_some();
+        for (name, arch, tool) in [
+            ("aarch64-unknown-linux-gnu-gcc", "aarch64", "gcc"),
+            ("aarch64-linux-gnu-g++", "aarch64", "g++"),
+            ("x86_64-w64-mingw32-gcc", "x86_64", "gcc"),
+            ("arm-none-eabi-ld", "arm", "ld"),
+            ("riscv64-unknown-linux-gnu-ld.lld", "riscv64", "ld.lld"),
+            (
+                "armv7l-unknown-linux-gnueabihf-collect2",
+                "armv7l",
+                "collect2",
+            ),
+            ("wasm32-wasi-clang", "wasm32", "clang"),
+        ] {
+            assert_eq!(
+                split_triple_prefix(name, is_tool),
+                Some((arch, tool)),
+                "{}",
+                name
+            );
+        }
+        for name in [
+            "gcc",
+            "x86_64",
+            "x86_64-",
+            "foo-linux-gnu-gcc",
+            "aarch64-linux-gnu-make",
+        ] {
+            assert_eq!(split_triple_prefix(name, is_tool), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_cross_tool_profile() {
+        let mut rules = RuleSet::default();
+        let profile = |rules: &RuleSet, name: &str| {
+            profile_for(&[name.into()], &Environ::default(), rules, &TOTAL).map(|m| m.profile)
+        };
+        assert_eq!(
+            profile(&rules, "aarch64-unknown-linux-gnu-g++"),
+            Some(ResourceProfile::new(1, 1))
+        );
+        rules.set_arch_scale("aarch64=1.5".parse().unwrap());
+        rules.insert("gcc".into(), Some(ResourceProfile::new(1, 3)));
+        assert_eq!(
+            profile(&rules, "aarch64-unknown-linux-gnu-gcc"),
+            Some(ResourceProfile::new(2, 5))
+        );
+        // Native tools and other architectures are not scaled.
+        assert_eq!(profile(&rules, "gcc"), Some(ResourceProfile::new(1, 3)));
+        assert_eq!(
+            profile(&rules, "riscv64-linux-gnu-gcc"),
+            Some(ResourceProfile::new(1, 3))
+        );
+        // Opting out the full name isn't undone by the tool's rule.
+        rules.insert("x86_64-unknown-linux-gnu-gcc".into(), None);
+        assert_eq!(profile(&rules, "x86_64-unknown-linux-gnu-gcc"), None);
+        assert_eq!(
+            profile(&rules, "aarch64-unknown-linux-gnu-gcc"),
+            Some(ResourceProfile::new(2, 5))
+        );
+
+        for bad in [
+            "aarch64",
+            "aarch64=0",
+            "aarch64=-1",
+            "aarch64=fast",
+            "z80=2",
+        ] {
+            assert!(bad.parse::<ArchScale>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_sibling_mem() {
+        let sibling: SiblingMem = "rustc=2".parse().unwrap();
+        assert_eq!(sibling.name, "rustc");
+        let rule = ResourceProfile::new(1, 4);
+        assert_eq!(sibling.apply(rule, 0), rule);
+        assert_eq!(sibling.apply(rule, 3), ResourceProfile::new(1, 2));
+        // Never more than the rule.
+        let big: SiblingMem = "rustc=8".parse().unwrap();
+        assert_eq!(big.apply(rule, 1), rule);
+
+        for bad in ["rustc", "=2", "rustc=-1", "rustc=lots"] {
+            assert!(bad.parse::<SiblingMem>().is_err(), "{}", bad);
+        }
+    }
 }
@@ -0,0 +1,119 @@
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::limiter::Limiter;
+
+/// Periodic background work that samples or publishes the limiter's state,
+/// such as the pressure file and the checkpoint. Samplers have no timers of
+/// their own: the main loop's tick drives them all at `--sample-interval`.
+pub trait Sampler {
+    fn on_tick(&mut self, limiter: &mut Limiter);
+}
+
+/// The samplers of a run, and when they are next due.
+///
+/// The main loop may tick more often than `interval` (to end grace periods
+/// and debounce windows on time), so `tick` only runs the samplers once the
+/// interval has passed. Deadlines advance by whole intervals, so a tick that
+/// is handled a little late doesn't push back the ones after it.
+pub struct Samplers {
+    interval: Duration,
+    samplers: Vec<Box<dyn Sampler>>,
+    /// When the samplers are next due, `None` before the first tick.
+    due: Option<Instant>,
+    clock: Box<dyn Clock>,
+}
+
+impl Samplers {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            samplers: Vec::new(),
+            due: None,
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Use `clock` instead of the system clock to tell when samplers are due.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    pub fn add(&mut self, sampler: impl Sampler + 'static) {
+        self.samplers.push(Box::new(sampler));
+    }
+
+    /// Called on every tick of the main loop: run the samplers if they are
+    /// due. Returns whether they ran.
+    pub fn tick(&mut self, limiter: &mut Limiter) -> bool {
+        let now = self.clock.now();
+        if self.due.is_some_and(|due| now < due) {
+            return false;
+        }
+        let next = self.due.map_or(now, |due| due + self.interval);
+        // After a stall, start counting from now rather than catching up.
+        self.due = Some(if next <= now {
+            now + self.interval
+        } else {
+            next
+        });
+        self.run(limiter);
+        true
+    }
+
+    /// Run every sampler now, whether due or not, e.g. once more on shutdown.
+    pub fn run(&mut self, limiter: &mut Limiter) {
+        for sampler in &mut self.samplers {
+            sampler.on_tick(limiter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::resources::ResourceProfile;
+
+    struct Counter(Rc<Cell<usize>>);
+
+    impl Sampler for Counter {
+        fn on_tick(&mut self, _: &mut Limiter) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_runs_at_interval() {
+        let clock = MockClock::new();
+        let count = Rc::new(Cell::new(0));
+        let mut samplers = Samplers::new(Duration::from_millis(200)).with_clock(clock.clone());
+        samplers.add(Counter(count.clone()));
+        let mut limiter = Limiter::new(ResourceProfile::new(1, 1), true);
+
+        // Due on the first tick, then not before the interval has passed.
+        assert!(samplers.tick(&mut limiter));
+        clock.advance(Duration::from_millis(50));
+        assert!(!samplers.tick(&mut limiter));
+        clock.advance(Duration::from_millis(160));
+        assert!(samplers.tick(&mut limiter));
+        assert_eq!(count.get(), 2);
+
+        // That tick was 10ms late; the next one is still due at 400ms.
+        clock.advance(Duration::from_millis(190));
+        assert!(samplers.tick(&mut limiter));
+
+        // After a stall, ticks don't bunch up to catch up.
+        clock.advance(Duration::from_secs(2));
+        assert!(samplers.tick(&mut limiter));
+        assert!(!samplers.tick(&mut limiter));
+        assert_eq!(count.get(), 4);
+
+        samplers.run(&mut limiter);
+        assert_eq!(count.get(), 5);
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::{getpid, Pid};
+
+use crate::resources::ResourceProfile;
+
+/// Claims of all nix-ubw instances sharing one machine, kept in a file so that
+/// independent instances (e.g. one per NUMA node) don't collectively
+/// over-admit.
+///
+/// The file holds one line per instance, `<pid> <cpus> <mem_gb>`, giving the
+/// resources that instance's active processes currently claim. Every access
+/// takes an exclusive `flock` on the file for the whole read-modify-write.
+/// Lines whose PID no longer exists belong to crashed instances and are
+/// dropped on the next write.
+pub struct SharedState {
+    path: PathBuf,
+    pid: Pid,
+}
+
+impl SharedState {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            pid: getpid(),
+        }
+    }
+
+    /// Record our own current claim and return the sum of every other live
+    /// instance's claim.
+    pub fn publish(&self, claimed: ResourceProfile) -> ResourceProfile {
+        self.update(Some(claimed)).unwrap_or_else(|e| {
+            warn!("Failed to update shared state {:?}: {:#}", self.path, e);
+            ResourceProfile::new(0, 0)
+        })
+    }
+
+    /// Remove our own line, e.g. on shutdown.
+    pub fn withdraw(&self) {
+        if let Err(e) = self.update(None) {
+            warn!(
+                "Failed to withdraw from shared state {:?}: {:#}",
+                self.path, e
+            );
+        }
+    }
+
+    fn update(&self, claimed: Option<ResourceProfile>) -> Result<ResourceProfile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {:?}", self.path))?;
+        let mut file = lock(file)?;
+
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+
+        let mut entries: Vec<(Pid, ResourceProfile)> = parse_entries(&data)
+            .into_iter()
+            .filter(|&(pid, _)| pid != self.pid)
+            .filter(|&(pid, _)| {
+                let alive = is_alive(pid);
+                if !alive {
+                    debug!("Dropping stale shared state entry of PID {}", pid);
+                }
+                alive
+            })
+            .collect();
+        let others = entries
+            .iter()
+            .fold(ResourceProfile::new(0, 0), |sum, &(_, p)| sum + p);
+
+        if let Some(claimed) = claimed {
+            entries.push((self.pid, claimed));
+        }
+        rewrite(&mut file, &format_entries(&entries))?;
+        Ok(others)
+    }
+}
+
+impl Drop for SharedState {
+    fn drop(&mut self) {
+        self.withdraw();
+    }
+}
+
+/// Take an exclusive lock, retrying if a signal (e.g. the main loop's tick)
+/// interrupts the wait.
+fn lock(mut file: File) -> Result<Flock<File>> {
+    loop {
+        match Flock::lock(file, FlockArg::LockExclusive) {
+            Ok(locked) => return Ok(locked),
+            Err((f, Errno::EINTR)) => file = f,
+            Err((_, e)) => return Err(e).context("Failed to lock"),
+        }
+    }
+}
+
+fn rewrite(file: &mut File, data: &str) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.set_len(0)?;
+    file.write_all(data.as_bytes())?;
+    Ok(())
+}
+
+/// Whether a process with this PID exists. EPERM still means it exists.
+fn is_alive(pid: Pid) -> bool {
+    !matches!(kill(pid, None), Err(Errno::ESRCH))
+}
+
+/// Parse the shared state file, skipping malformed lines.
+fn parse_entries(data: &str) -> Vec<(Pid, ResourceProfile)> {
+    data.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse().ok()?;
+            let cpus = fields.next()?.parse().ok()?;
+            let mem_gb = fields.next()?.parse().ok()?;
+            Some((Pid::from_raw(pid), ResourceProfile::new(cpus, mem_gb)))
+        })
+        .collect()
+}
+
+fn format_entries(entries: &[(Pid, ResourceProfile)]) -> String {
+    entries
+        .iter()
+        .map(|(pid, p)| format!("{} {} {}\n", pid, p.cpus, p.mem_gb))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PID above the kernel's maximum, so it never belongs to a live process.
+    const DEAD_PID: i32 = 0x7fff_fff0;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nix-ubw-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        let entries = parse_entries("1 2 4\ngarbage\n\n300 1 1\n");
+        assert_eq!(
+            entries,
+            vec![
+                (Pid::from_raw(1), ResourceProfile::new(2, 4)),
+                (Pid::from_raw(300), ResourceProfile::new(1, 1)),
+            ]
+        );
+        assert_eq!(parse_entries(&format_entries(&entries)), entries);
+    }
+
+    #[test]
+    fn test_publish_skips_self_and_stale() {
+        let path = temp_path("shared-publish");
+        std::fs::write(&path, format!("1 2 4\n{} 8 8\n", DEAD_PID)).unwrap();
+
+        let state = SharedState::new(path.clone());
+        // PID 1 always exists; the dead PID is dropped.
+        assert_eq!(
+            state.publish(ResourceProfile::new(1, 1)),
+            ResourceProfile::new(2, 4)
+        );
+        let own = format!("{} 1 1\n", getpid());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("1 2 4\n{}", own)
+        );
+
+        // Republishing replaces our own line instead of adding to it.
+        assert_eq!(
+            state.publish(ResourceProfile::new(3, 3)),
+            ResourceProfile::new(2, 4)
+        );
+        drop(state);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1 2 4\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static TICK: AtomicBool = AtomicBool::new(false);
+static SUMMARY: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown_signal(_: nix::libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_summary_signal(_: nix::libc::c_int) {
+    SUMMARY.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_reload_signal(_: nix::libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_tick_signal(_: nix::libc::c_int) {
+    TICK.store(true, Ordering::SeqCst);
+}
+
+/// Install handlers for SIGINT/SIGTERM that request an orderly shutdown, for
+/// SIGUSR1 that requests a summary of the run so far, and for SIGHUP that
+/// requests a reload of the rules.
+///
+/// The handlers are installed without `SA_RESTART`, so a blocking `waitpid`
+/// in the main loop returns `EINTR` and the loop gets a chance to notice.
+pub fn install() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_shutdown_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for sig in [Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        unsafe { sigaction(sig, &action) }
+            .with_context(|| format!("Failed to install {} handler", sig))?;
+    }
+    let action = SigAction::new(
+        SigHandler::Handler(on_summary_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe { sigaction(Signal::SIGUSR1, &action) }.context("Failed to install SIGUSR1 handler")?;
+    let action = SigAction::new(
+        SigHandler::Handler(on_reload_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe { sigaction(Signal::SIGHUP, &action) }.context("Failed to install SIGHUP handler")?;
+    Ok(())
+}
+
+/// Whether SIGINT/SIGTERM has been received.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Whether SIGUSR1 has been received since the last call.
+pub fn take_summary_request() -> bool {
+    SUMMARY.swap(false, Ordering::SeqCst)
+}
+
+/// Whether SIGHUP has been received since the last call.
+pub fn take_reload_request() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// Deliver SIGALRM every `interval` so the main loop wakes up from `waitpid`
+/// for periodic work even when no tracee has anything to report.
+pub fn start_ticker(interval: Duration) -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_tick_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe { sigaction(Signal::SIGALRM, &action) }.context("Failed to install SIGALRM handler")?;
+
+    let tv = nix::libc::timeval {
+        tv_sec: interval.as_secs() as nix::libc::time_t,
+        tv_usec: interval.subsec_micros() as nix::libc::suseconds_t,
+    };
+    let timer = nix::libc::itimerval {
+        it_interval: tv,
+        it_value: tv,
+    };
+    // SAFETY: `timer` is a valid itimerval and the old value is not requested.
+    if unsafe { nix::libc::setitimer(nix::libc::ITIMER_REAL, &timer, std::ptr::null_mut()) } != 0 {
+        bail!(
+            "Failed to start interval timer: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Whether a tick has elapsed since the last call.
+pub fn take_tick() -> bool {
+    TICK.swap(false, Ordering::SeqCst)
+}
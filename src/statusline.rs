@@ -0,0 +1,165 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use log::{Log, Metadata, Record};
+
+use crate::clock::{Clock, SystemClock};
+use crate::limiter::Limiter;
+use crate::resources::ResourceProfile;
+use crate::sampler::Sampler;
+
+/// Least time between two redraws, so a busy queue doesn't flicker.
+const MIN_REDRAW: Duration = Duration::from_millis(250);
+
+/// Move to the start of the line and erase it.
+const CLEAR: &str = "\r\x1b[2K";
+
+/// The status line currently drawn at the bottom of stderr, empty if none.
+/// Held while writing to stderr, so log lines and redraws don't interleave.
+static SHOWN: Mutex<String> = Mutex::new(String::new());
+
+/// A line at the bottom of the terminal (`--status-line`) showing how much
+/// of the budget is claimed and how many processes wait, redrawn in place
+/// when that changes. Log lines keep scrolling above it, through
+/// [`StatusLogger`]. Erased on drop.
+pub struct StatusLine {
+    last_draw: Option<Instant>,
+    clock: Box<dyn Clock>,
+}
+
+impl Default for StatusLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusLine {
+    pub fn new() -> Self {
+        Self {
+            last_draw: None,
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Use `clock` instead of the system clock to throttle redraws.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+}
+
+impl Sampler for StatusLine {
+    fn on_tick(&mut self, limiter: &mut Limiter) {
+        let now = self.clock.now();
+        if self
+            .last_draw
+            .is_some_and(|last| now.saturating_duration_since(last) < MIN_REDRAW)
+        {
+            return;
+        }
+        let text = format(limiter.total(), limiter.free(), limiter.paused_count());
+        if draw(&text) {
+            self.last_draw = Some(now);
+        }
+    }
+}
+
+impl Drop for StatusLine {
+    fn drop(&mut self) {
+        draw("");
+    }
+}
+
+/// Replace the status line with `text`, if it differs from what is shown.
+/// Returns whether it was redrawn.
+fn draw(text: &str) -> bool {
+    let mut shown = SHOWN.lock().unwrap_or_else(PoisonError::into_inner);
+    if *shown == text {
+        return false;
+    }
+    eprint!("{}{}", CLEAR, text);
+    text.clone_into(&mut shown);
+    true
+}
+
+/// Text of the status line.
+fn format(total: ResourceProfile, free: ResourceProfile, paused: usize) -> String {
+    let used = total - free;
+    format!(
+        "active {}/{} cpus, {}/{} GiB, {} paused",
+        used.cpus, total.cpus, used.mem_gb, total.mem_gb, paused
+    )
+}
+
+/// Logger for use with a status line: erases the line before each log line
+/// and draws it again after, so that it stays at the bottom.
+pub struct StatusLogger {
+    inner: env_logger::Logger,
+}
+
+impl StatusLogger {
+    pub fn new(inner: env_logger::Logger) -> Self {
+        Self { inner }
+    }
+
+    /// Most verbose level any target is logged at.
+    pub fn max_level(&self) -> log::LevelFilter {
+        self.inner.filter()
+    }
+}
+
+impl Log for StatusLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        let shown = SHOWN.lock().unwrap_or_else(PoisonError::into_inner);
+        if shown.is_empty() {
+            self.inner.log(record);
+            return;
+        }
+        eprint!("{}", CLEAR);
+        self.inner.log(record);
+        eprint!("{}", shown);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::Pid;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn shown() -> String {
+        SHOWN.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_status_line() {
+        let clock = MockClock::new();
+        let mut limiter = Limiter::new(ResourceProfile::new(4, 16), true);
+        let mut status = StatusLine::new().with_clock(clock.clone());
+        status.on_tick(&mut limiter);
+        assert_eq!(shown(), "active 0/4 cpus, 0/16 GiB, 0 paused");
+
+        // Changes are drawn, but not more often than MIN_REDRAW.
+        limiter.on_exec(Pid::from_raw(100), &["rustc".into()], &[], None);
+        status.on_tick(&mut limiter);
+        assert_eq!(shown(), "active 0/4 cpus, 0/16 GiB, 0 paused");
+        clock.advance(MIN_REDRAW);
+        status.on_tick(&mut limiter);
+        assert_eq!(shown(), "active 1/4 cpus, 4/16 GiB, 0 paused");
+
+        drop(status);
+        assert_eq!(shown(), "");
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::Result;
+use nix::unistd::Pid;
+
+use crate::resources::ResourceProfile;
+
+/// What a throttled process was doing during a slice of its timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting in the queue for budget.
+    Paused,
+    /// Admitted, holding `claim`.
+    Running { claim: ResourceProfile },
+}
+
+/// A finished stretch of one process's timeline.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Slice {
+    pub pid: Pid,
+    pub name: String,
+    pub phase: Phase,
+    pub start: Instant,
+    pub end: Instant,
+}
+
+/// When each throttled process was exec'd, waited and ran, for
+/// `--trace-output`. The limiter reports every transition; a slice is kept
+/// once it ends, at the process's next transition or its exit.
+#[derive(Default)]
+pub struct Timeline {
+    /// Exec of each throttled process, as `(pid, name, at)`.
+    execs: Vec<(Pid, String, Instant)>,
+    slices: Vec<Slice>,
+    /// The slice each process is in right now.
+    open: HashMap<Pid, (String, Phase, Instant)>,
+}
+
+impl Timeline {
+    /// A throttled process was exec'd.
+    pub fn exec(&mut self, pid: Pid, name: &str, at: Instant) {
+        self.execs.push((pid, name.to_owned(), at));
+    }
+
+    /// A process was paused to wait for budget.
+    pub fn paused(&mut self, pid: Pid, name: &str, at: Instant) {
+        self.enter(pid, name, Phase::Paused, at);
+    }
+
+    /// A process was admitted with `claim`, ending its wait if it had one.
+    pub fn admitted(&mut self, pid: Pid, name: &str, claim: ResourceProfile, at: Instant) {
+        self.enter(pid, name, Phase::Running { claim }, at);
+    }
+
+    /// A process exited, ending whatever slice it was in.
+    pub fn exited(&mut self, pid: Pid, at: Instant) {
+        if let Some((name, phase, start)) = self.open.remove(&pid) {
+            self.slices.push(Slice {
+                pid,
+                name,
+                phase,
+                start,
+                end: at,
+            });
+        }
+    }
+
+    /// End every open slice at `at`, e.g. on shutdown.
+    pub fn close(&mut self, at: Instant) {
+        let open: Vec<Pid> = self.open.keys().copied().collect();
+        for pid in open {
+            self.exited(pid, at);
+        }
+    }
+
+    pub fn slices(&self) -> &[Slice] {
+        &self.slices
+    }
+
+    fn enter(&mut self, pid: Pid, name: &str, phase: Phase, at: Instant) {
+        self.exited(pid, at);
+        self.open.insert(pid, (name.to_owned(), phase, at));
+    }
+
+    /// Earliest time on the timeline, which trace timestamps count from.
+    #[cfg(feature = "serde")]
+    fn origin(&self) -> Option<Instant> {
+        let execs = self.execs.iter().map(|(_, _, at)| *at);
+        let slices = self.slices.iter().map(|slice| slice.start);
+        execs.chain(slices).min()
+    }
+}
+
+/// Write `timeline` to `path` in the Chrome Trace Event Format, for
+/// `chrome://tracing` or Perfetto: one row per process, named after it, with
+/// an instant event at its exec, a `paused` slice while it waited and a
+/// slice named after the program while it ran, with its claim as arguments.
+#[cfg(feature = "serde")]
+pub fn write(timeline: &Timeline, path: &Path) -> Result<()> {
+    use anyhow::Context;
+
+    let json = serde_json::to_string(&trace_json(timeline, std::process::id()))?;
+    std::fs::write(path, json).with_context(|| format!("Failed to write {:?}", path))
+}
+
+#[cfg(not(feature = "serde"))]
+pub fn write(_timeline: &Timeline, _path: &Path) -> Result<()> {
+    anyhow::bail!("--trace-output needs the serde feature")
+}
+
+/// The trace of `timeline`, with `own_pid` as the process all rows are under.
+#[cfg(feature = "serde")]
+fn trace_json(timeline: &Timeline, own_pid: u32) -> serde_json::Value {
+    use serde_json::json;
+
+    let origin = timeline.origin().unwrap_or_else(Instant::now);
+    let micros = |at: Instant| at.saturating_duration_since(origin).as_micros() as u64;
+    let mut events = vec![json!({
+        "name": "process_name", "ph": "M", "pid": own_pid,
+        "args": { "name": "nix-ubw" },
+    })];
+    let mut named = std::collections::HashSet::new();
+    for (pid, name, at) in &timeline.execs {
+        if named.insert(*pid) {
+            events.push(json!({
+                "name": "thread_name", "ph": "M", "pid": own_pid, "tid": pid.as_raw(),
+                "args": { "name": format!("{} ({})", name, pid) },
+            }));
+        }
+        events.push(json!({
+            "name": "exec", "cat": "exec", "ph": "i", "s": "t",
+            "ts": micros(*at), "pid": own_pid, "tid": pid.as_raw(),
+            "args": { "name": name },
+        }));
+    }
+    for slice in &timeline.slices {
+        let (name, cat, args) = match slice.phase {
+            Phase::Paused => ("paused", "paused", json!({ "name": slice.name })),
+            Phase::Running { claim } => (
+                slice.name.as_str(),
+                "running",
+                json!({ "cpus": claim.cpus, "mem_gb": claim.mem_gb }),
+            ),
+        };
+        events.push(json!({
+            "name": name, "cat": cat, "ph": "X",
+            "ts": micros(slice.start),
+            "dur": slice.end.saturating_duration_since(slice.start).as_micros() as u64,
+            "pid": own_pid, "tid": slice.pid.as_raw(), "args": args,
+        }));
+    }
+    json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_slices() {
+        let t0 = Instant::now();
+        let at = |ms| t0 + Duration::from_millis(ms);
+        let (rustc, gcc) = (Pid::from_raw(100), Pid::from_raw(101));
+        let mut timeline = Timeline::default();
+        timeline.exec(rustc, "rustc", at(0));
+        timeline.paused(rustc, "rustc", at(0));
+        timeline.exec(gcc, "gcc", at(5));
+        timeline.admitted(gcc, "gcc", ResourceProfile::new(1, 1), at(5));
+        timeline.admitted(rustc, "rustc", ResourceProfile::new(1, 4), at(30));
+        timeline.exited(gcc, at(40));
+        // Processes that were never throttled have nothing to end.
+        timeline.exited(Pid::from_raw(200), at(45));
+        timeline.close(at(100));
+
+        let slices: Vec<_> = timeline
+            .slices()
+            .iter()
+            .map(|s| (s.pid, s.phase, s.start, s.end))
+            .collect();
+        let running = |cpus, mem_gb| Phase::Running {
+            claim: ResourceProfile::new(cpus, mem_gb),
+        };
+        assert_eq!(
+            slices,
+            [
+                (rustc, Phase::Paused, at(0), at(30)),
+                (gcc, running(1, 1), at(5), at(40)),
+                (rustc, running(1, 4), at(30), at(100)),
+            ]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_trace_json() {
+        let t0 = Instant::now();
+        let pid = Pid::from_raw(100);
+        let mut timeline = Timeline::default();
+        timeline.exec(pid, "rustc", t0);
+        timeline.paused(pid, "rustc", t0);
+        timeline.admitted(
+            pid,
+            "rustc",
+            ResourceProfile::new(1, 4),
+            t0 + Duration::from_millis(2),
+        );
+        timeline.exited(pid, t0 + Duration::from_millis(5));
+
+        let trace = trace_json(&timeline, 7);
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[1]["args"]["name"], "rustc (100)");
+        assert_eq!(events[2]["ph"], "i");
+        assert_eq!(events[3]["name"], "paused");
+        assert_eq!(events[3]["dur"], 2000);
+        assert_eq!(events[4]["name"], "rustc");
+        assert_eq!(events[4]["ts"], 2000);
+        assert_eq!(events[4]["dur"], 3000);
+        assert_eq!(events[4]["args"]["mem_gb"], 4);
+        assert!(events.iter().all(|e| e["pid"] == 7));
+    }
+}
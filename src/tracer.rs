@@ -1,121 +1,1017 @@
-use log::{debug, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+
+use log::{debug, info, log, warn, Level};
 use nix::libc;
 use nix::sys::ptrace;
 use nix::sys::signal::Signal;
 use nix::sys::wait::WaitStatus;
 use nix::unistd::Pid;
 
-use crate::limiter::Limiter;
+use crate::daemon;
+use crate::events::{EventCounters, EventKind};
+use crate::limiter::{Limiter, LimiterOptions};
 use crate::nixutil;
+use crate::ptrace::{Ptrace, SystemPtrace};
 use crate::resources::ResourceProfile;
 
+/// Log target of per-process events (`[exec]`, `[fork]`, `[exit]`, ...), so
+/// they can be filtered apart from limiter decisions (`--quiet`).
+pub const PROCESS_LOG: &str = "nix_ubw::process";
+
+/// Signals that end a process by default, as sent to cancel a build. A paused
+/// process with one of them pending is let go so it can act on it.
+const TERMINATING: [Signal; 4] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+];
+
+/// What we know about a traced process.
+///
+/// Every thread is a tracee of its own, so threads are tracked too, with the
+/// main thread of their process as the parent. Only processes are ever
+/// classified or throttled: a thread shares the memory and the claim of its
+/// process.
+pub struct TracedProcess {
+    /// The traced process it was forked from, if we saw the fork, or the
+    /// process a thread belongs to.
+    pub parent: Option<Pid>,
+    /// Whether this is a thread of `parent` rather than a process.
+    pub thread: bool,
+}
+
+impl TracedProcess {
+    /// A process we attached to directly.
+    pub fn root() -> Self {
+        Self {
+            parent: None,
+            thread: false,
+        }
+    }
+
+    pub fn child_of(parent: Pid) -> Self {
+        Self {
+            parent: Some(parent),
+            thread: false,
+        }
+    }
+
+    /// A thread of the process `leader`.
+    pub fn thread_of(leader: Pid) -> Self {
+        Self {
+            parent: Some(leader),
+            thread: true,
+        }
+    }
+}
+
 /// All state for the tracer.
 pub struct Tracer {
     /// Concurrency limiter for rate-limited processes.
     pub limiter: Limiter,
+    /// Every process we are currently the tracer of.
+    pub traced: HashMap<Pid, TracedProcess>,
+    /// Processes we are letting go of. They are detached at their next stop
+    /// instead of being continued; children they fork meanwhile join them.
+    detaching: HashSet<Pid>,
+    /// Processes whose every event is logged at info level (`--trace-pid`).
+    /// Children inherit membership at fork.
+    pub focused: HashSet<Pid>,
+    /// Our own PID. Our descendants are never throttled: pausing a helper we
+    /// are waiting on would deadlock us.
+    own_pid: Pid,
+    /// The command we started ourselves (`nix-ubw exec`). Its subtree is
+    /// traced and throttled like a daemon's even though it descends from us.
+    pub launched: Option<Pid>,
+    /// Forks are not traced (`--exec-only`); new processes are found by
+    /// `scan_new_processes`.
+    pub exec_only: bool,
+    /// Processes the scan interrupted to classify at their next stop.
+    late: HashSet<Pid>,
+    /// Forks are not followed below processes this many levels under a
+    /// root (`--max-depth`).
+    pub max_depth: Option<usize>,
+    /// Processes at `max_depth` whose fork tracing is turned off at their
+    /// next stop.
+    leaves: HashSet<Pid>,
+    /// Effective uid of each nix-daemon worker seen so far, read on first use.
+    worker_uids: HashMap<Pid, Option<u32>>,
+    /// Worker uids whose builds are throttled (`--only-uid`); all if empty.
+    pub only_uids: Vec<u32>,
+    /// Fork, exec and exit events handled so far.
+    pub events: EventCounters,
+    /// Every ptrace request and signal goes through here.
+    ptrace: Box<dyn Ptrace>,
 }
 
 impl Tracer {
-    pub fn new(total: ResourceProfile) -> Self {
+    pub fn new(total: ResourceProfile, options: LimiterOptions) -> Self {
         Self {
-            limiter: Limiter::new(total, false),
+            limiter: Limiter::with_options(total, options, false),
+            traced: HashMap::new(),
+            detaching: HashSet::new(),
+            focused: HashSet::new(),
+            own_pid: nix::unistd::getpid(),
+            launched: None,
+            exec_only: false,
+            late: HashSet::new(),
+            max_depth: None,
+            leaves: HashSet::new(),
+            worker_uids: HashMap::new(),
+            only_uids: Vec::new(),
+            events: EventCounters::default(),
+            ptrace: Box::new(SystemPtrace),
+        }
+    }
+
+    /// Make ptrace calls, ours and the limiter's, through `ptrace`, e.g. a
+    /// `MockPtrace` to inspect.
+    pub fn set_ptrace(&mut self, ptrace: impl Ptrace + Clone + 'static) {
+        self.limiter.set_ptrace(ptrace.clone());
+        self.ptrace = Box::new(ptrace);
+    }
+
+    /// Log level for detailed per-event messages about `pid`: info for
+    /// focused subtrees, debug for everything else.
+    fn detail_level(&self, pid: Pid) -> Level {
+        if self.focused.contains(&pid) {
+            Level::Info
+        } else {
+            Level::Debug
+        }
+    }
+
+    /// Stop tracing `pid` and all of its traced descendants, releasing their
+    /// limiter claims. Returns the number of processes being detached.
+    ///
+    /// Paused processes are already stopped and are detached (and thereby
+    /// resumed) right away. Running ones are interrupted and detached from
+    /// the main loop once the interrupt stop arrives.
+    pub fn detach_subtree(&mut self, pid: Pid) -> usize {
+        let subtree = self.subtree(pid);
+        for &p in &subtree {
+            if self.limiter.leaves_group_stop(p) {
+                // Detaching would bring back the group-stop it was admitted
+                // from.
+                let _ = self.ptrace.kill(p, Signal::SIGCONT);
+            }
+            if let Some(held) = self.limiter.forget(p) {
+                self.finish_detach(p, None);
+                if held.group_stop() {
+                    // Detaching leaves the group-stop in place.
+                    let _ = self.ptrace.kill(p, Signal::SIGCONT);
+                }
+            } else if self.detaching.insert(p) {
+                if let Err(e) = self.ptrace.interrupt(p) {
+                    warn!("Failed to interrupt {} for detach: {}", p, e);
+                }
+            }
+        }
+        info!(
+            "Detaching from {} processes under PID {}",
+            subtree.len(),
+            pid
+        );
+        subtree.len()
+    }
+
+    /// `pid` and every traced process descended from it.
+    fn subtree(&self, pid: Pid) -> Vec<Pid> {
+        let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+        for (&child, info) in &self.traced {
+            if let Some(parent) = info.parent {
+                children.entry(parent).or_default().push(child);
+            }
+        }
+        let mut result = Vec::new();
+        let mut stack = vec![pid];
+        while let Some(p) = stack.pop() {
+            if self.traced.contains_key(&p) {
+                result.push(p);
+            }
+            stack.extend(children.get(&p).into_iter().flatten());
+        }
+        result
+    }
+
+    /// Traced ancestors of `pid`, nearest first.
+    fn ancestors(&self, pid: Pid) -> Vec<Pid> {
+        let mut result = Vec::new();
+        let mut current = self.traced.get(&pid).and_then(|p| p.parent);
+        while let Some(parent) = current {
+            // Guard against cycles from PID reuse.
+            if result.contains(&parent) {
+                break;
+            }
+            result.push(parent);
+            current = self.traced.get(&parent).and_then(|p| p.parent);
+        }
+        result
+    }
+
+    /// How many processes `pid` is below the root it descends from. Threads
+    /// don't count, so a thread is as deep as its process.
+    fn depth(&self, pid: Pid) -> usize {
+        let is_process = |p: &Pid| self.traced.get(p).is_some_and(|p| !p.thread);
+        let processes = std::iter::once(pid)
+            .chain(self.ancestors(pid))
+            .filter(is_process)
+            .count();
+        processes.saturating_sub(1)
+    }
+
+    /// Whether `pid` is as deep as `--max-depth` allows, so that its own
+    /// exec is still traced but not what it forks.
+    fn is_leaf(&self, pid: Pid) -> bool {
+        self.max_depth.is_some_and(|max| self.depth(pid) >= max)
+    }
+
+    /// The ptrace options `pid` is traced with.
+    fn trace_options_for(&self, pid: Pid) -> ptrace::Options {
+        if !self.exec_only && self.is_leaf(pid) {
+            return ptrace::Options::PTRACE_O_TRACEEXEC;
+        }
+        daemon::trace_options(self.exec_only)
+    }
+
+    /// The uid builds under `pid` are attributed to: the effective uid of the
+    /// nix-daemon worker (the child of an attached root) it descends from.
+    fn owner_of(&mut self, pid: Pid, ancestors: &[Pid]) -> Option<u32> {
+        // The chain ends at a root, and the worker is the process just below.
+        let chain: Vec<Pid> = std::iter::once(pid)
+            .chain(ancestors.iter().copied())
+            .collect();
+        let root = *chain.last()?;
+        if chain.len() < 2 || self.traced.get(&root).is_none_or(|p| p.parent.is_some()) {
+            return None;
+        }
+        let worker = chain[chain.len() - 2];
+        *self
+            .worker_uids
+            .entry(worker)
+            .or_insert_with(|| nixutil::read_uid(worker))
+    }
+
+    /// Whether `pid` runs under a worker whose uid `--only-uid` leaves out.
+    /// A process that can't be attributed to a uid is in scope.
+    fn out_of_scope(&mut self, pid: Pid, ancestors: &[Pid]) -> bool {
+        !self.only_uids.is_empty()
+            && self
+                .owner_of(pid, ancestors)
+                .is_some_and(|uid| !self.only_uids.contains(&uid))
+    }
+
+    /// Whether `pid` is one of our own descendants outside the launched
+    /// command, e.g. a helper we spawned or, if we run inside the daemon's
+    /// tree, a process that could only have come from us.
+    fn is_own_helper(&self, pid: Pid, ancestors: &[Pid]) -> bool {
+        if let Some(root) = self.launched {
+            if pid == root || ancestors.contains(&root) {
+                return false;
+            }
+        }
+        nixutil::descends_from(pid, self.own_pid)
+    }
+
+    /// Detach a stopped tracee, delivering `sig` if it was stopped by one.
+    fn finish_detach(&mut self, pid: Pid, sig: Option<Signal>) {
+        self.detaching.remove(&pid);
+        self.traced.remove(&pid);
+        self.focused.remove(&pid);
+        match self.ptrace.detach(pid, sig) {
+            Ok(()) => debug!("Detached from PID {}", pid),
+            Err(e) => warn!("Failed to detach from {}: {}", pid, e),
         }
     }
 
     pub fn handle_wait_status(&mut self, status: WaitStatus) {
+        if let Some(pid) = status.pid().filter(|p| self.detaching.contains(p)) {
+            if self.handle_detaching(pid, status) {
+                return;
+            }
+        }
+        // Exhaustive on purpose: a status added by a newer nix has to be
+        // handled explicitly rather than blindly continued, which could resume
+        // a process at the wrong point.
         match status {
             WaitStatus::PtraceEvent(pid, _sig, event) => {
                 self.handle_ptrace_event(pid, event);
             }
             WaitStatus::Stopped(pid, sig) => {
-                let forward = if sig == Signal::SIGTRAP || sig == Signal::SIGSTOP {
+                if TERMINATING.contains(&sig) && self.limiter.holds_stopped(pid) {
+                    // Delivering it ends the job-control stop one way or
+                    // another, so the process is no longer paused.
+                    self.limiter.cancel_pause(pid, sig);
+                }
+                // A SIGSTOP is ours to suppress unless the limiter sent it to
+                // hold a paused process.
+                let ours = sig == Signal::SIGSTOP && !self.limiter.holds_stopped(pid);
+                let forward = if sig == Signal::SIGTRAP || ours {
                     None
                 } else {
                     Some(sig)
                 };
-                debug!("PID {} stopped by {:?}, forwarding={:?}", pid, sig, forward);
-                if let Err(e) = ptrace::cont(pid, forward) {
+                log!(
+                    target: PROCESS_LOG,
+                    self.detail_level(pid),
+                    "PID {} stopped by {:?}, forwarding={:?}",
+                    pid,
+                    sig,
+                    forward
+                );
+                if let Err(e) = self.ptrace.cont(pid, forward) {
                     warn!("Failed to continue {} after {:?}: {}", pid, sig, e);
                 }
             }
             WaitStatus::Exited(pid, code) => {
-                debug!("[exit] PID {} exited with code {}", pid, code);
-                self.limiter.on_exit(pid);
+                log!(
+                    target: PROCESS_LOG,
+                    self.detail_level(pid),
+                    "[exit] PID {} exited with code {}",
+                    pid,
+                    code
+                );
+                self.events.record(EventKind::Exit);
+                self.forget(pid);
             }
             WaitStatus::Signaled(pid, sig, _core) => {
-                debug!("[exit] PID {} killed by {:?}", pid, sig);
-                self.limiter.on_exit(pid);
+                log!(
+                    target: PROCESS_LOG,
+                    self.detail_level(pid),
+                    "[exit] PID {} killed by {:?}",
+                    pid,
+                    sig
+                );
+                self.events.record(EventKind::Exit);
+                self.forget(pid);
             }
-            other => {
-                debug!("PID {:?}: {:?}", other.pid(), other);
-                if let Some(pid) = other.pid() {
-                    let _ = ptrace::cont(pid, None);
+            // Syscall stops only happen after a PTRACE_SYSCALL restart, which
+            // we never issue. If one shows up anyway, continuing with
+            // PTRACE_CONT leaves syscall tracing, so it won't repeat.
+            WaitStatus::PtraceSyscall(pid) => {
+                warn!(
+                    "PID {} stopped at a syscall we did not ask for, continuing",
+                    pid
+                );
+                if let Err(e) = self.ptrace.cont(pid, None) {
+                    warn!("Failed to continue {} after a syscall stop: {}", pid, e);
                 }
             }
+            // Only reported with WCONTINUED, which we don't wait with. The
+            // process is running either way, so there is nothing to do.
+            WaitStatus::Continued(pid) => {
+                log!(
+                    target: PROCESS_LOG,
+                    self.detail_level(pid),
+                    "PID {} continued",
+                    pid
+                );
+            }
+            // Only returned by a WNOHANG wait that found nothing.
+            WaitStatus::StillAlive => {}
         }
     }
 
     fn handle_ptrace_event(&mut self, pid: Pid, event: i32) {
         match event {
             libc::PTRACE_EVENT_FORK | libc::PTRACE_EVENT_VFORK | libc::PTRACE_EVENT_CLONE => {
-                match ptrace::getevent(pid) {
+                match self.ptrace.getevent(pid) {
                     Ok(child_pid_raw) => {
                         let child_pid = Pid::from_raw(child_pid_raw as i32);
-                        let event_name = match event {
-                            libc::PTRACE_EVENT_FORK => "fork",
-                            libc::PTRACE_EVENT_VFORK => "vfork",
-                            libc::PTRACE_EVENT_CLONE => "clone",
-                            _ => unreachable!(),
+                        // PTRACE_EVENT_CLONE is also reported for new threads
+                        // (CLONE_THREAD), whose thread group is not their own.
+                        let leader = (event == libc::PTRACE_EVENT_CLONE)
+                            .then(|| nixutil::read_tgid(child_pid))
+                            .flatten()
+                            .filter(|&tgid| tgid != child_pid);
+                        let kind = match event {
+                            libc::PTRACE_EVENT_FORK => EventKind::Fork,
+                            libc::PTRACE_EVENT_VFORK => EventKind::Vfork,
+                            _ if leader.is_some() => EventKind::Thread,
+                            _ => EventKind::Clone,
                         };
-                        let basename = nixutil::read_cmdline(child_pid)
-                            .and_then(|a| a.into_iter().next())
-                            .unwrap_or_else(|| "<unavailable>".into());
-                        debug!(
-                            "[{}] PID {} -> PID {}: {}",
-                            event_name, pid, child_pid, basename
+                        self.events.record(kind);
+                        let info = match leader {
+                            Some(leader) => TracedProcess::thread_of(leader),
+                            None => TracedProcess::child_of(pid),
+                        };
+                        self.traced.insert(child_pid, info);
+                        if self.focused.contains(&pid) {
+                            self.focused.insert(child_pid);
+                        }
+                        if leader.is_none() && self.is_leaf(child_pid) {
+                            // It inherited our fork tracing. Its first stop
+                            // may already have passed, so make sure there is
+                            // another one to turn that off at.
+                            self.leaves.insert(child_pid);
+                            if let Err(e) = self.ptrace.interrupt(child_pid) {
+                                debug!("Failed to interrupt {}: {}", child_pid, e);
+                            }
+                        }
+                        // The child is only classified at its own exec, so
+                        // its cmdline (still the parent's, or already gone)
+                        // isn't read here.
+                        log!(
+                            target: PROCESS_LOG,
+                            self.detail_level(pid),
+                            "[{}] PID {} -> PID {}",
+                            kind.name(),
+                            pid,
+                            child_pid
                         );
                     }
                     Err(e) => {
                         warn!("Failed to get child PID from {}: {}", pid, e);
                     }
                 }
-                if let Err(e) = ptrace::cont(pid, None) {
+                if let Err(e) = self.ptrace.cont(pid, None) {
                     warn!("Failed to continue {} after fork: {}", pid, e);
                 }
             }
             libc::PTRACE_EVENT_EXEC => {
-                let args = nixutil::read_cmdline(pid);
-                let basename = args
-                    .as_ref()
-                    .and_then(|a| a.first())
-                    .map(|a| a.as_str())
-                    .unwrap_or("<unavailable>");
-
-                if let Some(ref a) = args {
-                    match self.limiter.on_exec(pid, a) {
-                        crate::limiter::OnExecResult::Throttled => {
-                            debug!("[exec] PID {}: {} (throttled)", pid, basename);
-                            // Do not call ptrace::cont - process stays stopped.
-                            return;
-                        }
-                        crate::limiter::OnExecResult::NotThrottled => {}
+                self.events.record(EventKind::Exec);
+                self.handle_exec(pid);
+            }
+            libc::PTRACE_EVENT_EXIT => {
+                // Still in /proc: the last chance to read its CPU time, its
+                // children's and its peak memory.
+                if self.limiter.is_active(pid) {
+                    if let Some(ticks) = nixutil::read_cpu_ticks(pid) {
+                        self.limiter.record_exit_cpu(pid, ticks);
+                    }
+                    if self.limiter.wants_exit_usage() {
+                        self.limiter.record_exit_usage(
+                            pid,
+                            nixutil::read_peak_rss_kb(pid),
+                            nixutil::read_children_cpu_ticks(pid),
+                        );
                     }
                 }
-                debug!("[exec] PID {}: {}", pid, basename);
-                if let Err(e) = ptrace::cont(pid, None) {
-                    warn!("Failed to continue {} after exec: {}", pid, e);
+                if let Err(e) = self.ptrace.cont(pid, None) {
+                    warn!("Failed to continue {} at exit: {}", pid, e);
                 }
             }
             libc::PTRACE_EVENT_STOP => {
-                debug!("PID {} PTRACE_EVENT_STOP", pid);
-                if let Err(e) = ptrace::cont(pid, None) {
+                log!(target: PROCESS_LOG, self.detail_level(pid), "PID {} PTRACE_EVENT_STOP", pid);
+                if self.leaves.remove(&pid) {
+                    log!(
+                        target: PROCESS_LOG,
+                        self.detail_level(pid),
+                        "PID {} is at --max-depth, not following its forks",
+                        pid
+                    );
+                    if let Err(e) = self.ptrace.setoptions(pid, self.trace_options_for(pid)) {
+                        warn!("Failed to stop following the forks of {}: {}", pid, e);
+                    }
+                }
+                if self.late.remove(&pid) {
+                    // Interrupted by the scan while already running a
+                    // throttled program: treat it as if it had just exec'd.
+                    self.handle_exec(pid);
+                } else if self.limiter.stopped_for_pause(pid) {
+                    // Interrupted after its claim grace period and still
+                    // paused: it stays stopped.
+                    log!(
+                        target: PROCESS_LOG,
+                        self.detail_level(pid),
+                        "PID {} stopped after grace period",
+                        pid
+                    );
+                } else if self.limiter.holds_stopped(pid) {
+                    // Group-stop of a paused process: stay stopped, but let
+                    // the SIGCONT that admits it through.
+                    if let Err(e) = self.ptrace.listen(pid) {
+                        warn!("Failed to keep {} stopped: {}", pid, e);
+                    }
+                } else if let Err(e) = self.ptrace.cont(pid, None) {
                     warn!("Failed to continue {} after stop: {}", pid, e);
                 }
             }
             _ => {
                 warn!("PID {} unknown event {}", pid, event);
-                let _ = ptrace::cont(pid, None);
+                let _ = self.ptrace.cont(pid, None);
             }
         }
     }
+
+    /// A process stopped right after exec (or, under `--exec-only`, found
+    /// already running a throttled program): classify it and keep it stopped
+    /// if the limiter pauses it.
+    fn handle_exec(&mut self, pid: Pid) {
+        // Classified here once and for all, even if the scan's interrupt is
+        // still on its way.
+        self.late.remove(&pid);
+        let args = nixutil::read_cmdline(pid);
+        let basename = args
+            .as_ref()
+            .and_then(|a| a.first())
+            .map(|a| a.as_str())
+            .unwrap_or("<unavailable>");
+
+        if let Some(ref a) = args {
+            let ancestors = self.ancestors(pid);
+            if self.out_of_scope(pid, &ancestors) {
+                log!(
+                    target: PROCESS_LOG,
+                    self.detail_level(pid),
+                    "[exec] PID {}: {} (uid not selected by --only-uid)",
+                    pid,
+                    basename
+                );
+                if let Err(e) = self.ptrace.cont(pid, None) {
+                    warn!("Failed to continue {} after exec: {}", pid, e);
+                }
+                return;
+            }
+            // Only walk /proc for processes that would be throttled;
+            // the classification is cached for on_exec below.
+            let matched = self.limiter.classify(pid, a);
+            if matched.is_some() && self.is_own_helper(pid, &ancestors) {
+                debug!(
+                    "[exec] PID {}: {} is our own descendant, not throttling",
+                    pid, basename
+                );
+                if let Err(e) = self.ptrace.cont(pid, None) {
+                    warn!("Failed to continue {} after exec: {}", pid, e);
+                }
+                return;
+            }
+            if matched.is_some() {
+                self.watch_exit(pid);
+            }
+            let owner = self.owner_of(pid, &ancestors);
+            match self.limiter.on_exec(pid, a, &ancestors, owner) {
+                crate::limiter::OnExecResult::Throttled => {
+                    log!(
+                        target: PROCESS_LOG,
+                        self.detail_level(pid),
+                        "[exec] PID {}: {} (throttled, matched rule '{}')",
+                        pid,
+                        basename,
+                        matched.map_or_else(String::new, |m| m.rule.to_string())
+                    );
+                    // Do not call ptrace::cont - process stays stopped.
+                    return;
+                }
+                crate::limiter::OnExecResult::NotThrottled => {}
+            }
+        }
+        log!(
+            target: PROCESS_LOG,
+            self.detail_level(pid),
+            "[exec] PID {}: {}",
+            pid,
+            args.as_deref()
+                .map(nixutil::shell_join)
+                .unwrap_or_else(|| basename.to_owned())
+        );
+        if let Err(e) = self.ptrace.cont(pid, None) {
+            warn!("Failed to continue {} after exec: {}", pid, e);
+        }
+    }
+
+    /// Have a throttled process stop once more when it exits, while its CPU
+    /// time can still be read. Only throttled processes get the extra stop;
+    /// their threads and children inherit it and are continued right away.
+    fn watch_exit(&self, pid: Pid) {
+        let options = self.trace_options_for(pid) | ptrace::Options::PTRACE_O_TRACEEXIT;
+        if let Err(e) = self.ptrace.setoptions(pid, options) {
+            debug!("Failed to watch the exit of {}: {}", pid, e);
+        }
+    }
+
+    /// Resume paused processes that have a terminating signal waiting, e.g.
+    /// from a cancelled build, that will end them: one they block or handle
+    /// doesn't, and they keep waiting for budget. A process held in its exec
+    /// stop can't act on the signal until it is continued, so cancelling
+    /// would otherwise hang until the budget frees up.
+    pub fn cancel_signalled(&mut self) {
+        for entry in self.limiter.paused_summary() {
+            let Some(pending) = nixutil::read_default_pending_signals(entry.pid) else {
+                continue;
+            };
+            if let Some(&sig) = TERMINATING
+                .iter()
+                .find(|&&sig| pending & (1 << (sig as i32 - 1)) != 0)
+            {
+                self.limiter.cancel_pause(entry.pid, sig);
+            }
+        }
+    }
+
+    /// End expired `--claim-grace` periods, interrupting the processes that
+    /// have to wait for budget.
+    pub fn expire_grace(&mut self) {
+        for pid in self.limiter.expire_grace() {
+            if let Err(e) = self.ptrace.interrupt(pid) {
+                warn!("Failed to interrupt {} to pause it: {}", pid, e);
+            }
+        }
+    }
+
+    /// Reclassify processes that renamed themselves after exec
+    /// (`--recheck-comm`), interrupting the ones that now have to wait.
+    pub fn recheck_comm(&mut self) {
+        for pid in self.limiter.recheck_comm() {
+            if let Err(e) = self.ptrace.interrupt(pid) {
+                warn!("Failed to interrupt {} to pause it: {}", pid, e);
+            }
+        }
+    }
+
+    /// The traced processes as an indented tree, one line per process with
+    /// its rule and state, for the control socket's `tree`. Threads are left
+    /// out, and a process forked by one is shown under the thread's process.
+    /// Processes whose parent we never saw are shown as roots.
+    pub fn tree(&self) -> String {
+        let mut children: BTreeMap<Option<Pid>, Vec<Pid>> = BTreeMap::new();
+        for (&pid, info) in self.traced.iter().filter(|(_, p)| !p.thread) {
+            let parent = info
+                .parent
+                .map(|p| self.process_of(p))
+                .filter(|p| self.traced.contains_key(p));
+            children.entry(parent).or_default().push(pid);
+        }
+        for pids in children.values_mut() {
+            pids.sort();
+        }
+        let mut out = String::new();
+        for &root in children.get(&None).into_iter().flatten() {
+            self.render_subtree(root, "", "", &children, &mut out);
+        }
+        out
+    }
+
+    fn render_subtree(
+        &self,
+        pid: Pid,
+        first: &str,
+        rest: &str,
+        children: &BTreeMap<Option<Pid>, Vec<Pid>>,
+        out: &mut String,
+    ) {
+        let (name, rule, state) = self.limiter.classification(pid);
+        let comm;
+        let name = match name {
+            Some(name) => name,
+            None => {
+                comm = fs::read_to_string(format!("/proc/{}/comm", pid));
+                comm.as_deref().unwrap_or("?").trim_end()
+            }
+        };
+        match rule {
+            Some(rule) => *out += &format!("{}{} {} [{}, {}]\n", first, pid, name, rule, state),
+            None => *out += &format!("{}{} {} [{}]\n", first, pid, name, state),
+        }
+        let kids = children.get(&Some(pid)).map_or(&[][..], Vec::as_slice);
+        for (i, &child) in kids.iter().enumerate() {
+            let (first, next) = if i + 1 == kids.len() {
+                ("`-- ", "    ")
+            } else {
+                ("|-- ", "|   ")
+            };
+            self.render_subtree(
+                child,
+                &format!("{}{}", rest, first),
+                &format!("{}{}", rest, next),
+                children,
+                out,
+            );
+        }
+    }
+
+    /// The process a traced thread belongs to, or `pid` itself.
+    fn process_of(&self, mut pid: Pid) -> Pid {
+        while let Some(info) = self.traced.get(&pid).filter(|p| p.thread) {
+            match info.parent {
+                Some(parent) if parent != pid => pid = parent,
+                _ => break,
+            }
+        }
+        pid
+    }
+
+    /// Number of processes we attached to directly (daemons, or the launched
+    /// command) that are still traced.
+    pub fn root_count(&self) -> usize {
+        self.traced.values().filter(|p| p.parent.is_none()).count()
+    }
+
+    /// `--exec-only`: seize descendants of the traced roots that appeared since
+    /// the last scan, so that their execs are seen. One that is already
+    /// running a throttled program is interrupted and handled as if it had
+    /// just exec'd. Returns the number of processes seized.
+    ///
+    /// Processes that fork and exec between two scans are missed entirely;
+    /// that is the price of not stopping on every fork.
+    pub fn scan_new_processes(&mut self) -> usize {
+        let mut stack: Vec<Pid> = self
+            .traced
+            .iter()
+            .filter(|(_, p)| p.parent.is_none())
+            .map(|(&pid, _)| pid)
+            .collect();
+        let mut seized = 0;
+        while let Some(pid) = stack.pop() {
+            for child in nixutil::read_children(pid) {
+                stack.push(child);
+                if self.traced.contains_key(&child)
+                    || self
+                        .ptrace
+                        .seize(child, daemon::trace_options(true))
+                        .is_err()
+                {
+                    continue;
+                }
+                self.traced.insert(child, TracedProcess::child_of(pid));
+                if self.focused.contains(&pid) {
+                    self.focused.insert(child);
+                }
+                seized += 1;
+                let ancestors = self.ancestors(child);
+                let throttled = !self.out_of_scope(child, &ancestors)
+                    && nixutil::read_cmdline(child)
+                        .is_some_and(|args| self.limiter.classify(child, &args).is_some());
+                if throttled && self.ptrace.interrupt(child).is_ok() {
+                    self.late.insert(child);
+                }
+                log!(
+                    target: PROCESS_LOG,
+                    self.detail_level(child),
+                    "[scan] PID {} -> PID {}{}",
+                    pid,
+                    child,
+                    if throttled {
+                        " (running throttled program)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        seized
+    }
+
+    /// Stops of a process being detached end in the detach. Returns whether
+    /// the status was consumed.
+    fn handle_detaching(&mut self, pid: Pid, status: WaitStatus) -> bool {
+        match status {
+            WaitStatus::PtraceEvent(_, _, event) => {
+                if matches!(
+                    event,
+                    libc::PTRACE_EVENT_FORK | libc::PTRACE_EVENT_VFORK | libc::PTRACE_EVENT_CLONE
+                ) {
+                    // The new child was auto-attached; let it go too once it
+                    // reports its initial stop.
+                    if let Ok(child) = self.ptrace.getevent(pid) {
+                        let child = Pid::from_raw(child as i32);
+                        self.traced.insert(child, TracedProcess::child_of(pid));
+                        self.detaching.insert(child);
+                    }
+                }
+                self.finish_detach(pid, None);
+                true
+            }
+            WaitStatus::Stopped(_, sig) => {
+                let forward = (sig != Signal::SIGTRAP && sig != Signal::SIGSTOP).then_some(sig);
+                self.finish_detach(pid, forward);
+                true
+            }
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                self.detaching.remove(&pid);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Drop all state for a process that has exited.
+    fn forget(&mut self, pid: Pid) {
+        let info = self.traced.remove(&pid);
+        let root = info.as_ref().is_some_and(|p| p.parent.is_none());
+        self.worker_uids.remove(&pid);
+        self.late.remove(&pid);
+        self.leaves.remove(&pid);
+        self.focused.remove(&pid);
+        if info.is_some_and(|p| p.thread) {
+            // Its process holds any claim, and exits on its own.
+            return;
+        }
+        self.limiter.on_exit(pid);
+        if root {
+            self.release_orphans(pid);
+        }
+    }
+
+    /// A root we attached to (or launched) is gone, e.g. a nix-daemon killed
+    /// out from under us. Whatever it left behind is no longer part of a
+    /// daemon we trace, so let go of it: paused processes are resumed, and
+    /// their claims and those of running ones are released.
+    fn release_orphans(&mut self, root: Pid) {
+        let orphans = self.subtree(root).len();
+        if orphans > 0 {
+            warn!(
+                "Traced root PID {} exited, releasing {} processes left under it",
+                root, orphans
+            );
+            self.detach_subtree(root);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ptrace::{MockPtrace, PtraceCall};
+
+    #[test]
+    fn test_root_exit_releases_orphans() {
+        let total = ResourceProfile::new(1, 4);
+        let mut tracer = Tracer::new(total, LimiterOptions::default());
+        tracer.limiter = Limiter::new(total, true);
+        let ptrace = MockPtrace::new();
+        tracer.set_ptrace(ptrace.clone());
+        let root = Pid::from_raw(100);
+        let worker = Pid::from_raw(101);
+        tracer.traced.insert(root, TracedProcess::root());
+        tracer.traced.insert(worker, TracedProcess::child_of(root));
+        for pid in [102, 103].map(Pid::from_raw) {
+            tracer.traced.insert(pid, TracedProcess::child_of(worker));
+            tracer
+                .limiter
+                .on_exec(pid, &["cc".into()], &[worker, root], None);
+        }
+        assert_eq!(tracer.limiter.active_summary().len(), 1);
+        assert_eq!(tracer.limiter.paused_summary().len(), 1);
+
+        ptrace.take_calls();
+        tracer.handle_wait_status(WaitStatus::Signaled(root, Signal::SIGKILL, false));
+
+        assert!(tracer.limiter.active_summary().is_empty());
+        assert!(tracer.limiter.paused_summary().is_empty());
+        assert_eq!(tracer.limiter.free(), total);
+        assert!(!tracer.traced.contains_key(&root));
+        // Each is let go of: right away if still paused, or else interrupted
+        // to be detached at its next stop.
+        let calls = ptrace.calls();
+        for pid in [101, 102, 103].map(Pid::from_raw) {
+            assert!(
+                calls.contains(&PtraceCall::Detach(pid, None))
+                    || calls.contains(&PtraceCall::Interrupt(pid)),
+                "{}",
+                pid
+            );
+        }
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let total = ResourceProfile::new(1, 4);
+        let mut tracer = Tracer::new(total, LimiterOptions::default());
+        let [root, worker, shell, thread, make, cc] =
+            [100, 101, 102, 103, 104, 105].map(Pid::from_raw);
+        tracer.traced.insert(root, TracedProcess::root());
+        tracer.traced.insert(worker, TracedProcess::child_of(root));
+        tracer.traced.insert(shell, TracedProcess::child_of(worker));
+        tracer
+            .traced
+            .insert(thread, TracedProcess::thread_of(shell));
+        tracer.traced.insert(make, TracedProcess::child_of(thread));
+        tracer.traced.insert(cc, TracedProcess::child_of(make));
+        assert_eq!(tracer.depth(root), 0);
+        assert_eq!(tracer.depth(thread), 2);
+        assert_eq!(tracer.depth(make), 3);
+        assert_eq!(tracer.trace_options_for(cc), daemon::trace_options(false));
+
+        tracer.max_depth = Some(3);
+        assert!(!tracer.is_leaf(shell));
+        assert!(tracer.is_leaf(make));
+        assert_eq!(
+            tracer.trace_options_for(make),
+            ptrace::Options::PTRACE_O_TRACEEXEC
+        );
+        assert_eq!(
+            tracer.trace_options_for(shell),
+            daemon::trace_options(false)
+        );
+    }
+
+    #[test]
+    fn test_only_uid() {
+        let total = ResourceProfile::new(1, 4);
+        let mut tracer = Tracer::new(total, LimiterOptions::default());
+        let root = Pid::from_raw(100);
+        tracer.traced.insert(root, TracedProcess::root());
+        for (worker, uid) in [(101, Some(1000)), (201, Some(2000)), (301, None)] {
+            let worker = Pid::from_raw(worker);
+            tracer.traced.insert(worker, TracedProcess::child_of(root));
+            tracer.worker_uids.insert(worker, uid);
+            let child = Pid::from_raw(worker.as_raw() + 1);
+            let grandchild = Pid::from_raw(worker.as_raw() + 2);
+            tracer.traced.insert(child, TracedProcess::child_of(worker));
+            tracer
+                .traced
+                .insert(grandchild, TracedProcess::child_of(child));
+        }
+        let out_of_scope = |tracer: &mut Tracer, pid: i32| {
+            let pid = Pid::from_raw(pid);
+            let ancestors = tracer.ancestors(pid);
+            tracer.out_of_scope(pid, &ancestors)
+        };
+        assert!(!out_of_scope(&mut tracer, 203));
+
+        tracer.only_uids = vec![1000];
+        assert!(!out_of_scope(&mut tracer, 102));
+        assert!(!out_of_scope(&mut tracer, 103));
+        // Descendants share their worker's uid.
+        assert!(out_of_scope(&mut tracer, 202));
+        assert!(out_of_scope(&mut tracer, 203));
+        // Without a known uid, everything is traced.
+        assert!(!out_of_scope(&mut tracer, 302));
+        assert!(!out_of_scope(&mut tracer, 100));
+    }
+
+    #[test]
+    fn test_threads_stay_out_of_the_limiter() {
+        let total = ResourceProfile::new(1, 4);
+        let mut tracer = Tracer::new(total, LimiterOptions::default());
+        tracer.limiter = Limiter::new(total, true);
+        tracer.set_ptrace(MockPtrace::new());
+        let root = Pid::from_raw(100);
+        let compiler = Pid::from_raw(101);
+        let thread = Pid::from_raw(102);
+        let linker = Pid::from_raw(103);
+        tracer.traced.insert(root, TracedProcess::root());
+        tracer
+            .traced
+            .insert(compiler, TracedProcess::child_of(root));
+        tracer
+            .limiter
+            .on_exec(compiler, &["rustc".into()], &[root], None);
+        tracer
+            .traced
+            .insert(thread, TracedProcess::thread_of(compiler));
+        tracer
+            .traced
+            .insert(linker, TracedProcess::child_of(thread));
+
+        // A process started from a thread descends from the thread's process.
+        assert!(tracer.ancestors(linker).contains(&compiler));
+
+        tracer.handle_wait_status(WaitStatus::Exited(thread, 0));
+        assert!(!tracer.traced.contains_key(&thread));
+        let active = tracer.limiter.active_summary();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].pid, compiler);
+    }
+
+    #[test]
+    fn test_tree() {
+        let total = ResourceProfile::new(1, 4);
+        let mut tracer = Tracer::new(total, LimiterOptions::default());
+        tracer.limiter = Limiter::new(total, true);
+        // PIDs that don't exist, so that no names are read from /proc.
+        let pid = |n: i32| Pid::from_raw(i32::MAX - n);
+        tracer.traced.insert(pid(0), TracedProcess::root());
+        tracer
+            .traced
+            .insert(pid(1), TracedProcess::child_of(pid(0)));
+        tracer
+            .traced
+            .insert(pid(2), TracedProcess::child_of(pid(1)));
+        tracer
+            .traced
+            .insert(pid(3), TracedProcess::thread_of(pid(2)));
+        tracer
+            .traced
+            .insert(pid(4), TracedProcess::child_of(pid(1)));
+        tracer
+            .traced
+            .insert(pid(5), TracedProcess::child_of(pid(0)));
+        // Forked by a thread: shown under the thread's process.
+        tracer
+            .traced
+            .insert(pid(6), TracedProcess::child_of(pid(3)));
+        tracer
+            .limiter
+            .on_exec(pid(2), &["cc".into()], &[pid(1), pid(0)], None);
+        tracer
+            .limiter
+            .on_exec(pid(4), &["rustc".into()], &[pid(1), pid(0)], None);
+        tracer
+            .limiter
+            .on_exec(pid(5), &["sh".into()], &[pid(0)], None);
+
+        let expected = [
+            format!("{} ? [unthrottled]", pid(0)),
+            format!("|-- {} sh [unthrottled]", pid(5)),
+            format!("`-- {} ? [unthrottled]", pid(1)),
+            format!("    |-- {} rustc [rustc, paused]", pid(4)),
+            format!("    `-- {} cc [c-compiler, active]", pid(2)),
+            format!("        `-- {} ? [unthrottled]", pid(6)),
+        ];
+        assert_eq!(tracer.tree(), expected.join("\n") + "\n");
+    }
 }